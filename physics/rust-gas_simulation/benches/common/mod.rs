@@ -0,0 +1,44 @@
+//! The gas the benchmarks run on.
+
+use std::f32::consts::TAU;
+
+use bevy::math::Vec2;
+use gas_simulation::{geometry::polygon_edges, physics::Gas, units::Units};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+pub const RADIUS: f32 = 5.;
+pub const SPEED: f32 = 1103.3;
+pub const DT: f64 = 1. / 256.;
+pub const WALL_THICKNESS: f32 = 10.;
+
+/// A square box with particles on a grid, a few diameters apart, moving in random directions.
+pub fn gas(number: usize) -> Gas {
+    let per_row = (number as f32).sqrt().ceil() as usize;
+    let spacing = 4. * RADIUS;
+    let half = per_row as f32 * spacing / 2. + spacing;
+    let mut rng = StdRng::seed_from_u64(0);
+    let positions: Vec<Vec2> = (0..number)
+        .map(|i| {
+            let (row, column) = (i / per_row, i % per_row);
+            Vec2::new(column as f32, row as f32) * spacing - Vec2::splat(half - spacing)
+        })
+        .collect();
+    let velocities: Vec<Vec2> = (0..number)
+        .map(|_| Vec2::from_angle(rng.gen_range(0.0..TAU)) * SPEED)
+        .collect();
+    let corners = [
+        Vec2::new(-half, half),
+        Vec2::new(half, half),
+        Vec2::new(half, -half),
+        Vec2::new(-half, -half),
+    ];
+    Gas::new(
+        &positions,
+        &velocities,
+        RADIUS,
+        1.,
+        &polygon_edges(&corners),
+        WALL_THICKNESS,
+        Units::SI,
+    )
+}
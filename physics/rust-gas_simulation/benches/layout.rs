@@ -0,0 +1,139 @@
+//! A physics step on the packed arrays of the gas against the same step on particle entities,
+//! which have to be gathered into arrays for the collisions and written back every tick. Run with
+//! `cargo bench --bench layout`.
+
+use std::hint::black_box;
+
+use bevy::{
+    math::{DVec2, Vec2},
+    prelude::*,
+    tasks::{ComputeTaskPool, TaskPool},
+};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use gas_simulation::{
+    geometry::WallShape,
+    physics::{collide_particles, wall_bounce, wall_contact, Gas},
+};
+
+use common::{gas, DT};
+
+mod common;
+
+#[derive(Component)]
+struct Particle {
+    radius: f32,
+    velocity: Vec2,
+}
+
+#[derive(Component)]
+struct Wall {
+    shape: WallShape,
+    thickness: f32,
+}
+
+/// The gas as one entity per particle and per wall.
+fn world(gas: &Gas) -> World {
+    // Parallel queries run on bevy's task pool, which the app would otherwise set up
+    ComputeTaskPool::get_or_init(TaskPool::default);
+    let mut world = World::new();
+    for i in 0..gas.positions.len() {
+        world.spawn((
+            Particle {
+                radius: gas.radii[i] as f32,
+                velocity: gas.velocity(i),
+            },
+            Transform::from_translation(gas.position(i).extend(0.)),
+        ));
+    }
+    for (shape, thickness) in &gas.walls {
+        world.spawn(Wall {
+            shape: shape.clone(),
+            thickness: *thickness as f32,
+        });
+    }
+    world
+}
+
+/// One tick the way the physics systems did it on entities.
+fn step_entities(
+    world: &mut World,
+    particles: &mut QueryState<(&mut Particle, &mut Transform)>,
+    walls: &mut QueryState<&Wall>,
+) {
+    particles
+        .par_iter_mut(world)
+        .for_each(|(particle, mut transform)| {
+            transform.translation += (particle.velocity * DT as f32).extend(0.);
+        });
+
+    let mut positions = Vec::new();
+    let mut velocities = Vec::new();
+    let mut radii = Vec::new();
+    let mut previous = Vec::new();
+    for (particle, transform) in particles.iter(world) {
+        let position = transform.translation.xy().as_dvec2();
+        positions.push(position);
+        velocities.push(particle.velocity.as_dvec2());
+        radii.push(particle.radius as f64);
+        previous.push(position - particle.velocity.as_dvec2() * DT);
+    }
+    let mut spins = vec![0.; positions.len()];
+    collide_particles(
+        &mut positions,
+        &mut velocities,
+        &mut spins,
+        &radii,
+        &previous,
+        DT,
+        [1., -1.],
+    );
+    for (i, (mut particle, mut transform)) in particles.iter_mut(world).enumerate() {
+        particle.velocity = velocities[i].as_vec2();
+        transform.translation = positions[i].as_vec2().extend(transform.translation.z);
+    }
+
+    let walls: Vec<(WallShape, f64)> = walls
+        .iter(world)
+        .map(|wall| (wall.shape.clone(), wall.thickness as f64))
+        .collect();
+    particles
+        .par_iter_mut(world)
+        .for_each(|(mut particle, mut transform)| {
+            for (shape, thickness) in &walls {
+                let position: DVec2 = transform.translation.xy().as_dvec2();
+                let velocity = particle.velocity.as_dvec2();
+                let radius = particle.radius as f64;
+                if let Some((normal, shift)) = wall_contact(position, radius, shape, *thickness) {
+                    if let Some((velocity, _)) =
+                        wall_bounce(velocity, 0., radius, normal, DVec2::ZERO, [1., -1.])
+                    {
+                        particle.velocity = velocity.as_vec2();
+                    }
+                    transform.translation -= shift.as_vec2().extend(0.);
+                }
+            }
+        });
+}
+
+fn layout(c: &mut Criterion) {
+    for number in [1_000, 10_000] {
+        let mut group = c.benchmark_group(format!("layout {number} particles"));
+        group.sample_size(20);
+
+        let mut arrays = gas(number);
+        group.bench_function(BenchmarkId::new("arrays", number), |b| {
+            b.iter(|| black_box(arrays.step(DT)))
+        });
+
+        let mut world = world(&gas(number));
+        let mut particles = world.query::<(&mut Particle, &mut Transform)>();
+        let mut walls = world.query::<&Wall>();
+        group.bench_function(BenchmarkId::new("entities", number), |b| {
+            b.iter(|| step_entities(&mut world, &mut particles, &mut walls))
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, layout);
+criterion_main!(benches);
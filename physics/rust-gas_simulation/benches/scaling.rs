@@ -0,0 +1,38 @@
+//! How the physics step scales with the number of threads, for a few gas sizes. Run with
+//! `cargo bench --bench scaling`.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use common::{gas, DT};
+
+mod common;
+
+fn thread_counts() -> Vec<usize> {
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut counts: Vec<usize> = (0..).map(|p| 1 << p).take_while(|t| *t < cores).collect();
+    counts.push(cores);
+    counts
+}
+
+fn step_scaling(c: &mut Criterion) {
+    for number in [1_000, 10_000, 50_000] {
+        let mut group = c.benchmark_group(format!("step {number} particles"));
+        group.sample_size(20);
+        for threads in thread_counts() {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let mut gas = gas(number);
+            group.bench_with_input(BenchmarkId::new("threads", threads), &threads, |b, _| {
+                pool.install(|| b.iter(|| black_box(gas.step(DT))));
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, step_scaling);
+criterion_main!(benches);
@@ -0,0 +1,94 @@
+use std::mem;
+
+use bevy::{app::RunFixedMainLoopSystem, prelude::*};
+
+use crate::{physics::Gas, playback::physics_running, SimulationConfig};
+
+/// Shortest time step, in seconds. Particles too fast for it are handled by substeps of the gas.
+const MIN_TIMESTEP: f64 = 1. / 16384.;
+/// Range of the playback speed, as a multiple of real time.
+pub const MIN_PLAYBACK_SPEED: f64 = 1. / 64.;
+pub const MAX_PLAYBACK_SPEED: f64 = 16.;
+
+/// Keeps simulated time apart from real time. Every physics step advances the
+/// [`SimulationClock`] by a time step adapted to the fastest particle, so that it never moves
+/// more than a fraction of a radius, and no longer than one over the configured physics rate.
+/// The playback speed is the speed of bevy's virtual time, which the fixed timestep loop
+/// consumes: it only changes how many physics steps run per rendered frame, never their length.
+pub struct ClockPlugin;
+
+impl Plugin for ClockPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SimulationClock>()
+            .add_systems(crate::BuildSimulation, start_clock)
+            .add_systems(
+                RunFixedMainLoop,
+                (
+                    adapt_timestep.in_set(RunFixedMainLoopSystem::BeforeFixedMainLoop),
+                    count_steps.in_set(RunFixedMainLoopSystem::AfterFixedMainLoop),
+                ),
+            )
+            .add_systems(FixedFirst, advance_clock.run_if(physics_running))
+            .add_systems(FixedLast, adapt_timestep);
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct SimulationClock {
+    /// Simulated time since the start, in seconds
+    pub elapsed: f64,
+    /// Length of the current physics step, in seconds
+    pub dt: f64,
+    /// Number of physics steps run during the last frame
+    pub steps_per_frame: u32,
+    /// Number of physics steps run so far during this frame
+    steps: u32,
+    /// Time steps to take next instead of adapted ones, last first
+    replay: Vec<f64>,
+}
+
+impl SimulationClock {
+    /// Take these time steps next, last first, so that a run can retrace its steps backwards.
+    pub fn replay(&mut self, timesteps: Vec<f64>) {
+        self.replay = timesteps;
+    }
+
+    /// Go back to a point in simulated time, dropping the time steps left to replay.
+    pub fn rewind(&mut self, elapsed: f64) {
+        self.elapsed = elapsed;
+        self.replay.clear();
+    }
+}
+
+fn start_clock(mut clock: ResMut<SimulationClock>) {
+    *clock = SimulationClock::default();
+}
+
+/// Set the length of the next physics step.
+fn adapt_timestep(
+    clock: Res<SimulationClock>,
+    gas: Option<Res<Gas>>,
+    config: Res<SimulationConfig>,
+    mut time: ResMut<Time<Fixed>>,
+) {
+    let longest = 1. / config.physics_rate;
+    let timestep = match (clock.replay.last(), gas) {
+        (Some(timestep), _) => *timestep,
+        (None, Some(gas)) => gas.timestep().clamp(MIN_TIMESTEP, longest),
+        (None, None) => longest,
+    };
+    if time.timestep().as_secs_f64() != timestep {
+        time.set_timestep_seconds(timestep);
+    }
+}
+
+fn advance_clock(mut clock: ResMut<SimulationClock>, time: Res<Time>) {
+    clock.replay.pop();
+    clock.dt = time.delta_secs_f64();
+    clock.elapsed += clock.dt;
+    clock.steps += 1;
+}
+
+fn count_steps(mut clock: ResMut<SimulationClock>) {
+    clock.steps_per_frame = mem::take(&mut clock.steps);
+}
@@ -0,0 +1,285 @@
+use std::f32::consts::SQRT_2;
+
+use bevy::{math::NormedVectorSpace, prelude::*, sprite::Anchor};
+
+use crate::{
+    colormap::{hue, viridis},
+    order::{update_bond_orders, BondOrders},
+    scenario::Scenario,
+    Particle, ParticleHistory, SimulationConfig, BOLTZMANN_CONSTANT, WALL_THICKNESS,
+};
+
+/// Number of materials the colormap is split into. Particles share these materials instead of
+/// each having their own, so recoloring them every frame doesn't allocate anything.
+const PALETTE_SIZE: usize = 64;
+const LEGEND_WIDTH: f32 = 200.;
+const LEGEND_HEIGHT: f32 = 12.;
+const LEGEND_STEPS: usize = 32;
+
+/// Color particles according to some scalar quantity (press C to switch).
+pub struct ColoringPlugin;
+
+impl Plugin for ColoringPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ColorBy>()
+            .add_systems(Startup, setup_coloring)
+            .add_systems(
+                Update,
+                (cycle_color_by, update_bond_orders, color_particles, update_legend).chain(),
+            );
+    }
+}
+
+/// The quantity particles are colored by.
+#[derive(Resource, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ColorBy {
+    #[default]
+    Nothing,
+    Speed,
+    KineticEnergy,
+    TimeSinceCollision,
+    Collisions,
+    /// Horizontal position the particle started from
+    Origin,
+    /// Modulus of the local bond orientational order ψ6
+    BondOrder,
+    /// Angle the hexagon of neighbours is turned by, from the phase of ψ6
+    BondOrientation,
+}
+
+#[derive(Resource)]
+struct Palette {
+    plain: Handle<ColorMaterial>,
+    colors: Vec<Handle<ColorMaterial>>,
+    /// Colors of a colormap which wraps around, for angles
+    cyclic: Vec<Handle<ColorMaterial>>,
+}
+
+/// The range of values the colormap currently spans, for the legend.
+#[derive(Resource, Default)]
+struct ColorRange {
+    min: f32,
+    max: f32,
+}
+
+/// A step of the colorbar, at a value between 0 and 1.
+#[derive(Component)]
+struct Legend(f32);
+
+#[derive(Component, Clone, Copy)]
+enum LegendText {
+    Label,
+    Min,
+    Max,
+}
+
+impl ColorBy {
+    pub const ALL: [ColorBy; 8] = [
+        ColorBy::Nothing,
+        ColorBy::Speed,
+        ColorBy::KineticEnergy,
+        ColorBy::TimeSinceCollision,
+        ColorBy::Collisions,
+        ColorBy::Origin,
+        ColorBy::BondOrder,
+        ColorBy::BondOrientation,
+    ];
+
+    fn next(self) -> Self {
+        match self {
+            ColorBy::Nothing => ColorBy::Speed,
+            ColorBy::Speed => ColorBy::KineticEnergy,
+            ColorBy::KineticEnergy => ColorBy::TimeSinceCollision,
+            ColorBy::TimeSinceCollision => ColorBy::Collisions,
+            ColorBy::Collisions => ColorBy::Origin,
+            ColorBy::Origin => ColorBy::BondOrder,
+            ColorBy::BondOrder => ColorBy::BondOrientation,
+            ColorBy::BondOrientation => ColorBy::Nothing,
+        }
+    }
+
+    /// Whether the quantity is an angle, colored with a colormap that wraps around.
+    fn cyclic(self) -> bool {
+        self == ColorBy::BondOrientation
+    }
+
+    fn value(self, particle: &Particle, history: &ParticleHistory, orders: &BondOrders) -> f32 {
+        let order = orders.local.get(particle.index).copied().unwrap_or_default();
+        match self {
+            ColorBy::Nothing => 0.,
+            ColorBy::Speed => particle.velocity.norm(),
+            ColorBy::KineticEnergy => {
+                particle.mass / 2. * particle.velocity.norm_squared() / BOLTZMANN_CONSTANT
+            }
+            ColorBy::TimeSinceCollision => history.time_since_collision,
+            ColorBy::Collisions => history.collisions as f32,
+            ColorBy::Origin => history.origin.x,
+            ColorBy::BondOrder => order.length() as f32,
+            // The hexagon looks the same turned by 60°
+            ColorBy::BondOrientation => (order.to_angle() / 6.).to_degrees() as f32,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ColorBy::Nothing => "nothing",
+            ColorBy::Speed => "speed [m/s]",
+            ColorBy::KineticEnergy => "kinetic energy [K]",
+            ColorBy::TimeSinceCollision => "time since last collision [s]",
+            ColorBy::Collisions => "number of collisions",
+            ColorBy::Origin => "starting x position [m]",
+            ColorBy::BondOrder => "bond order |ψ6|",
+            ColorBy::BondOrientation => "bond orientation [°]",
+        }
+    }
+}
+
+fn setup_coloring(
+    mut commands: Commands,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    scenario: Res<Scenario>,
+) {
+    let colors: Vec<Handle<ColorMaterial>> = (0..PALETTE_SIZE)
+        .map(|i| {
+            let t = i as f32 / (PALETTE_SIZE - 1) as f32;
+            materials.add(ColorMaterial::from_color(viridis(t)))
+        })
+        .collect();
+    let cyclic: Vec<Handle<ColorMaterial>> = (0..PALETTE_SIZE)
+        .map(|i| materials.add(ColorMaterial::from_color(hue(i as f32 / PALETTE_SIZE as f32))))
+        .collect();
+    commands.insert_resource(Palette {
+        plain: materials.add(ColorMaterial::from_color(Color::WHITE)),
+        colors,
+        cyclic,
+    });
+    commands.init_resource::<ColorRange>();
+
+    // Colorbar above the top right corner of the container
+    let bounds = scenario.bounds(WALL_THICKNESS);
+    let step = LEGEND_WIDTH / LEGEND_STEPS as f32;
+    for i in 0..LEGEND_STEPS {
+        let t = i as f32 / (LEGEND_STEPS - 1) as f32;
+        commands.spawn((
+            Legend(t),
+            Sprite {
+                color: viridis(t).into(),
+                custom_size: Some(Vec2::new(step, LEGEND_HEIGHT)),
+                ..default()
+            },
+            Transform::from_xyz(
+                bounds.max.x - LEGEND_WIDTH + (i as f32 + 0.5) * step,
+                bounds.max.y + 20.,
+                0.,
+            ),
+            Visibility::Hidden,
+        ));
+    }
+    let bar_top = bounds.max.y + 20. + LEGEND_HEIGHT / 2.;
+    let bar_bottom = bounds.max.y + 20. - LEGEND_HEIGHT / 2.;
+    for (text, x, y, anchor) in [
+        (LegendText::Label, bounds.max.x, bar_top + 5., Anchor::BottomRight),
+        (LegendText::Min, bounds.max.x - LEGEND_WIDTH, bar_bottom, Anchor::TopLeft),
+        (LegendText::Max, bounds.max.x, bar_bottom, Anchor::TopRight),
+    ] {
+        commands.spawn((
+            Text2d::new(""),
+            text,
+            Transform::from_xyz(x, y, 0.),
+            anchor,
+            TextFont::from_font_size(14.),
+        ));
+    }
+}
+
+fn cycle_color_by(keys: Res<ButtonInput<KeyCode>>, mut color_by: ResMut<ColorBy>) {
+    if keys.just_pressed(KeyCode::KeyC) {
+        *color_by = color_by.next();
+    }
+}
+
+/// Pick the material of every particle from the palette.
+fn color_particles(
+    color_by: Res<ColorBy>,
+    palette: Res<Palette>,
+    scenario: Res<Scenario>,
+    config: Res<SimulationConfig>,
+    orders: Res<BondOrders>,
+    mut range: ResMut<ColorRange>,
+    mut particles: Query<(
+        &Particle,
+        &ParticleHistory,
+        &mut MeshMaterial2d<ColorMaterial>,
+    )>,
+) {
+    let (min, max) = match *color_by {
+        ColorBy::Nothing => (0., 1.),
+        ColorBy::Speed => (0., config.max_speed),
+        // The energy distribution is exponential with mean kT, so this covers almost everything
+        ColorBy::KineticEnergy => (0., 4. * config.temperature),
+        ColorBy::TimeSinceCollision => {
+            // Mean free path of hard discs in 2D, divided by the average speed
+            let density = particles.iter().len() as f32 / scenario.free_area(WALL_THICKNESS);
+            let mean_free_path = 1. / (SQRT_2 * density * 2. * config.particle_radius);
+            (0., 3. * mean_free_path / config.starting_speed())
+        }
+        ColorBy::Collisions => {
+            let most = particles.iter().map(|(_, h, _)| h.collisions).max();
+            (0., most.unwrap_or(0).max(1) as f32)
+        }
+        ColorBy::Origin => {
+            let bounds = scenario.bounds(WALL_THICKNESS);
+            (bounds.min.x, bounds.max.x)
+        }
+        ColorBy::BondOrder => (0., 1.),
+        ColorBy::BondOrientation => (-30., 30.),
+    };
+    *range = ColorRange { min, max };
+
+    for (particle, history, mut material) in &mut particles {
+        let handle = if *color_by == ColorBy::Nothing {
+            &palette.plain
+        } else if color_by.cyclic() {
+            let t = (color_by.value(particle, history, &orders) - min) / (max - min);
+            let i = (t * PALETTE_SIZE as f32).round() as usize;
+            &palette.cyclic[i % PALETTE_SIZE]
+        } else {
+            let t = (color_by.value(particle, history, &orders) - min) / (max - min);
+            let i = (t.clamp(0., 1.) * (PALETTE_SIZE - 1) as f32).round() as usize;
+            &palette.colors[i]
+        };
+        // Only touch the component when needed to avoid triggering change detection
+        if material.0 != *handle {
+            material.0 = handle.clone();
+        }
+    }
+}
+
+fn update_legend(
+    color_by: Res<ColorBy>,
+    range: Res<ColorRange>,
+    mut legend: Query<(&Legend, &mut Sprite, &mut Visibility)>,
+    mut text_query: Query<(&LegendText, &mut Text2d)>,
+) {
+    let shown = *color_by != ColorBy::Nothing;
+    for (Legend(t), mut sprite, mut visibility) in &mut legend {
+        if color_by.is_changed() {
+            let color = if color_by.cyclic() { hue(*t) } else { viridis(*t) };
+            sprite.color = color.into();
+        }
+        *visibility = if shown {
+            Visibility::Visible
+        } else {
+            Visibility::Hidden
+        };
+    }
+
+    for (kind, mut text) in &mut text_query {
+        text.0 = match kind {
+            LegendText::Label => format!("Color: {} (C to change)", color_by.label()),
+            LegendText::Min if shown => format!("{:.3}", range.min),
+            LegendText::Max if shown => format!("{:.3}", range.max),
+            _ => String::new(),
+        };
+    }
+}
@@ -0,0 +1,25 @@
+use bevy::prelude::*;
+
+/// A few samples of matplotlib's viridis colormap, evenly spaced from 0 to 1.
+const VIRIDIS: [Srgba; 6] = [
+    Srgba::rgb(0.267, 0.005, 0.329),
+    Srgba::rgb(0.254, 0.265, 0.530),
+    Srgba::rgb(0.164, 0.471, 0.558),
+    Srgba::rgb(0.135, 0.659, 0.518),
+    Srgba::rgb(0.478, 0.821, 0.319),
+    Srgba::rgb(0.993, 0.906, 0.144),
+];
+
+/// Map a value between 0 and 1 to a color of the viridis colormap. Values outside of the range
+/// are clamped.
+pub fn viridis(t: f32) -> Srgba {
+    let scaled = t.clamp(0., 1.) * (VIRIDIS.len() - 1) as f32;
+    let i = (scaled.floor() as usize).min(VIRIDIS.len() - 2);
+    VIRIDIS[i].mix(&VIRIDIS[i + 1], scaled - i as f32)
+}
+
+/// Map a value between 0 and 1 to a color going once around the color wheel, so that both ends
+/// look the same. For angles, which wrap around.
+pub fn hue(t: f32) -> Srgba {
+    Hsla::hsl(360. * t.rem_euclid(1.), 0.75, 0.55).into()
+}
@@ -0,0 +1,275 @@
+use std::collections::VecDeque;
+
+use bevy::{math::DVec2, prelude::*};
+use bevy_egui::{egui, EguiContexts};
+use egui_plot::{HLine, Legend, Line, Plot};
+
+use crate::{
+    clock::SimulationClock, physics::Gas, playback::physics_running, scenario::Scenario,
+    SimulationState,
+};
+
+/// Relative drift above which the alarm goes off, unless changed in the window.
+const DEFAULT_TOLERANCE: f64 = 1e-9;
+/// Number of ticks of drift kept for the plot.
+const HISTORY_LENGTH: usize = 4096;
+
+/// Checks every tick that the physics conserves what it should. Hard discs and walls have no
+/// potential energy, so the total energy is the kinetic energy, which elastic collisions keep
+/// constant. Its drift is still plotted but can't raise the alarm when the gas is made to lose or
+/// gain energy, by inelastic collisions, random kicks or moving walls. The particles alone only
+/// keep their momentum in a periodic box, so the momentum they give to the walls is added back:
+/// that balance holds in any container. Drift is measured from
+/// the state of the gas when the simulation starts or is changed on purpose (temperature changes,
+/// time reversal, rewinding), and a warning is raised when it goes past the tolerance. Particles
+/// that end up outside of the container despite the swept collision tests are counted as well.
+pub struct ConservationPlugin;
+
+impl Plugin for ConservationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Conservation>()
+            .add_systems(OnExit(SimulationState::Setup), start_monitor)
+            .add_systems(
+                FixedPostUpdate,
+                (check_conservation, count_escapes).run_if(physics_running),
+            )
+            .add_systems(Update, conservation_window);
+    }
+}
+
+#[derive(Resource)]
+pub struct Conservation {
+    /// Largest relative drift of the energy or of the momentum balance before raising the alarm
+    pub tolerance: f64,
+    /// Energy and momentum balance the drift is measured from
+    baseline: Option<(f64, DVec2)>,
+    /// Time since the baseline was taken
+    time: f64,
+    /// Relative drift of the energy and of the momentum balance against time
+    energy_drift: VecDeque<[f64; 2]>,
+    momentum_drift: VecDeque<[f64; 2]>,
+    /// Whether the drift is past the tolerance
+    alarm: bool,
+    /// Number of particles outside of the container
+    escaped: usize,
+}
+
+impl Default for Conservation {
+    fn default() -> Self {
+        Conservation {
+            tolerance: DEFAULT_TOLERANCE,
+            baseline: None,
+            time: 0.,
+            energy_drift: VecDeque::new(),
+            momentum_drift: VecDeque::new(),
+            alarm: false,
+            escaped: 0,
+        }
+    }
+}
+
+impl Conservation {
+    /// Measure the drift from the next tick on, after the gas was changed on purpose.
+    pub fn reset(&mut self) {
+        *self = Conservation {
+            tolerance: self.tolerance,
+            ..default()
+        };
+    }
+
+    /// The latest relative drift of the energy and of the momentum balance.
+    fn latest(&self) -> Option<(f64, f64)> {
+        let energy = self.energy_drift.back()?;
+        let momentum = self.momentum_drift.back()?;
+        Some((energy[1], momentum[1]))
+    }
+
+    /// Measure the drift of the gas after a tick of length `dt`, or take it as the baseline if
+    /// there is none yet, and raise the alarm if the drift is past the tolerance.
+    fn measure(&mut self, gas: &Gas, dt: f64) {
+        let energy = gas.kinetic_energy();
+        let balance = gas.momentum_balance();
+        let Some((initial_energy, initial_balance)) = self.baseline else {
+            self.baseline = Some((energy, balance));
+            return;
+        };
+
+        self.time += dt;
+        let energy_drift = if initial_energy > 0. {
+            (energy - initial_energy).abs() / initial_energy
+        } else {
+            0.
+        };
+        let scale = gas.momentum_scale();
+        let momentum_drift = if scale > 0. {
+            (balance - initial_balance).length() / scale
+        } else {
+            0.
+        };
+        let t = self.time;
+        self.energy_drift.push_back([t, energy_drift]);
+        self.momentum_drift.push_back([t, momentum_drift]);
+        if self.energy_drift.len() > HISTORY_LENGTH {
+            self.energy_drift.pop_front();
+            self.momentum_drift.pop_front();
+        }
+
+        let checked_energy_drift = if gas.conserves_energy() {
+            energy_drift
+        } else {
+            0.
+        };
+        let alarm = checked_energy_drift.max(momentum_drift) > self.tolerance;
+        if alarm && !self.alarm {
+            warn!(
+                "Conservation drift past the tolerance of {:.0e} after {t:.2} s: \
+                 energy {energy_drift:.2e}, momentum {momentum_drift:.2e}",
+                self.tolerance
+            );
+        }
+        self.alarm = alarm;
+    }
+}
+
+fn start_monitor(mut conservation: ResMut<Conservation>) {
+    conservation.reset();
+}
+
+fn check_conservation(
+    mut conservation: ResMut<Conservation>,
+    gas: Res<Gas>,
+    clock: Res<SimulationClock>,
+) {
+    conservation.measure(&gas, clock.dt);
+}
+
+/// Count the particles that went through the container walls.
+fn count_escapes(mut conservation: ResMut<Conservation>, gas: Res<Gas>, scenario: Res<Scenario>) {
+    let escaped = (0..gas.positions.len())
+        .filter(|i| !scenario.is_inside(gas.position(*i)))
+        .count();
+    if escaped > conservation.escaped {
+        warn!(
+            "{} particles escaped the container, {escaped} are outside of it",
+            escaped - conservation.escaped
+        );
+    }
+    conservation.escaped = escaped;
+}
+
+fn conservation_window(
+    mut contexts: EguiContexts,
+    mut conservation: ResMut<Conservation>,
+    gas: Res<Gas>,
+    clock: Res<SimulationClock>,
+) {
+    // The warning goes in the title so that it shows even when the window is collapsed
+    let title = if conservation.alarm || conservation.escaped > 0 {
+        "Conservation ⚠ drifting"
+    } else {
+        "Conservation"
+    };
+    egui::Window::new(title)
+        .id(egui::Id::new("conservation"))
+        .default_pos([10., 900.])
+        .default_open(false)
+        .show(contexts.ctx_mut(), |ui| {
+            let mut tolerance = conservation.tolerance;
+            ui.add(
+                egui::Slider::new(&mut tolerance, 1e-15..=1e-1)
+                    .logarithmic(true)
+                    .custom_formatter(|value, _| format!("{value:.0e}"))
+                    .text("tolerance"),
+            );
+            if tolerance != conservation.tolerance {
+                conservation.tolerance = tolerance;
+            }
+            ui.label(format!("Substeps per tick: {}", gas.substeps(clock.dt)));
+            let escaped = format!("Particles outside of the container: {}", conservation.escaped);
+            if conservation.escaped > 0 {
+                ui.colored_label(egui::Color32::LIGHT_RED, escaped);
+            } else {
+                ui.label(escaped);
+            }
+
+            let Some((energy, momentum)) = conservation.latest() else {
+                ui.label("Waiting for the simulation to run");
+                return;
+            };
+            let text = format!(
+                "Relative drift of the energy: {energy:.2e}\n\
+                 Relative drift of the momentum balance: {momentum:.2e}"
+            );
+            if conservation.alarm {
+                ui.colored_label(egui::Color32::LIGHT_RED, text);
+            } else {
+                ui.label(text);
+            }
+            if !gas.conserves_energy() {
+                ui.label("Energy isn't checked: collisions lose some or random kicks add some");
+            }
+
+            let log_drift = |drift: &VecDeque<[f64; 2]>| -> Vec<[f64; 2]> {
+                drift
+                    .iter()
+                    .filter(|[_, d]| *d > 0.)
+                    .map(|[t, d]| [*t, d.log10()])
+                    .collect()
+            };
+            let energy = log_drift(&conservation.energy_drift);
+            let momentum = log_drift(&conservation.momentum_drift);
+            let tolerance = conservation.tolerance.log10();
+            Plot::new("conservation_drift")
+                .height(150.)
+                .legend(Legend::default())
+                .x_axis_label("time [s]")
+                .y_axis_label("log10 relative drift")
+                .show(ui, |plot| {
+                    plot.line(Line::new(energy).name("energy"));
+                    plot.line(Line::new(momentum).name("momentum"));
+                    plot.hline(HLine::new(tolerance).name("tolerance"));
+                });
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::{new_gas, SimulationConfig, PHYSICS_RATE};
+
+    #[test]
+    fn closed_elastic_box_stays_within_the_tolerance() {
+        let scenario = Scenario::default();
+        let config = SimulationConfig {
+            number_of_particles: 300,
+            seed: 0,
+            ..default()
+        };
+        let spawns = config
+            .initial_conditions
+            .generate(
+                &scenario,
+                config.number_of_particles,
+                config.particle_radius,
+                config.temperature,
+                &mut StdRng::seed_from_u64(config.seed),
+            )
+            .unwrap();
+        let mut gas = new_gas(&spawns, &scenario, &config);
+        assert!(gas.conserves_energy());
+        let mut conservation = Conservation::default();
+        let dt = 1. / PHYSICS_RATE;
+        conservation.measure(&gas, dt);
+        let mut collisions = 0;
+        for _ in 0..2000 {
+            collisions += gas.step(dt).collisions.len();
+            conservation.measure(&gas, dt);
+            assert!(!conservation.alarm, "{:?}", conservation.latest());
+        }
+        // The gas did collide, with itself and with the walls
+        assert!(collisions > 0);
+        assert!(gas.wall_momentum.length() > 0.);
+    }
+}
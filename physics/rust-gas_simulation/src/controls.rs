@@ -0,0 +1,351 @@
+use std::mem::discriminant;
+
+use bevy::{ecs::system::SystemParam, math::NormedVectorSpace, prelude::*};
+use bevy_egui::{egui, EguiContexts, EguiPlugin};
+
+use crate::{
+    calculate_temperature,
+    clock::{SimulationClock, MAX_PLAYBACK_SPEED, MIN_PLAYBACK_SPEED},
+    coloring::ColorBy,
+    conservation::Conservation,
+    demon::Demon,
+    fields::FieldOverlay,
+    initial::{Layout, SetupError, MAX_PACKING_FRACTION},
+    physics::Gas,
+    units::BOLTZMANN_CONSTANT,
+    SimulationConfig, SimulationState, PARTICLE_MASS,
+};
+
+/// A panel with sliders for the simulation parameters. Temperature, physics rate, playback
+/// speed, restitution, heating and the temperatures of thermal walls are applied on the fly,
+/// while the parameters that change what gets spawned are only applied when the simulation is
+/// reset.
+pub struct ControlsPlugin;
+
+impl Plugin for ControlsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(EguiPlugin)
+            .init_resource::<PointerOverPanel>()
+            .add_systems(Startup, setup_pending_config)
+            .add_systems(
+                Update,
+                (
+                    (check_reset_keys, control_panel),
+                    (apply_temperature, apply_dissipation)
+                        .run_if(resource_changed::<SimulationConfig>),
+                )
+                    .chain(),
+            );
+    }
+}
+
+/// Edits to the configuration that need a reset to take effect.
+#[derive(Resource)]
+struct PendingConfig(SimulationConfig);
+
+#[derive(SystemParam)]
+struct Playback<'w> {
+    state: Res<'w, State<SimulationState>>,
+    next_state: ResMut<'w, NextState<SimulationState>>,
+    time: ResMut<'w, Time<Virtual>>,
+    clock: Res<'w, SimulationClock>,
+}
+
+/// Toggles for the optional parts of the simulation.
+#[derive(SystemParam)]
+struct Modules<'w> {
+    overlay: ResMut<'w, FieldOverlay>,
+    color_by: ResMut<'w, ColorBy>,
+    demon: Option<ResMut<'w, Demon>>,
+}
+
+/// Whether the pointer is on the panel, so that clicks and drags meant for it don't also move
+/// the camera or select particles.
+#[derive(Resource, Default)]
+pub struct PointerOverPanel(bool);
+
+pub fn pointer_over_panel(over: Res<PointerOverPanel>) -> bool {
+    over.0
+}
+
+fn setup_pending_config(mut commands: Commands, config: Res<SimulationConfig>) {
+    commands.insert_resource(PendingConfig(config.clone()));
+}
+
+/// Apply the pending edits and go back to setup, optionally with a new seed.
+fn reset(
+    config: &mut SimulationConfig,
+    pending: &mut SimulationConfig,
+    reseed: bool,
+    next_state: &mut NextState<SimulationState>,
+) {
+    if reseed {
+        pending.seed = rand::random();
+    }
+    config.number_of_particles = pending.number_of_particles;
+    config.particle_radius = pending.particle_radius;
+    config.max_speed = pending.max_speed;
+    config.seed = pending.seed;
+    config.initial_conditions = pending.initial_conditions.clone();
+    config.reduced_units = pending.reduced_units;
+    config.tangential_restitution = pending.tangential_restitution;
+    next_state.set(SimulationState::Setup);
+}
+
+/// R resets the simulation with the same seed, Shift+R with a new one.
+fn check_reset_keys(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut config: ResMut<SimulationConfig>,
+    mut pending: ResMut<PendingConfig>,
+    mut next_state: ResMut<NextState<SimulationState>>,
+) {
+    if keys.just_pressed(KeyCode::KeyR) {
+        let reseed = keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+        reset(&mut config, &mut pending.0, reseed, &mut next_state);
+    }
+}
+
+fn control_panel(
+    mut contexts: EguiContexts,
+    mut config: ResMut<SimulationConfig>,
+    mut pending: ResMut<PendingConfig>,
+    mut playback: Playback,
+    mut modules: Modules,
+    mut over: ResMut<PointerOverPanel>,
+    setup_error: Res<SetupError>,
+) {
+    // Edit copies and write them back only if they changed, to keep change detection meaningful
+    let mut live = config.clone();
+    let mut overlay = *modules.overlay;
+    let mut color_by = *modules.color_by;
+
+    egui::Window::new("Controls")
+        .default_pos([10., 40.])
+        .show(contexts.ctx_mut(), |ui| {
+            ui.heading("Simulation");
+            let running = *playback.state.get() == SimulationState::Running;
+            if ui.button(if running { "Pause" } else { "Run" }).clicked() {
+                playback.next_state.set(if running {
+                    SimulationState::Paused
+                } else {
+                    SimulationState::Running
+                });
+            }
+            let mut speed = playback.time.relative_speed_f64();
+            let speed_changed = ui
+                .add(
+                    egui::Slider::new(&mut speed, MIN_PLAYBACK_SPEED..=MAX_PLAYBACK_SPEED)
+                        .logarithmic(true)
+                        .custom_formatter(|value, _| format!("{value:.2}x"))
+                        .text("playback speed"),
+                )
+                .changed();
+            if speed_changed {
+                playback.time.set_relative_speed_f64(speed);
+            }
+            ui.label(format!(
+                "{} steps per frame of {:.3} ms",
+                playback.clock.steps_per_frame,
+                playback.clock.dt * 1e3
+            ));
+            ui.add(
+                egui::Slider::new(&mut live.temperature, 1.0..=3000.0)
+                    .logarithmic(true)
+                    .text("temperature [K]"),
+            );
+            ui.add(
+                egui::Slider::new(&mut live.physics_rate, 30.0..=2048.0)
+                    .logarithmic(true)
+                    .text("min physics rate [Hz]"),
+            );
+            ui.add(egui::Slider::new(&mut live.restitution, 0.0..=1.0).text("restitution"))
+                .on_hover_text(
+                    "Fraction of their normal relative speed colliding particles keep. Below 1 \
+                     the gas cools down and clusters",
+                );
+            ui.add(
+                egui::Slider::new(&mut live.wall_restitution, 0.0..=1.0)
+                    .text("wall restitution"),
+            );
+            ui.add(
+                egui::Slider::new(&mut live.heating_rate, 0.0..=1e5)
+                    .logarithmic(true)
+                    .smallest_positive(1.)
+                    .text("heating [K/s]"),
+            )
+            .on_hover_text("Random kicks that heat every particle, to drive a cooling gas");
+            for thermal in &mut live.thermal_walls {
+                ui.add(
+                    egui::Slider::new(&mut thermal.temperature, 1.0..=3000.0)
+                        .logarithmic(true)
+                        .text(format!("wall {} temperature [K]", thermal.wall)),
+                )
+                .on_hover_text("Particles leave it with random velocities at its temperature");
+            }
+
+            ui.separator();
+            ui.heading("Applied on reset");
+            let next = &mut pending.0;
+            ui.add(egui::Slider::new(&mut next.number_of_particles, 1..=3000).text("particles"));
+            ui.add(egui::Slider::new(&mut next.particle_radius, 1.0..=20.0).text("radius [m]"));
+            ui.add(
+                egui::Slider::new(&mut next.max_speed, 500.0..=20000.0)
+                    .logarithmic(true)
+                    .text("histogram range [m/s]"),
+            );
+            initial_conditions_controls(ui, next);
+            ui.add(
+                egui::Slider::new(&mut next.tangential_restitution, -1.0..=1.0)
+                    .text("tangential restitution"),
+            )
+            .on_hover_text(
+                "-1 for smooth discs, 1 for perfectly rough discs that trade energy between \
+                 translation and rotation. Values in between lose energy at every collision",
+            );
+            ui.checkbox(&mut next.reduced_units, "reduced units").on_hover_text(
+                "Simulate with the particle diameter, mass and kT as units. \
+                 Everything is still shown in SI units",
+            );
+            ui.horizontal(|ui| {
+                ui.label("seed");
+                ui.add(egui::DragValue::new(&mut next.seed));
+            });
+            ui.horizontal(|ui| {
+                let reset_clicked = ui.button("Reset").on_hover_text("Start over (R)").clicked();
+                let reseed = ui
+                    .button("Reseed")
+                    .on_hover_text("Start over with a new seed (Shift+R)")
+                    .clicked();
+                if reset_clicked || reseed {
+                    reset(&mut live, next, reseed, &mut playback.next_state);
+                }
+            });
+            if let Some(e) = &setup_error.0 {
+                ui.colored_label(egui::Color32::LIGHT_RED, format!("Could not set up: {e}"));
+            }
+
+            ui.separator();
+            ui.heading("Modules");
+            egui::ComboBox::from_label("field overlay")
+                .selected_text(overlay.label())
+                .show_ui(ui, |ui| {
+                    for option in FieldOverlay::ALL {
+                        ui.selectable_value(&mut overlay, option, option.label());
+                    }
+                });
+            egui::ComboBox::from_label("particle color")
+                .selected_text(color_by.label())
+                .show_ui(ui, |ui| {
+                    for option in ColorBy::ALL {
+                        ui.selectable_value(&mut color_by, option, option.label());
+                    }
+                });
+            if let Some(demon) = &mut modules.demon {
+                let mut enabled = demon.enabled;
+                ui.checkbox(&mut enabled, "Maxwell's demon");
+                if enabled != demon.enabled {
+                    demon.enabled = enabled;
+                }
+            }
+        });
+
+    let ctx = contexts.ctx_mut();
+    over.0 = ctx.is_pointer_over_area() || ctx.is_using_pointer();
+
+    config.set_if_neq(live);
+    modules.overlay.set_if_neq(overlay);
+    modules.color_by.set_if_neq(color_by);
+}
+
+fn initial_conditions_controls(ui: &mut egui::Ui, config: &mut SimulationConfig) {
+    let layout = &mut config.initial_conditions.layout;
+    egui::ComboBox::from_label("initial layout")
+        .selected_text(layout.label())
+        .show_ui(ui, |ui| {
+            for option in Layout::ALL {
+                let selected = discriminant(layout) == discriminant(&option);
+                // Keep the current parameters when picking the same layout again
+                if ui.selectable_label(selected, option.label()).clicked() && !selected {
+                    *layout = option;
+                }
+            }
+        });
+    match layout {
+        Layout::Hexagonal { packing_fraction } => {
+            ui.add(
+                egui::Slider::new(packing_fraction, 0.01..=MAX_PACKING_FRACTION)
+                    .text("packing fraction"),
+            );
+        }
+        Layout::HotCold { temperature_ratio } => {
+            ui.add(
+                egui::Slider::new(temperature_ratio, 1.0..=20.0)
+                    .logarithmic(true)
+                    .text("temperature ratio"),
+            );
+        }
+        Layout::ShockTube {
+            density_ratio,
+            temperature_ratio,
+        } => {
+            ui.add(
+                egui::Slider::new(density_ratio, 1.0..=20.0)
+                    .logarithmic(true)
+                    .text("density ratio"),
+            );
+            ui.add(
+                egui::Slider::new(temperature_ratio, 1.0..=20.0)
+                    .logarithmic(true)
+                    .text("temperature ratio"),
+            );
+        }
+        Layout::Grid | Layout::Random | Layout::FreeExpansion => {}
+    }
+    ui.checkbox(
+        &mut config.initial_conditions.maxwellian,
+        "Maxwell-Boltzmann velocities",
+    );
+}
+
+/// Make collisions as inelastic, kicks as strong and thermal walls as hot as configured.
+fn apply_dissipation(config: Res<SimulationConfig>, mut gas: ResMut<Gas>) {
+    gas.restitution = config.restitution;
+    gas.wall_restitution = config.wall_restitution;
+    gas.set_heating_rate(config.heating_rate);
+    for thermal in &config.thermal_walls {
+        gas.set_wall_temperature(thermal.wall, Some(thermal.temperature as f64));
+    }
+}
+
+/// Rescale all velocities so that the gas is at the configured temperature, and the spins of
+/// rough discs so that their rotation is too.
+fn apply_temperature(
+    config: Res<SimulationConfig>,
+    mut last: Local<Option<f32>>,
+    mut gas: ResMut<Gas>,
+    mut conservation: ResMut<Conservation>,
+) {
+    let target = config.temperature;
+    if last.replace(target).is_none_or(|t| t == target) {
+        return;
+    }
+
+    let speeds: Vec<f32> = (0..gas.velocities.len()).map(|i| gas.velocity(i).norm()).collect();
+    let current = calculate_temperature(PARTICLE_MASS, speeds.len() as f32, speeds);
+    if current > 0. {
+        let factor = (target / current).sqrt();
+        for velocity in &mut gas.velocities {
+            *velocity *= factor as f64;
+        }
+        conservation.reset();
+    }
+    // A single degree of freedom, so kT is twice the mean rotational energy
+    let rotation = 2. * gas.rotational_energy() / (gas.spins.len() as f64 * BOLTZMANN_CONSTANT);
+    if rotation > 0. {
+        let factor = (target as f64 / rotation).sqrt();
+        for spin in &mut gas.spins {
+            *spin *= factor;
+        }
+        conservation.reset();
+    }
+}
@@ -0,0 +1,464 @@
+use std::{collections::HashSet, f32::consts::LN_2};
+
+use bevy::{math::NormedVectorSpace, prelude::*, sprite::Anchor};
+use serde::Deserialize;
+
+use crate::{
+    calculate_entropy, calculate_temperature,
+    clock::SimulationClock,
+    geometry::WallShape,
+    physics::Gas,
+    scenario::Scenario,
+    Particle, SimulationEntity, BOLTZMANN_CONSTANT, ELEMENTARY_CHARGE, PARTICLE_MASS,
+    WALL_THICKNESS,
+};
+
+/// Maxwell's demon. The container is split in two chambers by a vertical partition with a
+/// trapdoor in it. Whenever a particle reaches the trapdoor, the demon measures it and decides
+/// whether to let it through or to bounce it back, which lets it sort the gas in apparent
+/// violation of the second law.
+pub struct DemonPlugin;
+
+impl Plugin for DemonPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(crate::BuildSimulation, setup_demon)
+            .add_systems(
+                FixedUpdate,
+                operate_trapdoor
+                    .after(crate::step_gas)
+                    .run_if(crate::playback::physics_running)
+                    .run_if(resource_exists::<Demon>),
+            )
+            .add_systems(
+                Update,
+                (
+                    draw_trapdoor,
+                    update_demon_text.run_if(in_state(crate::SimulationState::Running)),
+                )
+                    .run_if(resource_exists::<Demon>),
+            );
+    }
+}
+
+/// Demon settings as written in the scenario file.
+#[derive(Deserialize, Debug, Clone)]
+pub struct DemonConfig {
+    /// Horizontal position of the partition.
+    #[serde(default)]
+    pub partition_x: f32,
+    /// Vertical position of the center of the trapdoor.
+    #[serde(default)]
+    pub trapdoor_y: f32,
+    pub trapdoor_width: f32,
+    pub rule: DemonRule,
+}
+
+/// The built-in rules the demon can follow. Other rules can be given as a closure with
+/// [`Demon::new`], from code only. Every particle of the gas has the same mass and radius, so
+/// there is no rule to sort species.
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub enum DemonRule {
+    /// Only let fast particles go right and slow particles go left. Heats up the right chamber
+    /// and cools down the left one.
+    SpeedThreshold { speed: f32 },
+    /// Only let particles through towards one chamber. Builds up a pressure difference.
+    OneWay { towards: Chamber },
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chamber {
+    Left,
+    Right,
+}
+
+/// Decides whether a particle coming from the given chamber may go through the trapdoor.
+pub type TrapdoorRule = Box<dyn Fn(&Particle, Chamber) -> bool + Send + Sync>;
+
+#[derive(Resource)]
+pub struct Demon {
+    /// When off, the trapdoor is left open and particles go through it freely
+    pub enabled: bool,
+    partition_x: f32,
+    trapdoor: WallShape,
+    rule: TrapdoorRule,
+    ledger: DemonLedger,
+    /// Indices of the particles the demon already let through which are still crossing the
+    /// trapdoor
+    crossing: HashSet<usize>,
+    /// Where every particle was when the demon last watched the gas, to catch the ones that go
+    /// through the trapdoor within a single step
+    previous: Vec<Vec2>,
+    /// Time of the gas when the demon last watched it, in units of the gas
+    watched_at: f64,
+    chamber_areas: [f32; 2],
+    initial_entropy: Option<f32>,
+}
+
+/// Tally of the decisions taken by the demon, to account for the information it gathers.
+#[derive(Default)]
+struct DemonLedger {
+    measurements: u32,
+    openings: u32,
+}
+
+#[derive(Component)]
+struct DemonText;
+
+impl DemonRule {
+    fn into_closure(self) -> TrapdoorRule {
+        match self {
+            DemonRule::SpeedThreshold { speed } => {
+                Box::new(move |particle, from| match from {
+                    Chamber::Left => particle.velocity.norm() > speed,
+                    Chamber::Right => particle.velocity.norm() < speed,
+                })
+            }
+            DemonRule::OneWay { towards } => Box::new(move |_, from| from != towards),
+        }
+    }
+}
+
+impl DemonConfig {
+    /// The two halves of the partition, above and below the trapdoor.
+    pub fn partition_walls(&self, scenario: &Scenario) -> Vec<WallShape> {
+        // Find where the partition meets the container by scanning along it
+        let bounds = scenario.bounds(WALL_THICKNESS);
+        let inside: Vec<f32> = (0..=bounds.height().ceil() as u32)
+            .map(|i| bounds.min.y + i as f32)
+            .filter(|y| scenario.is_inside(Vec2::new(self.partition_x, *y)))
+            .collect();
+        let (Some(bottom), Some(top)) = (inside.first(), inside.last()) else {
+            warn!("The demon partition at x = {} is outside the container", self.partition_x);
+            return Vec::new();
+        };
+
+        let x = self.partition_x;
+        let half_width = self.trapdoor_width / 2.;
+        vec![
+            WallShape::Segment {
+                start: Vec2::new(x, *top),
+                end: Vec2::new(x, self.trapdoor_y + half_width),
+            },
+            WallShape::Segment {
+                start: Vec2::new(x, self.trapdoor_y - half_width),
+                end: Vec2::new(x, *bottom),
+            },
+        ]
+    }
+
+    fn trapdoor(&self) -> WallShape {
+        let half_width = self.trapdoor_width / 2.;
+        WallShape::Segment {
+            start: Vec2::new(self.partition_x, self.trapdoor_y + half_width),
+            end: Vec2::new(self.partition_x, self.trapdoor_y - half_width),
+        }
+    }
+}
+
+impl Demon {
+    pub fn new(config: &DemonConfig, rule: TrapdoorRule, scenario: &Scenario) -> Self {
+        Demon {
+            enabled: true,
+            partition_x: config.partition_x,
+            trapdoor: config.trapdoor(),
+            rule,
+            ledger: DemonLedger::default(),
+            crossing: HashSet::new(),
+            previous: Vec::new(),
+            watched_at: f64::NAN,
+            chamber_areas: chamber_areas(scenario, config.partition_x),
+            initial_entropy: None,
+        }
+    }
+
+    fn chamber_of(&self, position: Vec2) -> Chamber {
+        if position.x < self.partition_x {
+            Chamber::Left
+        } else {
+            Chamber::Right
+        }
+    }
+
+    /// Whether a particle that went from `start` to `end` crossed the partition through the
+    /// trapdoor.
+    fn went_through(&self, start: Vec2, end: Vec2, radius: f32) -> bool {
+        if self.chamber_of(start) == self.chamber_of(end) {
+            return false;
+        }
+        let t = (self.partition_x - start.x) / (end.x - start.x);
+        let crossing = start.lerp(end, t);
+        crossing.distance(self.trapdoor.closest_point(crossing)) < radius + WALL_THICKNESS / 2.
+    }
+
+    /// Decide what to do with every particle that reached the trapdoor during the last step of
+    /// the gas, `dt` seconds long. Particles are judged from the chamber they started the step
+    /// in, so that the fast ones that went through the trapdoor within the step are caught too,
+    /// and sent back if the trapdoor should have stayed closed.
+    fn operate<'a>(
+        &mut self,
+        gas: &mut Gas,
+        dt: f64,
+        particles: impl IntoIterator<Item = &'a Particle>,
+    ) {
+        // After a reset or a rewind the demon doesn't know where the particles came from
+        let step = dt / gas.units.time();
+        let followed = self.previous.len() == gas.positions.len()
+            && (gas.time - step - self.watched_at).abs() <= 1e-6 * step;
+        if self.enabled {
+            for particle in particles {
+                let previous = followed.then(|| self.previous[particle.index]);
+                self.judge(gas, particle, previous);
+            }
+        }
+        self.previous = (0..gas.positions.len()).map(|i| gas.position(i)).collect();
+        self.watched_at = gas.time;
+    }
+
+    fn judge(&mut self, gas: &mut Gas, particle: &Particle, previous: Option<Vec2>) {
+        let i = particle.index;
+        let position = gas.position(i);
+        // The particle component is only updated once per frame, so measure the gas instead
+        let particle = Particle {
+            velocity: gas.velocity(i),
+            ..*particle
+        };
+        let went_through =
+            previous.is_some_and(|start| self.went_through(start, position, particle.radius));
+        let closest = self.trapdoor.closest_point(position);
+        let touching = position.distance(closest) < particle.radius + WALL_THICKNESS / 2.;
+        if self.crossing.contains(&i) || (!touching && !went_through) {
+            if !touching {
+                self.crossing.remove(&i);
+            }
+            return;
+        }
+
+        // Only particles heading into the trapdoor need a decision, unless they already went
+        // through it
+        let from = self.chamber_of(previous.unwrap_or(position));
+        let heading_in = match from {
+            Chamber::Left => particle.velocity.x > 0.,
+            Chamber::Right => particle.velocity.x < 0.,
+        };
+        if !heading_in && !went_through {
+            return;
+        }
+
+        let open = (self.rule)(&particle, from);
+        self.ledger.record(open);
+        if open {
+            if touching {
+                self.crossing.insert(i);
+            }
+        } else if let (Some(start), WallShape::Segment { start: top, end: bottom }) =
+            (previous.filter(|_| went_through), &self.trapdoor)
+        {
+            gas.bounce_back(i, [*top, *bottom], WALL_THICKNESS, start);
+        } else {
+            gas.bounce_off_wall(i, &self.trapdoor, WALL_THICKNESS);
+        }
+    }
+}
+
+impl DemonLedger {
+    fn record(&mut self, opened: bool) {
+        self.measurements += 1;
+        if opened {
+            self.openings += 1;
+        }
+    }
+
+    /// Shannon information of the sequence of open/closed decisions, in bits. This is the
+    /// least amount of memory the demon needs to store what it measured.
+    fn bits(&self) -> f32 {
+        if self.measurements == 0 {
+            return 0.;
+        }
+        let p = self.openings as f32 / self.measurements as f32;
+        let entropy_per_decision = if p == 0. || p == 1. {
+            0.
+        } else {
+            -p * p.log2() - (1. - p) * (1. - p).log2()
+        };
+        self.measurements as f32 * entropy_per_decision
+    }
+}
+
+/// Estimate the free area on each side of the partition by sampling the container on a grid.
+fn chamber_areas(scenario: &Scenario, partition_x: f32) -> [f32; 2] {
+    const CELL: f32 = 2.;
+    let mut areas = [0.; 2];
+    for point in scenario.free_cells(CELL, WALL_THICKNESS) {
+        let chamber = if point.x < partition_x { 0 } else { 1 };
+        areas[chamber] += CELL * CELL;
+    }
+    areas
+}
+
+fn setup_demon(mut commands: Commands, scenario: Res<Scenario>) {
+    let Some(config) = &scenario.demon else {
+        return;
+    };
+
+    let rule = config.rule.into_closure();
+    commands.insert_resource(Demon::new(config, rule, &scenario));
+
+    let bounds = scenario.bounds(WALL_THICKNESS);
+    commands.spawn((
+        Text2d::new("Demon"),
+        DemonText,
+        Transform::from_xyz(bounds.min.x, bounds.max.y + 30., 0.),
+        Anchor::BottomLeft,
+        SimulationEntity,
+    ));
+}
+
+/// Let the demon decide what to do with every particle that reaches the trapdoor.
+fn operate_trapdoor(
+    mut demon: ResMut<Demon>,
+    mut gas: ResMut<Gas>,
+    clock: Res<SimulationClock>,
+    particles: Query<&Particle>,
+) {
+    demon.operate(&mut gas, clock.dt, &particles);
+}
+
+fn draw_trapdoor(demon: Res<Demon>, mut gizmos: Gizmos) {
+    if !demon.enabled {
+        return;
+    }
+    if let WallShape::Segment { start, end } = demon.trapdoor {
+        gizmos.line_2d(start, end, Srgba::rgb(1., 0.5, 0.));
+    }
+}
+
+/// Show temperature and entropy of each chamber next to the information gathered by the demon.
+fn update_demon_text(
+    mut demon: ResMut<Demon>,
+    particles: Query<(&Particle, &Transform)>,
+    mut text_query: Query<&mut Text2d, With<DemonText>>,
+) {
+    let mut speeds = [Vec::new(), Vec::new()];
+    for (particle, transform) in &particles {
+        let chamber = demon.chamber_of(transform.translation.xy()) as usize;
+        speeds[chamber].push(particle.velocity.norm());
+    }
+
+    let mut lines = Vec::new();
+    let mut total_entropy = 0.;
+    for (i, name) in ["Left", "Right"].iter().enumerate() {
+        let n = speeds[i].len() as f32;
+        let (temperature, entropy) = if n > 0. {
+            let temperature = calculate_temperature(PARTICLE_MASS, n, speeds[i].clone());
+            let entropy = calculate_entropy(PARTICLE_MASS, n, temperature, demon.chamber_areas[i]);
+            (temperature, entropy / ELEMENTARY_CHARGE)
+        } else {
+            (0., 0.)
+        };
+        total_entropy += entropy;
+        lines.push(format!(
+            "{name} chamber: {n} particles, T = {temperature:.1} K, S = {entropy:.2} eV/K"
+        ));
+    }
+
+    let initial_entropy = *demon.initial_entropy.get_or_insert(total_entropy);
+    // Landauer's principle: erasing one bit of memory costs at least k ln 2 of entropy
+    let bits = demon.ledger.bits();
+    let erasure_entropy = bits * BOLTZMANN_CONSTANT * LN_2 / ELEMENTARY_CHARGE;
+    lines.push(format!(
+        "Gas entropy change: {:+.3} eV/K",
+        total_entropy - initial_entropy
+    ));
+    lines.push(format!(
+        "Demon: {} measurements, {} openings, {bits:.1} bits of information",
+        demon.ledger.measurements, demon.ledger.openings
+    ));
+    lines.push(format!(
+        "Entropy cost of erasing the demon's memory: {erasure_entropy:.3} eV/K"
+    ));
+
+    text_query.single_mut().0 = lines.join("\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use gas_simulation::units::Units;
+
+    use super::*;
+    use crate::PARTICLE_RADIUS;
+
+    const DT: f64 = 0.01;
+
+    /// A demon with its trapdoor at the origin, and a particle heading right which goes through
+    /// it within a single step, from well clear of it on the left to well clear of it on the
+    /// right.
+    fn fast_particle(rule: DemonRule) -> (Demon, Gas, Particle) {
+        let config = DemonConfig {
+            partition_x: 0.,
+            trapdoor_y: 0.,
+            trapdoor_width: 60.,
+            rule,
+        };
+        let demon = Demon::new(&config, config.rule.into_closure(), &Scenario::default());
+        let velocity = Vec2::new(3000., 0.);
+        let gas = Gas::new(
+            &[Vec2::new(-15., 3.)],
+            &[velocity],
+            PARTICLE_RADIUS,
+            PARTICLE_MASS,
+            &[],
+            WALL_THICKNESS,
+            Units::SI,
+        );
+        let particle = Particle {
+            radius: PARTICLE_RADIUS,
+            mass: PARTICLE_MASS,
+            velocity,
+            ..default()
+        };
+        (demon, gas, particle)
+    }
+
+    /// Let the demon watch the gas, step it and let the demon operate the trapdoor.
+    fn step(demon: &mut Demon, gas: &mut Gas, particle: &Particle) {
+        demon.operate(gas, DT, [particle]);
+        assert_eq!(demon.ledger.measurements, 0);
+        gas.step(DT);
+        demon.operate(gas, DT, [particle]);
+    }
+
+    #[test]
+    fn closed_trapdoor_sends_fast_particles_back() {
+        let (mut demon, mut gas, particle) = fast_particle(DemonRule::OneWay {
+            towards: Chamber::Left,
+        });
+        step(&mut demon, &mut gas, &particle);
+        assert_eq!((demon.ledger.measurements, demon.ledger.openings), (1, 0));
+        // Mirrored about the left face of the trapdoor, as if it had bounced off it
+        let face = -PARTICLE_RADIUS - WALL_THICKNESS / 2.;
+        assert!((gas.position(0).x - (2. * face - 15.)).abs() < 1e-3);
+        assert_eq!(gas.position(0).y, 3.);
+        assert_eq!(gas.velocity(0), Vec2::new(-3000., 0.));
+        assert!(gas.wall_momentum.x > 0.);
+    }
+
+    #[test]
+    fn open_trapdoor_records_fast_particles_going_through() {
+        let (mut demon, mut gas, particle) = fast_particle(DemonRule::OneWay {
+            towards: Chamber::Right,
+        });
+        step(&mut demon, &mut gas, &particle);
+        assert_eq!((demon.ledger.measurements, demon.ledger.openings), (1, 1));
+        assert!((gas.position(0).x - 15.).abs() < 1e-3);
+        assert!(demon.crossing.is_empty());
+
+        // A particle the demon didn't see before the step, as after a rewind, isn't judged
+        let (mut demon, mut gas, particle) = fast_particle(DemonRule::OneWay {
+            towards: Chamber::Left,
+        });
+        gas.step(DT);
+        demon.operate(&mut gas, DT, [&particle]);
+        assert_eq!(demon.ledger.measurements, 0);
+        assert!(gas.position(0).x > 0.);
+    }
+}
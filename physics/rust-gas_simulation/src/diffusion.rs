@@ -0,0 +1,762 @@
+use std::{
+    error::Error,
+    f64::consts::PI,
+    fs::{self, File},
+    io::{BufWriter, Write},
+};
+
+use bevy::{math::DVec2, prelude::*};
+use bevy_egui::{egui, EguiContexts};
+use egui_plot::{HLine, Legend, Line, Plot, VLine};
+use gas_simulation::kinetic;
+use rayon::prelude::*;
+
+use crate::{
+    clock::SimulationClock, lyapunov::fit_slope, physics::Gas, playback::physics_running,
+    scenario::Scenario, units::BOLTZMANN_CONSTANT, SimulationConfig, SimulationState,
+    PARTICLE_MASS, WALL_THICKNESS,
+};
+
+/// Largest number of frames in a recording, after which it stops and is analysed.
+const MAX_FRAMES: usize = 4096;
+/// Fewest frames worth analysing.
+const MIN_FRAMES: usize = 16;
+/// The long-time tail is fitted from where the velocity autocorrelation function has fallen
+/// below this fraction of its initial value, past the exponential decay of Enskog's theory.
+const TAIL_START: f64 = 0.1;
+
+/// Follows the diffusion of the particles. A recording keeps the position of every particle at
+/// evenly spaced times, which can be saved to a CSV file and loaded back. Its velocity
+/// autocorrelation function ⟨v(0)·v(t)⟩ and its mean squared displacement are averaged over the
+/// particles and over every time origin, with fast Fourier transforms. The diffusion coefficient
+/// is measured twice, from the integral of the former (Green-Kubo) and from the slope of the
+/// latter (Einstein), and compared with Enskog's theory. In two dimensions, the velocity
+/// autocorrelation function of hard discs doesn't decay exponentially but as 1/t (Alder and
+/// Wainwright's long-time tail), which the window fits on a log-log plot. The power spectrum of
+/// the velocities is the Fourier transform of the function, and its value at zero frequency is
+/// four times the diffusion coefficient.
+pub struct DiffusionPlugin;
+
+impl Plugin for DiffusionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Diffusion>()
+            .add_systems(OnEnter(SimulationState::Setup), stop_recording)
+            .add_systems(FixedPostUpdate, record_frames.run_if(physics_running))
+            .add_systems(Update, diffusion_window);
+    }
+}
+
+#[derive(Resource)]
+struct Diffusion {
+    /// Trajectory recorded from the gas or loaded from a file
+    trajectory: Option<Trajectory>,
+    recording: bool,
+    /// Simulated time and positions after the last tick, to interpolate the frames in between
+    previous: Option<(f64, Vec<DVec2>)>,
+    /// Simulated time of the first frame
+    start: f64,
+    /// Diffusion coefficient of Enskog's theory for the recorded gas, in m²/s
+    enskog: Option<f64>,
+    analysis: Option<Analysis>,
+    /// Longest lag taken into the diffusion coefficients and the spectrum, in seconds
+    cutoff: f64,
+    /// Path of the trajectory file to save to or load from
+    path: String,
+    message: Option<String>,
+}
+
+impl Default for Diffusion {
+    fn default() -> Self {
+        Diffusion {
+            trajectory: None,
+            recording: false,
+            previous: None,
+            start: 0.,
+            enskog: None,
+            analysis: None,
+            cutoff: 0.,
+            path: "trajectory.csv".into(),
+            message: None,
+        }
+    }
+}
+
+/// Positions of every particle at evenly spaced times.
+struct Trajectory {
+    /// Time between two frames, in seconds
+    interval: f64,
+    /// Positions of every particle, in meters, one frame after the other
+    frames: Vec<Vec<DVec2>>,
+}
+
+/// Correlation functions of a trajectory, for every lag up to half of its length.
+struct Analysis {
+    /// Time between two lags, in seconds
+    interval: f64,
+    /// Velocity autocorrelation function, in m²/s². The velocities are the mean velocities over
+    /// each interval between frames, so that it integrates exactly to the mean squared
+    /// displacement.
+    vacf: Vec<f64>,
+    /// Mean squared displacement, in m²
+    msd: Vec<f64>,
+}
+
+impl Diffusion {
+    fn start_recording(&mut self, gas: &Gas, clock: &SimulationClock, interval: f64) {
+        let positions = positions(gas);
+        self.trajectory = Some(Trajectory {
+            interval,
+            frames: vec![positions.clone()],
+        });
+        self.previous = Some((clock.elapsed, positions));
+        self.start = clock.elapsed;
+        self.recording = true;
+        self.analysis = None;
+        self.message = None;
+    }
+
+    fn stop_recording(&mut self, message: Option<String>) {
+        self.recording = false;
+        self.previous = None;
+        self.message = message;
+        self.analyse();
+    }
+
+    fn analyse(&mut self) {
+        self.analysis = self.trajectory.as_ref().and_then(Trajectory::analyse);
+        if let Some(analysis) = &self.analysis {
+            self.cutoff = analysis.plateau();
+        }
+    }
+}
+
+impl Trajectory {
+    /// Write one row per frame: the time, then the x and y of every particle, in SI units.
+    fn save(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let n = self.frames.first().map_or(0, Vec::len);
+        let columns: Vec<String> = (0..n).map(|i| format!("x{i},y{i}")).collect();
+        writeln!(writer, "time,{}", columns.join(","))?;
+        for (k, frame) in self.frames.iter().enumerate() {
+            let values: Vec<String> = frame.iter().map(|x| format!("{},{}", x.x, x.y)).collect();
+            writeln!(writer, "{},{}", k as f64 * self.interval, values.join(","))?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Read a trajectory written by [`Trajectory::save`], or by anything else that writes the
+    /// same columns.
+    fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        let mut times = Vec::new();
+        let mut frames = Vec::new();
+        // The first line is the header
+        for (number, line) in contents.lines().enumerate().skip(1) {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let values = line
+                .split(',')
+                .map(|value| value.trim().parse::<f64>())
+                .collect::<Result<Vec<f64>, _>>()
+                .map_err(|e| format!("line {}: {e}", number + 1))?;
+            if values.len() % 2 == 0 {
+                let line = number + 1;
+                return Err(format!("line {line}: expected a time and pairs of x and y").into());
+            }
+            times.push(values[0]);
+            frames.push(
+                values[1..]
+                    .chunks_exact(2)
+                    .map(|xy| DVec2::new(xy[0], xy[1]))
+                    .collect::<Vec<DVec2>>(),
+            );
+        }
+        if frames.len() < 2 {
+            return Err("a trajectory needs at least two frames".into());
+        }
+        if frames.iter().any(|frame| frame.len() != frames[0].len()) {
+            return Err("every frame should have the same number of particles".into());
+        }
+        let interval = (times[times.len() - 1] - times[0]) / (times.len() - 1) as f64;
+        let evenly_spaced = times
+            .windows(2)
+            .all(|t| ((t[1] - t[0]) / interval - 1.).abs() < 1e-6);
+        if !(interval > 0. && evenly_spaced) {
+            return Err("the frames should be evenly spaced in time".into());
+        }
+        Ok(Trajectory { interval, frames })
+    }
+
+    /// Duration of the recording, in seconds.
+    fn duration(&self) -> f64 {
+        (self.frames.len() - 1) as f64 * self.interval
+    }
+
+    /// Correlation functions up to half of the duration, averaged over every particle and every
+    /// time origin, or `None` if the trajectory is too short.
+    fn analyse(&self) -> Option<Analysis> {
+        let frames = self.frames.len();
+        let n = self.frames[0].len();
+        if frames < MIN_FRAMES || n == 0 {
+            return None;
+        }
+        let lags = frames / 2;
+        // Zero padding to twice the length keeps the circular correlation of the transform from
+        // wrapping around
+        let fourier = Fourier::new(2 * frames.next_power_of_two());
+        let (vacf_sums, msd_sums) = (0..n)
+            .into_par_iter()
+            .map(|i| {
+                // Centered, so that the squares don't drown the displacements in rounding errors
+                let mean = self.frames.iter().map(|frame| frame[i]).sum::<DVec2>() / frames as f64;
+                let path: Vec<DVec2> = self.frames.iter().map(|frame| frame[i] - mean).collect();
+                let velocities: Vec<DVec2> =
+                    path.windows(2).map(|x| (x[1] - x[0]) / self.interval).collect();
+                let vacf = fourier.correlation_sums(&velocities, lags);
+
+                // |x(t + m) - x(t)|² summed over the origins t is the sum of |x(t)|² over the
+                // first frames, of |x(t + m)|² over the last ones, and twice the correlation
+                let products = fourier.correlation_sums(&path, lags);
+                let mut squares: f64 = 2. * path.iter().map(|x| x.length_squared()).sum::<f64>();
+                let msd = (0..lags)
+                    .map(|m| {
+                        if m > 0 {
+                            squares -= path[m - 1].length_squared();
+                            squares -= path[frames - m].length_squared();
+                        }
+                        squares - 2. * products[m]
+                    })
+                    .collect();
+                (vacf, msd)
+            })
+            .reduce(
+                || (vec![0.; lags], vec![0.; lags]),
+                |(mut vacf, mut msd), (other_vacf, other_msd): (Vec<f64>, Vec<f64>)| {
+                    vacf.iter_mut().zip(other_vacf).for_each(|(a, b)| *a += b);
+                    msd.iter_mut().zip(other_msd).for_each(|(a, b)| *a += b);
+                    (vacf, msd)
+                },
+            );
+        let vacf = (0..lags)
+            .map(|m| vacf_sums[m] / ((frames - 1 - m) * n) as f64)
+            .collect();
+        let msd = (0..lags)
+            .map(|m| msd_sums[m] / ((frames - m) * n) as f64)
+            .collect();
+        Some(Analysis {
+            interval: self.interval,
+            vacf,
+            msd,
+        })
+    }
+}
+
+impl Analysis {
+    fn longest_lag(&self) -> f64 {
+        (self.vacf.len() - 1) as f64 * self.interval
+    }
+
+    /// Lag at which the integral of the velocity autocorrelation function is largest, in
+    /// seconds: where it levels off, before the walls bend it back down.
+    fn plateau(&self) -> f64 {
+        let running = self.running_diffusion();
+        let largest = (1..running.len())
+            .max_by(|a, b| running[*a][1].total_cmp(&running[*b][1]))
+            .unwrap_or(1);
+        largest as f64 * self.interval
+    }
+
+    /// Number of lags up to `cutoff` seconds, at least one.
+    fn lags(&self, cutoff: f64) -> usize {
+        ((cutoff / self.interval).round() as usize).clamp(1, self.vacf.len() - 1)
+    }
+
+    /// Half of the integral of the velocity autocorrelation function up to every lag, in m²/s.
+    /// The first lag only counts for half of an interval, see
+    /// [`crate::transport::Autocorrelation::running_integral`].
+    fn running_diffusion(&self) -> Vec<[f64; 2]> {
+        let mut integral = 0.;
+        self.vacf
+            .iter()
+            .enumerate()
+            .map(|(m, c)| {
+                integral += if m == 0 { c / 2. } else { *c } * self.interval;
+                [m as f64 * self.interval, integral / 2.]
+            })
+            .collect()
+    }
+
+    /// Diffusion coefficient from the velocity autocorrelation function integrated up to
+    /// `cutoff` seconds.
+    fn diffusion_from_vacf(&self, cutoff: f64) -> f64 {
+        self.running_diffusion()[self.lags(cutoff)][1]
+    }
+
+    /// Diffusion coefficient from the mean squared displacement, which grows as 4Dt once the
+    /// motion is diffusive: a quarter of its slope over the second half of the lags up to
+    /// `cutoff` seconds.
+    fn diffusion_from_msd(&self, cutoff: f64) -> Option<f64> {
+        let lags = self.lags(cutoff);
+        let points: Vec<[f64; 2]> = (lags / 2..=lags)
+            .map(|m| [m as f64 * self.interval, self.msd[m]])
+            .collect();
+        fit_slope(&points).map(|slope| slope / 4.)
+    }
+
+    /// Local slope of the mean squared displacement over four, against the lag, in m²/s.
+    fn msd_slope(&self) -> Vec<[f64; 2]> {
+        self.msd
+            .windows(3)
+            .enumerate()
+            .map(|(m, msd)| {
+                let t = (m + 1) as f64 * self.interval;
+                [t, (msd[2] - msd[0]) / (2. * self.interval) / 4.]
+            })
+            .collect()
+    }
+
+    /// Power spectrum of the velocities, the cosine transform of the velocity autocorrelation
+    /// function up to `cutoff` seconds, in m²/s against the frequency in hertz, up to the
+    /// Nyquist frequency.
+    fn spectrum(&self, cutoff: f64) -> Vec<[f64; 2]> {
+        let lags = self.lags(cutoff);
+        // Padded well beyond the cutoff for a smooth curve
+        let length = 4 * (lags + 1).next_power_of_two();
+        let mut values = vec![DVec2::ZERO; length];
+        values[0].x = self.vacf[0];
+        for m in 1..=lags {
+            // The function is even: the negative lags wrap around to the end
+            values[m].x = self.vacf[m];
+            values[length - m].x = self.vacf[m];
+        }
+        Fourier::new(length).transform(&mut values);
+        let resolution = 1. / (length as f64 * self.interval);
+        values[..=length / 2]
+            .iter()
+            .enumerate()
+            .map(|(k, value)| [k as f64 * resolution, value.x * self.interval])
+            .collect()
+    }
+
+    /// Time from which the long-time tail is fitted, once the function has fallen below
+    /// [`TAIL_START`] of its initial value.
+    fn tail_start(&self) -> Option<f64> {
+        let start = self.vacf.iter().position(|c| *c < TAIL_START * self.vacf[0])?;
+        Some(start as f64 * self.interval)
+    }
+
+    /// The velocity autocorrelation function relative to its initial value, on a log-log scale
+    /// where it is positive, and the exponent of the power law fitted to it from
+    /// [`Analysis::tail_start`] up to `cutoff` seconds.
+    fn tail(&self, cutoff: f64) -> (Vec<[f64; 2]>, Option<f64>) {
+        let initial = self.vacf[0];
+        let points: Vec<[f64; 2]> = self
+            .vacf
+            .iter()
+            .enumerate()
+            .skip(1)
+            .filter(|(_, c)| **c > 0.)
+            .map(|(m, c)| [(m as f64 * self.interval).log10(), (c / initial).log10()])
+            .collect();
+        let exponent = self.tail_start().and_then(|start| {
+            let range = start.log10()..=cutoff.log10();
+            let tail: Vec<[f64; 2]> =
+                points.iter().filter(|[t, _]| range.contains(t)).copied().collect();
+            fit_slope(&tail)
+        });
+        (points, exponent)
+    }
+}
+
+/// Discrete Fourier transforms of a fixed power of two length, with the radix 2 Cooley-Tukey
+/// algorithm. Complex numbers are stored as vectors of their real and imaginary parts, which
+/// [`DVec2::rotate`] multiplies.
+struct Fourier {
+    /// e^(-2πik/n) for the first half of the length n
+    twiddles: Vec<DVec2>,
+}
+
+impl Fourier {
+    fn new(length: usize) -> Self {
+        assert!(length.is_power_of_two());
+        Fourier {
+            twiddles: (0..length / 2)
+                .map(|k| DVec2::from_angle(-2. * PI * k as f64 / length as f64))
+                .collect(),
+        }
+    }
+
+    /// Transform `values` in place: value k becomes the sum of value j times e^(-2πijk/n).
+    fn transform(&self, values: &mut [DVec2]) {
+        let n = values.len();
+        debug_assert_eq!(n, 2 * self.twiddles.len());
+        // Bit reversal permutation, so that the butterflies work on neighbouring blocks
+        let mut j = 0;
+        for i in 1..n {
+            let mut bit = n >> 1;
+            while j & bit != 0 {
+                j ^= bit;
+                bit >>= 1;
+            }
+            j |= bit;
+            if i < j {
+                values.swap(i, j);
+            }
+        }
+        let mut size = 2;
+        while size <= n {
+            let stride = n / size;
+            for block in values.chunks_exact_mut(size) {
+                let (evens, odds) = block.split_at_mut(size / 2);
+                for (k, (even, odd)) in evens.iter_mut().zip(odds).enumerate() {
+                    let product = self.twiddles[k * stride].rotate(*odd);
+                    (*even, *odd) = (*even + product, *even - product);
+                }
+            }
+            size *= 2;
+        }
+    }
+
+    /// Sums over the time origins t of x(t)·x(t + m), for the first `lags` lags m. The signal
+    /// is zero padded to the length of the transform, which has to be at least twice its own.
+    fn correlation_sums(&self, signal: &[DVec2], lags: usize) -> Vec<f64> {
+        let mut values = vec![DVec2::ZERO; 2 * self.twiddles.len()];
+        values[..signal.len()].copy_from_slice(signal);
+        self.transform(&mut values);
+        // Wiener-Khinchin: the correlation is the inverse transform of the squared modulus. As
+        // that is real, its inverse transform is its transform over the length
+        for value in &mut values {
+            *value = DVec2::new(value.length_squared(), 0.);
+        }
+        self.transform(&mut values);
+        let n = values.len() as f64;
+        values[..lags].iter().map(|value| value.x / n).collect()
+    }
+}
+
+/// Positions of every particle, in meters.
+fn positions(gas: &Gas) -> Vec<DVec2> {
+    gas.positions.iter().map(|x| *x * gas.units.length).collect()
+}
+
+fn stop_recording(mut diffusion: ResMut<Diffusion>) {
+    if diffusion.recording {
+        diffusion.stop_recording(None);
+    }
+}
+
+/// Add the frames that fall within the last tick, interpolating the positions linearly: the
+/// particles move in straight lines between collisions, and the time steps don't have to line
+/// up with the frames.
+fn record_frames(mut diffusion: ResMut<Diffusion>, gas: Res<Gas>, clock: Res<SimulationClock>) {
+    if !diffusion.recording {
+        return;
+    }
+    let now = clock.elapsed;
+    let positions = positions(&gas);
+    let Some((before, previous)) = diffusion.previous.replace((now, positions.clone())) else {
+        return;
+    };
+    if now <= before {
+        diffusion.stop_recording(Some("Stopped: the simulation went back in time".into()));
+        return;
+    }
+    let start = diffusion.start;
+    let Some(trajectory) = &mut diffusion.trajectory else {
+        return;
+    };
+    loop {
+        let time = start + trajectory.frames.len() as f64 * trajectory.interval;
+        // Tolerate the rounding of the clock when the time steps are as long as the interval
+        if time > now + 1e-9 * trajectory.interval || trajectory.frames.len() >= MAX_FRAMES {
+            break;
+        }
+        let s = ((time - before) / (now - before)).min(1.);
+        let frame = previous.iter().zip(&positions).map(|(a, b)| a.lerp(*b, s)).collect();
+        trajectory.frames.push(frame);
+    }
+    if trajectory.frames.len() >= MAX_FRAMES {
+        diffusion.stop_recording(None);
+    }
+}
+
+fn diffusion_window(
+    mut contexts: EguiContexts,
+    mut diffusion: ResMut<Diffusion>,
+    gas: Res<Gas>,
+    clock: Res<SimulationClock>,
+    scenario: Res<Scenario>,
+    config: Res<SimulationConfig>,
+) {
+    egui::Window::new("Diffusion")
+        .default_pos([10., 1100.])
+        .default_open(false)
+        .show(contexts.ctx_mut(), |ui| {
+            let diffusion = &mut *diffusion;
+            ui.horizontal(|ui| {
+                if diffusion.recording {
+                    if ui.button("Stop recording").clicked() {
+                        diffusion.stop_recording(None);
+                    }
+                } else if ui.button("Record").clicked() {
+                    diffusion.start_recording(&gas, &clock, 1. / config.physics_rate);
+                    let n = gas.positions.len() as f64;
+                    let density = n / scenario.free_area(WALL_THICKNESS) as f64;
+                    let temperature = gas.translational_energy() / (n * BOLTZMANN_CONSTANT);
+                    diffusion.enskog = Some(kinetic::enskog_diffusion(
+                        density,
+                        2. * config.particle_radius as f64,
+                        PARTICLE_MASS as f64,
+                        temperature,
+                    ));
+                }
+                let frames = diffusion.trajectory.as_ref().map_or(0, |t| t.frames.len());
+                let analysable = frames >= MIN_FRAMES;
+                if ui.add_enabled(analysable, egui::Button::new("Analyse")).clicked() {
+                    diffusion.analyse();
+                }
+            });
+            if let Some(trajectory) = &diffusion.trajectory {
+                ui.label(format!(
+                    "{} frames every {:.2} ms, {:.2} s of {} particles",
+                    trajectory.frames.len(),
+                    trajectory.interval * 1e3,
+                    trajectory.duration(),
+                    trajectory.frames[0].len()
+                ));
+            }
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut diffusion.path);
+                let path = diffusion.path.clone();
+                let saveable = diffusion.trajectory.is_some() && !diffusion.recording;
+                if ui.add_enabled(saveable, egui::Button::new("Save")).clicked() {
+                    if let Some(trajectory) = &diffusion.trajectory {
+                        diffusion.message = Some(match trajectory.save(&path) {
+                            Ok(()) => format!("Saved to '{path}'"),
+                            Err(e) => format!("Could not save '{path}': {e}"),
+                        });
+                    }
+                }
+                if ui.add_enabled(!diffusion.recording, egui::Button::new("Load")).clicked() {
+                    match Trajectory::load(&path) {
+                        Ok(trajectory) => {
+                            diffusion.trajectory = Some(trajectory);
+                            // Nothing is known about the gas it came from
+                            diffusion.enskog = None;
+                            diffusion.message = None;
+                            diffusion.analyse();
+                        }
+                        Err(e) => {
+                            diffusion.message = Some(format!("Could not load '{path}': {e}"));
+                        }
+                    }
+                }
+            });
+            if let Some(message) = &diffusion.message {
+                ui.label(message);
+            }
+
+            let Some(analysis) = &diffusion.analysis else {
+                return;
+            };
+            ui.separator();
+            let interval = analysis.interval;
+            ui.add(
+                egui::Slider::new(&mut diffusion.cutoff, interval..=analysis.longest_lag())
+                    .logarithmic(true)
+                    .text("cutoff [s]"),
+            );
+            let cutoff = diffusion.cutoff;
+
+            let initial = analysis.vacf[0];
+            let vacf: Vec<[f64; 2]> = analysis
+                .vacf
+                .iter()
+                .enumerate()
+                .map(|(m, c)| [m as f64 * interval, c / initial])
+                .collect();
+            Plot::new("diffusion_vacf")
+                .height(120.)
+                .x_axis_label("time [s]")
+                .y_axis_label("C(t) / C(0)")
+                .show(ui, |plot| {
+                    plot.line(Line::new(vacf));
+                    plot.vline(VLine::new(cutoff));
+                });
+
+            let (tail, exponent) = analysis.tail(cutoff);
+            // A 1/t guide from the start of the fitted range
+            let guide: Vec<[f64; 2]> = analysis
+                .tail_start()
+                .and_then(|start| tail.iter().find(|[t, _]| *t >= start.log10()))
+                .map(|[t, c]| vec![[*t, *c], [cutoff.log10(), c - (cutoff.log10() - t)]])
+                .unwrap_or_default();
+            Plot::new("diffusion_tail")
+                .height(120.)
+                .legend(Legend::default())
+                .x_axis_label("log10 t [s]")
+                .y_axis_label("log10 C(t) / C(0)")
+                .show(ui, |plot| {
+                    plot.line(Line::new(tail).name("measured"));
+                    plot.line(Line::new(guide).name("1/t"));
+                });
+            match exponent {
+                Some(exponent) => ui.label(format!(
+                    "Long-time tail: C(t) ~ t^{exponent:.2}, 1/t in theory"
+                )),
+                None => ui.label("No tail within the cutoff"),
+            };
+
+            ui.separator();
+            let from_vacf = analysis.diffusion_from_vacf(cutoff);
+            let from_msd = analysis.diffusion_from_msd(cutoff).unwrap_or(f64::NAN);
+            Plot::new("diffusion_coefficient")
+                .height(120.)
+                .legend(Legend::default())
+                .x_axis_label("time [s]")
+                .y_axis_label("D [m²/s]")
+                .show(ui, |plot| {
+                    plot.line(Line::new(analysis.running_diffusion()).name("VACF integral"));
+                    plot.line(Line::new(analysis.msd_slope()).name("MSD slope / 4"));
+                    if let Some(enskog) = diffusion.enskog {
+                        plot.hline(HLine::new(enskog).name("Enskog"));
+                    }
+                    plot.vline(VLine::new(cutoff));
+                });
+            ui.label(format!(
+                "Diffusion coefficient: {from_vacf:.4e} m²/s from the VACF, {from_msd:.4e} m²/s \
+                 from the MSD"
+            ));
+            if let Some(enskog) = diffusion.enskog {
+                ui.label(format!("Enskog's theory: {enskog:.4e} m²/s"));
+            }
+
+            ui.separator();
+            let spectrum = analysis.spectrum(cutoff);
+            ui.label(format!(
+                "Velocity power spectrum, Z(0) = 4D = {:.4e} m²/s",
+                spectrum[0][1]
+            ));
+            Plot::new("diffusion_spectrum")
+                .height(120.)
+                .x_axis_label("frequency [Hz]")
+                .y_axis_label("Z(f) [m²/s]")
+                .show(ui, |plot| plot.line(Line::new(spectrum)));
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    fn random_vectors(count: usize, rng: &mut StdRng) -> Vec<DVec2> {
+        (0..count)
+            .map(|_| DVec2::new(rng.gen_range(-1. ..1.), rng.gen_range(-1. ..1.)))
+            .collect()
+    }
+
+    #[test]
+    fn transform_matches_the_direct_sum() {
+        let mut rng = StdRng::seed_from_u64(5);
+        for length in [2, 16, 64] {
+            let signal = random_vectors(length, &mut rng);
+            let mut values = signal.clone();
+            Fourier::new(length).transform(&mut values);
+            for (k, value) in values.iter().enumerate() {
+                let direct: DVec2 = signal
+                    .iter()
+                    .enumerate()
+                    .map(|(j, x)| {
+                        let angle = -2. * PI * (j * k % length) as f64 / length as f64;
+                        DVec2::from_angle(angle).rotate(*x)
+                    })
+                    .sum();
+                assert!(value.distance(direct) < 1e-10, "{length}, {k}");
+            }
+        }
+    }
+
+    #[test]
+    fn correlation_sums_match_the_direct_sums() {
+        let mut rng = StdRng::seed_from_u64(6);
+        let signal = random_vectors(20, &mut rng);
+        let sums = Fourier::new(64).correlation_sums(&signal, 20);
+        for (m, sum) in sums.iter().enumerate() {
+            let direct: f64 = (0..signal.len() - m).map(|t| signal[t].dot(signal[t + m])).sum();
+            assert!((sum - direct).abs() < 1e-10, "lag {m}");
+        }
+    }
+
+    #[test]
+    fn analysis_matches_the_direct_averages() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let (frames, n, interval) = (21, 3, 0.1);
+        let mut positions = random_vectors(n, &mut rng);
+        let mut trajectory = Trajectory {
+            interval,
+            frames: Vec::new(),
+        };
+        for _ in 0..frames {
+            trajectory.frames.push(positions.clone());
+            for (x, step) in positions.iter_mut().zip(random_vectors(n, &mut rng)) {
+                *x += step + DVec2::new(0.3, 0.);
+            }
+        }
+        let analysis = trajectory.analyse().unwrap();
+        let lags = frames / 2;
+        assert_eq!(analysis.vacf.len(), lags);
+        let path = |i: usize, t: usize| trajectory.frames[t][i];
+        let velocity = |i: usize, t: usize| (path(i, t + 1) - path(i, t)) / interval;
+        for m in 0..lags {
+            let origins = frames - 1 - m;
+            let vacf: f64 = (0..n)
+                .flat_map(|i| (0..origins).map(move |t| (i, t)))
+                .map(|(i, t)| velocity(i, t).dot(velocity(i, t + m)))
+                .sum::<f64>()
+                / (origins * n) as f64;
+            let origins = frames - m;
+            let msd: f64 = (0..n)
+                .flat_map(|i| (0..origins).map(move |t| (i, t)))
+                .map(|(i, t)| path(i, t + m).distance_squared(path(i, t)))
+                .sum::<f64>()
+                / (origins * n) as f64;
+            assert!((analysis.vacf[m] - vacf).abs() < 1e-9, "vacf at lag {m}");
+            assert!((analysis.msd[m] - msd).abs() < 1e-9, "msd at lag {m}");
+        }
+        // Too short to be worth it
+        trajectory.frames.truncate(MIN_FRAMES - 1);
+        assert!(trajectory.analyse().is_none());
+    }
+
+    #[test]
+    fn load_rejects_malformed_trajectories() {
+        let path = std::env::temp_dir().join("gas_simulation_trajectory_test.csv");
+        let path = path.to_str().unwrap();
+        let load = |contents: &str| {
+            fs::write(path, contents).unwrap();
+            Trajectory::load(path)
+        };
+        let header = "time,x0,y0\n";
+        let even = load(&format!("{header}0,1,2\n0.5,1.5,2\n1,2,2\n")).unwrap();
+        assert_eq!(even.frames.len(), 3);
+        assert!((even.interval - 0.5).abs() < 1e-12);
+        let uneven = load(&format!("{header}0,1,2\n0.5,1.5,2\n1.2,2,2\n"));
+        assert!(uneven.err().unwrap().to_string().contains("evenly spaced"));
+        let odd = load(&format!("{header}0,1,2\n0.5,1.5,2,3\n"));
+        assert!(odd.err().unwrap().to_string().contains("line 3"));
+        let backwards = load(&format!("{header}1,1,2\n0,1.5,2\n"));
+        assert!(backwards.is_err());
+        assert!(load(&format!("{header}0,1,2\n0.5,1.5,2,3,4\n")).is_err());
+        assert!(load(&format!("{header}0,1,2\n")).is_err());
+        assert!(load(&format!("{header}0,1,2\n0.5,a,2\n")).is_err());
+        // What is saved loads back
+        even.save(path).unwrap();
+        let again = Trajectory::load(path).unwrap();
+        assert_eq!(again.frames, even.frames);
+        fs::remove_file(path).unwrap();
+    }
+}
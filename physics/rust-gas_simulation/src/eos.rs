@@ -0,0 +1,571 @@
+use std::{
+    error::Error,
+    f64::consts::PI,
+    fs::File,
+    io::{BufWriter, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use bevy::{math::DVec2, prelude::*};
+use bevy_egui::{egui, EguiContexts};
+use egui_plot::{HLine, Legend, Line, Plot, Points, VLine};
+use gas_simulation::kinetic;
+
+use crate::{
+    initial::{InitError, Layout, MAX_PACKING_FRACTION},
+    order,
+    physics::{Gas, StepReport},
+    scenario::Scenario,
+    sweep,
+    units::BOLTZMANN_CONSTANT,
+    SimulationConfig, PARTICLE_MASS, WALL_THICKNESS,
+};
+
+/// Physics steps per mean time between two collisions of a particle, at most. The steps have to
+/// get shorter as the gas gets denser and collisions more frequent.
+const STEPS_PER_COLLISION: f64 = 8.;
+/// The gas starts on a lattice, which takes time to melt, so it is equilibrated for this many
+/// times as long as the measurement lasts.
+const EQUILIBRATION: f64 = 1.;
+/// The gas counts as disordered below this global bond order, and as ordered above the other.
+const DISORDERED: f64 = 0.3;
+const ORDERED: f64 = 0.7;
+
+/// Explores the equation of state of the hard disc fluid. A run in the background measures the
+/// gas at evenly spaced packing fractions, with the configured number of particles in the
+/// scenario's container. The packing fraction is set by the particle radius, and the particles
+/// start on a hexagonal lattice, the only way to fit them at the highest densities. The
+/// compressibility factor Z = PA/NkT is measured in a window in the middle of the container, away
+/// from the walls, in two ways: from the virial of the collisions, and from the collision rate,
+/// which tells how often discs touch. It is plotted against the ideal gas and the equations of
+/// state of Henderson, of scaled particle theory and of free volume theory for the solid. The
+/// global bond orientational order |Ψ6| and its fluctuations show where the disordered fluid
+/// turns into the hexatic phase and then into a solid.
+pub struct EosPlugin;
+
+impl Plugin for EosPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Explorer>()
+            .add_systems(Update, eos_window);
+    }
+}
+
+/// The equation of state measured at one density.
+#[derive(Clone, Copy)]
+struct StatePoint {
+    /// Packing fraction in the measurement window
+    packing_fraction: f64,
+    /// Compressibility factor from the virial of the collisions
+    virial: f64,
+    /// Compressibility factor from the collision rate
+    collision_rate: f64,
+    /// Mean global bond order |Ψ6|
+    bond_order: f64,
+    /// N (⟨|Ψ6|²⟩ - ⟨|Ψ6|⟩²), which peaks where the order sets in
+    susceptibility: f64,
+}
+
+/// What the run in the background has measured so far.
+#[derive(Default)]
+struct Progress {
+    points: Vec<StatePoint>,
+    errors: Vec<String>,
+    finished: bool,
+}
+
+#[derive(Resource)]
+struct Explorer {
+    /// Range of packing fractions and number of points in it
+    start: f64,
+    end: f64,
+    points: u32,
+    /// Mean number of collisions of each particle during each measurement
+    collisions: f64,
+    progress: Arc<Mutex<Progress>>,
+    /// Tells the run in the background to stop
+    stop: Arc<AtomicBool>,
+    running: bool,
+    /// Path of the CSV file to save the results to
+    path: String,
+    message: Option<String>,
+}
+
+impl Default for Explorer {
+    fn default() -> Self {
+        Explorer {
+            start: 0.05,
+            end: 0.75,
+            points: 15,
+            collisions: 100.,
+            progress: Arc::default(),
+            stop: Arc::default(),
+            running: false,
+            path: "eos.csv".into(),
+            message: None,
+        }
+    }
+}
+
+/// Sums over the steps of what the pressure is made of, in a window in the middle of the
+/// container, in SI units.
+struct Measurement {
+    /// Corners of the window
+    min: DVec2,
+    max: DVec2,
+    /// Width over which particles fade out of the window, in meters
+    edge: f64,
+    time: f64,
+    steps: u64,
+    /// Number of particles in the window, weighted by [`Measurement::weight`] and summed over
+    /// the steps
+    count: f64,
+    /// Their kinetic energy, summed over the steps
+    kinetic_energy: f64,
+    /// Sum over the collisions in the window of the separation of the two centers dotted with
+    /// the momentum the first particle got, weighted like the particles
+    virial: f64,
+    /// Number of collisions in the window, weighted the same way
+    collisions: f64,
+    /// |Ψ6| of the particles in the window, and its square, summed over the samples
+    order: f64,
+    order_squared: f64,
+    samples: u64,
+}
+
+impl Explorer {
+    fn packing_fractions(&self) -> Vec<f64> {
+        match self.points {
+            0 => Vec::new(),
+            1 => vec![self.start],
+            n => (0..n)
+                .map(|i| self.start + (self.end - self.start) * i as f64 / (n - 1) as f64)
+                .collect(),
+        }
+    }
+
+    /// Measure every packing fraction of the range, one after the other, in the background.
+    fn start(&mut self, scenario: &Scenario, config: &SimulationConfig) {
+        let progress = Arc::new(Mutex::new(Progress::default()));
+        let stop = Arc::new(AtomicBool::new(false));
+        self.progress = progress.clone();
+        self.stop = stop.clone();
+        self.running = true;
+        self.message = None;
+        let fractions = self.packing_fractions();
+        let collisions = self.collisions;
+        let (scenario, config) = (scenario.clone(), config.clone());
+        thread::spawn(move || {
+            for packing_fraction in fractions {
+                let result = measure(&scenario, &config, packing_fraction, collisions, &stop);
+                let mut progress = progress.lock().unwrap();
+                match result {
+                    Ok(Some(point)) => progress.points.push(point),
+                    Ok(None) => break,
+                    Err(e) => progress
+                        .errors
+                        .push(format!("packing fraction {packing_fraction:.3}: {e}")),
+                }
+            }
+            progress.lock().unwrap().finished = true;
+        });
+    }
+
+    /// Write one row per packing fraction, with the predictions next to the measurements.
+    fn save(&self, points: &[StatePoint]) -> Result<(), Box<dyn Error>> {
+        let mut writer = BufWriter::new(File::create(&self.path)?);
+        writeln!(
+            writer,
+            "packing_fraction,virial,collision_rate,henderson,scaled_particle,free_volume,\
+             bond_order,susceptibility"
+        )?;
+        for point in points {
+            let phi = point.packing_fraction;
+            writeln!(
+                writer,
+                "{phi},{},{},{},{},{},{},{}",
+                point.virial,
+                point.collision_rate,
+                kinetic::henderson_compressibility(phi),
+                kinetic::scaled_particle_compressibility(phi),
+                kinetic::free_volume_compressibility(phi),
+                point.bond_order,
+                point.susceptibility
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl Measurement {
+    /// Measure in the middle half of the inner corners of the container, for particles of the
+    /// given diameter. Obstacles there would throw the density off.
+    fn new(bounds: Rect, diameter: f64) -> Self {
+        let center = bounds.center().as_dvec2();
+        let half = bounds.half_size().as_dvec2() / 2.;
+        Measurement {
+            min: center - half,
+            max: center + half,
+            edge: diameter,
+            time: 0.,
+            steps: 0,
+            count: 0.,
+            kinetic_energy: 0.,
+            virial: 0.,
+            collisions: 0.,
+            order: 0.,
+            order_squared: 0.,
+            samples: 0,
+        }
+    }
+
+    fn contains(&self, x: DVec2) -> bool {
+        x.cmpge(self.min).all() && x.cmplt(self.max).all()
+    }
+
+    /// How much a particle at `x` counts: 1 inside of the window, 0 outside, and in between over
+    /// a diameter across its edges. Counting particles in or out would make the density jump by
+    /// a whole row whenever the rows of a crystal cross the edges, and there are few rows in the
+    /// window. The weights add up to the same area.
+    fn weight(&self, x: DVec2) -> f64 {
+        let depth = (x - self.min).min(self.max - x);
+        let inside = (depth / self.edge + 0.5).clamp(DVec2::ZERO, DVec2::ONE);
+        inside.x * inside.y
+    }
+
+    /// Add a step of `dt` seconds, which the gas just took.
+    fn record(&mut self, gas: &Gas, report: &StepReport, dt: f64) {
+        let speed = gas.units.speed();
+        for i in 0..gas.positions.len() {
+            let weight = self.weight(gas.positions[i] * gas.units.length);
+            if weight > 0. {
+                let mass = gas.masses[i] * gas.units.mass;
+                self.count += weight;
+                self.kinetic_energy +=
+                    weight * 0.5 * mass * (gas.velocities[i] * speed).length_squared();
+            }
+        }
+        for collision in &report.collisions {
+            let [x1, x2] = collision.centers;
+            let weight = self.weight((x1 + x2) / 2.);
+            if weight > 0. {
+                let mass = gas.masses[collision.pair.0] * gas.units.mass;
+                self.virial += weight * (x1 - x2).dot(mass * collision.velocity_change);
+                self.collisions += weight;
+            }
+        }
+        self.time += dt;
+        self.steps += 1;
+    }
+
+    /// Add a sample of the global bond order of the particles in the window. Their neighbours
+    /// can be outside of it.
+    fn sample_order(&mut self, gas: &Gas) {
+        let positions: Vec<DVec2> = gas.positions.iter().map(|x| *x * gas.units.length).collect();
+        let orders: Vec<DVec2> = order::bond_orders(&positions)
+            .into_iter()
+            .zip(&positions)
+            .filter(|(_, x)| self.contains(**x))
+            .map(|(order, _)| order)
+            .collect();
+        let order = order::global_bond_order(&orders);
+        self.order += order;
+        self.order_squared += order * order;
+        self.samples += 1;
+    }
+
+    fn state_point(&self, diameter: f64, mass: f64) -> StatePoint {
+        let size = self.max - self.min;
+        let particles = self.count / self.steps as f64;
+        let density = particles / (size.x * size.y);
+        let packing_fraction = kinetic::packing_fraction(density, diameter);
+        // In two dimensions the mean kinetic energy of a particle is kT
+        let thermal_energy = self.kinetic_energy / self.count;
+
+        // PA = NkT + W/2, with W the time average of the sum of r·F over the pairs, which for
+        // hard discs is the sum over the collisions of the separation dotted with the impulse
+        let virial = 1. + self.virial / (2. * self.time * particles * thermal_energy);
+        // Z = 1 + 2φχ, with χ how much more often the discs collide than in a dilute gas. Every
+        // collision involves two particles
+        let rate = 2. * self.collisions / (self.time * particles);
+        let temperature = thermal_energy / BOLTZMANN_CONSTANT;
+        let enskog = kinetic::collision_frequency(density, diameter, mass, temperature);
+        let chi = kinetic::contact_value(packing_fraction) * rate / enskog;
+        let collision_rate = 1. + 2. * packing_fraction * chi;
+
+        let samples = self.samples.max(1) as f64;
+        let bond_order = self.order / samples;
+        let susceptibility = particles * (self.order_squared / samples - bond_order * bond_order);
+        StatePoint {
+            packing_fraction,
+            virial,
+            collision_rate,
+            bond_order,
+            susceptibility,
+        }
+    }
+}
+
+/// Equilibrate the configured number of particles at `packing_fraction`, then measure them for
+/// as long as it takes each particle to collide `collisions` times. Returns `None` if stopped
+/// before the end.
+fn measure(
+    scenario: &Scenario,
+    config: &SimulationConfig,
+    packing_fraction: f64,
+    collisions: f64,
+    stop: &AtomicBool,
+) -> Result<Option<StatePoint>, InitError> {
+    let n = config.number_of_particles as f64;
+    let area = scenario.free_area(WALL_THICKNESS) as f64;
+    let radius = (packing_fraction * area / (n * PI)).sqrt();
+    let mut config = SimulationConfig {
+        particle_radius: radius as f32,
+        ..config.clone()
+    };
+    config.initial_conditions.maxwellian = true;
+    // If the edges of the container keep the lattice at the right packing fraction from fitting
+    // every particle, it is made a little denser
+    let mut lattice = (packing_fraction as f32).min(MAX_PACKING_FRACTION);
+    let mut gas = loop {
+        config.initial_conditions.layout = Layout::Hexagonal {
+            packing_fraction: lattice,
+        };
+        match sweep::new_gas(scenario, &config) {
+            Err(InitError::DoesNotFit { .. }) if lattice < MAX_PACKING_FRACTION => {
+                lattice = (lattice * 1.02).min(MAX_PACKING_FRACTION);
+            }
+            result => break result?,
+        }
+    };
+
+    let diameter = 2. * radius;
+    let mass = PARTICLE_MASS as f64;
+    let temperature = config.temperature as f64;
+    let frequency = kinetic::collision_frequency(n / area, diameter, mass, temperature);
+    let dt = (1. / (STEPS_PER_COLLISION * frequency)).min(1. / config.physics_rate);
+    let steps = (collisions / frequency / dt).round() as u32;
+    for _ in 0..(EQUILIBRATION * steps as f64) as u32 {
+        if stop.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        gas.step(dt);
+    }
+
+    let bounds = scenario.bounds(WALL_THICKNESS).inflate(-WALL_THICKNESS);
+    let mut measurement = Measurement::new(bounds, diameter);
+    // The order changes little between two collisions
+    let sample_every = (1. / (frequency * dt)).round().max(1.) as u32;
+    for step in 0..steps.max(1) {
+        if stop.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let report = gas.step(dt);
+        measurement.record(&gas, &report, dt);
+        if step % sample_every == 0 {
+            measurement.sample_order(&gas);
+        }
+    }
+    Ok(Some(measurement.state_point(diameter, mass)))
+}
+
+/// Packing fractions between which the gas goes from disordered to ordered: from the densest
+/// disordered point below the first ordered one, to that one. The points are sorted by packing
+/// fraction.
+fn ordering_region(points: &[StatePoint]) -> Option<(f64, f64)> {
+    let ordered = points.iter().position(|p| p.bond_order > ORDERED)?;
+    let disordered = points[..ordered]
+        .iter()
+        .rposition(|p| p.bond_order < DISORDERED)?;
+    Some((points[disordered].packing_fraction, points[ordered].packing_fraction))
+}
+
+/// A curve of the compressibility factor over a range of packing fractions.
+fn curve(range: (f64, f64), z: fn(f64) -> f64) -> Vec<[f64; 2]> {
+    (0..=100)
+        .map(|i| range.0 + (range.1 - range.0) * i as f64 / 100.)
+        .map(|phi| [phi, z(phi)])
+        .collect()
+}
+
+fn eos_window(
+    mut contexts: EguiContexts,
+    mut explorer: ResMut<Explorer>,
+    scenario: Res<Scenario>,
+    config: Res<SimulationConfig>,
+) {
+    let explorer = &mut *explorer;
+    let (mut points, errors, finished) = {
+        let progress = explorer.progress.lock().unwrap();
+        (progress.points.clone(), progress.errors.clone(), progress.finished)
+    };
+    if finished {
+        explorer.running = false;
+    }
+    points.sort_by(|a, b| a.packing_fraction.total_cmp(&b.packing_fraction));
+
+    egui::Window::new("Equation of state")
+        .default_pos([10., 1200.])
+        .default_open(false)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.add_enabled_ui(!explorer.running, |ui| {
+                let most = kinetic::CLOSE_PACKING - 0.05;
+                ui.add(egui::Slider::new(&mut explorer.start, 0.01..=most).text("from φ"));
+                ui.add(egui::Slider::new(&mut explorer.end, 0.01..=most).text("to φ"));
+                ui.add(egui::Slider::new(&mut explorer.points, 1..=40).text("points"));
+                ui.add(
+                    egui::Slider::new(&mut explorer.collisions, 10.0..=2000.)
+                        .logarithmic(true)
+                        .text("collisions per particle"),
+                );
+            });
+            ui.label(format!(
+                "{} particles in the current container, radius set by φ",
+                config.number_of_particles
+            ));
+            ui.horizontal(|ui| {
+                if explorer.running {
+                    if ui.button("Stop").clicked() {
+                        explorer.stop.store(true, Ordering::Relaxed);
+                    }
+                    ui.label(format!(
+                        "Measured {} of {}",
+                        points.len() + errors.len(),
+                        explorer.packing_fractions().len()
+                    ));
+                } else if ui.button("Run").clicked() {
+                    explorer.start(&scenario, &config);
+                }
+            });
+            for error in &errors {
+                ui.label(error);
+            }
+
+            let virial: Vec<[f64; 2]> =
+                points.iter().map(|p| [p.packing_fraction, p.virial]).collect();
+            let collision_rate: Vec<[f64; 2]> =
+                points.iter().map(|p| [p.packing_fraction, p.collision_rate]).collect();
+            let fluid = (0., kinetic::HEXATIC_SOLID);
+            let solid = (kinetic::LIQUID_HEXATIC.0, kinetic::CLOSE_PACKING - 0.02);
+            Plot::new("eos_compressibility")
+                .height(180.)
+                .legend(Legend::default())
+                .x_axis_label("packing fraction φ")
+                .y_axis_label("Z = PA/NkT")
+                .show(ui, |plot| {
+                    plot.hline(HLine::new(1.).name("ideal gas"));
+                    plot.line(
+                        Line::new(curve(fluid, kinetic::henderson_compressibility))
+                            .name("Henderson"),
+                    );
+                    plot.line(
+                        Line::new(curve(fluid, kinetic::scaled_particle_compressibility))
+                            .name("scaled particle"),
+                    );
+                    plot.line(
+                        Line::new(curve(solid, kinetic::free_volume_compressibility))
+                            .name("free volume"),
+                    );
+                    plot.points(Points::new(virial).radius(3.).name("virial"));
+                    plot.points(Points::new(collision_rate).radius(3.).name("collision rate"));
+                    plot.vline(VLine::new(kinetic::LIQUID_HEXATIC.0));
+                    plot.vline(VLine::new(kinetic::HEXATIC_SOLID));
+                });
+
+            let order: Vec<[f64; 2]> =
+                points.iter().map(|p| [p.packing_fraction, p.bond_order]).collect();
+            let susceptibility: Vec<[f64; 2]> =
+                points.iter().map(|p| [p.packing_fraction, p.susceptibility]).collect();
+            Plot::new("eos_order")
+                .height(120.)
+                .legend(Legend::default())
+                .x_axis_label("packing fraction φ")
+                .show(ui, |plot| {
+                    plot.line(Line::new(order).name("|Ψ6|"));
+                    plot.line(Line::new(susceptibility).name("susceptibility"));
+                    plot.vline(VLine::new(kinetic::LIQUID_HEXATIC.0));
+                    plot.vline(VLine::new(kinetic::HEXATIC_SOLID));
+                });
+            match ordering_region(&points) {
+                Some((low, high)) => ui.label(format!("Orders between φ = {low:.3} and {high:.3}")),
+                None => ui.label("No transition from disorder to order in the range"),
+            };
+            if let Some(peak) = points
+                .iter()
+                .max_by(|a, b| a.susceptibility.total_cmp(&b.susceptibility))
+            {
+                ui.label(format!(
+                    "Fluctuations of the order peak at φ = {:.3}",
+                    peak.packing_fraction
+                ));
+            }
+            ui.label(format!(
+                "In large systems: liquid and hexatic coexist from φ = {:.3} to {:.3}, solid \
+                 above {:.3}",
+                kinetic::LIQUID_HEXATIC.0,
+                kinetic::LIQUID_HEXATIC.1,
+                kinetic::HEXATIC_SOLID
+            ));
+
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut explorer.path);
+                if ui.add_enabled(!points.is_empty(), egui::Button::new("Save")).clicked() {
+                    let path = &explorer.path;
+                    explorer.message = Some(match explorer.save(&points) {
+                        Ok(()) => format!("Saved to '{path}'"),
+                        Err(e) => format!("Could not save '{path}': {e}"),
+                    });
+                }
+            });
+            if let Some(message) = &explorer.message {
+                ui.label(message);
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_point_adds_the_virial_to_the_ideal_gas() {
+        let bounds = Rect::from_center_half_size(Vec2::ZERO, Vec2::new(2., 1.));
+        let mut measurement = Measurement::new(bounds, 0.01);
+        // 10 particles in a window of 2 square meters for 100 steps, kT of 1 each
+        measurement.steps = 100;
+        measurement.time = 2.;
+        measurement.count = 1000.;
+        measurement.kinetic_energy = 1000.;
+        let point = measurement.state_point(0.01, PARTICLE_MASS as f64);
+        assert!((point.virial - 1.).abs() < 1e-12);
+        assert!((point.collision_rate - 1.).abs() < 1e-12);
+        let packing_fraction = kinetic::packing_fraction(5., 0.01);
+        assert!((point.packing_fraction - packing_fraction).abs() < 1e-12);
+        // A virial of 2 N kT t doubles the pressure
+        measurement.virial = 2. * 10. * 1. * 2.;
+        let point = measurement.state_point(0.01, PARTICLE_MASS as f64);
+        assert!((point.virial - 2.).abs() < 1e-12);
+    }
+
+    #[test]
+    fn dilute_gas_is_nearly_ideal() {
+        let config = SimulationConfig {
+            number_of_particles: 200,
+            seed: 1,
+            ..default()
+        };
+        let stop = AtomicBool::new(false);
+        let point = measure(&Scenario::default(), &config, 0.02, 30., &stop)
+            .unwrap()
+            .unwrap();
+        assert!((point.packing_fraction - 0.02).abs() < 0.005, "{}", point.packing_fraction);
+        let henderson = kinetic::henderson_compressibility(point.packing_fraction);
+        assert!((point.virial - henderson).abs() < 0.03, "{}", point.virial);
+        assert!((point.collision_rate - henderson).abs() < 0.03, "{}", point.collision_rate);
+        assert!(point.bond_order < DISORDERED);
+    }
+}
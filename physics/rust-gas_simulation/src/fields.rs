@@ -0,0 +1,384 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+use bevy::{math::NormedVectorSpace, prelude::*, sprite::Anchor};
+use serde::Deserialize;
+
+use crate::{
+    clock::SimulationClock, colormap::viridis, physics::Gas, playback::physics_running,
+    scenario::Scenario, Particle, SimulationConfig, SimulationState, BOLTZMANN_CONSTANT,
+    PARTICLE_MASS, WALL_THICKNESS,
+};
+
+/// Spatially resolved fields. The container is split in a grid and each cell keeps track of the
+/// density, flow velocity and temperature of the particles inside of it. The fields can be shown
+/// as a heatmap with flow arrows on top (press F to switch) and exported as a CSV time series,
+/// sampled from the gas at evenly spaced simulated times whatever the playback speed.
+pub struct FieldsPlugin;
+
+impl Plugin for FieldsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<FieldOverlay>()
+            .add_systems(Startup, setup_fields)
+            .add_systems(
+                Update,
+                (
+                    cycle_field_overlay,
+                    compute_fields.run_if(in_state(SimulationState::Running)),
+                    draw_fields,
+                )
+                    .chain(),
+            )
+            .add_systems(FixedPostUpdate, export_fields.run_if(physics_running));
+    }
+}
+
+/// Field settings as written in the scenario file.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct FieldsConfig {
+    pub columns: u32,
+    pub rows: u32,
+    /// Path of a CSV file to write the fields to. Nothing is written if missing.
+    pub export: Option<String>,
+    /// Simulated time between two exported samples, in seconds.
+    pub export_interval: f32,
+}
+
+impl Default for FieldsConfig {
+    fn default() -> Self {
+        FieldsConfig {
+            columns: 10,
+            rows: 7,
+            export: None,
+            export_interval: 0.25,
+        }
+    }
+}
+
+/// Which field, if any, is drawn on top of the container.
+#[derive(Resource, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FieldOverlay {
+    #[default]
+    Off,
+    Temperature,
+    Density,
+}
+
+#[derive(Resource)]
+struct FieldGrid {
+    columns: u32,
+    rows: u32,
+    bounds: Rect,
+    cells: Vec<Cell>,
+}
+
+#[derive(Default, Clone)]
+struct Cell {
+    /// Free area in the cell, walls and obstacles excluded
+    area: f32,
+    particles: u32,
+    density: f32,
+    flow_velocity: Vec2,
+    /// Temperature measured relative to the flow velocity. NaN if there are less than two
+    /// particles in the cell.
+    temperature: f32,
+}
+
+#[derive(Resource)]
+struct FieldExport {
+    writer: BufWriter<File>,
+    /// Simulated time between two samples, in seconds
+    interval: f64,
+    /// Simulated time of the next sample
+    next: f64,
+    samples: u32,
+}
+
+#[derive(Component)]
+struct HeatmapCell(usize);
+
+#[derive(Component)]
+struct FieldText;
+
+impl FieldGrid {
+    fn cell_size(&self) -> Vec2 {
+        self.bounds.size() / Vec2::new(self.columns as f32, self.rows as f32)
+    }
+
+    fn cell_center(&self, index: usize) -> Vec2 {
+        let column = index as u32 % self.columns;
+        let row = index as u32 / self.columns;
+        self.bounds.min + self.cell_size() * Vec2::new(column as f32 + 0.5, row as f32 + 0.5)
+    }
+
+    fn cell_index(&self, position: Vec2) -> Option<usize> {
+        let relative = (position - self.bounds.min) / self.cell_size();
+        if relative.x < 0. || relative.y < 0. {
+            return None;
+        }
+        let (column, row) = (relative.x as u32, relative.y as u32);
+        (column < self.columns && row < self.rows).then(|| (row * self.columns + column) as usize)
+    }
+
+    /// Bin the particles, given by their positions and velocities, and compute the density, flow
+    /// velocity and temperature of every cell.
+    fn measure(&mut self, particles: impl Iterator<Item = (Vec2, Vec2)>) {
+        let mut velocities: Vec<Vec<Vec2>> = vec![Vec::new(); self.cells.len()];
+        for (position, velocity) in particles {
+            if let Some(i) = self.cell_index(position) {
+                velocities[i].push(velocity);
+            }
+        }
+
+        for (cell, velocities) in self.cells.iter_mut().zip(velocities) {
+            let n = velocities.len();
+            cell.particles = n as u32;
+            cell.density = if cell.area > 0. {
+                n as f32 / cell.area
+            } else {
+                0.
+            };
+            cell.flow_velocity = if n > 0 {
+                velocities.iter().sum::<Vec2>() / n as f32
+            } else {
+                Vec2::ZERO
+            };
+            // Same convention as the global temperature: kT/2 of kinetic energy per degree of
+            // freedom, two per particle. Measuring the velocities from the flow velocity takes
+            // away the two degrees of freedom of the flow, which leaves 2n - 2
+            cell.temperature = if n > 1 {
+                let thermal_energy = PARTICLE_MASS / 2.
+                    * velocities
+                        .iter()
+                        .map(|v| (*v - cell.flow_velocity).norm_squared())
+                        .sum::<f32>();
+                let degrees_of_freedom = (2 * n - 2) as f32;
+                2. * thermal_energy / degrees_of_freedom / BOLTZMANN_CONSTANT
+            } else {
+                f32::NAN
+            };
+        }
+    }
+}
+
+impl FieldOverlay {
+    pub const ALL: [FieldOverlay; 3] = [
+        FieldOverlay::Off,
+        FieldOverlay::Temperature,
+        FieldOverlay::Density,
+    ];
+
+    fn next(self) -> Self {
+        match self {
+            FieldOverlay::Off => FieldOverlay::Temperature,
+            FieldOverlay::Temperature => FieldOverlay::Density,
+            FieldOverlay::Density => FieldOverlay::Off,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            FieldOverlay::Off => "off",
+            FieldOverlay::Temperature => "temperature",
+            FieldOverlay::Density => "density",
+        }
+    }
+}
+
+fn setup_fields(mut commands: Commands, scenario: Res<Scenario>) {
+    let config = &scenario.fields;
+    let bounds = scenario.bounds(WALL_THICKNESS);
+    let mut grid = FieldGrid {
+        columns: config.columns,
+        rows: config.rows,
+        bounds,
+        cells: vec![Cell::default(); (config.columns * config.rows) as usize],
+    };
+
+    // Estimate the free area of each cell by sampling it on a finer grid
+    const SAMPLING: f32 = 2.;
+    for point in scenario.free_cells(SAMPLING, WALL_THICKNESS) {
+        if let Some(i) = grid.cell_index(point) {
+            grid.cells[i].area += SAMPLING * SAMPLING;
+        }
+    }
+
+    for i in 0..grid.cells.len() {
+        commands.spawn((
+            HeatmapCell(i),
+            Sprite {
+                custom_size: Some(grid.cell_size()),
+                ..default()
+            },
+            Transform::from_translation(grid.cell_center(i).extend(-1.)),
+            Visibility::Hidden,
+        ));
+    }
+    commands.insert_resource(grid);
+
+    commands.spawn((
+        Text2d::new(""),
+        FieldText,
+        Transform::from_xyz(bounds.min.x, bounds.min.y - 70., 0.),
+        Anchor::TopLeft,
+    ));
+
+    if let Some(path) = &config.export {
+        match File::create(path) {
+            Ok(file) => {
+                let mut writer = BufWriter::new(file);
+                let header = "sample,time,column,row,x,y,particles,density,velocity_x,\
+                              velocity_y,temperature";
+                if let Err(e) = writeln!(writer, "{header}") {
+                    warn!("Could not write to field export file '{path}': {e}");
+                }
+                commands.insert_resource(FieldExport {
+                    writer,
+                    interval: config.export_interval.max(f32::EPSILON) as f64,
+                    next: 0.,
+                    samples: 0,
+                });
+            }
+            Err(e) => warn!("Could not create field export file '{path}': {e}"),
+        }
+    }
+}
+
+fn cycle_field_overlay(keys: Res<ButtonInput<KeyCode>>, mut overlay: ResMut<FieldOverlay>) {
+    if keys.just_pressed(KeyCode::KeyF) {
+        *overlay = overlay.next();
+    }
+}
+
+/// Measure the fields of the particles as drawn.
+fn compute_fields(mut grid: ResMut<FieldGrid>, particles: Query<(&Particle, &Transform)>) {
+    grid.measure(
+        particles
+            .iter()
+            .map(|(particle, transform)| (transform.translation.xy(), particle.velocity)),
+    );
+}
+
+/// Color the heatmap and draw the flow field.
+fn draw_fields(
+    grid: Res<FieldGrid>,
+    overlay: Res<FieldOverlay>,
+    scenario: Res<Scenario>,
+    config: Res<SimulationConfig>,
+    mut heatmap: Query<(&HeatmapCell, &mut Sprite, &mut Visibility)>,
+    mut text_query: Query<&mut Text2d, With<FieldText>>,
+    mut gizmos: Gizmos,
+) {
+    let particles: u32 = grid.cells.iter().map(|c| c.particles).sum();
+    let mean_density = particles as f32 / scenario.free_area(WALL_THICKNESS);
+    let mut text = text_query.single_mut();
+    text.0 = match *overlay {
+        FieldOverlay::Off => "Fields: off (F to change)".to_string(),
+        FieldOverlay::Temperature => {
+            format!("Fields: temperature, 0 to {:.0} K (F to change)", 2. * config.temperature)
+        }
+        FieldOverlay::Density => format!(
+            "Fields: density, 0 to {:.2e} particles/m^2 (F to change)",
+            2. * mean_density
+        ),
+    };
+
+    for (cell, mut sprite, mut visibility) in &mut heatmap {
+        let stats = &grid.cells[cell.0];
+        let value = match *overlay {
+            FieldOverlay::Off => f32::NAN,
+            FieldOverlay::Temperature => stats.temperature / (2. * config.temperature),
+            FieldOverlay::Density => stats.density / (2. * mean_density),
+        };
+        if value.is_nan() || stats.area == 0. {
+            *visibility = Visibility::Hidden;
+        } else {
+            *visibility = Visibility::Visible;
+            sprite.color = viridis(value).with_alpha(0.6).into();
+        }
+    }
+
+    if *overlay != FieldOverlay::Off {
+        // Scale so that an average particle speed is half a cell long
+        let scale = 0.5 * grid.cell_size().min_element() / config.starting_speed();
+        for (i, cell) in grid.cells.iter().enumerate() {
+            if cell.particles > 0 {
+                let center = grid.cell_center(i);
+                gizmos.arrow_2d(center, center + cell.flow_velocity * scale, Color::WHITE);
+            }
+        }
+    }
+}
+
+/// Write a sample of the fields after the physics step that reaches the time of the next one.
+/// After going back in time, samples are taken from there on.
+fn export_fields(
+    mut grid: ResMut<FieldGrid>,
+    export: Option<ResMut<FieldExport>>,
+    gas: Res<Gas>,
+    clock: Res<SimulationClock>,
+) {
+    let Some(mut export) = export else {
+        return;
+    };
+    let time = clock.elapsed;
+    if time + export.interval < export.next {
+        export.next = time;
+    }
+    if time < export.next {
+        return;
+    }
+    export.next += export.interval * ((time - export.next) / export.interval + 1.).floor();
+
+    grid.measure((0..gas.positions.len()).map(|i| (gas.position(i), gas.velocity(i))));
+    let sample = export.samples;
+    let mut result = Ok(());
+    for (i, cell) in grid.cells.iter().enumerate() {
+        let center = grid.cell_center(i);
+        result = result.and_then(|_| {
+            writeln!(
+                export.writer,
+                "{sample},{time},{},{},{},{},{},{},{},{},{}",
+                i as u32 % grid.columns,
+                i as u32 / grid.columns,
+                center.x,
+                center.y,
+                cell.particles,
+                cell.density,
+                cell.flow_velocity.x,
+                cell.flow_velocity.y,
+                cell.temperature
+            )
+        });
+    }
+    if let Err(e) = result.and_then(|_| export.writer.flush()) {
+        warn!("Could not write field sample {sample}: {e}");
+    }
+    export.samples += 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temperature_leaves_out_the_degrees_of_freedom_of_the_flow() {
+        let mut grid = FieldGrid {
+            columns: 1,
+            rows: 1,
+            bounds: Rect::new(0., 0., 10., 10.),
+            cells: vec![Cell::default()],
+        };
+        // Two particles moving apart at 3 m/s, carried along by a flow of 100 m/s: only the
+        // relative motion is thermal, with its two degrees of freedom
+        let (flow, spread) = (Vec2::new(100., 0.), Vec2::new(0., 3.));
+        grid.measure([(Vec2::ONE, flow + spread), (Vec2::splat(2.), flow - spread)].into_iter());
+        let cell = &grid.cells[0];
+        assert_eq!(cell.flow_velocity, flow);
+        let expected = PARTICLE_MASS * spread.length_squared() / BOLTZMANN_CONSTANT;
+        assert!((cell.temperature / expected - 1.).abs() < 1e-4, "{}", cell.temperature);
+    }
+}
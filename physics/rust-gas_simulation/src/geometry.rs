@@ -0,0 +1,427 @@
+use std::f32::consts::{PI, TAU};
+
+use bevy::{
+    math::DVec2,
+    prelude::*,
+    render::{
+        mesh::{Indices, PrimitiveTopology},
+        render_asset::RenderAssetUsages,
+    },
+};
+use serde::Deserialize;
+
+/// The centerline of a wall. Walls have a thickness, so the actual solid is every point within
+/// half a thickness of this line (a capsule for segments, an annular sector for arcs).
+#[derive(Clone, Debug)]
+pub enum WallShape {
+    Segment {
+        start: Vec2,
+        end: Vec2,
+    },
+    /// A circular arc going counterclockwise from `start_angle` to `end_angle` (in radians).
+    Arc {
+        center: Vec2,
+        radius: f32,
+        start_angle: f32,
+        end_angle: f32,
+    },
+}
+
+impl WallShape {
+    /// A full circle of the given radius.
+    pub fn circle(center: Vec2, radius: f32) -> Self {
+        WallShape::Arc {
+            center,
+            radius,
+            start_angle: 0.,
+            end_angle: TAU,
+        }
+    }
+
+    /// The point on the wall centerline closest to `point`.
+    pub fn closest_point(&self, point: Vec2) -> Vec2 {
+        self.closest_point_f64(point.as_dvec2()).as_vec2()
+    }
+
+    /// [`WallShape::closest_point`] in double precision, for the physics.
+    pub fn closest_point_f64(&self, point: DVec2) -> DVec2 {
+        match *self {
+            WallShape::Segment { start, end } => {
+                let (start, end) = (start.as_dvec2(), end.as_dvec2());
+                let along = end - start;
+                let t = ((point - start).dot(along) / along.length_squared()).clamp(0., 1.);
+                start + t * along
+            }
+            WallShape::Arc {
+                center,
+                radius,
+                start_angle,
+                end_angle,
+            } => {
+                let (center, radius) = (center.as_dvec2(), radius as f64);
+                let (start_angle, end_angle) = (start_angle as f64, end_angle as f64);
+                let offset = point - center;
+                let sweep = end_angle - start_angle;
+                let angle = (offset.to_angle() - start_angle).rem_euclid(std::f64::consts::TAU);
+                if angle <= sweep {
+                    let direction = offset
+                        .try_normalize()
+                        .unwrap_or(DVec2::from_angle(start_angle));
+                    center + radius * direction
+                } else {
+                    // Outside of the angular range the closest point is one of the two ends
+                    let start = center + radius * DVec2::from_angle(start_angle);
+                    let end = center + radius * DVec2::from_angle(end_angle);
+                    if point.distance_squared(start) < point.distance_squared(end) {
+                        start
+                    } else {
+                        end
+                    }
+                }
+            }
+        }
+    }
+
+    /// The same wall with every length multiplied by `factor`, to change its units.
+    pub fn scaled(&self, factor: f32) -> Self {
+        match *self {
+            WallShape::Segment { start, end } => WallShape::Segment {
+                start: start * factor,
+                end: end * factor,
+            },
+            WallShape::Arc {
+                center,
+                radius,
+                start_angle,
+                end_angle,
+            } => WallShape::Arc {
+                center: center * factor,
+                radius: radius * factor,
+                start_angle,
+                end_angle,
+            },
+        }
+    }
+
+    /// The same wall moved by `offset`.
+    pub fn translated(&self, offset: Vec2) -> Self {
+        match *self {
+            WallShape::Segment { start, end } => WallShape::Segment {
+                start: start + offset,
+                end: end + offset,
+            },
+            WallShape::Arc {
+                center,
+                radius,
+                start_angle,
+                end_angle,
+            } => WallShape::Arc {
+                center: center + offset,
+                radius,
+                start_angle,
+                end_angle,
+            },
+        }
+    }
+
+    /// Direction along the wall at a point of its centerline: from the start to the end of
+    /// segments, and counterclockwise around arcs.
+    pub fn tangent_f64(&self, point: DVec2) -> DVec2 {
+        match *self {
+            WallShape::Segment { start, end } => (end - start).as_dvec2().normalize_or_zero(),
+            WallShape::Arc { center, .. } => (point - center.as_dvec2()).perp().normalize_or_zero(),
+        }
+    }
+
+    /// Length of the wall centerline.
+    pub fn length(&self) -> f32 {
+        match *self {
+            WallShape::Segment { start, end } => start.distance(end),
+            WallShape::Arc {
+                radius,
+                start_angle,
+                end_angle,
+                ..
+            } => radius * (end_angle - start_angle),
+        }
+    }
+
+    /// Build the mesh of a wall with the given thickness. The mesh is centered on the origin and
+    /// needs to be placed with the transform returned by [`WallShape::transform`].
+    pub fn mesh(&self, thickness: f32) -> Mesh {
+        match *self {
+            WallShape::Segment { start, end } => {
+                Capsule2d::new(thickness / 2., start.distance(end)).into()
+            }
+            WallShape::Arc {
+                radius,
+                start_angle,
+                end_angle,
+                ..
+            } => annular_sector_mesh(radius, thickness, start_angle, end_angle),
+        }
+    }
+
+    /// The transform that places the mesh returned by [`WallShape::mesh`] in the world.
+    pub fn transform(&self) -> Transform {
+        match *self {
+            WallShape::Segment { start, end } => {
+                let midpoint = (start + end) / 2.;
+                // Capsules are built along the y axis, so turn them a quarter less
+                let angle = (end - start).to_angle() - PI / 2.;
+                Transform::from_translation(midpoint.extend(0.))
+                    .with_rotation(Quat::from_rotation_z(angle))
+            }
+            WallShape::Arc { center, .. } => Transform::from_translation(center.extend(0.)),
+        }
+    }
+}
+
+/// How a wall moves, as a displacement from where it is at the start. Lengths are in meters and
+/// times in seconds, unless converted with [`WallMotion::scaled`].
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub enum WallMotion {
+    #[default]
+    Fixed,
+    /// Oscillates about where it started, `amplitude` away at most, `frequency` times per second.
+    Vibrating {
+        amplitude: (f32, f32),
+        frequency: f32,
+    },
+    /// Moves in a straight line at a constant velocity.
+    Constant { velocity: (f32, f32) },
+    /// Stays in place while its surface slides along it, like a conveyor belt, at `speed` from
+    /// the start to the end of a segment or counterclockwise around an arc.
+    Sliding { speed: f32 },
+    /// Goes through the given `(time, displacement)` points, in a straight line from one to the
+    /// next. It stays at the last one, or starts over from the first one if `periodic`.
+    Path {
+        points: Vec<(f32, (f32, f32))>,
+        #[serde(default)]
+        periodic: bool,
+    },
+}
+
+impl WallMotion {
+    /// The same motion with every length divided by `length` and every time by `time`, to
+    /// change its units.
+    pub fn scaled(&self, length: f64, time: f64) -> Self {
+        let scale = |(x, y): (f32, f32), factor: f64| {
+            ((x as f64 * factor) as f32, (y as f64 * factor) as f32)
+        };
+        let speed = time / length;
+        match self {
+            WallMotion::Fixed => WallMotion::Fixed,
+            WallMotion::Vibrating {
+                amplitude,
+                frequency,
+            } => WallMotion::Vibrating {
+                amplitude: scale(*amplitude, 1. / length),
+                frequency: (*frequency as f64 * time) as f32,
+            },
+            WallMotion::Constant { velocity } => WallMotion::Constant {
+                velocity: scale(*velocity, speed),
+            },
+            WallMotion::Sliding { speed: sliding } => WallMotion::Sliding {
+                speed: (*sliding as f64 * speed) as f32,
+            },
+            WallMotion::Path { points, periodic } => WallMotion::Path {
+                points: points
+                    .iter()
+                    .map(|(t, displacement)| {
+                        ((*t as f64 / time) as f32, scale(*displacement, 1. / length))
+                    })
+                    .collect(),
+                periodic: *periodic,
+            },
+        }
+    }
+
+    /// How far the wall moved from where it started after a time `t`.
+    pub fn displacement(&self, t: f64) -> DVec2 {
+        let vector = |(x, y): (f32, f32)| DVec2::new(x as f64, y as f64);
+        match self {
+            WallMotion::Fixed | WallMotion::Sliding { .. } => DVec2::ZERO,
+            WallMotion::Vibrating {
+                amplitude,
+                frequency,
+            } => vector(*amplitude) * (std::f64::consts::TAU * *frequency as f64 * t).sin(),
+            WallMotion::Constant { velocity } => vector(*velocity) * t,
+            WallMotion::Path { points, periodic } => {
+                let (Some(first), Some(last)) = (points.first(), points.last()) else {
+                    return DVec2::ZERO;
+                };
+                let period = last.0 as f64;
+                let t = if *periodic && period > 0. {
+                    t.rem_euclid(period)
+                } else {
+                    t
+                };
+                if t <= first.0 as f64 {
+                    return vector(first.1);
+                }
+                points
+                    .windows(2)
+                    .find(|pair| t <= pair[1].0 as f64)
+                    .map(|pair| {
+                        let (start, end) = (pair[0].0 as f64, pair[1].0 as f64);
+                        let along = if end > start { (t - start) / (end - start) } else { 1. };
+                        vector(pair[0].1).lerp(vector(pair[1].1), along)
+                    })
+                    .unwrap_or(vector(last.1))
+            }
+        }
+    }
+
+    /// Speed at which the surface slides along the wall, on top of the wall moving.
+    pub fn sliding_speed(&self) -> f64 {
+        match self {
+            WallMotion::Sliding { speed } => *speed as f64,
+            _ => 0.,
+        }
+    }
+}
+
+/// Build a mesh for a thick circular arc centered on the origin.
+fn annular_sector_mesh(radius: f32, thickness: f32, start_angle: f32, end_angle: f32) -> Mesh {
+    let inner = radius - thickness / 2.;
+    let outer = radius + thickness / 2.;
+    // Roughly one subdivision every few world units so that large circles still look round
+    let subdivisions = ((end_angle - start_angle) * outer / 4.).ceil().max(8.) as u32;
+
+    let mut positions = Vec::new();
+    let mut uvs = Vec::new();
+    for i in 0..=subdivisions {
+        let t = i as f32 / subdivisions as f32;
+        let direction = Vec2::from_angle(start_angle + t * (end_angle - start_angle));
+        positions.push((inner * direction).extend(0.).to_array());
+        positions.push((outer * direction).extend(0.).to_array());
+        uvs.push([t, 0.]);
+        uvs.push([t, 1.]);
+    }
+
+    let mut indices = Vec::new();
+    for i in 0..subdivisions {
+        let base = 2 * i;
+        indices.extend_from_slice(&[base, base + 1, base + 3, base, base + 3, base + 2]);
+    }
+
+    let normals = vec![[0., 0., 1.]; positions.len()];
+    Mesh::new(PrimitiveTopology::TriangleList, RenderAssetUsages::default())
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+        .with_inserted_attribute(Mesh::ATTRIBUTE_UV_0, uvs)
+        .with_inserted_indices(Indices::U32(indices))
+}
+
+/// Whether `point` lies inside the closed polygon with the given vertices, using the
+/// crossing number algorithm. Works for both convex and concave polygons.
+pub fn polygon_contains(vertices: &[Vec2], point: Vec2) -> bool {
+    let mut inside = false;
+    for (i, &a) in vertices.iter().enumerate() {
+        let b = vertices[(i + 1) % vertices.len()];
+        if (a.y > point.y) != (b.y > point.y) {
+            let crossing_x = a.x + (point.y - a.y) / (b.y - a.y) * (b.x - a.x);
+            if point.x < crossing_x {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+/// Area of a simple polygon using the shoelace formula.
+pub fn polygon_area(vertices: &[Vec2]) -> f32 {
+    let mut double_area = 0.;
+    for (i, &a) in vertices.iter().enumerate() {
+        let b = vertices[(i + 1) % vertices.len()];
+        double_area += a.perp_dot(b);
+    }
+    double_area.abs() / 2.
+}
+
+/// The segments making up the edges of a closed polygon.
+pub fn polygon_edges(vertices: &[Vec2]) -> Vec<WallShape> {
+    (0..vertices.len())
+        .map(|i| WallShape::Segment {
+            start: vertices[i],
+            end: vertices[(i + 1) % vertices.len()],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_PI_2;
+
+    use super::*;
+    use crate::physics::{wall_bounce, wall_contact};
+
+    #[test]
+    fn closest_point_on_a_segment_is_clamped_to_its_ends() {
+        let wall = WallShape::Segment {
+            start: Vec2::new(-10., 0.),
+            end: Vec2::new(10., 0.),
+        };
+        assert_eq!(wall.closest_point(Vec2::new(3., 5.)), Vec2::new(3., 0.));
+        assert_eq!(wall.closest_point(Vec2::new(-30., -2.)), Vec2::new(-10., 0.));
+        assert_eq!(wall.closest_point(Vec2::new(15., 1.)), Vec2::new(10., 0.));
+        let far = DVec2::new(1e6, 1e-3);
+        assert_eq!(wall.closest_point_f64(far), DVec2::new(10., 0.));
+    }
+
+    #[test]
+    fn closest_point_on_an_arc_is_radial_within_its_range() {
+        // The right half of a circle of radius 10 around (5, 5)
+        let wall = WallShape::Arc {
+            center: Vec2::new(5., 5.),
+            radius: 10.,
+            start_angle: -FRAC_PI_2,
+            end_angle: FRAC_PI_2,
+        };
+        let inside = wall.closest_point(Vec2::new(8., 9.));
+        assert!(inside.distance(Vec2::new(11., 13.)) < 1e-5);
+        let outside = wall.closest_point(Vec2::new(30., 5.));
+        assert!(outside.distance(Vec2::new(15., 5.)) < 1e-5);
+        // Beyond the ends, the closest end wins
+        let above = wall.closest_point(Vec2::new(0., 30.));
+        assert!(above.distance(Vec2::new(5., 15.)) < 1e-5);
+        let below = wall.closest_point(Vec2::new(-1., -20.));
+        assert!(below.distance(Vec2::new(5., -5.)) < 1e-5);
+        // The center is as close to every point of a full circle, any of them will do
+        let circle = WallShape::circle(Vec2::ZERO, 4.);
+        assert!((circle.closest_point(Vec2::ZERO).length() - 4.).abs() < 1e-6);
+        assert!((circle.length() - TAU * 4.).abs() < 1e-4);
+    }
+
+    #[test]
+    fn polygon_contains_handles_concave_outlines() {
+        // An L made of three unit squares, wound clockwise
+        let l_shape = [(0., 0.), (0., 2.), (1., 2.), (1., 1.), (2., 1.), (2., 0.)].map(Vec2::from);
+        assert!(polygon_contains(&l_shape, Vec2::new(0.5, 1.5)));
+        assert!(polygon_contains(&l_shape, Vec2::new(1.5, 0.5)));
+        assert!(!polygon_contains(&l_shape, Vec2::new(1.5, 1.5)));
+        assert!(!polygon_contains(&l_shape, Vec2::new(-0.5, 0.5)));
+        assert!(!polygon_contains(&l_shape, Vec2::new(0.5, 3.)));
+        assert_eq!(polygon_area(&l_shape), 3.);
+        let reversed: Vec<Vec2> = l_shape.iter().rev().copied().collect();
+        assert_eq!(polygon_area(&reversed), 3.);
+        assert_eq!(polygon_edges(&l_shape).len(), 6);
+    }
+
+    #[test]
+    fn discs_reflect_off_a_circle_about_its_radius() {
+        let circle = WallShape::circle(Vec2::ZERO, 10.);
+        // A disc touching the wall from the inside, heading out at an angle
+        let position = DVec2::new(0.6, 0.8) * 9.05;
+        let (normal, _) = wall_contact(position, 1., &circle, 0.2).unwrap();
+        assert!((normal + DVec2::new(0.6, 0.8)).length() < 1e-9);
+        let velocity = DVec2::new(3., 1.);
+        let (bounced, _) = wall_bounce(velocity, 0., 1., normal, DVec2::ZERO, [1., -1.]).unwrap();
+        assert!((bounced.dot(normal) + velocity.dot(normal)).abs() < 1e-9);
+        assert!((bounced.dot(normal.perp()) - velocity.dot(normal.perp())).abs() < 1e-9);
+        // Heading back in, it is left alone
+        assert!(wall_bounce(-velocity, 0., 1., normal, DVec2::ZERO, [1., -1.]).is_none());
+        assert!(circle.tangent_f64(position).dot(normal).abs() < 1e-9);
+    }
+}
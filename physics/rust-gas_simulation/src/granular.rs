@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use egui_plot::{Legend, Line, Plot};
+use gas_simulation::kinetic;
+
+use crate::{
+    clock::SimulationClock, fields::FieldOverlay, lyapunov::fit_slope, physics::Gas,
+    playback::physics_running, scenario::Scenario, units::BOLTZMANN_CONSTANT, SimulationConfig,
+    SimulationState, PARTICLE_MASS, WALL_THICKNESS,
+};
+
+/// Largest number of points kept for the plots. Once there are that many, every other one is
+/// dropped and points are recorded half as often, so that the whole run always fits.
+const HISTORY_LENGTH: usize = 4096;
+/// Size of the cells particles are counted in to measure clustering, in particle diameters.
+const CLUSTER_CELL_DIAMETERS: f32 = 4.;
+
+/// Follows a granular gas, whose collisions lose energy. Left alone, it cools down following
+/// Haff's law, T0 / (1 + t/τ)², for as long as it stays uniform. Slower particles collide more
+/// often and lose more energy, though, so dense regions get denser: the gas falls apart into
+/// clusters. The window plots the temperature against Haff's law and a clustering index, the
+/// variance over the mean of the number of particles in small cells. With random kicks heating
+/// the gas, it reaches a steady state out of equilibrium instead, compared to the temperature at
+/// which the kicks make up for the collisions. The record starts over whenever the restitution
+/// or the heating changes.
+pub struct GranularPlugin;
+
+impl Plugin for GranularPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Cooling>()
+            .add_systems(OnExit(SimulationState::Setup), start_cooling)
+            .add_systems(FixedPostUpdate, record_cooling.run_if(physics_running))
+            .add_systems(Update, granular_window);
+    }
+}
+
+#[derive(Resource, Default)]
+struct Cooling {
+    /// Restitution and heating rate of the gas the record was taken with
+    regime: (f64, f64),
+    /// Time since the record started, in seconds
+    time: f64,
+    /// Translational temperature when the record started
+    initial: Option<f64>,
+    /// Translational temperature against time
+    temperatures: Vec<[f64; 2]>,
+    /// Clustering index against time
+    clustering: Vec<[f64; 2]>,
+    /// Number of ticks between two recorded points
+    stride: u32,
+    ticks: u32,
+    /// The free cells particles are counted in, indexed by their position in the grid
+    cells: HashMap<IVec2, usize>,
+    cell_size: f32,
+    /// Corner of the grid
+    origin: Vec2,
+}
+
+impl Cooling {
+    fn restart(&mut self, regime: (f64, f64)) {
+        self.regime = regime;
+        self.time = 0.;
+        self.initial = None;
+        self.temperatures.clear();
+        self.clustering.clear();
+        self.stride = 1;
+        self.ticks = 0;
+    }
+
+    /// Add a tick of length `dt` to the record, starting it over if the restitution or the
+    /// heating of the gas changed.
+    fn record(&mut self, gas: &Gas, dt: f64) {
+        let regime = (gas.restitution, gas.heating_rate);
+        if regime != self.regime {
+            self.restart(regime);
+        }
+        self.time += dt;
+        self.ticks += 1;
+        if self.ticks < self.stride {
+            return;
+        }
+        self.ticks = 0;
+
+        let n = gas.positions.len() as f64;
+        let temperature = gas.translational_energy() / (n * BOLTZMANN_CONSTANT);
+        self.initial.get_or_insert(temperature);
+        let index = self.clustering_index(gas);
+        self.temperatures.push([self.time, temperature]);
+        self.clustering.push([self.time, index]);
+        if self.temperatures.len() >= HISTORY_LENGTH {
+            for record in [&mut self.temperatures, &mut self.clustering] {
+                *record = record.iter().step_by(2).copied().collect();
+            }
+            self.stride *= 2;
+        }
+    }
+
+    /// Variance over the mean of the number of particles per cell: about 1 for particles
+    /// scattered at random, less for a dense uniform gas, and more once it clusters.
+    fn clustering_index(&self, gas: &Gas) -> f64 {
+        let mut counts = vec![0.; self.cells.len()];
+        for i in 0..gas.positions.len() {
+            if let Some(k) = self.cells.get(&self.cell(gas.position(i))) {
+                counts[*k] += 1.;
+            }
+        }
+        let n = counts.len() as f64;
+        let mean = counts.iter().sum::<f64>() / n;
+        let variance = counts.iter().map(|c| (c - mean).powi(2)).sum::<f64>() / n;
+        if mean > 0. {
+            variance / mean
+        } else {
+            0.
+        }
+    }
+
+    fn cell(&self, point: Vec2) -> IVec2 {
+        ((point - self.origin) / self.cell_size).floor().as_ivec2()
+    }
+
+    /// Haff time predicted from the initial temperature.
+    fn haff_time(&self, density: f64, diameter: f64) -> Option<f64> {
+        let frequency =
+            kinetic::collision_frequency(density, diameter, PARTICLE_MASS as f64, self.initial?);
+        let tau = kinetic::haff_time(self.regime.0, frequency);
+        tau.is_finite().then_some(tau)
+    }
+
+    /// Haff time fitted to the record: the square root of T0 / T grows linearly with time, with
+    /// a slope of 1/τ.
+    fn fitted_haff_time(&self) -> Option<f64> {
+        let initial = self.initial?;
+        let points: Vec<[f64; 2]> = self
+            .temperatures
+            .iter()
+            .filter(|[_, temperature]| *temperature > 0.)
+            .map(|[t, temperature]| [*t, (initial / temperature).sqrt() - 1.])
+            .collect();
+        let slope = fit_slope(&points)?;
+        (slope > 0.).then(|| 1. / slope)
+    }
+
+    /// Mean temperature over the second half of the record, once a driven gas has settled.
+    fn steady_temperature(&self) -> Option<f64> {
+        let settled = &self.temperatures[self.temperatures.len() / 2..];
+        (!settled.is_empty())
+            .then(|| settled.iter().map(|[_, t]| t).sum::<f64>() / settled.len() as f64)
+    }
+}
+
+fn start_cooling(
+    mut cooling: ResMut<Cooling>,
+    gas: Res<Gas>,
+    scenario: Res<Scenario>,
+    config: Res<SimulationConfig>,
+) {
+    cooling.restart((gas.restitution, gas.heating_rate));
+    // Same grid as the free cells, so that each of them is one cell of the grid
+    cooling.cell_size = CLUSTER_CELL_DIAMETERS * 2. * config.particle_radius;
+    cooling.origin = scenario.bounds(WALL_THICKNESS).min;
+    let free_cells = scenario.free_cells(cooling.cell_size, WALL_THICKNESS);
+    cooling.cells = free_cells
+        .into_iter()
+        .enumerate()
+        .map(|(k, center)| (cooling.cell(center), k))
+        .collect();
+}
+
+fn record_cooling(mut cooling: ResMut<Cooling>, gas: Res<Gas>, clock: Res<SimulationClock>) {
+    cooling.record(&gas, clock.dt);
+}
+
+fn granular_window(
+    mut contexts: EguiContexts,
+    mut cooling: ResMut<Cooling>,
+    mut overlay: ResMut<FieldOverlay>,
+    gas: Res<Gas>,
+    scenario: Res<Scenario>,
+    config: Res<SimulationConfig>,
+) {
+    egui::Window::new("Granular gas")
+        .default_pos([10., 1000.])
+        .default_open(false)
+        .show(contexts.ctx_mut(), |ui| {
+            if gas.restitution == 1. && gas.heating_rate == 0. {
+                ui.label(
+                    "Collisions are elastic: lower the restitution in the controls to let the \
+                     gas cool down",
+                );
+            }
+            ui.horizontal(|ui| {
+                if ui.button("Restart record").clicked() {
+                    let regime = cooling.regime;
+                    cooling.restart(regime);
+                }
+                if ui.button("Show density").clicked() {
+                    overlay.set_if_neq(FieldOverlay::Density);
+                }
+            });
+
+            let density = gas.positions.len() as f64 / scenario.free_area(WALL_THICKNESS) as f64;
+            let diameter = 2. * config.particle_radius as f64;
+            let driven = gas.heating_rate > 0.;
+            // Haff's law only holds for a gas left alone
+            let tau = cooling.haff_time(density, diameter).filter(|_| !driven);
+            let log_temperature: Vec<[f64; 2]> = cooling
+                .temperatures
+                .iter()
+                .filter(|[_, temperature]| *temperature > 0.)
+                .map(|[t, temperature]| [*t, temperature.log10()])
+                .collect();
+            let haff: Vec<[f64; 2]> = match (tau, cooling.initial) {
+                (Some(tau), Some(initial)) => cooling
+                    .temperatures
+                    .iter()
+                    .map(|[t, _]| [*t, (initial / (1. + t / tau).powi(2)).log10()])
+                    .collect(),
+                _ => Vec::new(),
+            };
+            Plot::new("granular_temperature")
+                .height(150.)
+                .legend(Legend::default())
+                .x_axis_label("time [s]")
+                .y_axis_label("log10 T [K]")
+                .show(ui, |plot| {
+                    plot.line(Line::new(log_temperature).name("measured"));
+                    if !haff.is_empty() {
+                        plot.line(Line::new(haff).name("Haff's law"));
+                    }
+                });
+
+            if driven {
+                let predicted = kinetic::driven_temperature(
+                    gas.restitution,
+                    config.heating_rate,
+                    density,
+                    diameter,
+                    PARTICLE_MASS as f64,
+                );
+                let measured = cooling.steady_temperature().unwrap_or(f64::NAN);
+                ui.label(format!(
+                    "Steady state temperature: {measured:.1} K, predicted {predicted:.1} K"
+                ));
+            } else if let Some(tau) = tau {
+                let fitted = cooling.fitted_haff_time().unwrap_or(f64::NAN);
+                ui.label(format!("Haff time: {fitted:.3} s, predicted {tau:.3} s"));
+            }
+            if gas.wall_restitution < 1. || gas.tangential_restitution > -1. {
+                ui.label("The predictions leave out losses to the walls and to rotation");
+            }
+
+            ui.separator();
+            ui.label(format!(
+                "Clustering: variance over mean of the particle count in cells of \
+                 {CLUSTER_CELL_DIAMETERS} diameters"
+            ));
+            Plot::new("granular_clustering")
+                .height(120.)
+                .x_axis_label("time [s]")
+                .y_axis_label("index")
+                .show(ui, |plot| {
+                    plot.line(Line::new(cooling.clustering.clone()));
+                });
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        initial::{InitialConditions, Layout},
+        new_gas,
+    };
+
+    #[test]
+    fn dilute_granular_gas_cools_at_the_haff_rate() {
+        let scenario = Scenario::default();
+        let config = SimulationConfig {
+            number_of_particles: 400,
+            seed: 2,
+            restitution: 0.8,
+            initial_conditions: InitialConditions {
+                layout: Layout::Random,
+                maxwellian: true,
+            },
+            ..default()
+        };
+        let spawns = config
+            .initial_conditions
+            .generate(
+                &scenario,
+                config.number_of_particles,
+                config.particle_radius,
+                config.temperature,
+                &mut StdRng::seed_from_u64(config.seed),
+            )
+            .unwrap();
+        let mut gas = new_gas(&spawns, &scenario, &config);
+        let mut cooling = Cooling::default();
+        cooling.restart((gas.restitution, gas.heating_rate));
+        cooling.record(&gas, 0.);
+
+        let density = spawns.len() as f64 / scenario.free_area(WALL_THICKNESS) as f64;
+        let diameter = 2. * config.particle_radius as f64;
+        let tau = cooling.haff_time(density, diameter).unwrap();
+        // Long enough for the temperature to fall by a factor of 9, before clusters form
+        let dt = 1. / 256.;
+        for _ in 0..(2. * tau / dt) as u32 {
+            gas.step(dt);
+            cooling.record(&gas, dt);
+        }
+        let fitted = cooling.fitted_haff_time().unwrap();
+        assert!((fitted / tau - 1.).abs() < 0.1, "fitted {fitted}, predicted {tau}");
+        let last = cooling.temperatures.last().unwrap()[1];
+        assert!(last < cooling.initial.unwrap() / 4.);
+    }
+
+    #[test]
+    fn steady_temperature_averages_the_second_half() {
+        let mut cooling = Cooling::default();
+        assert_eq!(cooling.steady_temperature(), None);
+        assert_eq!(cooling.fitted_haff_time(), None);
+        cooling.temperatures = vec![[0., 40.], [1., 20.], [2., 4.], [3., 6.]];
+        assert_eq!(cooling.steady_temperature(), Some(5.));
+    }
+}
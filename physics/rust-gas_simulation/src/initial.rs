@@ -0,0 +1,398 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    f32::consts::PI,
+    fmt::{self, Display},
+};
+
+use bevy::prelude::*;
+use rand::{rngs::StdRng, Rng};
+use rand_distr::{Distribution, Normal};
+use serde::Deserialize;
+
+use crate::{
+    geometry::WallShape, scenario::Scenario, BOLTZMANN_CONSTANT, PARTICLE_MASS, WALL_THICKNESS,
+};
+
+/// Packing fraction of touching discs on a hexagonal lattice, the densest possible packing.
+pub const MAX_PACKING_FRACTION: f32 = 0.9069;
+/// Random sequential addition gives up after this many failed attempts in a row.
+const MAX_FAILED_ATTEMPTS: u32 = 10_000;
+
+/// How particles are placed and how fast they go when the simulation is set up. Can be given in
+/// the scenario file, e.g. `initial_conditions: (layout: Random, maxwellian: true)`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct InitialConditions {
+    pub layout: Layout,
+    /// Draw velocities from the Maxwell-Boltzmann distribution instead of giving every particle
+    /// the same speed in a random direction.
+    pub maxwellian: bool,
+}
+
+/// Where the particles start from. Layouts that split the container use the demon partition if
+/// there is one and the middle of the container otherwise.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Layout {
+    /// Square grid starting from the top left corner, four radii apart.
+    #[default]
+    Grid,
+    /// Random non-overlapping positions (random sequential addition).
+    Random,
+    /// Hexagonal lattice, spaced so that it covers the given fraction of the area.
+    Hexagonal { packing_fraction: f32 },
+    /// Everything in the left half, to watch the gas expand into the empty right half.
+    FreeExpansion,
+    /// Random positions, with the left half this many times hotter than the right one.
+    HotCold { temperature_ratio: f32 },
+    /// A denser and hotter gas on the left and a thinner, colder one on the right, with nothing
+    /// in between. The two halves are taken to have the same area.
+    ShockTube {
+        density_ratio: f32,
+        temperature_ratio: f32,
+    },
+}
+
+/// Why the particles could not be set up.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InitError {
+    DoesNotFit { placed: u32, requested: u32 },
+    InvalidParameter(String),
+}
+
+/// The error of the last setup, if any, so that it can be shown to the user.
+#[derive(Resource, Default)]
+pub struct SetupError(pub Option<InitError>);
+
+/// Initial state of a single particle.
+pub struct Spawn {
+    pub position: Vec2,
+    pub velocity: Vec2,
+}
+
+impl Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InitError::DoesNotFit { placed, requested } => write!(
+                f,
+                "only {placed} out of {requested} particles fit, try fewer or smaller particles"
+            ),
+            InitError::InvalidParameter(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl Error for InitError {}
+
+impl Layout {
+    /// Every layout with reasonable default parameters.
+    pub const ALL: [Layout; 6] = [
+        Layout::Grid,
+        Layout::Random,
+        Layout::Hexagonal {
+            packing_fraction: 0.3,
+        },
+        Layout::FreeExpansion,
+        Layout::HotCold {
+            temperature_ratio: 4.,
+        },
+        Layout::ShockTube {
+            density_ratio: 4.,
+            temperature_ratio: 2.,
+        },
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Layout::Grid => "grid",
+            Layout::Random => "random",
+            Layout::Hexagonal { .. } => "hexagonal lattice",
+            Layout::FreeExpansion => "free expansion",
+            Layout::HotCold { .. } => "hot and cold halves",
+            Layout::ShockTube { .. } => "shock tube",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Side {
+    Anywhere,
+    Left,
+    Right,
+}
+
+/// Everything needed to decide whether a particle can be placed somewhere.
+struct Placement<'a> {
+    scenario: &'a Scenario,
+    walls: Vec<WallShape>,
+    radius: f32,
+    split_x: f32,
+    /// Positions already taken, binned in square cells one diameter wide so that only the
+    /// neighbouring cells need to be checked for overlaps
+    taken: HashMap<IVec2, Vec<Vec2>>,
+}
+
+impl<'a> Placement<'a> {
+    fn new(scenario: &'a Scenario, radius: f32) -> Self {
+        let split_x = match &scenario.demon {
+            Some(demon) => demon.partition_x,
+            None => scenario.bounds(WALL_THICKNESS).center().x,
+        };
+        Placement {
+            scenario,
+            walls: scenario.walls(),
+            radius,
+            split_x,
+            taken: HashMap::new(),
+        }
+    }
+
+    fn cell(&self, point: Vec2) -> IVec2 {
+        (point / (2. * self.radius)).floor().as_ivec2()
+    }
+
+    fn is_free(&self, point: Vec2, side: Side) -> bool {
+        let on_side = match side {
+            Side::Anywhere => true,
+            Side::Left => point.x + self.radius < self.split_x,
+            Side::Right => point.x - self.radius > self.split_x,
+        };
+        let cell = self.cell(point);
+        let overlaps = (-1..=1)
+            .flat_map(|i| (-1..=1).map(move |j| cell + IVec2::new(i, j)))
+            .filter_map(|c| self.taken.get(&c))
+            .flatten()
+            .any(|other| other.distance(point) < 2. * self.radius);
+        on_side
+            && !overlaps
+            && self
+                .scenario
+                .fits_among(&self.walls, point, self.radius, WALL_THICKNESS)
+    }
+
+    fn take(&mut self, point: Vec2) {
+        self.taken.entry(self.cell(point)).or_default().push(point);
+    }
+
+    /// Candidate points on a grid covering the side, row by row from the top left.
+    fn lattice(&self, spacing: Vec2, row_shift: f32) -> impl Iterator<Item = Vec2> {
+        let bounds = self.scenario.bounds(WALL_THICKNESS);
+        let top_left = Vec2::new(bounds.min.x, bounds.max.y);
+        (0..)
+            .map(move |row| (row, top_left.y - spacing.y * (row as f32 + 0.5)))
+            .take_while(move |(_, y)| *y > bounds.min.y)
+            .flat_map(move |(row, y)| {
+                let shift = if row % 2 == 0 { 0. } else { row_shift };
+                (0..)
+                    .map(move |col| top_left.x + shift + spacing.x * (col as f32 + 0.5))
+                    .take_while(move |x| *x < bounds.max.x)
+                    .map(move |x| Vec2::new(x, y))
+            })
+    }
+
+    /// Place up to `number` particles on the lattice points that are free.
+    fn fill_lattice(&mut self, number: u32, spacing: Vec2, shift: f32, side: Side) -> Vec<Vec2> {
+        let candidates: Vec<Vec2> = self.lattice(spacing, shift).collect();
+        let mut placed = Vec::new();
+        for point in candidates {
+            if placed.len() == number as usize {
+                break;
+            }
+            if self.is_free(point, side) {
+                self.take(point);
+                placed.push(point);
+            }
+        }
+        placed
+    }
+
+    /// Random sequential addition: try random points and keep those that don't overlap anything.
+    fn fill_random(&mut self, number: u32, side: Side, rng: &mut StdRng) -> Vec<Vec2> {
+        let bounds = self.scenario.bounds(WALL_THICKNESS);
+        let mut placed = Vec::new();
+        let mut failed = 0;
+        while placed.len() < number as usize && failed < MAX_FAILED_ATTEMPTS {
+            let point = Vec2::new(
+                rng.gen_range(bounds.min.x..bounds.max.x),
+                rng.gen_range(bounds.min.y..bounds.max.y),
+            );
+            if self.is_free(point, side) {
+                self.take(point);
+                placed.push(point);
+                failed = 0;
+            } else {
+                failed += 1;
+            }
+        }
+        placed
+    }
+}
+
+impl InitialConditions {
+    /// Place `number` particles of the given radius in the scenario, at an overall temperature
+    /// of `temperature`. Fails if the parameters make no sense or if not all particles fit.
+    pub fn generate(
+        &self,
+        scenario: &Scenario,
+        number: u32,
+        radius: f32,
+        temperature: f32,
+        rng: &mut StdRng,
+    ) -> Result<Vec<Spawn>, InitError> {
+        if radius <= 0. {
+            return Err(InitError::InvalidParameter("the radius must be positive".into()));
+        }
+        if temperature <= 0. {
+            return Err(InitError::InvalidParameter("the temperature must be positive".into()));
+        }
+
+        let mut placement = Placement::new(scenario, radius);
+        // Groups of positions, each with the temperature relative to the others
+        let groups: Vec<(Vec<Vec2>, f32)> = match self.layout {
+            Layout::Grid => {
+                let spacing = Vec2::splat(4. * radius);
+                vec![(placement.fill_lattice(number, spacing, 0., Side::Anywhere), 1.)]
+            }
+            Layout::Random => vec![(placement.fill_random(number, Side::Anywhere, rng), 1.)],
+            Layout::Hexagonal { packing_fraction } => {
+                if packing_fraction <= 0. || packing_fraction > MAX_PACKING_FRACTION {
+                    return Err(InitError::InvalidParameter(format!(
+                        "the packing fraction must be between 0 and {MAX_PACKING_FRACTION}"
+                    )));
+                }
+                // Each disc takes up a hexagon of area sqrt(3)/2 a^2, where a is the spacing
+                let a = radius * (2. * PI / (3_f32.sqrt() * packing_fraction)).sqrt();
+                let spacing = Vec2::new(a, a * 3_f32.sqrt() / 2.);
+                vec![(placement.fill_lattice(number, spacing, a / 2., Side::Anywhere), 1.)]
+            }
+            Layout::FreeExpansion => vec![(placement.fill_random(number, Side::Left, rng), 1.)],
+            Layout::HotCold { temperature_ratio } => {
+                check_ratio(temperature_ratio, "temperature")?;
+                let positions = placement.fill_random(number, Side::Anywhere, rng);
+                let split_x = placement.split_x;
+                let (left, right) = positions.into_iter().partition(|p| p.x < split_x);
+                vec![(left, temperature_ratio), (right, 1.)]
+            }
+            Layout::ShockTube {
+                density_ratio,
+                temperature_ratio,
+            } => {
+                check_ratio(density_ratio, "density")?;
+                check_ratio(temperature_ratio, "temperature")?;
+                let left = (number as f32 * density_ratio / (1. + density_ratio)).round() as u32;
+                vec![
+                    (placement.fill_random(left, Side::Left, rng), temperature_ratio),
+                    (placement.fill_random(number - left, Side::Right, rng), 1.),
+                ]
+            }
+        };
+
+        let placed: usize = groups.iter().map(|(positions, _)| positions.len()).sum();
+        if placed < number as usize {
+            return Err(InitError::DoesNotFit {
+                placed: placed as u32,
+                requested: number,
+            });
+        }
+
+        // Scale the relative temperatures so that the whole gas is at the requested temperature
+        let weighted: f32 = groups.iter().map(|(p, t)| p.len() as f32 * t).sum();
+        let scale = temperature * placed as f32 / weighted;
+        let mut spawns = Vec::with_capacity(placed);
+        for (positions, relative_temperature) in groups {
+            let velocities = self.velocities(positions.len(), relative_temperature * scale, rng);
+            spawns.extend(
+                positions
+                    .into_iter()
+                    .zip(velocities)
+                    .map(|(position, velocity)| Spawn { position, velocity }),
+            );
+        }
+        Ok(spawns)
+    }
+
+    /// Velocities of `number` particles at the given temperature.
+    fn velocities(&self, number: usize, temperature: f32, rng: &mut StdRng) -> Vec<Vec2> {
+        if !self.maxwellian {
+            let speed = (2. * BOLTZMANN_CONSTANT * temperature / PARTICLE_MASS).sqrt();
+            return (0..number).map(|_| Dir2::from_rng(rng) * speed).collect();
+        }
+
+        // Each component is normally distributed with variance kT/m
+        let sigma = (BOLTZMANN_CONSTANT * temperature / PARTICLE_MASS).sqrt();
+        let normal = Normal::new(0., sigma).unwrap();
+        let mut velocities: Vec<Vec2> = (0..number)
+            .map(|_| Vec2::new(normal.sample(rng), normal.sample(rng)))
+            .collect();
+        if number < 2 {
+            return velocities;
+        }
+        // Remove the drift and rescale, so that the sample is at exactly the right temperature
+        let drift = velocities.iter().sum::<Vec2>() / number as f32;
+        let mean_square = velocities
+            .iter()
+            .map(|v| (*v - drift).length_squared())
+            .sum::<f32>()
+            / number as f32;
+        let factor = (2. * sigma * sigma / mean_square).sqrt();
+        for velocity in &mut velocities {
+            *velocity = (*velocity - drift) * factor;
+        }
+        velocities
+    }
+}
+
+fn check_ratio(ratio: f32, name: &str) -> Result<(), InitError> {
+    if ratio > 0. {
+        Ok(())
+    } else {
+        Err(InitError::InvalidParameter(format!("the {name} ratio must be positive")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    #[test]
+    fn layouts_that_cannot_fit_give_up_with_an_error() {
+        // Far more discs than the default container can hold, even packed as tightly as possible
+        let scenario = Scenario::default();
+        let mut rng = StdRng::seed_from_u64(0);
+        for layout in Layout::ALL {
+            let conditions = InitialConditions {
+                layout,
+                maxwellian: false,
+            };
+            let result = conditions.generate(&scenario, 10_000, 5., 300., &mut rng);
+            match result {
+                Err(InitError::DoesNotFit { placed, requested }) => {
+                    assert!(placed < requested, "{}", layout.label());
+                    assert_eq!(requested, 10_000);
+                }
+                _ => panic!("{} did not fail to fit", layout.label()),
+            }
+        }
+    }
+
+    #[test]
+    fn maxwellian_velocities_carry_no_momentum() {
+        let scenario = Scenario::default();
+        let mut rng = StdRng::seed_from_u64(0);
+        for layout in Layout::ALL {
+            let conditions = InitialConditions {
+                layout,
+                maxwellian: true,
+            };
+            let spawns = conditions
+                .generate(&scenario, 500, 5., 300., &mut rng)
+                .unwrap();
+            assert_eq!(spawns.len(), 500);
+            let momentum: Vec2 = spawns.iter().map(|s| s.velocity).sum();
+            let scale: f32 = spawns.iter().map(|s| s.velocity.length()).sum();
+            assert!(momentum.length() < 1e-5 * scale, "{}", layout.label());
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::VecDeque;
+
+use bevy::{math::NormedVectorSpace, prelude::*, window::PrimaryWindow};
+
+use crate::{Particle, ParticleHistory, BOLTZMANN_CONSTANT, ELEMENTARY_CHARGE};
+
+/// Number of past positions kept in the trail of the selected particle.
+const TRAIL_LENGTH: usize = 5000;
+
+/// Click on a particle to select it. The selected particle leaves a trail behind and its state is
+/// shown in a panel. The camera can be set to follow it (press T).
+pub struct InspectorPlugin;
+
+impl Plugin for InspectorPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Trail>()
+            .init_resource::<FollowSelected>()
+            .add_systems(Startup, setup_inspector_panel)
+            .add_systems(
+                Update,
+                (
+                    check_inspector_keys,
+                    select_particle.run_if(not(crate::controls::pointer_over_panel)),
+                    record_trail,
+                    draw_trail,
+                    update_inspector_panel,
+                )
+                    .chain(),
+            );
+    }
+}
+
+/// Marks the particle that is currently being inspected.
+#[derive(Component)]
+pub struct Selected;
+
+/// Whether the camera is locked on the selected particle.
+#[derive(Resource, Default)]
+pub struct FollowSelected(pub bool);
+
+/// Past positions of the selected particle, oldest first.
+#[derive(Resource, Default)]
+struct Trail(VecDeque<Vec2>);
+
+#[derive(Component)]
+struct InspectorPanel;
+
+#[derive(Component)]
+struct InspectorText;
+
+fn setup_inspector_panel(mut commands: Commands) {
+    commands
+        .spawn((
+            InspectorPanel,
+            Node {
+                position_type: PositionType::Absolute,
+                top: Val::Px(10.),
+                right: Val::Px(10.),
+                padding: UiRect::all(Val::Px(8.)),
+                ..default()
+            },
+            BackgroundColor(Color::BLACK.with_alpha(0.8)),
+            Visibility::Hidden,
+        ))
+        .with_child((
+            Text::default(),
+            TextFont::from_font_size(16.),
+            InspectorText,
+        ));
+}
+
+/// Deselect with Escape and toggle following with T.
+fn check_inspector_keys(
+    mut commands: Commands,
+    keys: Res<ButtonInput<KeyCode>>,
+    selected: Query<Entity, With<Selected>>,
+    mut trail: ResMut<Trail>,
+    mut follow: ResMut<FollowSelected>,
+) {
+    if keys.just_pressed(KeyCode::Escape) {
+        for entity in &selected {
+            commands.entity(entity).remove::<Selected>();
+        }
+        trail.0.clear();
+        follow.0 = false;
+    }
+    if keys.just_pressed(KeyCode::KeyT) {
+        follow.0 = !follow.0;
+    }
+}
+
+/// Select the particle under the cursor on click.
+fn select_particle(
+    mut commands: Commands,
+    buttons: Res<ButtonInput<MouseButton>>,
+    window: Query<&Window, With<PrimaryWindow>>,
+    camera: Query<(&Camera, &GlobalTransform)>,
+    particles: Query<(Entity, &Particle, &Transform)>,
+    selected: Query<Entity, With<Selected>>,
+    mut trail: ResMut<Trail>,
+) {
+    if !buttons.just_pressed(MouseButton::Left) {
+        return;
+    }
+    let (camera, camera_transform) = camera.single();
+    let Some(cursor) = window
+        .single()
+        .cursor_position()
+        .and_then(|c| camera.viewport_to_world_2d(camera_transform, c).ok())
+    else {
+        return;
+    };
+
+    // Clicking on empty space does nothing so that the camera can still be dragged around
+    let clicked = particles
+        .iter()
+        .find(|(_, p, t)| t.translation.xy().distance(cursor) < p.radius * 1.5);
+    if let Some((entity, _, transform)) = clicked {
+        for old in &selected {
+            commands.entity(old).remove::<Selected>();
+        }
+        commands.entity(entity).insert(Selected);
+        trail.0.clear();
+        trail.0.push_back(transform.translation.xy());
+    }
+}
+
+fn record_trail(mut trail: ResMut<Trail>, selected: Query<&Transform, With<Selected>>) {
+    let Ok(transform) = selected.get_single() else {
+        return;
+    };
+    let position = transform.translation.xy();
+    if trail.0.back() != Some(&position) {
+        trail.0.push_back(position);
+    }
+    if trail.0.len() > TRAIL_LENGTH {
+        trail.0.pop_front();
+    }
+}
+
+fn draw_trail(
+    trail: Res<Trail>,
+    selected: Query<(&Particle, &Transform), With<Selected>>,
+    mut gizmos: Gizmos,
+) {
+    let Ok((particle, transform)) = selected.get_single() else {
+        return;
+    };
+    // Fade the trail out towards its oldest end
+    let length = trail.0.len() as f32;
+    gizmos.linestrip_gradient_2d(trail.0.iter().enumerate().map(|(i, p)| {
+        let alpha = i as f32 / length;
+        (*p, Color::srgba(1., 0.8, 0., alpha))
+    }));
+    gizmos.circle_2d(
+        Isometry2d::from_translation(transform.translation.xy()),
+        particle.radius * 2.,
+        Color::srgb(1., 0.8, 0.),
+    );
+}
+
+fn update_inspector_panel(
+    selected: Query<(&Particle, &ParticleHistory, &Transform), With<Selected>>,
+    follow: Res<FollowSelected>,
+    mut panel: Query<&mut Visibility, With<InspectorPanel>>,
+    mut text_query: Query<&mut Text, With<InspectorText>>,
+) {
+    let mut visibility = panel.single_mut();
+    let Ok((particle, history, transform)) = selected.get_single() else {
+        *visibility = Visibility::Hidden;
+        return;
+    };
+    *visibility = Visibility::Visible;
+
+    let position = transform.translation.xy();
+    let speed = particle.velocity.norm();
+    let kinetic_energy = particle.mass / 2. * speed * speed;
+    let mean_free_path = if history.collisions > 0 {
+        format!("{:.1} m", history.distance_travelled / history.collisions as f32)
+    } else {
+        "no collisions yet".to_string()
+    };
+
+    text_query.single_mut().0 = format!(
+        "Selected particle (Esc to deselect)\n\
+         Position: ({:.1}, {:.1}) m\n\
+         Velocity: ({:.1}, {:.1}) m/s\n\
+         Speed: {:.1} m/s\n\
+         Kinetic energy: {:.4} eV ({:.1} K)\n\
+         Collisions: {}\n\
+         Distance travelled: {:.0} m\n\
+         Mean free path: {}\n\
+         Camera: {} (T to toggle)",
+        position.x,
+        position.y,
+        particle.velocity.x,
+        particle.velocity.y,
+        speed,
+        kinetic_energy / ELEMENTARY_CHARGE,
+        kinetic_energy / BOLTZMANN_CONSTANT,
+        history.collisions,
+        history.distance_travelled,
+        mean_free_path,
+        if follow.0 { "following" } else { "free" },
+    );
+}
@@ -0,0 +1,238 @@
+//! Predictions of the kinetic theory of hard discs, in SI units, to compare the simulation with.
+//! Densities are numbers of particles per unit area and temperatures are in kelvins.
+
+use std::f64::consts::PI;
+
+use crate::units::BOLTZMANN_CONSTANT;
+
+/// Fraction of the area covered by discs of the given diameter.
+pub fn packing_fraction(density: f64, diameter: f64) -> f64 {
+    density * PI * diameter * diameter / 4.
+}
+
+/// Pair correlation function at contact, from Henderson's equation of state for hard discs. It
+/// is how much more often discs touch than they would if they didn't take up any room.
+pub fn contact_value(packing_fraction: f64) -> f64 {
+    (1. - 7. * packing_fraction / 16.) / (1. - packing_fraction).powi(2)
+}
+
+/// Number of collisions per particle per unit of time in Enskog's theory. Two discs meet when
+/// their centers pass within a diameter of each other, on either side, at a mean relative speed
+/// of √(πkT/m).
+pub fn collision_frequency(density: f64, diameter: f64, mass: f64, temperature: f64) -> f64 {
+    let chi = contact_value(packing_fraction(density, diameter));
+    2. * density * diameter * chi * (PI * BOLTZMANN_CONSTANT * temperature / mass).sqrt()
+}
+
+/// Rate at which collisions with a normal restitution coefficient below one cool a gas at rest,
+/// as a fraction of its temperature per unit of time. On average a collision loses
+/// (1 - e²) kT, shared between the two particles, each of which has kT.
+pub fn cooling_rate(restitution: f64, collision_frequency: f64) -> f64 {
+    (1. - restitution * restitution) * collision_frequency / 2.
+}
+
+/// Haff's law: a freely cooling granular gas in its homogeneous state, starting at `initial`
+/// temperature, is at `initial / (1 + t / τ)²` after a time t. Returns τ.
+pub fn haff_time(restitution: f64, initial_collision_frequency: f64) -> f64 {
+    2. / cooling_rate(restitution, initial_collision_frequency)
+}
+
+/// Temperature at which random kicks heating every particle at `heating_rate` kelvins per unit
+/// of time make up for the energy lost in collisions.
+pub fn driven_temperature(
+    restitution: f64,
+    heating_rate: f64,
+    density: f64,
+    diameter: f64,
+    mass: f64,
+) -> f64 {
+    // The cooling rate goes as the square root of the temperature, since collisions get more
+    // frequent as particles go faster
+    let rate_at_one_kelvin = cooling_rate(
+        restitution,
+        collision_frequency(density, diameter, mass, 1.),
+    );
+    (heating_rate / rate_at_one_kelvin).powf(2. / 3.)
+}
+
+/// Shear viscosity of a dilute gas of hard discs, a force per unit length over a velocity
+/// gradient in two dimensions, so in kg/s. From the first Sonine approximation of
+/// Chapman-Enskog theory, σ⁻¹ √(mkT/π) / 2, and Gass's correction for the higher ones.
+pub fn dilute_viscosity(diameter: f64, mass: f64, temperature: f64) -> f64 {
+    1.022 / (2. * diameter) * (mass * BOLTZMANN_CONSTANT * temperature / PI).sqrt()
+}
+
+/// Thermal conductivity of a dilute gas of hard discs, a heat flow per unit length over a
+/// temperature gradient in two dimensions, so in W/K. From the first Sonine approximation,
+/// 2kσ⁻¹ √(kT/πm), and Gass's correction for the higher ones.
+pub fn dilute_conductivity(diameter: f64, mass: f64, temperature: f64) -> f64 {
+    1.029 * 2. * BOLTZMANN_CONSTANT / diameter
+        * (BOLTZMANN_CONSTANT * temperature / (PI * mass)).sqrt()
+}
+
+/// Shear viscosity of hard discs at any density in Enskog's theory. Collisions are more
+/// frequent by the contact value χ, and they carry momentum across the diameter separating the
+/// two discs on top of the particles carrying it along their paths.
+pub fn enskog_viscosity(density: f64, diameter: f64, mass: f64, temperature: f64) -> f64 {
+    let phi = packing_fraction(density, diameter);
+    let chi = contact_value(phi);
+    dilute_viscosity(diameter, mass, temperature)
+        * (1. / chi + 2. * phi + (1. + 8. / PI) * chi * phi * phi)
+}
+
+/// Thermal conductivity of hard discs at any density in Enskog's theory, see
+/// [`enskog_viscosity`].
+pub fn enskog_conductivity(density: f64, diameter: f64, mass: f64, temperature: f64) -> f64 {
+    let phi = packing_fraction(density, diameter);
+    let chi = contact_value(phi);
+    dilute_conductivity(diameter, mass, temperature)
+        * (1. / chi + 3. * phi + (9. / 4. + 4. / PI) * chi * phi * phi)
+}
+
+/// Self-diffusion coefficient of hard discs in Enskog's theory, in m²/s, from the first Sonine
+/// approximation: (2nσχ)⁻¹ √(kT/πm). In two dimensions the long-time tail of the velocity
+/// autocorrelation function adds to it, more so the larger the container.
+pub fn enskog_diffusion(density: f64, diameter: f64, mass: f64, temperature: f64) -> f64 {
+    let chi = contact_value(packing_fraction(density, diameter));
+    (BOLTZMANN_CONSTANT * temperature / (PI * mass)).sqrt() / (2. * density * diameter * chi)
+}
+
+/// Packing fraction of discs on a hexagonal lattice where they all touch, π/(2√3), the densest
+/// they can get.
+pub const CLOSE_PACKING: f64 = 0.9069;
+
+/// Packing fractions between which the liquid coexists with the hexatic phase, which has
+/// orientational order but no positional order, and packing fraction above which the hexatic
+/// phase turns into a solid, from Bernard and Krauth's simulations of a million discs (2011).
+pub const LIQUID_HEXATIC: (f64, f64) = (0.700, 0.716);
+pub const HEXATIC_SOLID: f64 = 0.720;
+
+/// Compressibility factor PA/NkT of the hard disc fluid from Henderson's equation of state,
+/// (1 + φ²/8)/(1 - φ)². It is 1 + 2φχ, with χ the [`contact_value`].
+pub fn henderson_compressibility(packing_fraction: f64) -> f64 {
+    1. + 2. * packing_fraction * contact_value(packing_fraction)
+}
+
+/// Compressibility factor of the hard disc fluid from scaled particle theory, 1/(1 - φ)². It
+/// overestimates the pressure of the dense fluid.
+pub fn scaled_particle_compressibility(packing_fraction: f64) -> f64 {
+    1. / (1. - packing_fraction).powi(2)
+}
+
+/// Compressibility factor of the hard disc solid from free volume theory, where every disc
+/// rattles in the cage of its neighbours frozen on the lattice: 2/(1 - √(φ/φcp)). It gets
+/// exact towards close packing.
+pub fn free_volume_compressibility(packing_fraction: f64) -> f64 {
+    2. / (1. - (packing_fraction / CLOSE_PACKING).sqrt())
+}
+
+/// Smallest area of the Voronoi cell of a disc, the hexagon around it when six neighbours touch
+/// it: √3σ²/2, the area per disc at close packing.
+pub fn smallest_cell_area(diameter: f64) -> f64 {
+    3f64.sqrt() / 2. * diameter * diameter
+}
+
+/// Probability density of the reduced free area x = (a - a_min)/(⟨a⟩ - a_min) of Voronoi cells,
+/// k^k x^(k-1) e^(-kx)/Γ(k): the k-gamma distribution of Aste and Di Matteo (2008), found in
+/// packings and fluids alike. The shape k is 1/Var(x).
+pub fn k_gamma_density(shape: f64, x: f64) -> f64 {
+    if x <= 0. {
+        return 0.;
+    }
+    (shape * shape.ln() + (shape - 1.) * x.ln() - shape * x - ln_gamma(shape)).exp()
+}
+
+/// Logarithm of the gamma function of a positive number, from Stirling's series once the
+/// recurrence Γ(x + 1) = xΓ(x) has made it large enough.
+fn ln_gamma(x: f64) -> f64 {
+    let (mut x, mut shift) = (x, 0.);
+    while x < 7. {
+        shift -= x.ln();
+        x += 1.;
+    }
+    shift + (x - 0.5) * x.ln() - x + (2. * PI).ln() / 2. + 1. / (12. * x)
+        - 1. / (360. * x.powi(3))
+        + 1. / (1260. * x.powi(5))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn henderson_compressibility_is_the_closed_form() {
+        for i in 0..=70 {
+            let phi = i as f64 / 100.;
+            let closed_form = (1. + phi * phi / 8.) / (1. - phi).powi(2);
+            assert!((henderson_compressibility(phi) - closed_form).abs() < 1e-12, "{phi}");
+        }
+        assert_eq!(contact_value(0.), 1.);
+        assert_eq!(scaled_particle_compressibility(0.), 1.);
+    }
+
+    #[test]
+    fn ln_gamma_matches_known_values() {
+        let known = [
+            (0.1, 9.513_507_698_668_732),
+            (0.5, PI.sqrt()),
+            (1., 1.),
+            (2., 1.),
+            (3.5, 3.323_350_970_447_843),
+            (5., 24.),
+            (10., 362_880.),
+            (30., 8.841_761_993_739_701e30),
+        ];
+        for (x, gamma) in known {
+            assert!((ln_gamma(x) - f64::ln(gamma)).abs() < 1e-8, "Γ({x})");
+        }
+    }
+
+    #[test]
+    fn k_gamma_density_is_normalized_with_unit_mean() {
+        for shape in [1., 2.5, 6., 20.] {
+            // Midpoint rule, far enough out for the tail to vanish
+            let (steps, end) = (200_000, 30.);
+            let dx = end / steps as f64;
+            let (mut total, mut mean) = (0., 0.);
+            for i in 0..steps {
+                let x = (i as f64 + 0.5) * dx;
+                total += k_gamma_density(shape, x) * dx;
+                mean += x * k_gamma_density(shape, x) * dx;
+            }
+            assert!((total - 1.).abs() < 1e-6, "k = {shape}: {total}");
+            assert!((mean - 1.).abs() < 1e-6, "k = {shape}: {mean}");
+        }
+        assert_eq!(k_gamma_density(3., 0.), 0.);
+        assert_eq!(k_gamma_density(3., -1.), 0.);
+    }
+
+    #[test]
+    fn driven_temperature_balances_heating_and_cooling() {
+        let (diameter, mass, density) = (10., 6.6e-27, 5e-4);
+        for restitution in [0.5, 0.9, 0.99] {
+            let heating_rate = 1e3;
+            let temperature =
+                driven_temperature(restitution, heating_rate, density, diameter, mass);
+            let frequency = collision_frequency(density, diameter, mass, temperature);
+            let rate = cooling_rate(restitution, frequency);
+            assert!((rate * temperature / heating_rate - 1.).abs() < 1e-9, "{restitution}");
+            assert!((haff_time(restitution, frequency) * rate - 2.).abs() < 1e-12);
+        }
+        // Elastic collisions don't cool the gas at all
+        assert_eq!(cooling_rate(1., 10.), 0.);
+        assert!(haff_time(1., 10.).is_infinite());
+    }
+
+    #[test]
+    fn collision_frequency_goes_to_the_dilute_limit() {
+        let (diameter, mass, temperature) = (1e-10, 6.6e-27, 300.);
+        let density = 1e12;
+        let dilute =
+            2. * density * diameter * (PI * BOLTZMANN_CONSTANT * temperature / mass).sqrt();
+        let frequency = collision_frequency(density, diameter, mass, temperature);
+        assert!((frequency / dilute - 1.).abs() < 1e-6);
+        // Denser gases collide more often than their density alone would make them
+        let dense = collision_frequency(1e4 * density, diameter, mass, temperature);
+        assert!(dense / frequency > 1e4);
+    }
+}
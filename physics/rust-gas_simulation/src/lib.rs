@@ -0,0 +1,10 @@
+//! The parts of the gas simulation that don't need the app: the geometry of the walls, the
+//! physics core and its units, which can be benchmarked and run without a window, the
+//! predictions of kinetic theory to check it against, and the Delaunay triangulation and Voronoi
+//! cells that find the neighbours of the particles.
+
+pub mod geometry;
+pub mod kinetic;
+pub mod physics;
+pub mod tessellation;
+pub mod units;
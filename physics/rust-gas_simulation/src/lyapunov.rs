@@ -0,0 +1,408 @@
+use std::mem;
+
+use bevy::{ecs::system::SystemParam, math::DVec2, prelude::*};
+use bevy_egui::{egui, EguiContexts};
+use egui_plot::{Line, Plot, PlotPoints, Points};
+
+use crate::{
+    clock::SimulationClock, inspector::Selected, physics::Gas, playback::physics_running,
+    Particle, SimulationState,
+};
+
+/// Initial displacement of the perturbed particle, in units of length of the gas. It has to stay
+/// well above the rounding error of the positions.
+const PERTURBATION: f64 = 1e-6;
+/// The perturbed copy is pulled back towards the reference once their separation has grown by
+/// this factor, long before it saturates at the size of the container.
+const RENORMALIZE_AT: f64 = 100.;
+/// Velocities are multiplied by this time, in units of time of the gas, to be compared with
+/// positions in the phase space distance. The exponents don't depend on it.
+const VELOCITY_SCALE: f64 = 1.;
+/// Largest number of particles for which the full spectrum is computed. It takes one copy of
+/// the gas for each of the 4N phase space directions.
+const SPECTRUM_MAX_PARTICLES: usize = 16;
+/// Number of ticks between two orthonormalizations of the spectrum's tangent vectors.
+const SPECTRUM_INTERVAL: u32 = 8;
+/// Cap on the points of the separation curve of a twin run. Reaching it halves the resolution of
+/// the curve, so that a run left going for hours holds a bounded amount of memory and the fit of
+/// the exponent still spans all of it.
+const CURVE_LENGTH: usize = 4096;
+
+/// Measures how chaotic the gas is. A twin run steps two headless copies of the gas, one of them
+/// with a single particle displaced by a tiny amount, and tracks how fast they drift apart in
+/// phase space (L). The rate of that exponential divergence is the largest Lyapunov exponent.
+/// For a handful of particles, the full Lyapunov spectrum can be computed as well.
+pub struct LyapunovPlugin;
+
+impl Plugin for LyapunovPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(OnEnter(SimulationState::Setup), stop_runs)
+            .add_systems(
+                FixedUpdate,
+                (
+                    advance_twin.run_if(resource_exists::<Twin>),
+                    advance_spectrum.run_if(resource_exists::<Spectrum>),
+                )
+                    .after(crate::step_gas)
+                    .run_if(physics_running),
+            )
+            .add_systems(Update, (check_chaos_keys, chaos_window));
+    }
+}
+
+/// A reference copy of the gas and a perturbed one, stepped side by side.
+#[derive(Resource)]
+struct Twin {
+    reference: Gas,
+    perturbed: Gas,
+    time: f64,
+    /// Growth of the separation taken out by the renormalizations, as a logarithm
+    log_growth: f64,
+    /// Logarithm of the separation relative to the initial one against time, as if it had never
+    /// been renormalized
+    curve: Vec<[f64; 2]>,
+    /// Number of ticks between two points of the curve
+    stride: u32,
+    ticks: u32,
+}
+
+/// Benettin's method: a reference copy of the gas and one copy displaced along each phase space
+/// direction. The displacements are regularly orthonormalized, and the logarithms of how much
+/// each one grew before that add up to the exponents.
+#[derive(Resource)]
+struct Spectrum {
+    reference: Gas,
+    offsets: Vec<Gas>,
+    /// Ticks since the last orthonormalization
+    ticks: u32,
+    /// Time since the last orthonormalization
+    interval: f64,
+    /// Time of the last orthonormalization
+    time: f64,
+    log_growths: Vec<f64>,
+}
+
+/// Everything needed to copy the gas on screen.
+#[derive(SystemParam)]
+struct GasSource<'w, 's> {
+    gas: Res<'w, Gas>,
+    selected: Query<'w, 's, &'static Particle, With<Selected>>,
+}
+
+impl GasSource<'_, '_> {
+    fn number_of_particles(&self) -> usize {
+        self.gas.positions.len()
+    }
+
+    /// A headless copy of the gas and the index of the selected particle, if any.
+    fn copy(&self) -> (Gas, Option<usize>) {
+        let selected = self.selected.get_single().ok().map(|particle| particle.index);
+        (self.gas.clone(), selected)
+    }
+}
+
+impl Twin {
+    /// Start from the gas on screen, displacing the selected particle or else the first one.
+    fn new(source: &GasSource) -> Option<Self> {
+        let (reference, selected) = source.copy();
+        if reference.positions.is_empty() {
+            return None;
+        }
+        let mut perturbed = reference.clone();
+        perturbed.positions[selected.unwrap_or(0)].x += PERTURBATION;
+        Some(Twin {
+            reference,
+            perturbed,
+            time: 0.,
+            log_growth: 0.,
+            curve: Vec::new(),
+            stride: 1,
+            ticks: 0,
+        })
+    }
+
+    /// Least squares estimate of the largest exponent over the second half of the run, leaving
+    /// out the transient while the separation lines up with the most unstable direction.
+    fn exponent(&self) -> Option<f64> {
+        fit_slope(&self.curve[self.curve.len() / 2..])
+    }
+}
+
+impl Spectrum {
+    fn new(source: &GasSource) -> Self {
+        let (reference, _) = source.copy();
+        let origin = phase_point(&reference);
+        let offsets = (0..origin.len())
+            .map(|k| {
+                let mut point = origin.clone();
+                point[k] += PERTURBATION;
+                let mut offset = reference.clone();
+                set_phase_point(&mut offset, &point);
+                offset
+            })
+            .collect();
+        Spectrum {
+            reference,
+            offsets,
+            ticks: 0,
+            interval: 0.,
+            time: 0.,
+            log_growths: vec![0.; origin.len()],
+        }
+    }
+
+    /// The exponents measured so far, from largest to smallest.
+    fn exponents(&self) -> Vec<f64> {
+        if self.time == 0. {
+            return Vec::new();
+        }
+        let mut exponents: Vec<f64> = self.log_growths.iter().map(|g| g / self.time).collect();
+        exponents.sort_by(|a, b| b.total_cmp(a));
+        exponents
+    }
+}
+
+/// Positions and scaled velocities of every particle, as a single point in phase space.
+fn phase_point(gas: &Gas) -> Vec<f64> {
+    gas.positions
+        .iter()
+        .zip(&gas.velocities)
+        .flat_map(|(x, v)| {
+            let v = *v * VELOCITY_SCALE;
+            [x.x, x.y, v.x, v.y]
+        })
+        .collect()
+}
+
+fn set_phase_point(gas: &mut Gas, point: &[f64]) {
+    for (i, chunk) in point.chunks_exact(4).enumerate() {
+        gas.positions[i] = DVec2::new(chunk[0], chunk[1]);
+        gas.velocities[i] = DVec2::new(chunk[2], chunk[3]) / VELOCITY_SCALE;
+    }
+}
+
+fn norm(vector: &[f64]) -> f64 {
+    vector.iter().map(|x| x * x).sum::<f64>().sqrt()
+}
+
+/// Least squares slope of a curve, or `None` if it doesn't have enough distinct points.
+pub fn fit_slope(points: &[[f64; 2]]) -> Option<f64> {
+    if points.len() < 2 {
+        return None;
+    }
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p[0]).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p[1]).sum::<f64>() / n;
+    let covariance: f64 = points.iter().map(|p| (p[0] - mean_x) * (p[1] - mean_y)).sum();
+    let variance: f64 = points.iter().map(|p| (p[0] - mean_x).powi(2)).sum();
+    (variance > 0.).then(|| covariance / variance)
+}
+
+fn stop_runs(mut commands: Commands) {
+    commands.remove_resource::<Twin>();
+    commands.remove_resource::<Spectrum>();
+}
+
+fn advance_twin(mut twin: ResMut<Twin>, clock: Res<SimulationClock>) {
+    let dt = clock.dt;
+    let twin = &mut *twin;
+    twin.reference.step(dt);
+    twin.perturbed.step(dt);
+    twin.time += dt;
+
+    let reference = phase_point(&twin.reference);
+    let mut separation: Vec<f64> = phase_point(&twin.perturbed)
+        .iter()
+        .zip(&reference)
+        .map(|(p, r)| p - r)
+        .collect();
+    let distance = norm(&separation);
+    if distance == 0. {
+        // Both copies ran into the same rounding and merged back, nothing to measure
+        return;
+    }
+    let growth = (distance / PERTURBATION).ln();
+    twin.ticks += 1;
+    if twin.ticks >= twin.stride {
+        twin.ticks = 0;
+        twin.curve.push([twin.time, twin.log_growth + growth]);
+        if twin.curve.len() >= CURVE_LENGTH {
+            twin.curve = twin.curve.iter().step_by(2).copied().collect();
+            twin.stride *= 2;
+        }
+    }
+
+    // Bring the perturbed copy back along the separation, which keeps pointing in the most
+    // unstable direction
+    if distance > RENORMALIZE_AT * PERTURBATION {
+        twin.log_growth += growth;
+        for (s, r) in separation.iter_mut().zip(&reference) {
+            *s = r + *s * PERTURBATION / distance;
+        }
+        set_phase_point(&mut twin.perturbed, &separation);
+    }
+}
+
+fn advance_spectrum(mut spectrum: ResMut<Spectrum>, clock: Res<SimulationClock>) {
+    let dt = clock.dt;
+    let spectrum = &mut *spectrum;
+    spectrum.reference.step(dt);
+    for offset in &mut spectrum.offsets {
+        offset.step(dt);
+    }
+    // Time steps change with the speed of the fastest particle, so they are added up one by one
+    spectrum.interval += dt;
+    spectrum.ticks += 1;
+    if spectrum.ticks < SPECTRUM_INTERVAL {
+        return;
+    }
+    spectrum.ticks = 0;
+    spectrum.time += mem::take(&mut spectrum.interval);
+
+    // Gram-Schmidt on the displacements, in order, so that the k-th one measures how the volume
+    // spanned by the first k grows
+    let reference = phase_point(&spectrum.reference);
+    let mut basis: Vec<Vec<f64>> = Vec::with_capacity(spectrum.offsets.len());
+    for (k, offset) in spectrum.offsets.iter_mut().enumerate() {
+        let mut vector: Vec<f64> = phase_point(offset)
+            .iter()
+            .zip(&reference)
+            .map(|(p, r)| p - r)
+            .collect();
+        for e in &basis {
+            let projection: f64 = vector.iter().zip(e).map(|(v, e)| v * e).sum();
+            for (v, e) in vector.iter_mut().zip(e) {
+                *v -= projection * e;
+            }
+        }
+        let length = norm(&vector);
+        if length > 0. {
+            spectrum.log_growths[k] += (length / PERTURBATION).ln();
+            vector.iter_mut().for_each(|v| *v /= length);
+        }
+
+        let point: Vec<f64> = reference
+            .iter()
+            .zip(&vector)
+            .map(|(r, e)| r + PERTURBATION * e)
+            .collect();
+        set_phase_point(offset, &point);
+        basis.push(vector);
+    }
+}
+
+/// L starts a twin run from the current state of the gas, or stops it.
+fn check_chaos_keys(
+    mut commands: Commands,
+    keys: Res<ButtonInput<KeyCode>>,
+    twin: Option<Res<Twin>>,
+    source: GasSource,
+) {
+    if keys.just_pressed(KeyCode::KeyL) {
+        toggle_twin(&mut commands, twin.is_some(), &source);
+    }
+}
+
+fn toggle_twin(commands: &mut Commands, running: bool, source: &GasSource) {
+    if running {
+        commands.remove_resource::<Twin>();
+    } else if let Some(twin) = Twin::new(source) {
+        commands.insert_resource(twin);
+    }
+}
+
+fn chaos_window(
+    mut commands: Commands,
+    mut contexts: EguiContexts,
+    twin: Option<Res<Twin>>,
+    spectrum: Option<Res<Spectrum>>,
+    source: GasSource,
+) {
+    egui::Window::new("Chaos")
+        .default_pos([10., 800.])
+        .default_open(false)
+        .show(contexts.ctx_mut(), |ui| {
+            let label = if twin.is_some() { "Stop twin run" } else { "Start twin run" };
+            if ui.button(label).on_hover_text("L").clicked() {
+                toggle_twin(&mut commands, twin.is_some(), &source);
+            }
+            if let Some(twin) = &twin {
+                ui.label("Separation from the perturbed copy");
+                Plot::new("twin_separation")
+                    .height(150.)
+                    .x_axis_label("time [s]")
+                    .y_axis_label("ln(d / d0)")
+                    .show(ui, |plot| {
+                        plot.line(Line::new(PlotPoints::from_iter(twin.curve.iter().copied())))
+                    });
+                match twin.exponent() {
+                    Some(exponent) => ui.label(format!("Largest exponent: {exponent:.2} 1/s")),
+                    None => ui.label("Not enough data yet"),
+                };
+            }
+
+            ui.separator();
+            let n = source.number_of_particles();
+            let label = if spectrum.is_some() { "Stop spectrum" } else { "Full spectrum" };
+            let button = ui
+                .add_enabled(n <= SPECTRUM_MAX_PARTICLES, egui::Button::new(label))
+                .on_disabled_hover_text(format!(
+                    "Only for up to {SPECTRUM_MAX_PARTICLES} particles"
+                ));
+            if button.clicked() {
+                if spectrum.is_some() {
+                    commands.remove_resource::<Spectrum>();
+                } else {
+                    commands.insert_resource(Spectrum::new(&source));
+                }
+            }
+            let Some(spectrum) = &spectrum else {
+                return;
+            };
+            let exponents = spectrum.exponents();
+            let points: Vec<[f64; 2]> = exponents
+                .iter()
+                .enumerate()
+                .map(|(i, exponent)| [i as f64, *exponent])
+                .collect();
+            Plot::new("lyapunov_spectrum")
+                .height(150.)
+                .x_axis_label("index")
+                .y_axis_label("exponent [1/s]")
+                .show(ui, |plot| plot.points(Points::new(points).radius(3.)));
+            // Exact hard disc dynamics conserve phase space volume, so the exponents would come
+            // in pairs of opposite sign and add up to zero. Pushing overlapping particles apart
+            // after the fact doesn't, and the sum shows by how much
+            let sum: f64 = exponents.iter().sum();
+            let entropy: f64 = exponents.iter().filter(|e| **e > 0.).sum();
+            ui.label(format!(
+                "Sum of the exponents: {sum:.2} 1/s\n\
+                 Kolmogorov-Sinai entropy (Pesin): {entropy:.2} 1/s"
+            ));
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fit_slope_recovers_a_line() {
+        let points: Vec<[f64; 2]> = (0..10).map(|i| [i as f64 * 0.5, 3. - 2. * i as f64]).collect();
+        assert!((fit_slope(&points).unwrap() + 4.).abs() < 1e-12);
+        // Noise symmetric about the line leaves the slope alone
+        let noisy: Vec<[f64; 2]> = points
+            .iter()
+            .enumerate()
+            .map(|(i, p)| [p[0], p[1] + if i % 2 == 0 { 0.1 } else { -0.1 }])
+            .collect();
+        assert!((fit_slope(&noisy).unwrap() + 4.).abs() < 0.05);
+    }
+
+    #[test]
+    fn fit_slope_needs_two_distinct_abscissas() {
+        assert_eq!(fit_slope(&[]), None);
+        assert_eq!(fit_slope(&[[1., 2.]]), None);
+        assert_eq!(fit_slope(&[[1., 2.], [1., 5.], [1., -3.]]), None);
+    }
+}
@@ -1,19 +1,79 @@
-use std::f32::consts::PI;
+use std::f64::consts::PI;
 
 use bevy::{
     diagnostic::{DiagnosticsStore, FrameTimeDiagnosticsPlugin},
+    ecs::schedule::ScheduleLabel,
     input::mouse::{MouseMotion, MouseWheel},
-    math::{
-        bounding::{Bounded2d, IntersectsVolume},
-        NormedVectorSpace,
-    },
+    math::NormedVectorSpace,
     prelude::*,
     sprite::Anchor,
     window::PrimaryWindow,
 };
-use rand;
+use clock::{ClockPlugin, SimulationClock, MAX_PLAYBACK_SPEED, MIN_PLAYBACK_SPEED};
+use coloring::ColoringPlugin;
+use conservation::ConservationPlugin;
+use controls::{pointer_over_panel, ControlsPlugin};
+use demon::DemonPlugin;
+use diffusion::DiffusionPlugin;
+use eos::EosPlugin;
+use fields::FieldsPlugin;
+use granular::GranularPlugin;
+use gas_simulation::{
+    geometry::{self, WallMotion},
+    physics::{self, Collision, Gas, INERTIA_FACTOR},
+    units::{self, Units},
+};
+use initial::{InitialConditions, SetupError, Spawn};
+use inspector::{FollowSelected, InspectorPlugin, Selected};
+use lyapunov::LyapunovPlugin;
+use order::OrderPlugin;
+use playback::{physics_running, PlaybackPlugin};
+use rand::{rngs::StdRng, SeedableRng};
+use scenario::{Scenario, ThermalWall};
+use voronoi::VoronoiPlugin;
+
+mod clock;
+mod coloring;
+mod colormap;
+mod conservation;
+mod controls;
+mod demon;
+mod diffusion;
+mod eos;
+mod fields;
+mod granular;
+mod initial;
+mod inspector;
+mod lyapunov;
+mod order;
+mod playback;
+mod scenario;
+mod sweep;
+mod transport;
+mod voronoi;
 
 fn main() {
+    let mut args = std::env::args().skip(1);
+    let first = args.next();
+    if first.as_deref() == Some("sweep") {
+        // Batch runs don't open a window
+        let Some(path) = args.next() else {
+            eprintln!("Usage: gas-simulation sweep <sweep file>");
+            std::process::exit(2);
+        };
+        if let Err(e) = sweep::run(&path) {
+            eprintln!("Sweep '{path}' failed: {e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let scenario = match first {
+        Some(path) => Scenario::load(&path)
+            .unwrap_or_else(|e| panic!("Could not load scenario file '{path}': {e}")),
+        None => Scenario::default(),
+    };
+
     App::new()
         .add_plugins(DefaultPlugins.set(WindowPlugin {
             primary_window: Some(Window {
@@ -22,66 +82,154 @@ fn main() {
             }),
             ..default()
         }))
-        .add_plugins(FrameTimeDiagnosticsPlugin)
-        .init_state::<PauseState>()
-        .insert_resource(Time::<Fixed>::from_hz(256.))
-        .add_systems(Startup, (setup, setup_fps_counter))
+        .add_plugins((
+            FrameTimeDiagnosticsPlugin,
+            DemonPlugin,
+            FieldsPlugin,
+            ColoringPlugin,
+            InspectorPlugin,
+            ControlsPlugin,
+            PlaybackPlugin,
+            LyapunovPlugin,
+            ConservationPlugin,
+            ClockPlugin,
+            GranularPlugin,
+            DiffusionPlugin,
+            EosPlugin,
+            OrderPlugin,
+            VoronoiPlugin,
+        ))
+        .insert_resource(SimulationConfig {
+            initial_conditions: scenario.initial_conditions.clone(),
+            thermal_walls: scenario.thermal_walls.clone(),
+            ..default()
+        })
+        .insert_resource(scenario)
+        .init_state::<SimulationState>()
+        .insert_resource(Time::<Fixed>::from_hz(PHYSICS_RATE))
+        .init_schedule(BuildSimulation)
+        .add_systems(Startup, (setup_camera, setup_fps_counter))
+        .add_systems(OnEnter(SimulationState::Setup), build_simulation)
+        .add_systems(BuildSimulation, setup)
+        .add_systems(FixedUpdate, step_gas.run_if(physics_running))
         .add_systems(
-            FixedUpdate,
-            (
-                move_particles,
-                check_particle_collisions,
-                check_wall_collisions,
-            )
-                .chain()
-                .run_if(in_state(PauseState::Running)),
+            PostUpdate,
+            (sync_particles, sync_walls)
+                .before(TransformSystem::TransformPropagate)
+                .run_if(resource_exists_and_changed::<Gas>),
         )
         .add_systems(
             Update,
             (
                 check_keyboard_input,
-                drag_camera,
-                update_histogram.run_if(in_state(PauseState::Running)),
+                drag_camera.run_if(not(pointer_over_panel)),
+                update_histogram,
+                update_mb_distribution.run_if(resource_changed::<SimulationConfig>),
                 draw_distribution_overlay,
                 update_fps,
-                (update_temperature_text, update_entropy_text)
-                    .chain()
-                    .run_if(in_state(PauseState::Running)),
-                update_slow_mo_text,
+                (update_temperature_text, update_entropy_text).chain(),
+                update_playback_speed_text,
             ),
         )
         .run();
 }
 
+/// The simulation starts in `Setup`, where everything is built from the configuration, and then
+/// goes to `Paused`. Going back to `Setup` resets the simulation.
 #[derive(States, Default, Debug, Clone, PartialEq, Eq, Hash)]
-enum PauseState {
+enum SimulationState {
     #[default]
+    Setup,
     Paused,
     Running,
 }
 
+/// Schedule that builds everything in the simulation from the current configuration. Runs every
+/// time the simulation enters [`SimulationState::Setup`].
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+struct BuildSimulation;
+
+/// Marks the entities that are despawned and built again when resetting the simulation.
 #[derive(Component, Default)]
-#[require(Transform, Mesh2d, MeshMaterial2d<ColorMaterial>)]
+struct SimulationEntity;
+
+/// Simulation parameters that can be changed at runtime from the control panel.
+#[derive(Resource, Clone, PartialEq)]
+struct SimulationConfig {
+    number_of_particles: u32,
+    particle_radius: f32,
+    /// Sets the starting speed of the particles
+    temperature: f32,
+    /// Smallest number of physics steps per simulated second. Steps get shorter when particles
+    /// are fast
+    physics_rate: f64,
+    /// Upper end of the speed histogram
+    max_speed: f32,
+    /// Seed of the random number generator used to set up the particles
+    seed: u64,
+    initial_conditions: InitialConditions,
+    /// Simulate in reduced units, with the particle diameter, mass and the thermal energy at the
+    /// configured temperature as units, instead of SI units
+    reduced_units: bool,
+    /// -1 for smooth discs, up to 1 for perfectly rough discs that trade energy between
+    /// translation and rotation when they collide
+    tangential_restitution: f64,
+    /// Fraction of the normal relative speed particles keep when colliding with each other, 1
+    /// for elastic collisions
+    restitution: f64,
+    /// Fraction of the normal speed particles keep when bouncing off a wall
+    wall_restitution: f64,
+    /// Kelvins per second the random kicks heat every particle by, zero for none
+    heating_rate: f64,
+    /// Walls kept at a temperature, as set up by the scenario
+    thermal_walls: Vec<ThermalWall>,
+}
+
+/// A copy of the state of a particle in the [`Gas`], updated once per frame. The physics only
+/// works on the gas, so changes to the particle have to be made there.
+#[derive(Component, Default, Clone, Copy)]
+#[require(Transform, Mesh2d, MeshMaterial2d<ColorMaterial>, SimulationEntity)]
 struct Particle {
+    /// Index of the particle in the gas
+    index: usize,
     radius: f32,
     mass: f32,
+    /// Moment of inertia, in kg m^2
+    inertia: f32,
     velocity: Vec2,
+    /// Angular velocity, in radians per second, counterclockwise
+    spin: f32,
 }
 
-enum WallOrientation {
-    Vertical,
-    Horizontal,
+/// What happened to a particle since the simulation started.
+#[derive(Component, Default, Clone, Copy)]
+struct ParticleHistory {
+    /// Where the particle was spawned
+    origin: Vec2,
+    /// Number of collisions with other particles. Bounces off walls are not counted
+    collisions: u32,
+    /// Simulated time since the last collision with another particle (or since the start)
+    time_since_collision: f32,
+    distance_travelled: f32,
 }
 
+/// A line from the center of a rough particle to its edge, which shows how it turns.
+#[derive(Component)]
+#[require(Transform, Mesh2d, MeshMaterial2d<ColorMaterial>, SimulationEntity)]
+struct SpinMarker;
+
+/// The mesh of a wall. The walls particles bounce off are the ones in the [`Gas`].
 #[derive(Component)]
-#[require(Transform, Mesh2d, MeshMaterial2d<ColorMaterial>)]
+#[require(Transform, Mesh2d, MeshMaterial2d<ColorMaterial>, SimulationEntity)]
 struct Wall {
-    width: f32,
-    height: f32,
-    orientation: WallOrientation,
+    /// Index of the wall in the gas
+    index: usize,
+    /// Where the mesh is when the wall is where it started
+    rest: Vec3,
 }
 
 #[derive(Component)]
+#[require(SimulationEntity)]
 struct HistogramBar {
     elems: u32,
 }
@@ -89,6 +237,11 @@ struct HistogramBar {
 #[derive(Resource)]
 struct HistogramBins(Vec<f32>);
 
+/// Center of the first histogram bar when it is empty. Everything else in the histogram is placed
+/// relative to it.
+#[derive(Resource)]
+struct HistogramOrigin(Vec3);
+
 #[derive(Resource)]
 struct MBDistribution(CubicCurve<f32>);
 
@@ -105,10 +258,7 @@ struct EntropyText;
 struct Entropy(f32);
 
 #[derive(Component)]
-struct SlowMoText;
-
-#[derive(Resource)]
-struct SlowMo(f32);
+struct PlaybackSpeedText;
 
 #[derive(Component)]
 struct FpsText;
@@ -125,186 +275,225 @@ const STARTING_SPEED: f32 = 1103.3; // m/s (average speed of helium at T = 293 K
 const AVG_KINETIC_ENERGY: f32 = PARTICLE_MASS / 2. * STARTING_SPEED * STARTING_SPEED;
 // Recalculate temp from speed just to check that we did the equipartition math right
 const TEMPERATURE: f32 = AVG_KINETIC_ENERGY / BOLTZMANN_CONSTANT;
-const BOLTZMANN_CONSTANT: f32 = 1.38e-23;
-const REDUCED_PLANCK_CONSTANT: f32 = 1.055e-34; // Js
+const BOLTZMANN_CONSTANT: f32 = units::BOLTZMANN_CONSTANT as f32;
+const REDUCED_PLANCK_CONSTANT: f64 = 1.055e-34; // Js
 const ATOMIC_MASS_UNIT: f32 = 1.660e-27; // kg
 const ELEMENTARY_CHARGE: f32 = 1.602e-19; // C, to convert to electronvolts
 
-// Parameters for the spawn grid
-const SPAWN_TOP_LEFT: Vec2 = Vec2::new(
-    BOX_TOP_LEFT.x + 2. * WALL_THICKNESS,
-    BOX_TOP_LEFT.y - 2. * WALL_THICKNESS,
-);
-const SPAWN_BOTTOM_RIGHT: Vec2 = Vec2::new(
-    BOX_BOTTOM_RIGHT.x - 2. * WALL_THICKNESS,
-    BOX_BOTTOM_RIGHT.y + 2. * WALL_THICKNESS,
-);
-const SPAWN_X_GAP: f32 = PARTICLE_RADIUS * 4.;
-const SPAWN_Y_GAP: f32 = PARTICLE_RADIUS * 4.;
-
 // Walls
-const BOX_WIDTH: f32 = 1000.;
-const BOX_HEIGHT: f32 = 700.;
 const WALL_THICKNESS: f32 = 10.;
-const BOX_AREA: f32 = (BOX_WIDTH - WALL_THICKNESS) * (BOX_HEIGHT - WALL_THICKNESS);
-
-const HORI_OFFSET: f32 = BOX_WIDTH / 2.;
-const VERT_OFFSET: f32 = (BOX_HEIGHT - WALL_THICKNESS) / 2.;
-const LEFT_WALL_CENTER: Vec3 = Vec3::new(-HORI_OFFSET, 0., 0.);
-const RIGHT_WALL_CENTER: Vec3 = Vec3::new(HORI_OFFSET, 0., 0.);
-const TOP_WALL_CENTER: Vec3 = Vec3::new(0., VERT_OFFSET, 0.);
-const BOTTOM_WALL_CENTER: Vec3 = Vec3::new(0., -VERT_OFFSET, 0.);
-
-const BOX_BOTTOM_RIGHT: Vec3 = Vec3::new(
-    HORI_OFFSET + WALL_THICKNESS / 2.,
-    -VERT_OFFSET - WALL_THICKNESS / 2.,
-    0.,
-);
-const BOX_BOTTOM_LEFT: Vec3 = Vec3::new(
-    -HORI_OFFSET - WALL_THICKNESS / 2.,
-    -VERT_OFFSET - WALL_THICKNESS / 2.,
-    0.,
-);
-const BOX_TOP_RIGHT: Vec3 = Vec3::new(
-    HORI_OFFSET + WALL_THICKNESS / 2.,
-    VERT_OFFSET + WALL_THICKNESS / 2.,
-    0.,
-);
-const BOX_TOP_LEFT: Vec3 = Vec3::new(
-    -HORI_OFFSET - WALL_THICKNESS / 2.,
-    VERT_OFFSET + WALL_THICKNESS / 2.,
-    0.,
-);
 
 // Histogram
 const BINS: u32 = 10;
 const MAX_SPEED: f32 = STARTING_SPEED * 3.;
-const BIN_WIDTH: f32 = MAX_SPEED / BINS as f32;
 
-const BAR_WIDTH: f32 = 20.; // This is in world units, for the mesh geometry. Not to be confused with BIN_WIDTH.
-const HIST_HEIGHT: f32 = BOX_HEIGHT - 100.;
+const BAR_WIDTH: f32 = 20.; // This is in world units, for the mesh geometry. Not to be confused with the bin width.
+const HIST_HEIGHT: f32 = 600.;
 const BAR_GAP: f32 = 10.;
-const HEIGHT_PER_ELEM: f32 = HIST_HEIGHT / (0.3 * NUMBER_OF_PARTICLES as f32);
 const LABEL_OFFSET: f32 = -30.;
+const HIST_WIDTH: f32 = (BINS as f32 - 1.) * (BAR_WIDTH + BAR_GAP) + BAR_WIDTH;
 
 const GAP_FROM_BOX: f32 = 20.;
-const FIRST_BAR_INIITAL_CENTER: Vec3 = Vec3::new(
-    BOX_BOTTOM_RIGHT.x + GAP_FROM_BOX + BAR_WIDTH / 2.,
-    BOX_BOTTOM_RIGHT.y - LABEL_OFFSET + 10.,
-    0.,
-);
-const LAST_BAR_INIITAL_CENTER: Vec3 = Vec3::new(
-    FIRST_BAR_INIITAL_CENTER.x + (BINS as f32 - 1.) * (BAR_WIDTH + BAR_GAP),
-    FIRST_BAR_INIITAL_CENTER.y,
-    0.,
-);
-const HIST_WIDTH: f32 = LAST_BAR_INIITAL_CENTER.x - FIRST_BAR_INIITAL_CENTER.x + BAR_WIDTH;
 
 // Physics
 // const BOLTZMANN_CONSTANT: f32 = 1.;
 // const REDUCED_PLANCK_CONSTANT: f32 = 1.;
+const PHYSICS_RATE: f64 = 256.; // Hz, smallest number of physics steps per simulated second
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        SimulationConfig {
+            number_of_particles: NUMBER_OF_PARTICLES,
+            particle_radius: PARTICLE_RADIUS,
+            temperature: TEMPERATURE,
+            physics_rate: PHYSICS_RATE,
+            max_speed: MAX_SPEED,
+            seed: rand::random(),
+            initial_conditions: InitialConditions::default(),
+            reduced_units: false,
+            tangential_restitution: -1.,
+            restitution: 1.,
+            wall_restitution: 1.,
+            heating_rate: 0.,
+            thermal_walls: Vec::new(),
+        }
+    }
+}
+
+impl SimulationConfig {
+    /// Speed of a particle with the average kinetic energy at the configured temperature.
+    fn starting_speed(&self) -> f32 {
+        (2. * BOLTZMANN_CONSTANT * self.temperature / PARTICLE_MASS).sqrt()
+    }
+
+    fn rough(&self) -> bool {
+        self.tangential_restitution > -1.
+    }
+
+    /// Moment of inertia of a particle.
+    fn particle_inertia(&self) -> f32 {
+        INERTIA_FACTOR as f32 * PARTICLE_MASS * self.particle_radius.powi(2)
+    }
+
+    /// The units the gas is simulated in.
+    fn units(&self) -> Units {
+        if self.reduced_units {
+            Units::reduced(
+                2. * self.particle_radius as f64,
+                PARTICLE_MASS as f64,
+                self.temperature as f64,
+            )
+        } else {
+            Units::SI
+        }
+    }
+
+    fn bin_width(&self) -> f32 {
+        self.max_speed / BINS as f32
+    }
+
+    fn height_per_elem(&self) -> f32 {
+        HIST_HEIGHT / (0.3 * self.number_of_particles as f32)
+    }
+}
 
 /* SYSTEMS */
-fn setup(
+fn setup_camera(
     mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
     mut window: Query<&mut Window, With<PrimaryWindow>>,
+    scenario: Res<Scenario>,
 ) {
     window.single_mut().set_maximized(true);
 
-    commands.spawn((Camera2d::default(), Transform::from_xyz(160., 0., 0.)));
+    let bounds = scenario.bounds(WALL_THICKNESS);
+    commands.spawn((
+        Camera2d::default(),
+        Transform::from_translation(bounds.center().extend(0.) + Vec3::new(160., 0., 0.)),
+    ));
+}
 
-    let mesh_id = meshes.add(Circle::new(PARTICLE_RADIUS));
-    let material_id = materials.add(ColorMaterial::from_color(Color::WHITE));
+/// Despawn whatever is left of the previous run and build everything again from the current
+/// configuration. A fresh simulation always starts paused.
+fn build_simulation(world: &mut World) {
+    let mut query = world.query_filtered::<Entity, With<SimulationEntity>>();
+    let entities: Vec<Entity> = query.iter(world).collect();
+    for entity in entities {
+        world.despawn(entity);
+    }
+    world.run_schedule(BuildSimulation);
+    world
+        .resource_mut::<NextState<SimulationState>>()
+        .set(SimulationState::Paused);
+}
 
-    let mut rng = rand::thread_rng();
-    let mut spawn_point = SPAWN_TOP_LEFT;
-    for _ in 0..NUMBER_OF_PARTICLES {
-        let translation = Vec3::new(spawn_point.x, spawn_point.y, 0.);
-        let velocity = Dir2::from_rng(&mut rng) * STARTING_SPEED;
+fn setup(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    scenario: Res<Scenario>,
+    config: Res<SimulationConfig>,
+    time: Res<Time<Virtual>>,
+) {
+    let bounds = scenario.bounds(WALL_THICKNESS);
+    let box_top_left = Vec3::new(bounds.min.x, bounds.max.y, 0.);
+    let box_top_right = bounds.max.extend(0.);
+    let box_bottom_left = bounds.min.extend(0.);
+    let box_bottom_right = Vec3::new(bounds.max.x, bounds.min.y, 0.);
 
-        commands.spawn((
+    let mesh_id = meshes.add(Circle::new(config.particle_radius));
+    let material_id = materials.add(ColorMaterial::from_color(Color::WHITE));
+    let radius = config.particle_radius;
+    let marker_mesh = meshes.add(Rectangle::new(radius, radius / 5.));
+    let marker_material = materials.add(ColorMaterial::from_color(Color::BLACK));
+
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let spawns = config.initial_conditions.generate(
+        &scenario,
+        config.number_of_particles,
+        config.particle_radius,
+        config.temperature,
+        &mut rng,
+    );
+    // Leave the container empty rather than silently starting from something else
+    let spawns = match spawns {
+        Ok(spawns) => {
+            commands.insert_resource(SetupError(None));
+            spawns
+        }
+        Err(e) => {
+            error!("Could not set up the particles: {e}");
+            commands.insert_resource(SetupError(Some(e)));
+            Vec::new()
+        }
+    };
+
+    let mut speeds = Vec::with_capacity(spawns.len());
+    for (index, spawn) in spawns.iter().enumerate() {
+        speeds.push(spawn.velocity.norm());
+        let mut particle = commands.spawn((
             Particle {
-                radius: PARTICLE_RADIUS,
+                index,
+                radius: config.particle_radius,
                 mass: PARTICLE_MASS,
-                velocity,
+                inertia: config.particle_inertia(),
+                velocity: spawn.velocity,
+                spin: 0.,
+            },
+            ParticleHistory {
+                origin: spawn.position,
+                ..default()
             },
-            Transform::from_translation(translation),
+            Transform::from_translation(spawn.position.extend(0.)),
             Mesh2d(mesh_id.clone()),
             MeshMaterial2d(material_id.clone()),
         ));
-
-        if spawn_point.x + SPAWN_X_GAP < SPAWN_BOTTOM_RIGHT.x {
-            spawn_point.x += SPAWN_X_GAP;
-        } else {
-            spawn_point.y -= SPAWN_Y_GAP;
-            spawn_point.x = SPAWN_TOP_LEFT.x;
+        if config.rough() {
+            particle.with_child((
+                SpinMarker,
+                Mesh2d(marker_mesh.clone()),
+                MeshMaterial2d(marker_material.clone()),
+                Transform::from_xyz(radius / 2., 0., 0.1),
+            ));
         }
     }
+    let spawned = speeds.len();
+    commands.insert_resource(new_gas(&spawns, &scenario, &config));
 
-    // Outer walls
-    let horizontal_mesh = meshes.add(Rectangle::new(BOX_WIDTH - WALL_THICKNESS, WALL_THICKNESS));
-    let vertical_mesh = meshes.add(Rectangle::new(WALL_THICKNESS, BOX_HEIGHT));
+    // Container walls and obstacles
     let wall_material = materials.add(ColorMaterial::from_color(Color::BLACK));
-
-    // Left wall
-    commands.spawn((
-        Wall {
-            width: WALL_THICKNESS,
-            height: BOX_HEIGHT,
-            orientation: WallOrientation::Vertical,
-        },
-        Transform::from_translation(LEFT_WALL_CENTER),
-        Mesh2d(vertical_mesh.clone()),
-        MeshMaterial2d(wall_material.clone()),
-    ));
-    // Right wall
-    commands.spawn((
-        Wall {
-            width: WALL_THICKNESS,
-            height: BOX_HEIGHT,
-            orientation: WallOrientation::Vertical,
-        },
-        Transform::from_translation(RIGHT_WALL_CENTER),
-        Mesh2d(vertical_mesh),
-        MeshMaterial2d(wall_material.clone()),
-    ));
-    // Top wall
-    commands.spawn((
-        Wall {
-            width: BOX_WIDTH,
-            height: WALL_THICKNESS,
-            orientation: WallOrientation::Horizontal,
-        },
-        Transform::from_translation(TOP_WALL_CENTER),
-        Mesh2d(horizontal_mesh.clone()),
-        MeshMaterial2d(wall_material.clone()),
-    ));
-    // Bottom wall
-    commands.spawn((
-        Wall {
-            width: BOX_WIDTH,
-            height: WALL_THICKNESS,
-            orientation: WallOrientation::Horizontal,
-        },
-        Transform::from_translation(BOTTOM_WALL_CENTER),
-        Mesh2d(horizontal_mesh),
-        MeshMaterial2d(wall_material),
-    ));
+    for (index, shape) in scenario.walls().iter().enumerate() {
+        let transform = shape.transform();
+        commands.spawn((
+            Mesh2d(meshes.add(shape.mesh(WALL_THICKNESS))),
+            MeshMaterial2d(wall_material.clone()),
+            transform,
+            Wall {
+                index,
+                rest: transform.translation,
+            },
+        ));
+    }
 
     // Histogram
-    let mut spawn_point = FIRST_BAR_INIITAL_CENTER;
-    let bar_mesh = meshes.add(Rectangle::new(BAR_WIDTH, HEIGHT_PER_ELEM));
+    let histogram_origin = Vec3::new(
+        box_bottom_right.x + GAP_FROM_BOX + BAR_WIDTH / 2.,
+        box_bottom_right.y - LABEL_OFFSET + 10.,
+        0.,
+    );
+    commands.insert_resource(HistogramOrigin(histogram_origin));
+    let mut spawn_point = histogram_origin;
+    let bar_mesh = meshes.add(Rectangle::new(BAR_WIDTH, config.height_per_elem()));
     let bar_color = materials.add(ColorMaterial::from_color(Srgba::rgb(0., 100., 100.)));
 
-    let thresholds: Vec<f32> = (0..=BINS).map(|i| i as f32 * BIN_WIDTH as f32).collect();
+    let bin_width = config.bin_width();
+    let thresholds: Vec<f32> = (0..=BINS).map(|i| i as f32 * bin_width).collect();
     commands.insert_resource(HistogramBins(thresholds));
 
     commands.spawn((
         Text2d::new("2D Maxwell-Boltzmann\nspeed distribution\n(in number of particles)"),
-        Transform::from_translation(BOX_TOP_RIGHT + Vec3::new(GAP_FROM_BOX, 0., 0.)),
+        Transform::from_translation(box_top_right + Vec3::new(GAP_FROM_BOX, 0., 0.)),
         Anchor::TopLeft,
+        SimulationEntity,
     ));
 
     for i in 0..BINS {
@@ -317,88 +506,108 @@ fn setup(
 
         if i % 2 == 0 {
             commands.spawn((
-                Text2d::new(format!("{:.0}", i as f32 * BIN_WIDTH)),
+                Text2d::new(format!("{:.0}", i as f32 * bin_width)),
                 Transform::from_xyz(spawn_point.x, spawn_point.y + LABEL_OFFSET, spawn_point.z),
+                SimulationEntity,
             ));
         }
 
         spawn_point.x += BAR_WIDTH + BAR_GAP;
     }
     commands.spawn((
-        Text2d::new(format!("{:.0}", BINS as f32 * BIN_WIDTH)),
+        Text2d::new(format!("{:.0}", BINS as f32 * bin_width)),
         Transform::from_xyz(spawn_point.x, spawn_point.y + LABEL_OFFSET, spawn_point.z),
+        SimulationEntity,
     ));
     commands.spawn((
         Text2d::new(format!("speed [m/s]")),
         Transform::from_xyz(
-            BOX_BOTTOM_RIGHT.x + (LAST_BAR_INIITAL_CENTER - FIRST_BAR_INIITAL_CENTER).x / 2.,
+            box_bottom_right.x + (HIST_WIDTH - BAR_WIDTH) / 2.,
             spawn_point.y + LABEL_OFFSET * 2.,
             spawn_point.z,
         ),
+        SimulationEntity,
     ));
 
     // Maxwell-Boltzmann distribution overlay
-    let sampling_points: Vec<f32> = (0..20).map(|i| i as f32 * MAX_SPEED / 20.).collect();
-    let pdf_points: Vec<f32> = sampling_points
-        .iter()
-        .map(|v| maxwell_boltzmann_2d_pdf(*v, PARTICLE_MASS, TEMPERATURE))
-        .collect();
-    let pdf_curve = CubicCardinalSpline::new(0.5, pdf_points)
-        .to_curve()
-        .unwrap();
-    commands.insert_resource(MBDistribution(pdf_curve));
+    commands.insert_resource(maxwell_boltzmann_curve(&config));
 
     // Info text
-    let speeds: Vec<f32> = vec![STARTING_SPEED; NUMBER_OF_PARTICLES as usize];
-    let temperature = calculate_temperature(PARTICLE_MASS, NUMBER_OF_PARTICLES as f32, speeds);
+    let temperature = calculate_temperature(PARTICLE_MASS, spawned as f32, speeds);
     commands.spawn((
-        Text2d::new(format!("Temperature: {TEMPERATURE:.1} K")),
+        Text2d::new(format!("Temperature: {temperature:.1} K")),
         TemperatureText,
-        Transform::from_translation(BOX_BOTTOM_RIGHT),
+        Transform::from_translation(box_bottom_right),
         Anchor::TopRight,
+        SimulationEntity,
     ));
     commands.insert_resource(Temperature(temperature));
 
-    let entropy = calculate_entropy(PARTICLE_MASS, NUMBER_OF_PARTICLES as f32, temperature);
+    let entropy = calculate_entropy(
+        PARTICLE_MASS,
+        spawned as f32,
+        temperature,
+        scenario.free_area(WALL_THICKNESS),
+    );
     commands.spawn((
         Text2d::new(format!("Entropy: {:.2} eV/K", entropy / ELEMENTARY_CHARGE)),
         EntropyText,
-        Transform::from_translation(BOX_BOTTOM_RIGHT - Vec3::new(0., 20., 0.)),
+        Transform::from_translation(box_bottom_right - Vec3::new(0., 20., 0.)),
         Anchor::TopRight,
+        SimulationEntity,
     ));
     commands.insert_resource(Entropy(entropy));
 
     commands.spawn((
-        Text2d::new(format!("Box dimensions: {BOX_WIDTH} m x {BOX_HEIGHT} m")),
-        Transform::from_translation(BOX_BOTTOM_LEFT),
+        Text2d::new(format!(
+            "Free area: {:.0} m^2",
+            scenario.free_area(WALL_THICKNESS)
+        )),
+        Transform::from_translation(box_bottom_left),
         Anchor::TopLeft,
+        SimulationEntity,
     ));
 
-    let slow_mo = SlowMo(1.);
     commands.spawn((
-        SlowMoText,
-        Text2d::new(format!(
-            "Slow motion: {}\n(Up/Down arrow to change, Spacebar to pause)",
-            slow_mo.0
-        )),
-        Transform::from_translation(BOX_BOTTOM_LEFT - Vec3::new(0., 20., 0.)),
+        PlaybackSpeedText,
+        Text2d::new(playback_speed_text(time.relative_speed_f64())),
+        Transform::from_translation(box_bottom_left - Vec3::new(0., 20., 0.)),
         Anchor::TopLeft,
+        SimulationEntity,
     ));
-    commands.insert_resource(slow_mo);
 
     commands.spawn((
         Text2d::new(format!(
             "Left click and drag to move camera, scroll to zoom"
         )),
-        Transform::from_translation(BOX_TOP_LEFT),
+        Transform::from_translation(box_top_left),
         Anchor::BottomLeft,
+        SimulationEntity,
     ));
 }
 
+/// Sample the 2D Maxwell-Boltzmann distribution at the configured temperature.
+fn maxwell_boltzmann_curve(config: &SimulationConfig) -> MBDistribution {
+    let sampling_points: Vec<f32> = (0..20).map(|i| i as f32 * config.max_speed / 20.).collect();
+    let pdf_points: Vec<f32> = sampling_points
+        .iter()
+        .map(|v| maxwell_boltzmann_2d_pdf(*v, PARTICLE_MASS, config.temperature))
+        .collect();
+    let pdf_curve = CubicCardinalSpline::new(0.5, pdf_points)
+        .to_curve()
+        .unwrap();
+    MBDistribution(pdf_curve)
+}
+
+fn update_mb_distribution(mut commands: Commands, config: Res<SimulationConfig>) {
+    commands.insert_resource(maxwell_boltzmann_curve(&config));
+}
+
 fn update_histogram(
     mut bar_query: Query<(&mut Transform, &mut HistogramBar)>,
     p_query: Query<&Particle>,
     thresholds: Res<HistogramBins>,
+    config: Res<SimulationConfig>,
 ) {
     // Initialize array of bin contents
     let mut bins: Vec<u32> = Vec::new();
@@ -422,117 +631,125 @@ fn update_histogram(
         let change = bins[i] as i32 - bar.elems as i32;
         bar.elems = bins[i];
         transform.scale.y = bar.elems as f32;
-        transform.translation.y += (change as f32 * HEIGHT_PER_ELEM) / 2.;
+        transform.translation.y += (change as f32 * config.height_per_elem()) / 2.;
     }
 }
 
-/// Move particles one time step.
-fn move_particles(
-    time: Res<Time>,
-    mut query: Query<(&Particle, &mut Transform)>,
-    slow_mo: Res<SlowMo>,
-) {
-    for (particle, mut transform) in &mut query {
-        transform.translation.x += particle.velocity.x * time.delta_secs() / slow_mo.0;
-        transform.translation.y += particle.velocity.y * time.delta_secs() / slow_mo.0;
+/// The gas of a new simulation, in the configured units.
+fn new_gas(spawns: &[Spawn], scenario: &Scenario, config: &SimulationConfig) -> Gas {
+    let positions: Vec<Vec2> = spawns.iter().map(|s| s.position).collect();
+    let velocities: Vec<Vec2> = spawns.iter().map(|s| s.velocity).collect();
+    let mut gas = Gas::new(
+        &positions,
+        &velocities,
+        config.particle_radius,
+        PARTICLE_MASS,
+        &scenario.walls(),
+        WALL_THICKNESS,
+        config.units(),
+    );
+    gas.tangential_restitution = config.tangential_restitution;
+    gas.restitution = config.restitution;
+    gas.wall_restitution = config.wall_restitution;
+    gas.set_heating_rate(config.heating_rate);
+    for thermal in &config.thermal_walls {
+        gas.set_wall_temperature(thermal.wall, Some(thermal.temperature as f64));
     }
-}
-
-/// Handle collisions between particles.
-fn check_particle_collisions(mut query: Query<(&mut Particle, &mut Transform)>) {
-    let mut combinations = query.iter_combinations_mut();
-    while let Some([(mut particle1, mut transform1), (mut particle2, mut transform2)]) =
-        combinations.fetch_next()
-    {
-        let x1 = transform1.translation.xy();
-        let x2 = transform2.translation.xy();
-        let isometry1 = Isometry2d::new(x1, Rot2::IDENTITY);
-        let isometry2 = Isometry2d::new(x2, Rot2::IDENTITY);
-        let circle1 = Circle::new(particle1.radius).bounding_circle(isometry1);
-        let circle2 = Circle::new(particle2.radius).bounding_circle(isometry2);
-
-        if circle1.intersects(&circle2) {
-            let v1 = particle1.velocity;
-            let v2 = particle2.velocity;
-
-            // Calculate the change in velocity due to an elastic collision
-            let delta_v = compute_velocity_delta(x1, x2, v1, v2);
-            particle1.velocity += delta_v;
-            particle2.velocity -= delta_v;
-
-            // "Unstuck" particles by moving them so that they do not overlap
-            let shift = compute_particle_overlap(x1, x2, particle1.radius, particle2.radius);
-            transform1.translation += shift / 2.;
-            transform2.translation -= shift / 2.;
+    gas.noise = StdRng::seed_from_u64(config.seed);
+    for (w, motion) in scenario.wall_motions().iter().enumerate() {
+        if *motion != WallMotion::Fixed {
+            gas.set_wall_motion(w, motion);
         }
     }
+    gas
 }
 
-/// Handle collisions between particles and walls.
-fn check_wall_collisions(
-    mut particles: Query<(&mut Particle, &mut Transform)>,
-    mut walls: Query<(&Wall, &Transform), Without<Particle>>,
+/// Advance the gas one time step and keep track of what happened to each particle.
+fn step_gas(
+    clock: Res<SimulationClock>,
+    mut gas: ResMut<Gas>,
+    mut histories: Query<(&Particle, &mut ParticleHistory)>,
 ) {
-    for (mut particle, mut p_transform) in &mut particles {
-        for (wall, w_transform) in &mut walls {
-            let p_pos = p_transform.translation.xy();
-            let w_pos = w_transform.translation.xy();
-            let p_iso = Isometry2d::new(p_pos, Rot2::IDENTITY);
-            let w_iso = Isometry2d::new(w_pos, Rot2::IDENTITY);
-            let p_hitbox = Circle::new(particle.radius).bounding_circle(p_iso);
-            let w_hitbox = Rectangle::new(wall.width, wall.height).aabb_2d(w_iso);
-
-            if p_hitbox.intersects(&w_hitbox) {
-                // Since walls are immovable objects, we just need to flip
-                // the velocity in the right direction when a particle hits one
-                match wall.orientation {
-                    WallOrientation::Horizontal => particle.velocity.y = -particle.velocity.y,
-                    WallOrientation::Vertical => particle.velocity.x = -particle.velocity.x,
-                }
-
-                // "Unstuck" particles by moving them so that they do not overlap
-                let shift = compute_wall_overlap(
-                    p_pos,
-                    w_pos,
-                    particle.radius,
-                    WALL_THICKNESS,
-                    &wall.orientation,
-                );
-                p_transform.translation -= shift;
-            }
+    let dt = clock.dt as f32;
+    let speeds: Vec<f32> = (0..gas.velocities.len()).map(|i| gas.velocity(i).norm()).collect();
+    let report = gas.step(clock.dt);
+    let mut collisions = vec![0; speeds.len()];
+    for Collision { pair: (i, j), .. } in report.collisions {
+        collisions[i] += 1;
+        collisions[j] += 1;
+    }
+
+    histories.par_iter_mut().for_each(|(particle, mut history)| {
+        history.time_since_collision += dt;
+        history.distance_travelled += speeds[particle.index] * dt;
+        if collisions[particle.index] > 0 {
+            history.collisions += collisions[particle.index];
+            history.time_since_collision = 0.;
+        }
+    });
+}
+
+/// Copy the state of the gas to the particles, for rendering and for everything that reads them.
+/// Runs at the end of the frame so that changes made to the gas during the frame are drawn.
+fn sync_particles(gas: Res<Gas>, mut particles: Query<(&mut Particle, &mut Transform)>) {
+    particles
+        .par_iter_mut()
+        .for_each(|(mut particle, mut transform)| {
+            let position = gas.position(particle.index);
+            transform.translation = position.extend(transform.translation.z);
+            transform.rotation = Quat::from_rotation_z(gas.orientations[particle.index] as f32);
+            particle.velocity = gas.velocity(particle.index);
+            particle.spin = gas.spin(particle.index);
+        });
+}
+
+/// Move the meshes of the moving walls to where the walls are in the gas.
+fn sync_walls(gas: Res<Gas>, mut walls: Query<(&Wall, &mut Transform)>) {
+    for (wall, mut transform) in &mut walls {
+        if gas.wall_motions[wall.index] != WallMotion::Fixed {
+            transform.translation = wall.rest + gas.wall_offset(wall.index).extend(0.);
         }
     }
 }
 
 fn check_keyboard_input(
     keys: Res<ButtonInput<KeyCode>>,
-    state: Res<State<PauseState>>,
-    mut next_state: ResMut<NextState<PauseState>>,
-    mut slow_mo: ResMut<SlowMo>,
+    state: Res<State<SimulationState>>,
+    mut next_state: ResMut<NextState<SimulationState>>,
+    mut time: ResMut<Time<Virtual>>,
 ) {
     if keys.just_pressed(KeyCode::Space) {
         match state.get() {
-            PauseState::Paused => next_state.set(PauseState::Running),
-            PauseState::Running => next_state.set(PauseState::Paused),
+            SimulationState::Setup => {}
+            SimulationState::Paused => next_state.set(SimulationState::Running),
+            SimulationState::Running => next_state.set(SimulationState::Paused),
         }
     }
+    // The playback speed doubles or halves, so that every speed is only a few presses away
+    let speed = time.relative_speed_f64();
     if keys.just_pressed(KeyCode::ArrowUp) {
-        slow_mo.0 += 1.;
+        time.set_relative_speed_f64((speed * 2.).min(MAX_PLAYBACK_SPEED));
     }
-    if keys.just_pressed(KeyCode::ArrowDown) && slow_mo.0 >= 2. {
-        slow_mo.0 -= 1.;
+    if keys.just_pressed(KeyCode::ArrowDown) {
+        time.set_relative_speed_f64((speed / 2.).max(MIN_PLAYBACK_SPEED));
     }
 }
 
-/// Handle camera movement.
+/// Handle camera movement. When following the selected particle, the camera can only be zoomed.
 fn drag_camera(
     buttons: Res<ButtonInput<MouseButton>>,
     mut evread_motion: EventReader<MouseMotion>,
     mut evread_scroll: EventReader<MouseWheel>,
     mut query: Query<(&mut Transform, &mut OrthographicProjection), With<Camera2d>>,
+    follow: Res<FollowSelected>,
+    selected: Query<&Transform, (With<Selected>, Without<Camera2d>)>,
 ) {
     let (mut cam_transform, mut proj) = query.single_mut();
-    if buttons.pressed(MouseButton::Left) {
+    let followed = selected.get_single().ok().filter(|_| follow.0);
+    if let Some(target) = followed {
+        cam_transform.translation.x = target.translation.x;
+        cam_transform.translation.y = target.translation.y;
+    } else if buttons.pressed(MouseButton::Left) {
         for ev in evread_motion.read() {
             // Weigh camera drag by the current projection scale (i.e. zoom) so that it feels
             // the same at every zoom level
@@ -553,22 +770,24 @@ fn drag_camera(
     }
 }
 
-fn draw_distribution_overlay(mb_distr: Res<MBDistribution>, mut gizmos: Gizmos) {
+fn draw_distribution_overlay(
+    mb_distr: Res<MBDistribution>,
+    origin: Res<HistogramOrigin>,
+    config: Res<SimulationConfig>,
+    mut gizmos: Gizmos,
+) {
     let curve = &mb_distr.0;
     let resolution = 100 * curve.segments().len();
-    let starting_point = Vec2::new(
-        FIRST_BAR_INIITAL_CENTER.x - BAR_WIDTH / 2.,
-        FIRST_BAR_INIITAL_CENTER.y,
-    );
+    let starting_point = Vec2::new(origin.0.x - BAR_WIDTH / 2., origin.0.y);
     let points: Vec<Vec2> = curve
         .iter_positions(resolution)
         .enumerate()
         .map(|(i, p)| {
             // Probability needs to be weight by total number and bin width to bring it in histogram units
-            let predicted_elems = p * NUMBER_OF_PARTICLES as f32 * BIN_WIDTH;
+            let predicted_elems = p * config.number_of_particles as f32 * config.bin_width();
             Vec2::new(
                 starting_point.x + HIST_WIDTH / resolution as f32 * i as f32,
-                starting_point.y + predicted_elems * HEIGHT_PER_ELEM,
+                starting_point.y + predicted_elems * config.height_per_elem(),
             )
         })
         .collect();
@@ -605,97 +824,56 @@ fn update_temperature_text(
     mut text_query: Query<&mut Text2d, With<TemperatureText>>,
     particle_query: Query<&Particle>,
     mut temperature: ResMut<Temperature>,
+    config: Res<SimulationConfig>,
 ) {
     let mut text = text_query.single_mut();
     let speeds: Vec<f32> = particle_query.iter().map(|p| p.velocity.norm()).collect();
-    let new_temp = calculate_temperature(PARTICLE_MASS, NUMBER_OF_PARTICLES as f32, speeds);
+    let new_temp = calculate_temperature(PARTICLE_MASS, speeds.len() as f32, speeds);
     temperature.0 = new_temp;
     text.0 = format!("Temperature: {:.1} K", new_temp);
+    if config.rough() {
+        // Rotation is one more degree of freedom on top of the two of translation, so at
+        // equipartition the energy per particle goes from kT to 3/2 kT
+        let rotation = calculate_rotational_temperature(particle_query.iter());
+        let heat_capacity = 1. + rotation / (2. * new_temp);
+        text.0 += &format!(
+            "\nRotational temperature: {rotation:.1} K\n\
+             Heat capacity: {heat_capacity:.2} k per particle"
+        );
+    }
 }
 
 fn update_entropy_text(
     mut text_query: Query<&mut Text2d, With<EntropyText>>,
     temperature: Res<Temperature>,
     mut entropy: ResMut<Entropy>,
+    scenario: Res<Scenario>,
+    particle_query: Query<&Particle>,
 ) {
     let mut text = text_query.single_mut();
-    let new_entr = calculate_entropy(PARTICLE_MASS, NUMBER_OF_PARTICLES as f32, temperature.0)
-        / ELEMENTARY_CHARGE;
+    let new_entr = calculate_entropy(
+        PARTICLE_MASS,
+        particle_query.iter().len() as f32,
+        temperature.0,
+        scenario.free_area(WALL_THICKNESS),
+    ) / ELEMENTARY_CHARGE;
     entropy.0 = new_entr;
     text.0 = format!("Entropy: {:.2} eV/K", new_entr);
 }
 
-fn update_slow_mo_text(mut query: Query<&mut Text2d, With<SlowMoText>>, slow_mo: Res<SlowMo>) {
+fn update_playback_speed_text(
+    mut query: Query<&mut Text2d, With<PlaybackSpeedText>>,
+    time: Res<Time<Virtual>>,
+) {
     let mut text = query.single_mut();
-    text.0 = format!(
-        "Slow motion: {}\n(Up/Down arrow to change, Spacebar to pause)",
-        slow_mo.0
-    );
+    text.0 = playback_speed_text(time.relative_speed_f64());
 }
 
-/* UTILITY FUNCTIONS */
-/// Computes the velocity difference after an elastic collision of two rigid spheres of equal mass.
-fn compute_velocity_delta(x1: Vec2, x2: Vec2, v1: Vec2, v2: Vec2) -> Vec2 {
-    let delta_v = v1 - v2;
-    let delta_x = x1 - x2;
-
-    return -delta_v.dot(delta_x) / delta_x.norm_squared() * delta_x;
-}
-
-/// Computes the velocity difference after an elastic collision of two rigid spheres of different mass.
-// fn compute_velocity_delta_masses(x1: Vec2, x2: Vec2, v1: Vec2, v2: Vec2, m1: f32, m2: f32) -> Vec2 {
-//     let total_m = m1 + m2;
-//     let delta_v = v1 - v2;
-//     let delta_x = x1 - x2;
-
-//     return -2.0 * m2 / total_m * delta_v.dot(delta_x) / delta_x.norm_squared() * delta_x;
-// }
-
-/// Compute the vector that describes the overlap between two intersecting spheres.
-/// The direction of the vector is `x2` towards `x1`.
-fn compute_particle_overlap(x1: Vec2, x2: Vec2, radius1: f32, radius2: f32) -> Vec3 {
-    let distance_between_centers = x1 - x2;
-    let distance = distance_between_centers.norm().max(0.);
-    let overlap = radius1 + radius2 - distance;
-    let overlap_vec = overlap * distance_between_centers / distance;
-    return Vec3::new(overlap_vec.x, overlap_vec.y, 0.);
-}
-
-/// Compute the vector that describes the overlap between a sphere intersecting an axis-aligned rectangle.
-/// Direction of the vector is towards the wall.
-fn compute_wall_overlap(
-    part_center: Vec2,
-    wall_center: Vec2,
-    radius: f32,
-    wall_thickness: f32,
-    wall_orientation: &WallOrientation,
-) -> Vec3 {
-    // Relies on walls being on opposite sides of the origin
-    let half_thickness = wall_thickness / 2.;
-    match wall_orientation {
-        WallOrientation::Horizontal => {
-            let y_overlap = if wall_center.y > 0. {
-                // Top wall
-                (part_center.y + radius) - (wall_center.y - half_thickness)
-            } else {
-                // Bottom wall
-                -(wall_center.y + half_thickness) + (part_center.y - radius)
-            };
-            return Vec3::new(0., y_overlap, 0.);
-        }
-        WallOrientation::Vertical => {
-            let x_overlap = if wall_center.x > 0. {
-                // Right wall
-                (part_center.x + radius) - (wall_center.x - half_thickness)
-            } else {
-                // Left wall
-                -(wall_center.x + half_thickness) + (part_center.x - radius)
-            };
-            return Vec3::new(x_overlap, 0., 0.);
-        }
-    }
+fn playback_speed_text(speed: f64) -> String {
+    format!("Playback speed: {speed:.2}x\n(Up/Down arrow to change, Spacebar to pause)")
 }
 
+/* UTILITY FUNCTIONS */
 /// The probability density function for a 2D Maxwell-Boltzmann distribution.
 fn maxwell_boltzmann_2d_pdf(speed: f32, mass: f32, temperature: f32) -> f32 {
     let a_sq = BOLTZMANN_CONSTANT * temperature / mass;
@@ -705,22 +883,39 @@ fn maxwell_boltzmann_2d_pdf(speed: f32, mass: f32, temperature: f32) -> f32 {
 
 /// Calculate the system temperature from the particle velocities. Assumes free particles.
 fn calculate_temperature(particle_mass: f32, number_of_particles: f32, speeds: Vec<f32>) -> f32 {
+    // Kinetic energies are around 1e-20 J, so they are added up in double precision
+    let sum_of_squares = speeds.iter().map(|v| (*v as f64).powi(2)).sum::<f64>();
     let avg_kinetic_energy =
-        particle_mass / 2. * speeds.iter().map(|v| v * v).sum::<f32>() / number_of_particles;
-    return avg_kinetic_energy / BOLTZMANN_CONSTANT;
+        particle_mass as f64 / 2. * sum_of_squares / number_of_particles as f64;
+    return (avg_kinetic_energy / units::BOLTZMANN_CONSTANT) as f32;
+}
+
+/// Calculate the temperature of the rotation of the particles, which has a single degree of
+/// freedom.
+fn calculate_rotational_temperature<'a>(particles: impl Iterator<Item = &'a Particle>) -> f32 {
+    let (sum, count) = particles.fold((0., 0), |(sum, count), p| {
+        (sum + p.inertia as f64 * (p.spin as f64).powi(2), count + 1)
+    });
+    if count == 0 {
+        return 0.;
+    }
+    (sum / count as f64 / units::BOLTZMANN_CONSTANT) as f32
 }
 
 /// Calculate the system entropy from the temperature using the Sackur-Tetrode equation for a 2D monatomic ideal gas.
-fn calculate_entropy(particle_mass: f32, number_of_particles: f32, temperature: f32) -> f32 {
-    // Order of magnitude is calculated manually to avoid floating point underflow
-    // e-34 * e-34 / (e-27 * e-23) = e-68 / e-50 = e-14
-    let de_broglie_thermal_wavelength_square =
-        2. * PI * (REDUCED_PLANCK_CONSTANT * 1e34) * (REDUCED_PLANCK_CONSTANT * 1e34)
-            / (particle_mass * 1e27 * BOLTZMANN_CONSTANT * 1e23 * temperature)
-            * 1e-14;
-    let particle_density = number_of_particles / BOX_AREA;
-
-    return BOLTZMANN_CONSTANT
-        * number_of_particles
-        * (5. / 2. - ops::ln(particle_density * de_broglie_thermal_wavelength_square));
+fn calculate_entropy(
+    particle_mass: f32,
+    number_of_particles: f32,
+    temperature: f32,
+    area: f32,
+) -> f32 {
+    // In double precision, since ħ² alone is around 1e-68, out of the range of f32
+    let de_broglie_thermal_wavelength_square = 2. * PI * REDUCED_PLANCK_CONSTANT.powi(2)
+        / (particle_mass as f64 * units::BOLTZMANN_CONSTANT * temperature as f64);
+    let particle_density = number_of_particles as f64 / area as f64;
+
+    return (units::BOLTZMANN_CONSTANT
+        * number_of_particles as f64
+        * (5. / 2. - (particle_density * de_broglie_thermal_wavelength_square).ln()))
+        as f32;
 }
@@ -0,0 +1,293 @@
+use bevy::{math::DVec2, prelude::*};
+use bevy_egui::{egui, EguiContexts};
+use egui_plot::{Legend, Line, Plot, Points};
+use gas_simulation::tessellation::Triangulation;
+use rayon::prelude::*;
+
+use crate::{coloring::ColorBy, lyapunov::fit_slope, physics::Gas, SimulationState};
+
+/// Width of the bins of the correlation function, in particle diameters.
+const BIN_WIDTH: f64 = 0.25;
+/// The decay of the correlation function is fitted from this distance on, in particle
+/// diameters, past the first shells of neighbours.
+const DECAY_START: f64 = 2.;
+/// Largest decay exponent of the correlation function in the hexatic phase, in the KTHNY theory
+/// of melting in two dimensions.
+const HEXATIC_EXPONENT: f64 = 0.25;
+
+/// Measures the local bond orientational order ψ6 of every particle, from its neighbours in the
+/// Voronoi diagram, which the particles can be colored by. The window shows the global order and
+/// measures its correlation function g6(r) = ⟨ψ6(0) ψ6*(r)⟩, whose decay tells the phases of
+/// melting in two dimensions apart: exponential in the liquid, a power law no slower than
+/// r^(-1/4) in the hexatic phase, and none at all in the solid.
+pub struct OrderPlugin;
+
+impl Plugin for OrderPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<BondOrders>()
+            .add_systems(OnEnter(SimulationState::Setup), stop_measuring)
+            .add_systems(Update, order_window);
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct BondOrders {
+    /// ψ6 of every particle, by index in the gas. Empty when nothing needs it
+    pub local: Vec<DVec2>,
+    /// |Ψ6| of all the particles
+    global: f64,
+    /// Whether the window was open on the last frame
+    shown: bool,
+    correlation: Option<Correlation>,
+    measuring: bool,
+}
+
+/// Correlation function of the local orders, summed over the pairs of particles at each distance
+/// and over the samples.
+struct Correlation {
+    /// Mean diameter of the particles, in meters
+    diameter: f64,
+    /// Sum of Re(ψ6(i) ψ6*(j)) over the pairs of particles in each bin of distances
+    sums: Vec<f64>,
+    pairs: Vec<u64>,
+    samples: usize,
+    /// Sums of |Ψ6| and of its square over the samples
+    order: f64,
+    order_squared: f64,
+}
+
+impl BondOrders {
+    fn needed(&self, color_by: ColorBy) -> bool {
+        self.shown
+            || self.measuring
+            || matches!(color_by, ColorBy::BondOrder | ColorBy::BondOrientation)
+    }
+
+    fn update(&mut self, gas: &Gas) {
+        let positions: Vec<DVec2> = gas.positions.iter().map(|x| *x * gas.units.length).collect();
+        self.local = bond_orders(&positions);
+        self.global = global_bond_order(&self.local);
+        if self.measuring {
+            let correlation = self.correlation.get_or_insert_with(|| Correlation::new(gas));
+            correlation.sample(&positions, &self.local, self.global);
+        }
+    }
+}
+
+impl Correlation {
+    /// Bins out to half of the smaller side of the region the particles are in.
+    fn new(gas: &Gas) -> Self {
+        let diameter = 2. * gas.radii.iter().sum::<f64>() / gas.radii.len().max(1) as f64
+            * gas.units.length;
+        let min = gas.positions.iter().copied().reduce(DVec2::min).unwrap_or_default();
+        let max = gas.positions.iter().copied().reduce(DVec2::max).unwrap_or_default();
+        let range = (max - min).min_element() * gas.units.length / 2.;
+        let bins = (range / (BIN_WIDTH * diameter)).ceil().max(1.) as usize;
+        Correlation {
+            diameter,
+            sums: vec![0.; bins],
+            pairs: vec![0; bins],
+            samples: 0,
+            order: 0.,
+            order_squared: 0.,
+        }
+    }
+
+    fn sample(&mut self, positions: &[DVec2], orders: &[DVec2], global: f64) {
+        let bins = self.sums.len();
+        let width = BIN_WIDTH * self.diameter;
+        let (sums, pairs) = (0..positions.len())
+            .into_par_iter()
+            .fold(
+                || (vec![0.; bins], vec![0; bins]),
+                |(mut sums, mut pairs), i| {
+                    for j in i + 1..positions.len() {
+                        let bin = ((positions[j] - positions[i]).length() / width) as usize;
+                        if bin < bins {
+                            sums[bin] += orders[i].dot(orders[j]);
+                            pairs[bin] += 1;
+                        }
+                    }
+                    (sums, pairs)
+                },
+            )
+            .reduce(
+                || (vec![0.; bins], vec![0; bins]),
+                |(mut sums, mut pairs), (other_sums, other_pairs)| {
+                    sums.iter_mut().zip(other_sums).for_each(|(s, o)| *s += o);
+                    pairs.iter_mut().zip(other_pairs).for_each(|(p, o)| *p += o);
+                    (sums, pairs)
+                },
+            );
+        self.sums.iter_mut().zip(sums).for_each(|(s, o)| *s += o);
+        self.pairs.iter_mut().zip(pairs).for_each(|(p, o)| *p += o);
+        self.samples += 1;
+        self.order += global;
+        self.order_squared += global * global;
+    }
+
+    /// g6(r) at the middle of every bin holding pairs, r in particle diameters.
+    fn function(&self) -> Vec<[f64; 2]> {
+        self.sums
+            .iter()
+            .zip(&self.pairs)
+            .enumerate()
+            .filter(|(_, (_, pairs))| **pairs > 0)
+            .map(|(bin, (sum, pairs))| [(bin as f64 + 0.5) * BIN_WIDTH, sum / *pairs as f64])
+            .collect()
+    }
+
+    /// Exponent η6 of the power law g6(r) ~ r^(-η6) fitted past the first shells of neighbours,
+    /// as long as the function stays positive.
+    fn decay_exponent(&self) -> Option<f64> {
+        let tail: Vec<[f64; 2]> = self
+            .function()
+            .into_iter()
+            .filter(|[r, _]| *r >= DECAY_START)
+            .take_while(|[_, g]| *g > 0.)
+            .map(|[r, g]| [r.ln(), g.ln()])
+            .collect();
+        fit_slope(&tail).map(|slope| -slope)
+    }
+
+    /// Mean |Ψ6| over the samples and the susceptibility N (⟨Ψ6²⟩ - ⟨Ψ6⟩²) of its fluctuations.
+    fn order_statistics(&self, particles: usize) -> (f64, f64) {
+        let samples = self.samples.max(1) as f64;
+        let mean = self.order / samples;
+        let susceptibility = particles as f64 * (self.order_squared / samples - mean * mean);
+        (mean, susceptibility)
+    }
+}
+
+/// Local bond orientational order ψ6 of every particle: the mean of e^(6iθ) over the bonds to
+/// its neighbours in the Voronoi diagram, θ being the angle of the bond. Its modulus is 1 when
+/// the neighbours sit on a perfect hexagon, and its phase tells how that hexagon is turned.
+/// Complex numbers are stored as vectors of their real and imaginary parts.
+pub fn bond_orders(positions: &[DVec2]) -> Vec<DVec2> {
+    Triangulation::new(positions)
+        .neighbours(positions.len())
+        .iter()
+        .enumerate()
+        .map(|(i, neighbours)| {
+            let bonds: Vec<DVec2> =
+                neighbours.iter().map(|j| positions[*j] - positions[i]).collect();
+            hexatic_mean(&bonds)
+        })
+        .collect()
+}
+
+/// Mean of e^(6iθ) over the bonds, zero without any.
+fn hexatic_mean(bonds: &[DVec2]) -> DVec2 {
+    if bonds.is_empty() {
+        return DVec2::ZERO;
+    }
+    let sum: DVec2 = bonds
+        .iter()
+        .map(|bond| DVec2::from_angle(6. * bond.to_angle()))
+        .sum();
+    sum / bonds.len() as f64
+}
+
+/// Global bond orientational order |Ψ6| of the given particles, the modulus of the mean of their
+/// ψ6: close to 1 when they all line up on the same hexagonal lattice, and down to about 1/√N
+/// when their local orders point every which way, as in a liquid.
+pub fn global_bond_order(orders: &[DVec2]) -> f64 {
+    if orders.is_empty() {
+        return 0.;
+    }
+    (orders.iter().sum::<DVec2>() / orders.len() as f64).length()
+}
+
+/// Measure the local orders when they are colored by, shown or correlated, and only after the
+/// gas has changed. Runs before the particles are colored.
+pub fn update_bond_orders(
+    gas: Res<Gas>,
+    color_by: Res<ColorBy>,
+    mut orders: ResMut<BondOrders>,
+) {
+    if !orders.needed(*color_by) {
+        orders.local.clear();
+    } else if gas.is_changed() || orders.local.len() != gas.positions.len() {
+        orders.update(&gas);
+    }
+}
+
+fn stop_measuring(mut orders: ResMut<BondOrders>) {
+    orders.measuring = false;
+    orders.correlation = None;
+    orders.local.clear();
+}
+
+fn order_window(mut contexts: EguiContexts, gas: Res<Gas>, mut orders: ResMut<BondOrders>) {
+    let orders = &mut *orders;
+    orders.shown = false;
+    egui::Window::new("Bond order")
+        .default_pos([10., 1300.])
+        .default_open(false)
+        .show(contexts.ctx_mut(), |ui| {
+            orders.shown = true;
+            let magnitude = orders.local.iter().map(|o| o.length()).sum::<f64>()
+                / orders.local.len().max(1) as f64;
+            ui.label(format!("Global order |Ψ6|: {:.3}", orders.global));
+            ui.label(format!("Mean local order |ψ6|: {magnitude:.3}"));
+
+            ui.horizontal(|ui| {
+                if orders.measuring {
+                    if ui.button("Stop").clicked() {
+                        orders.measuring = false;
+                    }
+                } else if ui.button("Measure correlation").clicked() {
+                    orders.measuring = true;
+                }
+                if ui.button("Clear").clicked() {
+                    orders.correlation = None;
+                }
+            });
+            let Some(correlation) = &orders.correlation else {
+                ui.label("The correlation function is averaged over every frame while measuring");
+                return;
+            };
+            let (mean, susceptibility) = correlation.order_statistics(gas.positions.len());
+            ui.label(format!(
+                "{} samples, ⟨|Ψ6|⟩ = {mean:.3}, susceptibility {susceptibility:.3}",
+                correlation.samples
+            ));
+
+            let function = correlation.function();
+            let decay: Vec<[f64; 2]> = function
+                .iter()
+                .filter(|[r, g]| *r >= 1. && *g > 0.)
+                .map(|[r, g]| [r.log10(), g.log10()])
+                .collect();
+            // Slowest decay of the hexatic phase, through the first point past the neighbours
+            let guide = decay
+                .iter()
+                .find(|[r, _]| *r >= DECAY_START.log10())
+                .zip(decay.last())
+                .map(|([r, g], [end, _])| {
+                    vec![[*r, *g], [*end, g - HEXATIC_EXPONENT * (end - r)]]
+                })
+                .unwrap_or_default();
+            Plot::new("order_correlation")
+                .height(150.)
+                .x_axis_label("r / σ")
+                .y_axis_label("g6(r)")
+                .show(ui, |plot| plot.line(Line::new(function)));
+            Plot::new("order_decay")
+                .height(150.)
+                .legend(Legend::default())
+                .x_axis_label("log10 r / σ")
+                .y_axis_label("log10 g6(r)")
+                .show(ui, |plot| {
+                    plot.points(Points::new(decay).radius(2.).name("measured"));
+                    plot.line(Line::new(guide).name("r^(-1/4)"));
+                });
+            match correlation.decay_exponent() {
+                Some(exponent) => ui.label(format!(
+                    "Power law fit: η6 = {exponent:.3} (liquid above {HEXATIC_EXPONENT}, \
+                     hexatic below, solid 0)"
+                )),
+                None => ui.label("Too few points to fit the decay"),
+            };
+        });
+}
@@ -0,0 +1,1284 @@
+use std::{collections::HashMap, f64::consts::TAU};
+
+use bevy::{math::DVec2, prelude::*};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand_distr::{Distribution, StandardNormal};
+use rayon::prelude::*;
+
+use crate::{
+    geometry::{WallMotion, WallShape},
+    units::{Units, BOLTZMANN_CONSTANT},
+};
+
+/// Neighbours whose pairs a cell of the collision grid takes care of, on top of its own. Each
+/// pair of neighbouring cells is handled by exactly one of the two.
+const FORWARD_NEIGHBOURS: [IVec2; 4] = [
+    IVec2::new(1, 0),
+    IVec2::new(1, 1),
+    IVec2::new(0, 1),
+    IVec2::new(-1, 1),
+];
+/// Cells of the collision grid get one of `COLORS * COLORS` colors, so that cells of the same
+/// color are at least this many cells apart and never touch the same particles.
+const COLORS: i32 = 3;
+/// Steps are split in substeps short enough that no particle travels more than this fraction of
+/// the smallest radius in one of them.
+const MAX_TRAVEL: f64 = 0.5;
+/// Upper bound on the number of substeps, so that a single runaway particle can't stall the
+/// simulation. Beyond it, the swept tests still catch what the substeps miss.
+const MAX_SUBSTEPS: u32 = 64;
+/// Largest number of walls a particle bounces off in a single substep, for corners.
+const MAX_BOUNCES: u32 = 4;
+/// Largest number of conservative advancement iterations when looking for a wall contact. Only
+/// particles grazing a wall need more than a few.
+const MAX_ADVANCEMENTS: u32 = 32;
+/// Moment of inertia of a particle over its mass times its radius squared, for uniform discs.
+pub const INERTIA_FACTOR: f64 = 0.5;
+/// Particles count as touching a wall once the gap between them is below this fraction of the
+/// distance at which they touch.
+const CONTACT_GAP: f64 = 1e-9;
+
+/// State of every particle, in plain arrays indexed by particle number. This is what
+/// the physics systems move; particle entities only get a copy of it once per frame, to be drawn.
+/// Also used on its own to run copies of the simulation next to the one on screen, or without a
+/// window at all, in which case Maxwell's demon is left out and its trapdoor is always open.
+///
+/// Everything is in double precision and in the [`Units`] of the gas, while the methods take and
+/// return SI units.
+#[derive(Resource, Clone)]
+pub struct Gas {
+    pub positions: Vec<DVec2>,
+    pub velocities: Vec<DVec2>,
+    /// Angular velocities, counterclockwise
+    pub spins: Vec<f64>,
+    /// Angle each particle turned by since the start, only used to draw it
+    pub orientations: Vec<f64>,
+    pub radii: Vec<f64>,
+    pub masses: Vec<f64>,
+    pub inertias: Vec<f64>,
+    /// From -1 for smooth discs, which never spin, to 1 for perfectly rough ones. See
+    /// [`compute_tangential_delta`]
+    pub tangential_restitution: f64,
+    /// Fraction of their normal relative speed colliding particles keep, 1 for elastic collisions
+    pub restitution: f64,
+    /// Fraction of their normal speed particles keep when bouncing off a wall
+    pub wall_restitution: f64,
+    /// Thermal energy kT of every wall that keeps a temperature, in units of the gas, `None` for
+    /// the others. Particles leave these walls with random velocities at their temperature,
+    /// whatever their restitution. See [`thermal_bounce`]
+    pub wall_temperatures: Vec<Option<f64>>,
+    /// Temperature random kicks give every particle per unit of time, in units of energy of the
+    /// gas over its units of time. Zero for a gas left alone
+    pub heating_rate: f64,
+    /// Where the random kicks come from
+    pub noise: StdRng,
+    /// Centerline and thickness of every wall, where it is now
+    pub walls: Vec<(WallShape, f64)>,
+    /// How every wall moves, see [`Gas::set_wall_motion`]
+    pub wall_motions: Vec<WallMotion>,
+    /// Where every wall was at the start
+    rest_walls: Vec<WallShape>,
+    /// Velocity of every wall over the current substep
+    wall_velocities: Vec<DVec2>,
+    /// Total momentum the particles gave to the walls so far
+    pub wall_momentum: DVec2,
+    /// Time since the start, which sets where moving walls are
+    pub time: f64,
+    pub units: Units,
+}
+
+/// What happened during a step.
+pub struct StepReport {
+    /// Every collision between particles, in SI units
+    pub collisions: Vec<Collision>,
+    /// Momentum given to each wall along its normal, in kg m/s. What slides along the surface
+    /// of rough discs or of sliding walls doesn't push on the wall and is left out
+    pub wall_impulses: Vec<f64>,
+    /// Number of substeps the step was split in
+    pub substeps: u32,
+}
+
+/// Particles of a block of cells after resolving the collisions in it.
+struct BlockUpdate {
+    particles: Vec<usize>,
+    positions: Vec<DVec2>,
+    velocities: Vec<DVec2>,
+    spins: Vec<f64>,
+    collisions: Vec<Collision>,
+}
+
+/// A collision between two particles of the same mass, and what it carried from one to the
+/// other. In units of the gas when returned by [`collide_particles`], in SI units in a
+/// [`StepReport`].
+#[derive(Clone, Copy, Debug)]
+pub struct Collision {
+    pub pair: (usize, usize),
+    /// Centers of the two particles when they touched
+    pub centers: [DVec2; 2],
+    /// Change in velocity of the first particle. The second one gets the opposite
+    pub velocity_change: DVec2,
+    /// Change in kinetic energy of the first particle, translation and rotation, per unit of
+    /// mass. The second one gets the opposite if the collision is elastic
+    pub energy_change: f64,
+}
+
+/// What the collisions between particles are resolved from: the particles at the end of a
+/// substep of length `dt`, before colliding, and where they came from.
+struct Substep<'a> {
+    positions: &'a [DVec2],
+    velocities: &'a [DVec2],
+    spins: &'a [f64],
+    radii: &'a [f64],
+    previous: &'a [DVec2],
+    dt: f64,
+    restitution: [f64; 2],
+}
+
+/// How particles bounce off the walls of the gas, over one pass through them.
+struct Bounces<'a> {
+    restitution: [f64; 2],
+    /// See [`Gas::wall_temperatures`]
+    temperatures: &'a [Option<f64>],
+    /// Where the random velocities given by walls with a temperature come from, `None` without
+    /// such walls. Each particle mixes its index in, so that what it draws doesn't depend on how
+    /// the particles are split between threads
+    seed: Option<u64>,
+}
+
+impl Gas {
+    /// A gas of identical smooth particles in the given units, from SI positions, velocities and
+    /// sizes.
+    pub fn new(
+        positions: &[Vec2],
+        velocities: &[Vec2],
+        radius: f32,
+        mass: f32,
+        walls: &[WallShape],
+        wall_thickness: f32,
+        units: Units,
+    ) -> Self {
+        let n = positions.len();
+        let radius = radius as f64 / units.length;
+        let mass = mass as f64 / units.mass;
+        let walls: Vec<(WallShape, f64)> = walls
+            .iter()
+            .map(|shape| {
+                let shape = shape.scaled((1. / units.length) as f32);
+                (shape, wall_thickness as f64 / units.length)
+            })
+            .collect();
+        Gas {
+            positions: positions.iter().map(|x| x.as_dvec2() / units.length).collect(),
+            velocities: velocities.iter().map(|v| v.as_dvec2() / units.speed()).collect(),
+            spins: vec![0.; n],
+            orientations: vec![0.; n],
+            radii: vec![radius; n],
+            masses: vec![mass; n],
+            inertias: vec![INERTIA_FACTOR * mass * radius * radius; n],
+            tangential_restitution: -1.,
+            restitution: 1.,
+            wall_restitution: 1.,
+            wall_temperatures: vec![None; walls.len()],
+            heating_rate: 0.,
+            noise: StdRng::seed_from_u64(0),
+            wall_motions: vec![WallMotion::Fixed; walls.len()],
+            rest_walls: walls.iter().map(|(shape, _)| shape.clone()).collect(),
+            wall_velocities: vec![DVec2::ZERO; walls.len()],
+            walls,
+            wall_momentum: DVec2::ZERO,
+            time: 0.,
+            units,
+        }
+    }
+
+    /// Position of a particle, in meters.
+    pub fn position(&self, i: usize) -> Vec2 {
+        (self.positions[i] * self.units.length).as_vec2()
+    }
+
+    /// Velocity of a particle, in meters per second.
+    pub fn velocity(&self, i: usize) -> Vec2 {
+        (self.velocities[i] * self.units.speed()).as_vec2()
+    }
+
+    /// Angular velocity of a particle, in radians per second.
+    pub fn spin(&self, i: usize) -> f32 {
+        (self.spins[i] / self.units.time()) as f32
+    }
+
+    /// Heat every particle by `kelvins_per_second` with random kicks, or stop them with zero.
+    pub fn set_heating_rate(&mut self, kelvins_per_second: f64) {
+        self.heating_rate =
+            kelvins_per_second * BOLTZMANN_CONSTANT / self.units.energy * self.units.time();
+    }
+
+    /// Keep a wall at a temperature, in kelvins, or make it bounce particles back again with
+    /// `None`.
+    pub fn set_wall_temperature(&mut self, w: usize, kelvins: Option<f64>) {
+        self.wall_temperatures[w] = kelvins.map(|t| t * BOLTZMANN_CONSTANT / self.units.energy);
+    }
+
+    /// Make a wall move, with lengths and times of the motion in SI units. It moves from where
+    /// it was at the start.
+    pub fn set_wall_motion(&mut self, w: usize, motion: &WallMotion) {
+        self.wall_motions[w] = motion.scaled(self.units.length, self.units.time());
+        self.place_walls();
+    }
+
+    /// Go to a point in time, in seconds, and put the moving walls where they were then.
+    pub fn set_time(&mut self, seconds: f64) {
+        self.time = seconds / self.units.time();
+        self.place_walls();
+    }
+
+    /// Offset of a wall from where it was at the start, in meters.
+    pub fn wall_offset(&self, w: usize) -> Vec2 {
+        (self.wall_motions[w].displacement(self.time) * self.units.length).as_vec2()
+    }
+
+    /// Whether nothing adds or removes energy: collisions are elastic, discs are either smooth
+    /// or perfectly rough, walls stay still without a temperature and there are no random kicks.
+    pub fn conserves_energy(&self) -> bool {
+        self.restitution == 1.
+            && self.wall_restitution == 1.
+            && self.wall_temperatures.iter().all(Option::is_none)
+            && self.tangential_restitution.abs() == 1.
+            && self.heating_rate == 0.
+            && self.wall_motions.iter().all(|m| *m == WallMotion::Fixed)
+    }
+
+    /// Put the moving walls where they are now.
+    fn place_walls(&mut self) {
+        for (w, motion) in self.wall_motions.iter().enumerate() {
+            if *motion != WallMotion::Fixed {
+                let offset = motion.displacement(self.time).as_vec2();
+                self.walls[w].0 = self.rest_walls[w].translated(offset);
+            }
+        }
+    }
+
+    /// Give the moving walls the velocity that takes them from where they are now to where they
+    /// are after `dt`, in units of the gas, so that they go in a straight line over a substep.
+    fn pace_walls(&mut self, dt: f64) {
+        for (w, motion) in self.wall_motions.iter().enumerate() {
+            if *motion != WallMotion::Fixed {
+                let change = motion.displacement(self.time + dt) - motion.displacement(self.time);
+                self.wall_velocities[w] = change / dt;
+            }
+        }
+    }
+
+    /// Advance the gas by `dt` seconds: move every particle, bouncing it off the walls in its
+    /// way, then resolve collisions between particles. The step is split in [`Gas::substeps`] so
+    /// that fast particles don't go through walls or through each other. Random kicks, if any,
+    /// come at the end of the step.
+    pub fn step(&mut self, dt: f64) -> StepReport {
+        let substeps = self.substeps(dt);
+        let dt = dt / self.units.time() / substeps as f64;
+        let mut report = StepReport {
+            collisions: Vec::new(),
+            wall_impulses: vec![0.; self.walls.len()],
+            substeps,
+        };
+        for _ in 0..substeps {
+            let previous = self.positions.clone();
+            self.pace_walls(dt);
+            let mut impulses = self.move_particles(dt);
+            // The rest of the substep happens with the walls where they end up
+            self.time += dt;
+            self.place_walls();
+            for (orientation, spin) in self.orientations.iter_mut().zip(&self.spins) {
+                *orientation = (*orientation + spin * dt).rem_euclid(TAU);
+            }
+            let moved = self.positions.clone();
+            let collisions = collide_particles(
+                &mut self.positions,
+                &mut self.velocities,
+                &mut self.spins,
+                &self.radii,
+                &previous,
+                dt,
+                [self.restitution, self.tangential_restitution],
+            );
+            let units = self.units;
+            report
+                .collisions
+                .extend(collisions.into_iter().map(|c| c.to_si(&units)));
+            // Collisions between particles can push some into a wall, or through it
+            impulses.extend(self.confine_particles(&moved));
+            impulses.extend(self.collide_with_walls());
+            // Contacts come in particle order and are summed here, so that the sums don't depend
+            // on how the particles were split between threads
+            for (w, change, normal) in impulses {
+                report.wall_impulses[w] += change.dot(normal) * self.units.momentum();
+                self.wall_momentum -= change;
+            }
+        }
+        self.kick(dt * substeps as f64);
+        report
+    }
+
+    /// Longest time step, in seconds, over which no particle travels more than a fraction of the
+    /// smallest radius, relative to the fastest wall. Infinite if everything is at rest.
+    pub fn timestep(&self) -> f64 {
+        let speed = |velocities: &[DVec2]| velocities.iter().map(|v| v.length()).fold(0., f64::max);
+        let fastest = speed(&self.velocities) + speed(&self.wall_velocities);
+        let smallest = self.radii.iter().copied().fold(f64::INFINITY, f64::min);
+        MAX_TRAVEL * smallest / fastest * self.units.time()
+    }
+
+    /// Give every particle a random kick, such that on average the gas heats by `heating_rate`
+    /// over `dt`, in units of the gas. The mean kick is taken out so that the momentum stays
+    /// the same.
+    fn kick(&mut self, dt: f64) {
+        if self.heating_rate <= 0. || self.velocities.is_empty() {
+            return;
+        }
+        // kT is the mean kinetic energy of a particle, so each velocity component gets a
+        // variance of heating_rate dt / m
+        let noise = &mut self.noise;
+        let kicks: Vec<DVec2> = self
+            .masses
+            .iter()
+            .map(|m| {
+                let x: f64 = StandardNormal.sample(noise);
+                let y: f64 = StandardNormal.sample(noise);
+                DVec2::new(x, y) * (self.heating_rate * dt / m).sqrt()
+            })
+            .collect();
+        let mean = kicks.iter().sum::<DVec2>() / kicks.len() as f64;
+        for (velocity, kick) in self.velocities.iter_mut().zip(kicks) {
+            *velocity += kick - mean;
+        }
+    }
+
+    /// Number of substeps needed to advance the gas by `dt` seconds.
+    pub fn substeps(&self, dt: f64) -> u32 {
+        let substeps = (dt / self.timestep()).ceil();
+        if substeps.is_finite() {
+            (substeps as u32).clamp(1, MAX_SUBSTEPS)
+        } else {
+            1
+        }
+    }
+
+    /// Bounce a particle off a wall that isn't part of the gas, given in SI units. Returns whether
+    /// the two touched.
+    pub fn bounce_off_wall(&mut self, i: usize, shape: &WallShape, thickness: f32) -> bool {
+        let length = self.units.length;
+        let shape = shape.scaled((1. / length) as f32);
+        let thickness = thickness as f64 / length;
+        let contact = wall_contact(self.positions[i], self.radii[i], &shape, thickness);
+        if let Some((normal, shift)) = contact {
+            let restitution = [self.wall_restitution, self.tangential_restitution];
+            let (velocity, spin) = (self.velocities[i], self.spins[i]);
+            let surface = DVec2::ZERO;
+            if let Some((new_velocity, new_spin)) =
+                wall_bounce(velocity, spin, self.radii[i], normal, surface, restitution)
+            {
+                self.wall_momentum -= self.masses[i] * (new_velocity - velocity);
+                self.velocities[i] = new_velocity;
+                self.spins[i] = new_spin;
+            }
+            self.positions[i] -= shift;
+        }
+        contact.is_some()
+    }
+
+    /// Send a particle that went through the straight wall from `start` to `end` during the last
+    /// step back to the side of `from`, as if it had bounced off the face of the wall on that
+    /// side: it is mirrored about the face and bounces as off any wall. Returns whether the
+    /// particle had gone past the face.
+    pub fn bounce_back(
+        &mut self,
+        i: usize,
+        [start, end]: [Vec2; 2],
+        thickness: f32,
+        from: Vec2,
+    ) -> bool {
+        let length = self.units.length;
+        let start = start.as_dvec2() / length;
+        let mut normal = (end.as_dvec2() / length - start).perp().normalize();
+        if (from.as_dvec2() / length - start).dot(normal) < 0. {
+            normal = -normal;
+        }
+        let face = self.radii[i] + thickness as f64 / length / 2.;
+        let depth = face - (self.positions[i] - start).dot(normal);
+        if depth <= 0. {
+            return false;
+        }
+        self.positions[i] += 2. * depth * normal;
+        let restitution = [self.wall_restitution, self.tangential_restitution];
+        let (velocity, spin) = (self.velocities[i], self.spins[i]);
+        if let Some((new_velocity, new_spin)) =
+            wall_bounce(velocity, spin, self.radii[i], normal, DVec2::ZERO, restitution)
+        {
+            self.wall_momentum -= self.masses[i] * (new_velocity - velocity);
+            self.velocities[i] = new_velocity;
+            self.spins[i] = new_spin;
+        }
+        true
+    }
+
+    /// Move every particle for `dt`, in units of the gas, bouncing it off the walls on its way.
+    /// Moving walls are swept along with the particles, at the velocity they have over the
+    /// substep. Returns the change in momentum of the particles at every bounce, with the wall
+    /// they hit and its normal there.
+    fn move_particles(&mut self, dt: f64) -> Vec<(usize, DVec2, DVec2)> {
+        let walls = &self.walls;
+        let motions = &self.wall_motions;
+        let wall_velocities = &self.wall_velocities;
+        let restitution = [self.wall_restitution, self.tangential_restitution];
+        let bounces = Bounces::new(&mut self.noise, &self.wall_temperatures, restitution);
+        self.positions
+            .par_iter_mut()
+            .zip(self.velocities.par_iter_mut().zip(self.spins.par_iter_mut()))
+            .zip(self.radii.par_iter().zip(self.masses.par_iter()))
+            .enumerate()
+            .flat_map_iter(|(i, ((position, (velocity, spin)), (radius, mass)))| {
+                let mut noise = bounces.noise(i);
+                let mut contacts = Vec::new();
+                let mut remaining = dt;
+                for _ in 0..MAX_BOUNCES {
+                    // Seen from each wall, which is where it was at the start of the substep
+                    let hit = walls
+                        .iter()
+                        .enumerate()
+                        .filter_map(|(w, (shape, thickness))| {
+                            let reach = radius + thickness / 2.;
+                            let offset = wall_velocities[w] * (dt - remaining);
+                            let relative = *velocity - wall_velocities[w];
+                            let start = *position - offset;
+                            wall_time_of_impact(start, relative, reach, shape, remaining)
+                                .map(|t| (t, w))
+                        })
+                        .min_by(|a, b| a.0.total_cmp(&b.0));
+                    let Some((t, w)) = hit else {
+                        break;
+                    };
+                    *position += *velocity * t;
+                    remaining -= t;
+                    let offset = wall_velocities[w] * (dt - remaining);
+                    let shape = &walls[w].0;
+                    let closest = shape.closest_point_f64(*position - offset);
+                    let normal = (*position - offset - closest).normalize_or_zero();
+                    let surface = surface_velocity(shape, &motions[w], wall_velocities[w], closest);
+                    let disc = (*radius, *mass);
+                    if let Some((new_velocity, new_spin)) =
+                        bounces.bounce(w, (*velocity, *spin), disc, normal, surface, &mut noise)
+                    {
+                        contacts.push((w, mass * (new_velocity - *velocity), normal));
+                        *velocity = new_velocity;
+                        *spin = new_spin;
+                    }
+                }
+                *position += *velocity * remaining;
+                contacts
+            })
+            .collect()
+    }
+
+    /// Stop particles that were pushed through a wall by collisions between particles, on their
+    /// way from where they were `moved` to, and bounce them off it. Returns the change in
+    /// momentum of the particles at every bounce, with the wall they hit and its normal there.
+    fn confine_particles(&mut self, moved: &[DVec2]) -> Vec<(usize, DVec2, DVec2)> {
+        let walls = &self.walls;
+        let motions = &self.wall_motions;
+        let wall_velocities = &self.wall_velocities;
+        let restitution = [self.wall_restitution, self.tangential_restitution];
+        let bounces = Bounces::new(&mut self.noise, &self.wall_temperatures, restitution);
+        self.positions
+            .par_iter_mut()
+            .zip(self.velocities.par_iter_mut().zip(self.spins.par_iter_mut()))
+            .zip(self.radii.par_iter().zip(self.masses.par_iter()))
+            .zip(moved.par_iter())
+            .enumerate()
+            .flat_map_iter(|(i, (((position, (velocity, spin)), (radius, mass)), start))| {
+                let path = *position - *start;
+                let hit = walls
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(w, (shape, thickness))| {
+                        let reach = radius + thickness / 2.;
+                        wall_time_of_impact(*start, path, reach, shape, 1.).map(|t| (t, w))
+                    })
+                    .min_by(|a, b| a.0.total_cmp(&b.0));
+                let (t, w) = hit?;
+                *position = *start + path * t;
+                let shape = &walls[w].0;
+                let closest = shape.closest_point_f64(*position);
+                let normal = (*position - closest).normalize_or_zero();
+                let surface = surface_velocity(shape, &motions[w], wall_velocities[w], closest);
+                let (new_velocity, new_spin) = bounces.bounce(
+                    w,
+                    (*velocity, *spin),
+                    (*radius, *mass),
+                    normal,
+                    surface,
+                    &mut bounces.noise(i),
+                )?;
+                let change = mass * (new_velocity - *velocity);
+                *velocity = new_velocity;
+                *spin = new_spin;
+                Some((w, change, normal))
+            })
+            .collect()
+    }
+
+    /// Bounce particles that overlap a wall. Returns the change in momentum of the particles at
+    /// every bounce, with the wall they hit and its normal there.
+    fn collide_with_walls(&mut self) -> Vec<(usize, DVec2, DVec2)> {
+        let walls = &self.walls;
+        let motions = &self.wall_motions;
+        let wall_velocities = &self.wall_velocities;
+        let restitution = [self.wall_restitution, self.tangential_restitution];
+        let bounces = Bounces::new(&mut self.noise, &self.wall_temperatures, restitution);
+        self.positions
+            .par_iter_mut()
+            .zip(self.velocities.par_iter_mut().zip(self.spins.par_iter_mut()))
+            .zip(self.radii.par_iter().zip(self.masses.par_iter()))
+            .enumerate()
+            .flat_map_iter(|(i, ((position, (velocity, spin)), (radius, mass)))| {
+                let mut noise = bounces.noise(i);
+                let mut contacts = Vec::new();
+                for (w, (shape, thickness)) in walls.iter().enumerate() {
+                    let Some((normal, shift)) = wall_contact(*position, *radius, shape, *thickness)
+                    else {
+                        continue;
+                    };
+                    let surface =
+                        surface_velocity(shape, &motions[w], wall_velocities[w], *position);
+                    let disc = (*radius, *mass);
+                    if let Some((new_velocity, new_spin)) =
+                        bounces.bounce(w, (*velocity, *spin), disc, normal, surface, &mut noise)
+                    {
+                        contacts.push((w, mass * (new_velocity - *velocity), normal));
+                        *velocity = new_velocity;
+                        *spin = new_spin;
+                    }
+                    *position -= shift;
+                }
+                contacts
+            })
+            .collect()
+    }
+
+    /// Total kinetic energy, translation and rotation, in joules.
+    pub fn kinetic_energy(&self) -> f64 {
+        self.translational_energy() + self.rotational_energy()
+    }
+
+    /// Kinetic energy of the motion of the particles, in joules.
+    pub fn translational_energy(&self) -> f64 {
+        let energy: f64 = self
+            .velocities
+            .iter()
+            .zip(&self.masses)
+            .map(|(v, m)| 0.5 * m * v.length_squared())
+            .sum();
+        energy * self.units.energy
+    }
+
+    /// Kinetic energy of the rotation of the particles, in joules.
+    pub fn rotational_energy(&self) -> f64 {
+        let energy: f64 = self
+            .spins
+            .iter()
+            .zip(&self.inertias)
+            .map(|(w, i)| 0.5 * i * w * w)
+            .sum();
+        energy * self.units.energy
+    }
+
+    /// Total momentum of the particles, in kg m/s.
+    pub fn momentum(&self) -> DVec2 {
+        let momentum: DVec2 = self
+            .velocities
+            .iter()
+            .zip(&self.masses)
+            .map(|(v, m)| m * *v)
+            .sum();
+        momentum * self.units.momentum()
+    }
+
+    /// Sum of the magnitudes of the momenta of the particles, in kg m/s. The scale against which
+    /// changes in total momentum are measured, since the total itself is often close to zero.
+    pub fn momentum_scale(&self) -> f64 {
+        let scale: f64 = self
+            .velocities
+            .iter()
+            .zip(&self.masses)
+            .map(|(v, m)| m * v.length())
+            .sum();
+        scale * self.units.momentum()
+    }
+
+    /// Momentum of the particles plus the momentum they gave to the walls, in kg m/s. Collisions
+    /// keep it constant, so it only changes when something outside of the physics pushes the
+    /// particles.
+    pub fn momentum_balance(&self) -> DVec2 {
+        self.momentum() + self.wall_momentum * self.units.momentum()
+    }
+}
+
+/// Resolve every collision between particles during a substep of length `dt`, in which they
+/// moved from `previous` to `positions`, and return the collisions. Paths are swept, so
+/// that pairs collide where they first touched, even if they went through each other. Unless
+/// the discs are smooth, collisions also exchange spin, see [`compute_tangential_delta`].
+/// `restitution` holds the normal and the tangential restitution coefficients.
+///
+/// Particles are sorted into a grid of cells one diameter plus twice the longest path wide, so
+/// that a particle can only touch the particles of its own and neighbouring cells. Cells are colored
+/// such that cells of the same color never share a neighbour: they are handled in parallel, one
+/// color after the other. The outcome depends on the order of the particles but not on the
+/// number of threads.
+pub fn collide_particles(
+    positions: &mut [DVec2],
+    velocities: &mut [DVec2],
+    spins: &mut [f64],
+    radii: &[f64],
+    previous: &[DVec2],
+    dt: f64,
+    restitution: [f64; 2],
+) -> Vec<Collision> {
+    let longest_path = positions
+        .iter()
+        .zip(previous)
+        .map(|(x, p)| x.distance(*p))
+        .fold(0., f64::max);
+    // Two discs that touch during the substep can each travel up to the longest path after
+    // they touched, so they end it up to a diameter and two paths apart
+    let cell_size = 2. * (radii.iter().copied().fold(0., f64::max) + longest_path);
+    if cell_size <= 0. {
+        return Vec::new();
+    }
+    let mut cells: HashMap<IVec2, Vec<usize>> = HashMap::new();
+    for (i, position) in positions.iter().enumerate() {
+        let cell = (*position / cell_size).floor().as_ivec2();
+        cells.entry(cell).or_default().push(i);
+    }
+
+    let mut collisions = Vec::new();
+    for color in 0..COLORS * COLORS {
+        let mut colored: Vec<IVec2> = cells
+            .keys()
+            .copied()
+            .filter(|c| c.x.rem_euclid(COLORS) + COLORS * c.y.rem_euclid(COLORS) == color)
+            .collect();
+        colored.sort_by_key(|c| (c.y, c.x));
+        let substep = Substep {
+            positions,
+            velocities,
+            spins,
+            radii,
+            previous,
+            dt,
+            restitution,
+        };
+        let updates: Vec<BlockUpdate> = colored
+            .par_iter()
+            .map(|cell| resolve_block(*cell, &cells, &substep))
+            .collect();
+        for update in updates {
+            for (k, i) in update.particles.iter().enumerate() {
+                positions[*i] = update.positions[k];
+                velocities[*i] = update.velocities[k];
+                spins[*i] = update.spins[k];
+            }
+            collisions.extend(update.collisions);
+        }
+    }
+    collisions
+}
+
+/// Resolve the collisions within a cell and between the cell and its forward neighbours.
+fn resolve_block(
+    cell: IVec2,
+    cells: &HashMap<IVec2, Vec<usize>>,
+    substep: &Substep,
+) -> BlockUpdate {
+    let Substep {
+        positions,
+        velocities,
+        spins,
+        radii,
+        previous,
+        dt,
+        restitution,
+    } = *substep;
+    let own = cells[&cell].len();
+    let mut particles = cells[&cell].clone();
+    for offset in FORWARD_NEIGHBOURS {
+        if let Some(neighbours) = cells.get(&(cell + offset)) {
+            particles.extend(neighbours);
+        }
+    }
+    let mut block = BlockUpdate {
+        positions: particles.iter().map(|i| positions[*i]).collect(),
+        velocities: particles.iter().map(|i| velocities[*i]).collect(),
+        spins: particles.iter().map(|i| spins[*i]).collect(),
+        particles,
+        collisions: Vec::new(),
+    };
+
+    for a in 0..own {
+        for b in a + 1..block.particles.len() {
+            let (i, j) = (block.particles[a], block.particles[b]);
+            let (x1, x2) = (block.positions[a], block.positions[b]);
+            let pair_radii = [radii[i], radii[j]];
+            let (path1, path2) = (x1 - previous[i], x2 - previous[j]);
+            let reach = radii[i] + radii[j];
+            if let Some(t) = pair_time_of_impact(previous[i], previous[j], path1, path2, reach) {
+                // They touched during the substep, and may even have gone through each other:
+                // collide where they touched, which gets the direction of the collision right,
+                // and spend the rest of the substep moving apart
+                let (c1, c2) = (previous[i] + t * path1, previous[j] + t * path2);
+                block.collide((a, b), [c1, c2], pair_radii, restitution);
+                block.positions[a] = c1 + block.velocities[a] * (1. - t) * dt;
+                block.positions[b] = c2 + block.velocities[b] * (1. - t) * dt;
+            } else if discs_overlap(x1, radii[i], x2, radii[j]) {
+                // Already touching at the start of the substep, after being pushed together
+                block.collide((a, b), [x1, x2], pair_radii, restitution);
+
+                // "Unstuck" particles by moving them so that they do not overlap
+                let shift = compute_particle_overlap(x1, x2, radii[i], radii[j]);
+                block.positions[a] += shift / 2.;
+                block.positions[b] -= shift / 2.;
+            }
+        }
+    }
+    block
+}
+
+impl BlockUpdate {
+    /// Collide the `a`-th and `b`-th particles of the block, touching at `x1` and `x2`, and keep
+    /// a record of the collision.
+    fn collide(
+        &mut self,
+        (a, b): (usize, usize),
+        [x1, x2]: [DVec2; 2],
+        radii: [f64; 2],
+        [restitution, tangential_restitution]: [f64; 2],
+    ) {
+        let energy = |v: DVec2, spin: f64| {
+            0.5 * v.length_squared() + 0.5 * INERTIA_FACTOR * (radii[0] * spin).powi(2)
+        };
+        let before = energy(self.velocities[a], self.spins[a]);
+        // Calculate the change in velocity due to an elastic collision, of which an inelastic one
+        // only keeps part of the rebound
+        let (v1, v2) = (self.velocities[a], self.velocities[b]);
+        let mut delta_v = compute_velocity_delta(x1, x2, v1, v2) * (1. + restitution) / 2.;
+        if tangential_restitution > -1. {
+            let spins = [self.spins[a], self.spins[b]];
+            let (tangential, delta_spins) =
+                compute_tangential_delta(x1, x2, v1, v2, spins, radii, tangential_restitution);
+            delta_v += tangential;
+            self.spins[a] += delta_spins[0];
+            self.spins[b] += delta_spins[1];
+        }
+        self.velocities[a] += delta_v;
+        self.velocities[b] -= delta_v;
+        self.collisions.push(Collision {
+            pair: (self.particles[a], self.particles[b]),
+            centers: [x1, x2],
+            velocity_change: delta_v,
+            energy_change: energy(self.velocities[a], self.spins[a]) - before,
+        });
+    }
+}
+
+impl<'a> Bounces<'a> {
+    /// Only draws a seed from the noise of the gas if some walls have a temperature, so that
+    /// other runs draw the same kicks as before.
+    fn new(noise: &mut StdRng, temperatures: &'a [Option<f64>], restitution: [f64; 2]) -> Self {
+        let thermal = temperatures.iter().any(Option::is_some);
+        Bounces {
+            restitution,
+            temperatures,
+            seed: thermal.then(|| noise.gen()),
+        }
+    }
+
+    /// Random numbers for particle `i`, if any wall needs them.
+    fn noise(&self, i: usize) -> Option<StdRng> {
+        // Spread the indices over all the bits of the seed
+        let mix = (i as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        self.seed.map(|seed| StdRng::seed_from_u64(seed ^ mix))
+    }
+
+    /// The velocity and spin of a disc of the given radius and mass after bouncing off wall `w`,
+    /// with [`thermal_bounce`] if the wall has a temperature and [`wall_bounce`] otherwise.
+    /// Rough discs also leave a wall with a temperature spinning at its temperature.
+    fn bounce(
+        &self,
+        w: usize,
+        (velocity, spin): (DVec2, f64),
+        (radius, mass): (f64, f64),
+        normal: DVec2,
+        surface_velocity: DVec2,
+        noise: &mut Option<StdRng>,
+    ) -> Option<(DVec2, f64)> {
+        let (Some(thermal_energy), Some(noise)) = (self.temperatures[w], noise) else {
+            return wall_bounce(velocity, spin, radius, normal, surface_velocity, self.restitution);
+        };
+        let thermal_speed = (thermal_energy / mass).sqrt();
+        let velocity = thermal_bounce(velocity, normal, surface_velocity, thermal_speed, noise)?;
+        if self.restitution[1] == -1. {
+            return Some((velocity, spin));
+        }
+        // kT / I, with I the moment of inertia
+        let spread = thermal_speed / (INERTIA_FACTOR.sqrt() * radius);
+        let spin: f64 = StandardNormal.sample(noise);
+        Some((velocity, spread * spin))
+    }
+}
+
+impl Collision {
+    /// The same collision in SI units, from the units of the gas.
+    fn to_si(self, units: &Units) -> Self {
+        Collision {
+            centers: self.centers.map(|x| x * units.length),
+            velocity_change: self.velocity_change * units.speed(),
+            energy_change: self.energy_change * units.speed().powi(2),
+            ..self
+        }
+    }
+}
+
+/// Fraction of their paths after which two discs moving in straight lines from `start1` and
+/// `start2` first touch, or `None` if they don't within their paths. Discs that already touch
+/// at the start are left out.
+pub fn pair_time_of_impact(
+    start1: DVec2,
+    start2: DVec2,
+    path1: DVec2,
+    path2: DVec2,
+    reach: f64,
+) -> Option<f64> {
+    let offset = start1 - start2;
+    let path = path1 - path2;
+    let a = path.length_squared();
+    let b = 2. * offset.dot(path);
+    let c = offset.length_squared() - reach * reach;
+    if c <= 0. || b >= 0. || a == 0. {
+        return None;
+    }
+    let discriminant = b * b - 4. * a * c;
+    if discriminant < 0. {
+        return None;
+    }
+    let t = (-b - discriminant.sqrt()) / (2. * a);
+    (t <= 1.).then_some(t)
+}
+
+/// Time after which a disc moving at `velocity` first comes within `reach` of a wall centerline
+/// while heading into it, or `None` if it doesn't within `duration`. Found by conservative
+/// advancement: the disc can always move as far as its distance to the wall without touching it.
+pub fn wall_time_of_impact(
+    position: DVec2,
+    velocity: DVec2,
+    reach: f64,
+    shape: &WallShape,
+    duration: f64,
+) -> Option<f64> {
+    let speed = velocity.length();
+    if speed == 0. {
+        return None;
+    }
+    let mut t = 0.;
+    for _ in 0..MAX_ADVANCEMENTS {
+        let point = position + velocity * t;
+        let closest = shape.closest_point_f64(point);
+        let gap = point.distance(closest) - reach;
+        if gap <= CONTACT_GAP * reach {
+            let normal = (point - closest).normalize_or_zero();
+            return (velocity.dot(normal) < 0.).then_some(t);
+        }
+        t += gap / speed;
+        if t > duration {
+            return None;
+        }
+    }
+    None
+}
+
+/// How far a disc overlaps a wall, and the normal of the wall at the contact, pointing from the
+/// wall to the disc, or `None` if the two don't touch.
+pub fn wall_contact(
+    position: DVec2,
+    radius: f64,
+    shape: &WallShape,
+    thickness: f64,
+) -> Option<(DVec2, DVec2)> {
+    let closest = shape.closest_point_f64(position);
+    if position.distance(closest) >= radius + thickness / 2. {
+        return None;
+    }
+    let normal = (position - closest).normalize_or_zero();
+    Some((normal, compute_wall_overlap(position, closest, radius, thickness)))
+}
+
+/// Velocity of the surface of a wall next to `point`: the velocity of the wall, plus the speed at
+/// which its surface slides along it.
+fn surface_velocity(
+    shape: &WallShape,
+    motion: &WallMotion,
+    velocity: DVec2,
+    point: DVec2,
+) -> DVec2 {
+    velocity + motion.sliding_speed() * shape.tangent_f64(point)
+}
+
+/// The velocity and spin of a disc after bouncing off a wall along `normal`, pointing from the
+/// wall to the disc, whose surface moves at `surface_velocity`. `restitution` holds the normal
+/// and the tangential restitution coefficients, which work as in [`BlockUpdate::collide`] and
+/// [`compute_tangential_delta`] with a wall too heavy to be moved. Discs already moving away from
+/// the wall are left alone so that they don't get stuck bouncing inside it, and `None` is
+/// returned.
+pub fn wall_bounce(
+    velocity: DVec2,
+    spin: f64,
+    radius: f64,
+    normal: DVec2,
+    surface_velocity: DVec2,
+    [restitution, tangential_restitution]: [f64; 2],
+) -> Option<(DVec2, f64)> {
+    // Walls are immovable objects, so in their frame we just need to reflect the velocity about
+    // the wall normal at the contact point
+    let relative = velocity - surface_velocity;
+    let normal_speed = relative.dot(normal);
+    if normal_speed >= 0. {
+        return None;
+    }
+    let mut velocity = velocity - (1. + restitution) * normal_speed * normal;
+    let mut spin = spin;
+    if tangential_restitution > -1. {
+        let tangent = normal.perp();
+        let slip = relative.dot(tangent) - radius * spin;
+        // The disc takes the whole impulse, shared between its translation and its rotation
+        let delta_v = -(1. + tangential_restitution) * slip / (1. + 1. / INERTIA_FACTOR);
+        velocity += delta_v * tangent;
+        spin -= delta_v / (INERTIA_FACTOR * radius);
+    }
+    Some((velocity, spin))
+}
+
+/// The velocity of a disc after bouncing off a wall kept at a temperature, whose surface moves at
+/// `surface_velocity`, with `thermal_speed` the square root of kT over the mass of the disc at
+/// that temperature. The disc forgets how it came in and leaves like a particle of a gas at the
+/// temperature of the wall crossing a line: its velocity along the surface is normally
+/// distributed, and its speed away from the wall follows a Rayleigh distribution, since the
+/// faster particles cross more often. `normal` points from the wall to the disc. Discs already
+/// moving away from the wall are left alone, and `None` is returned.
+pub fn thermal_bounce(
+    velocity: DVec2,
+    normal: DVec2,
+    surface_velocity: DVec2,
+    thermal_speed: f64,
+    noise: &mut impl Rng,
+) -> Option<DVec2> {
+    if (velocity - surface_velocity).dot(normal) >= 0. {
+        return None;
+    }
+    let uniform: f64 = noise.gen();
+    let normal_speed = thermal_speed * (-2. * (1. - uniform).ln()).sqrt();
+    let tangential: f64 = StandardNormal.sample(noise);
+    let tangential_speed = thermal_speed * tangential;
+    Some(surface_velocity + normal_speed * normal + tangential_speed * normal.perp())
+}
+
+/// Whether two discs intersect.
+pub fn discs_overlap(x1: DVec2, radius1: f64, x2: DVec2, radius2: f64) -> bool {
+    x1.distance_squared(x2) <= (radius1 + radius2).powi(2)
+}
+
+/// Computes the velocity difference after an elastic collision of two rigid spheres of equal mass.
+pub fn compute_velocity_delta(x1: DVec2, x2: DVec2, v1: DVec2, v2: DVec2) -> DVec2 {
+    let delta_v = v1 - v2;
+    let delta_x = x1 - x2;
+
+    return -delta_v.dot(delta_x) / delta_x.length_squared() * delta_x;
+}
+
+/// Computes the tangential velocity difference and the changes in spin after a collision of two
+/// rough discs of equal mass, on top of the normal velocity difference. The tangential
+/// restitution coefficient is the fraction of the slip between the contact points that is
+/// reversed: -1 for smooth discs, which leaves them alone, and 1 for perfectly rough ones. Both
+/// keep the energy, while values in between turn some of it into heat.
+pub fn compute_tangential_delta(
+    x1: DVec2,
+    x2: DVec2,
+    v1: DVec2,
+    v2: DVec2,
+    spins: [f64; 2],
+    radii: [f64; 2],
+    tangential_restitution: f64,
+) -> (DVec2, [f64; 2]) {
+    let tangent = (x1 - x2).normalize().perp();
+    let slip = (v1 - v2).dot(tangent) - radii[0] * spins[0] - radii[1] * spins[1];
+    // The impulse is shared between the translation of both discs and their rotation, which
+    // takes up 1 / INERTIA_FACTOR times as much of it
+    let delta_v = -(1. + tangential_restitution) * slip / (2. + 2. / INERTIA_FACTOR);
+    let delta_spins = radii.map(|r| -delta_v / (INERTIA_FACTOR * r));
+    (delta_v * tangent, delta_spins)
+}
+
+/// Computes the velocity difference after an elastic collision of two rigid spheres of different mass.
+// fn compute_velocity_delta_masses(x1: Vec2, x2: Vec2, v1: Vec2, v2: Vec2, m1: f32, m2: f32) -> Vec2 {
+//     let total_m = m1 + m2;
+//     let delta_v = v1 - v2;
+//     let delta_x = x1 - x2;
+
+//     return -2.0 * m2 / total_m * delta_v.dot(delta_x) / delta_x.length_squared() * delta_x;
+// }
+
+/// Compute the vector that describes the overlap between two intersecting spheres.
+/// The direction of the vector is `x2` towards `x1`.
+pub fn compute_particle_overlap(x1: DVec2, x2: DVec2, radius1: f64, radius2: f64) -> DVec2 {
+    let distance_between_centers = x1 - x2;
+    let distance = distance_between_centers.length().max(0.);
+    let overlap = radius1 + radius2 - distance;
+    return overlap * distance_between_centers / distance;
+}
+
+/// Compute the vector that describes the overlap between a sphere and a wall, given the closest
+/// point on the wall centerline. Direction of the vector is towards the wall.
+pub fn compute_wall_overlap(
+    part_center: DVec2,
+    closest_point: DVec2,
+    radius: f64,
+    wall_thickness: f64,
+) -> DVec2 {
+    let distance_to_wall = closest_point - part_center;
+    let distance = distance_to_wall.length();
+    let overlap = radius + wall_thickness / 2. - distance;
+    overlap * distance_to_wall / distance
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A single particle of unit radius and mass in SI units, above a horizontal wall along the
+    /// x axis.
+    fn gas_above_floor(position: Vec2, velocity: Vec2) -> Gas {
+        let floor = WallShape::Segment {
+            start: Vec2::new(-100., 0.),
+            end: Vec2::new(100., 0.),
+        };
+        Gas::new(&[position], &[velocity], 1., 1., &[floor], 0.2, Units::SI)
+    }
+
+    #[test]
+    fn rough_disc_gives_a_wall_only_its_normal_momentum() {
+        let mut gas = gas_above_floor(Vec2::new(0., 2.), Vec2::new(10., -1.));
+        gas.tangential_restitution = 1.;
+        let report = gas.step(1.);
+        // The bounce reverses the normal velocity, and spins the disc up along the wall
+        assert!((gas.velocities[0].y - 1.).abs() < 1e-9);
+        assert!(gas.spins[0] != 0.);
+        assert!((report.wall_impulses[0] - 2.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn pairs_meet_where_their_discs_first_touch() {
+        let (start1, start2, reach) = (DVec2::ZERO, DVec2::new(10., 0.), 2.);
+        let path = DVec2::new(5., 0.);
+        // Head on, the gap of 8 closes at a combined 10 per path
+        let t = pair_time_of_impact(start1, start2, path, -path, reach).unwrap();
+        assert!((t - 0.8).abs() < 1e-12);
+        // Slightly off center, they touch a little later
+        let offset = DVec2::new(0., 1.999);
+        let t = pair_time_of_impact(start1, start2 + offset, path, -path, reach).unwrap();
+        let expected = (10. - (reach * reach - offset.y * offset.y).sqrt()) / 10.;
+        assert!((t - expected).abs() < 1e-9);
+        // Further off, they pass each other by
+        let offset = DVec2::new(0., 2.5);
+        assert!(pair_time_of_impact(start1, start2 + offset, path, -path, reach).is_none());
+        // Too short paths, parting discs and discs that already touch don't collide
+        let short = DVec2::new(3., 0.);
+        assert!(pair_time_of_impact(start1, start2, short, -short, reach).is_none());
+        assert!(pair_time_of_impact(start1, start2, -path, path, reach).is_none());
+        let touching = DVec2::new(1.5, 0.);
+        assert!(pair_time_of_impact(start1, touching, path, -path, reach).is_none());
+    }
+
+    #[test]
+    fn discs_reach_a_wall_only_when_heading_into_it() {
+        let floor = WallShape::Segment {
+            start: Vec2::new(-100., 0.),
+            end: Vec2::new(100., 0.),
+        };
+        let (position, reach) = (DVec2::new(0., 5.), 1.1);
+        let t = wall_time_of_impact(position, DVec2::new(0., -2.), reach, &floor, 10.).unwrap();
+        assert!((t - 1.95).abs() < 1e-6);
+        let slanted = DVec2::new(1., -2.);
+        let t = wall_time_of_impact(position, slanted, reach, &floor, 10.).unwrap();
+        assert!((t - 1.95).abs() < 1e-6);
+        // Grazing along the wall, moving away from it, or stopping short of it
+        let grazing = DVec2::new(0., reach);
+        assert!(wall_time_of_impact(grazing, DVec2::X, reach, &floor, 10.).is_none());
+        assert!(wall_time_of_impact(position, DVec2::Y, reach, &floor, 10.).is_none());
+        assert!(wall_time_of_impact(position, -DVec2::Y, reach, &floor, 3.).is_none());
+        // Past the end of the wall, the disc falls by
+        let beside = DVec2::new(150., 5.);
+        assert!(wall_time_of_impact(beside, -DVec2::Y, reach, &floor, 10.).is_none());
+    }
+
+    #[test]
+    fn elastic_pair_collision_keeps_energy_and_momentum() {
+        let (x1, x2) = (DVec2::new(0., 0.), DVec2::new(1.6, 1.2));
+        let (v1, v2) = (DVec2::new(3., 1.), DVec2::new(-1., 0.5));
+        let energy = |v1: DVec2, v2: DVec2, spins: [f64; 2]| {
+            0.5 * (v1.length_squared() + v2.length_squared())
+                + 0.5 * INERTIA_FACTOR * (spins[0].powi(2) + spins[1].powi(2))
+        };
+        // Smooth discs
+        let delta = compute_velocity_delta(x1, x2, v1, v2);
+        let (w1, w2) = (v1 + delta, v2 - delta);
+        assert!((w1 + w2 - v1 - v2).length() < 1e-12);
+        assert!((energy(w1, w2, [0.; 2]) - energy(v1, v2, [0.; 2])).abs() < 1e-12);
+        // They now part along the line between their centers
+        assert!((w1 - w2).dot(x1 - x2) > 0.);
+        // Perfectly rough discs of unit radius also keep the energy, counting their spin
+        let spins = [0.7, -0.2];
+        let (tangential, delta_spins) =
+            compute_tangential_delta(x1, x2, w1, w2, spins, [1.; 2], 1.);
+        let (u1, u2) = (w1 + tangential, w2 - tangential);
+        let new_spins = [spins[0] + delta_spins[0], spins[1] + delta_spins[1]];
+        assert!((u1 + u2 - v1 - v2).length() < 1e-12);
+        assert!((energy(u1, u2, new_spins) - energy(v1, v2, spins)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn wall_bounce_keeps_energy_and_angular_momentum_at_the_contact() {
+        let (velocity, spin, radius) = (DVec2::new(2., -3.), 0.4, 1.5);
+        let normal = DVec2::new(1., 1.).normalize();
+        let energy = |v: DVec2, spin: f64| {
+            0.5 * v.length_squared() + 0.5 * INERTIA_FACTOR * (radius * spin).powi(2)
+        };
+        // A smooth wall reverses the normal velocity and keeps the rest
+        let (v, w) = wall_bounce(velocity, spin, radius, normal, DVec2::ZERO, [1., -1.]).unwrap();
+        assert!((v.dot(normal) + velocity.dot(normal)).abs() < 1e-12);
+        assert!((v.dot(normal.perp()) - velocity.dot(normal.perp())).abs() < 1e-12);
+        assert_eq!(w, spin);
+        // A perfectly rough one trades spin for sliding, but keeps the energy, and the impulse
+        // going through the contact point leaves the angular momentum about it alone
+        let (v, w) = wall_bounce(velocity, spin, radius, normal, DVec2::ZERO, [1., 1.]).unwrap();
+        assert!((energy(v, w) - energy(velocity, spin)).abs() < 1e-12);
+        let angular_momentum = |v: DVec2, spin: f64| {
+            radius * v.dot(normal.perp()) + INERTIA_FACTOR * radius.powi(2) * spin
+        };
+        assert!((angular_momentum(v, w) - angular_momentum(velocity, spin)).abs() < 1e-12);
+        // The wall takes the normal momentum the disc gives up
+        let mut gas = gas_above_floor(Vec2::new(0., 2.), Vec2::new(3., -4.));
+        let momentum = gas.momentum();
+        let report = gas.step(1.);
+        assert!((gas.momentum().y - momentum.y - report.wall_impulses[0]).abs() < 1e-9);
+        assert!((gas.momentum().x - momentum.x).abs() < 1e-12);
+        assert!(wall_bounce(-velocity, spin, radius, normal, DVec2::ZERO, [1., 1.]).is_none());
+    }
+
+    #[test]
+    fn fast_particle_does_not_tunnel_through_a_thin_wall() {
+        for speed in [1e3, 1e6] {
+            let mut gas = gas_above_floor(Vec2::new(0., 5.), Vec2::new(0., -speed));
+            let report = gas.step(0.01);
+            assert!(report.substeps > 1);
+            assert!(gas.positions[0].y > 1., "{speed}: {}", gas.positions[0]);
+            assert!((gas.velocities[0].y - speed as f64).abs() < 1e-6 * speed as f64);
+            assert!((report.wall_impulses[0] - 2. * speed as f64).abs() < 1e-6 * speed as f64);
+        }
+    }
+
+    #[test]
+    fn fast_pair_collides_across_a_cell_boundary() {
+        // Two discs that touch right after the start of the substep, on paths as long as the
+        // longest one, and would end it far apart. A cell one diameter plus one path wide would
+        // put them two cells apart, out of each other's neighbourhood.
+        let path = DVec2::new(5., 10. * 3_f64.sqrt() / 2.);
+        let previous = [DVec2::new(0., 3.5), DVec2::new(2.01, 3.5)];
+        let mut positions = [previous[0] + path, previous[1] - path];
+        let mut velocities = [path, -path];
+        let mut spins = [0.; 2];
+        let momentum = velocities[0] + velocities[1];
+        let energy = velocities[0].length_squared() + velocities[1].length_squared();
+        let collisions = collide_particles(
+            &mut positions,
+            &mut velocities,
+            &mut spins,
+            &[1.; 2],
+            &previous,
+            1.,
+            [1., -1.],
+        );
+        assert_eq!(collisions.len(), 1);
+        assert!((velocities[0] + velocities[1] - momentum).length() < 1e-12);
+        let after = velocities[0].length_squared() + velocities[1].length_squared();
+        assert!((after - energy).abs() < 1e-9 * energy);
+        // They bounced back the way they came instead of going through each other
+        assert!(positions[0].x < positions[1].x);
+    }
+
+    #[test]
+    fn thermal_bounce_sends_particles_away_at_the_wall_temperature() {
+        let mut noise = StdRng::seed_from_u64(1);
+        let (normal, thermal_speed) = (DVec2::Y, 2.);
+        let draws = 100_000;
+        let (mut normal_square, mut tangential_square) = (0., 0.);
+        for _ in 0..draws {
+            let incoming = DVec2::new(3., -5.);
+            let v = thermal_bounce(incoming, normal, DVec2::ZERO, thermal_speed, &mut noise)
+                .unwrap();
+            assert!(v.y > 0.);
+            normal_square += v.y * v.y / draws as f64;
+            tangential_square += v.x * v.x / draws as f64;
+        }
+        // The flux through a line weighs the normal speeds by themselves, which doubles the mean
+        // of their square
+        assert!((normal_square / (2. * thermal_speed.powi(2)) - 1.).abs() < 0.02);
+        assert!((tangential_square / thermal_speed.powi(2) - 1.).abs() < 0.02);
+        let away = thermal_bounce(DVec2::Y, normal, DVec2::ZERO, thermal_speed, &mut noise);
+        assert!(away.is_none());
+    }
+
+    #[test]
+    fn thermal_wall_is_pushed_only_by_the_normal_momentum() {
+        let mut gas = gas_above_floor(Vec2::new(0., 2.), Vec2::new(0., -1.));
+        gas.wall_temperatures = vec![Some(1.)];
+        let momentum = gas.momentum();
+        let report = gas.step(1.);
+        // The wall sends the disc off at an angle, but only pushes back along its normal
+        let change = gas.momentum() - momentum;
+        assert!(change.x != 0.);
+        assert!((report.wall_impulses[0] - change.y).abs() < 1e-9);
+    }
+
+    #[test]
+    fn thermal_walls_bring_a_cold_gas_to_their_temperature() {
+        let corners = [(-20., 20.), (20., 20.), (20., -20.), (-20., -20.)].map(Vec2::from);
+        let walls: Vec<WallShape> = (0..4)
+            .map(|k| WallShape::Segment {
+                start: corners[k],
+                end: corners[(k + 1) % 4],
+            })
+            .collect();
+        let positions: Vec<Vec2> = (0..25)
+            .map(|k| Vec2::new((k % 5) as f32, (k / 5) as f32) * 6. - 12.)
+            .collect();
+        let velocities = vec![Vec2::new(0.01, 0.02); positions.len()];
+        let mut gas = Gas::new(&positions, &velocities, 1., 1., &walls, 0.2, Units::SI);
+        // kT of 1 in units where the mass is 1
+        gas.wall_temperatures = vec![Some(1.); 4];
+        assert!(!gas.conserves_energy());
+        let mut thermal_energy = 0.;
+        let steps = 4000;
+        for step in 0..2 * steps {
+            gas.step(0.25);
+            if step >= steps {
+                thermal_energy += gas.translational_energy() / 25. / steps as f64;
+            }
+        }
+        assert!((thermal_energy - 1.).abs() < 0.15, "{thermal_energy}");
+    }
+}
@@ -0,0 +1,476 @@
+use std::{collections::VecDeque, mem};
+
+use bevy::{ecs::system::SystemParam, math::DVec2, prelude::*};
+use bevy_egui::{egui, EguiContexts};
+use egui_plot::{Line, Plot};
+use rand::rngs::StdRng;
+
+use gas_simulation::geometry::WallMotion;
+
+use crate::{
+    clock::SimulationClock, conservation::Conservation, lyapunov::fit_slope, physics::Gas,
+    Particle, ParticleHistory, SimulationState,
+};
+
+/// Largest number of physics ticks kept in the rewind buffer.
+const REWIND_LENGTH: usize = 1024;
+/// Most memory the rewind buffer may take up, in bytes. Large gases keep fewer ticks.
+const REWIND_BYTES: usize = 64 << 20;
+/// Distances outside of this range, in meters, are left out of the divergence fit. Below it the
+/// error is still double precision rounding noise and above it the trajectories have nothing to
+/// do with each other.
+const FIT_RANGE: (f64, f64) = (1e-9, 10.);
+
+/// Finer control over time: advance the physics one tick at a time (Right arrow), scrub back
+/// through the last few seconds (Left arrow) and flip every velocity to run the gas backwards (V).
+/// After a reversal, the reversed trajectory is compared to the forward one to show how chaos
+/// amplifies rounding errors until the gas no longer finds its way back (Loschmidt's paradox).
+/// The reversed run takes the time steps of the forward run in reverse order, so that only
+/// rounding errors keep it from retracing it. Moving walls follow the clock, which only goes
+/// forward, so scenarios with moving walls can't be reversed.
+pub struct PlaybackPlugin;
+
+impl Plugin for PlaybackPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PendingSteps>()
+            .init_resource::<Rewind>()
+            .init_resource::<Reversal>()
+            .add_systems(OnExit(SimulationState::Setup), start_recording)
+            .add_systems(
+                FixedLast,
+                (record_snapshot, track_reversal, consume_step)
+                    .chain()
+                    .run_if(physics_running),
+            )
+            .add_systems(
+                Update,
+                (check_playback_keys, playback_window, restore_snapshot).chain(),
+            );
+    }
+}
+
+/// Number of physics ticks to run while paused.
+#[derive(Resource, Default)]
+pub struct PendingSteps(u32);
+
+/// The state of every particle after a physics tick, and of what the gas keeps track of along
+/// with them, so that carrying on from a rewound tick goes the same way as the first time.
+#[derive(Clone)]
+struct Snapshot {
+    tick: u64,
+    /// Simulated time, in seconds
+    time: f64,
+    /// Length of the step that led here, in seconds
+    dt: f64,
+    positions: Vec<DVec2>,
+    velocities: Vec<DVec2>,
+    spins: Vec<f64>,
+    orientations: Vec<f64>,
+    /// See [`Gas::wall_momentum`]
+    wall_momentum: DVec2,
+    /// Where the random kicks and the velocities off thermal walls come from next
+    noise: StdRng,
+    /// History of every particle, by index in the gas
+    histories: Vec<ParticleHistory>,
+}
+
+/// Ring buffer of the most recent ticks, oldest first.
+#[derive(Resource, Default)]
+struct Rewind {
+    snapshots: VecDeque<Snapshot>,
+    /// Number of ticks kept, see [`Rewind::capacity`]
+    capacity: usize,
+    /// Snapshot currently shown while scrubbing. The history after it is dropped as soon as the
+    /// simulation moves on from there.
+    cursor: Option<usize>,
+    /// Whether the particles still need to be moved to the snapshot under the cursor
+    dirty: bool,
+}
+
+#[derive(Resource, Default)]
+struct Reversal {
+    /// Tick and simulated time at which the velocities were flipped
+    tick: Option<u64>,
+    time: f64,
+    /// Forward run, from the moment of the reversal backwards, which the reversed run should
+    /// retrace
+    forward: Vec<Snapshot>,
+    /// Mean distance between the reversed and the forward trajectory against the time since the
+    /// reversal
+    divergence: Vec<[f64; 2]>,
+    report: Option<String>,
+}
+
+/// The gas, for edits the conservation monitor has to start over from and that move the clock.
+#[derive(SystemParam)]
+struct GasEdit<'w> {
+    gas: ResMut<'w, Gas>,
+    conservation: ResMut<'w, Conservation>,
+    clock: ResMut<'w, SimulationClock>,
+}
+
+/// The histories of the particles, which are recorded in the snapshots along with the gas.
+type Histories<'w, 's, T> = Query<'w, 's, (&'static Particle, T)>;
+
+impl Rewind {
+    /// Number of ticks kept for a gas of `particles` particles: [`REWIND_LENGTH`], or as many as
+    /// fit in [`REWIND_BYTES`] if that is fewer.
+    fn capacity(particles: usize) -> usize {
+        (REWIND_BYTES / Snapshot::size(particles)).clamp(2, REWIND_LENGTH)
+    }
+
+    fn latest(&self) -> usize {
+        self.cursor.unwrap_or(self.snapshots.len().saturating_sub(1))
+    }
+
+    fn seek(&mut self, index: usize) {
+        if index < self.snapshots.len() {
+            self.cursor = Some(index);
+            self.dirty = true;
+        }
+    }
+}
+
+/// Run condition for everything that advances the physics.
+pub fn physics_running(state: Res<State<SimulationState>>, steps: Res<PendingSteps>) -> bool {
+    *state.get() == SimulationState::Running || steps.0 > 0
+}
+
+impl Snapshot {
+    fn new(
+        tick: u64,
+        gas: &Gas,
+        clock: &SimulationClock,
+        particles: &Histories<&ParticleHistory>,
+    ) -> Self {
+        let mut snapshot = Snapshot {
+            tick,
+            time: 0.,
+            dt: 0.,
+            positions: Vec::new(),
+            velocities: Vec::new(),
+            spins: Vec::new(),
+            orientations: Vec::new(),
+            wall_momentum: DVec2::ZERO,
+            noise: gas.noise.clone(),
+            histories: Vec::new(),
+        };
+        snapshot.overwrite(tick, gas, clock, particles);
+        snapshot
+    }
+
+    /// Record the current state in place of this snapshot, reusing its buffers.
+    fn overwrite(
+        &mut self,
+        tick: u64,
+        gas: &Gas,
+        clock: &SimulationClock,
+        particles: &Histories<&ParticleHistory>,
+    ) {
+        self.tick = tick;
+        self.time = clock.elapsed;
+        self.dt = clock.dt;
+        self.positions.clone_from(&gas.positions);
+        self.velocities.clone_from(&gas.velocities);
+        self.spins.clone_from(&gas.spins);
+        self.orientations.clone_from(&gas.orientations);
+        self.wall_momentum = gas.wall_momentum;
+        self.noise.clone_from(&gas.noise);
+        self.histories.clear();
+        self.histories.resize(gas.positions.len(), ParticleHistory::default());
+        for (particle, history) in particles {
+            self.histories[particle.index] = *history;
+        }
+    }
+
+    /// Memory taken up by a snapshot of `particles` particles, in bytes.
+    fn size(particles: usize) -> usize {
+        let state = 2 * mem::size_of::<DVec2>() + 2 * mem::size_of::<f64>();
+        let per_particle = state + mem::size_of::<ParticleHistory>();
+        mem::size_of::<Snapshot>() + particles * per_particle
+    }
+}
+
+/// Whether the gas can run backwards. Moving walls follow the clock, which only goes forward, so
+/// the particles would meet them where they weren't on the way out.
+fn reversible(gas: &Gas) -> bool {
+    gas.wall_motions.iter().all(|motion| *motion == WallMotion::Fixed)
+}
+
+/// Start a fresh history from the initial conditions.
+fn start_recording(
+    mut rewind: ResMut<Rewind>,
+    mut reversal: ResMut<Reversal>,
+    mut steps: ResMut<PendingSteps>,
+    gas: Res<Gas>,
+    clock: Res<SimulationClock>,
+    particles: Histories<&ParticleHistory>,
+) {
+    *reversal = Reversal::default();
+    steps.0 = 0;
+    rewind.snapshots.clear();
+    rewind.cursor = None;
+    rewind.capacity = Rewind::capacity(gas.positions.len());
+    rewind.snapshots.push_back(Snapshot::new(0, &gas, &clock, &particles));
+}
+
+fn record_snapshot(
+    mut rewind: ResMut<Rewind>,
+    gas: Res<Gas>,
+    clock: Res<SimulationClock>,
+    particles: Histories<&ParticleHistory>,
+) {
+    // Moving on from a rewound state overwrites what came after it
+    if let Some(cursor) = rewind.cursor.take() {
+        rewind.snapshots.truncate(cursor + 1);
+    }
+    let tick = rewind.snapshots.back().map_or(0, |s| s.tick + 1);
+    // Once the buffer is full, the oldest tick makes room and lends its buffers to the newest
+    let full = rewind.snapshots.len() >= rewind.capacity;
+    let snapshot = match full.then(|| rewind.snapshots.pop_front()).flatten() {
+        Some(mut oldest) => {
+            oldest.overwrite(tick, &gas, &clock, &particles);
+            oldest
+        }
+        None => Snapshot::new(tick, &gas, &clock, &particles),
+    };
+    rewind.snapshots.push_back(snapshot);
+}
+
+/// Compare the reversed run to the forward run at the same distance from the reversal.
+fn track_reversal(
+    rewind: Res<Rewind>,
+    mut reversal: ResMut<Reversal>,
+    gas: Res<Gas>,
+) {
+    let (Some(start), Some(now)) = (reversal.tick, rewind.snapshots.back()) else {
+        return;
+    };
+    if reversal.report.is_some() {
+        return;
+    }
+    let elapsed = now.tick.saturating_sub(start) as usize;
+    let Some(forward) = reversal.forward.get(elapsed) else {
+        return;
+    };
+
+    let distances: Vec<f64> = now
+        .positions
+        .iter()
+        .zip(&forward.positions)
+        .map(|(a, b)| a.distance(*b) * gas.units.length)
+        .collect();
+    let mean = distances.iter().sum::<f64>() / distances.len().max(1) as f64;
+    let time = now.time - reversal.time;
+    reversal.divergence.push([time, mean]);
+
+    if elapsed + 1 == reversal.forward.len() {
+        let max = distances.iter().copied().fold(0., f64::max);
+        let rate = match divergence_rate(&reversal.divergence) {
+            Some(rate) => format!("grows as exp({rate:.2} t)"),
+            None => "is too small to fit".to_string(),
+        };
+        reversal.report = Some(format!(
+            "Back to the state of {:.2} s before the reversal\n\
+             Mean drift: {mean:.2e} m, largest: {max:.2e} m\n\
+             The distance to the forward run {rate}",
+            time
+        ));
+    }
+}
+
+/// Least squares slope of the logarithm of the divergence against time, a rough estimate of the
+/// largest Lyapunov exponent.
+fn divergence_rate(divergence: &[[f64; 2]]) -> Option<f64> {
+    let points: Vec<[f64; 2]> = divergence
+        .iter()
+        .filter(|[_, d]| *d > FIT_RANGE.0 && *d < FIT_RANGE.1)
+        .map(|[t, d]| [*t, d.ln()])
+        .collect();
+    fit_slope(&points)
+}
+
+fn consume_step(mut steps: ResMut<PendingSteps>) {
+    steps.0 = steps.0.saturating_sub(1);
+}
+
+fn check_playback_keys(
+    keys: Res<ButtonInput<KeyCode>>,
+    state: Res<State<SimulationState>>,
+    mut next_state: ResMut<NextState<SimulationState>>,
+    mut steps: ResMut<PendingSteps>,
+    mut rewind: ResMut<Rewind>,
+    mut reversal: ResMut<Reversal>,
+    mut edit: GasEdit,
+) {
+    if *state.get() == SimulationState::Setup {
+        return;
+    }
+    if keys.just_pressed(KeyCode::ArrowRight) {
+        next_state.set(SimulationState::Paused);
+        step_forward(&mut steps, &mut rewind);
+    }
+    if keys.just_pressed(KeyCode::ArrowLeft) {
+        next_state.set(SimulationState::Paused);
+        let latest = rewind.latest();
+        rewind.seek(latest.saturating_sub(1));
+    }
+    if keys.just_pressed(KeyCode::KeyV) {
+        reverse(&mut rewind, &mut reversal, &mut edit);
+    }
+}
+
+/// Replay the next tick from the buffer if there is one, otherwise compute it.
+fn step_forward(steps: &mut PendingSteps, rewind: &mut Rewind) {
+    match rewind.cursor {
+        Some(cursor) if cursor + 1 < rewind.snapshots.len() => rewind.seek(cursor + 1),
+        _ => steps.0 += 1,
+    }
+}
+
+/// Flip every velocity and spin and start comparing against the forward run. The recording so
+/// far becomes the forward run, and the rewind buffer starts over from the reversal.
+fn reverse(rewind: &mut Rewind, reversal: &mut Reversal, edit: &mut GasEdit) {
+    if !reversible(&edit.gas) {
+        warn!("Moving walls can't run backwards, time can't be reversed in this scenario");
+        return;
+    }
+    // Reversing from a rewound state continues from there
+    if let Some(cursor) = rewind.cursor.take() {
+        rewind.snapshots.truncate(cursor + 1);
+    }
+    for velocity in &mut edit.gas.velocities {
+        *velocity = -*velocity;
+    }
+    for spin in &mut edit.gas.spins {
+        *spin = -*spin;
+    }
+    edit.conservation.reset();
+    let mut forward = Vec::from(mem::take(&mut rewind.snapshots));
+    // The steps that led to each snapshot, taken again from the latest one back
+    let timesteps = forward.iter().skip(1).map(|s| s.dt).collect();
+    edit.clock.replay(timesteps);
+    forward.reverse();
+    if let Some(latest) = forward.first() {
+        rewind.snapshots.push_back(latest.clone());
+    }
+    *reversal = Reversal {
+        tick: forward.first().map(|s| s.tick),
+        time: forward.first().map_or(0., |s| s.time),
+        forward,
+        ..default()
+    };
+}
+
+/// Move the particles to the snapshot under the cursor.
+fn restore_snapshot(
+    mut rewind: ResMut<Rewind>,
+    mut edit: GasEdit,
+    mut particles: Histories<&mut ParticleHistory>,
+) {
+    if !rewind.dirty {
+        return;
+    }
+    rewind.dirty = false;
+    let Some(snapshot) = rewind.cursor.and_then(|c| rewind.snapshots.get(c)) else {
+        return;
+    };
+    edit.gas.positions.clone_from(&snapshot.positions);
+    edit.gas.velocities.clone_from(&snapshot.velocities);
+    edit.gas.spins.clone_from(&snapshot.spins);
+    edit.gas.orientations.clone_from(&snapshot.orientations);
+    edit.gas.wall_momentum = snapshot.wall_momentum;
+    edit.gas.noise.clone_from(&snapshot.noise);
+    for (particle, mut history) in &mut particles {
+        if let Some(saved) = snapshot.histories.get(particle.index) {
+            *history = *saved;
+        }
+    }
+    edit.gas.set_time(snapshot.time);
+    edit.conservation.reset();
+    edit.clock.rewind(snapshot.time);
+}
+
+fn playback_window(
+    mut contexts: EguiContexts,
+    mut next_state: ResMut<NextState<SimulationState>>,
+    mut steps: ResMut<PendingSteps>,
+    mut rewind: ResMut<Rewind>,
+    mut reversal: ResMut<Reversal>,
+    mut edit: GasEdit,
+) {
+    egui::Window::new("Playback")
+        .default_pos([10., 500.])
+        .show(contexts.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                if ui.button("Step").on_hover_text("Right arrow").clicked() {
+                    next_state.set(SimulationState::Paused);
+                    step_forward(&mut steps, &mut rewind);
+                }
+                let button = egui::Button::new("Reverse time");
+                if ui
+                    .add_enabled(reversible(&edit.gas), button)
+                    .on_hover_text("V")
+                    .on_disabled_hover_text("Moving walls can't run backwards")
+                    .clicked()
+                {
+                    reverse(&mut rewind, &mut reversal, &mut edit);
+                }
+            });
+
+            let len = rewind.snapshots.len();
+            let mut index = rewind.latest();
+            let scrubbed = len > 1
+                && ui
+                    .add(
+                        egui::Slider::new(&mut index, 0..=len - 1)
+                            .show_value(false)
+                            .text("rewind (Left arrow)"),
+                    )
+                    .changed();
+            if scrubbed {
+                next_state.set(SimulationState::Paused);
+                rewind.seek(index);
+            }
+            if let Some(snapshot) = rewind.snapshots.get(index) {
+                ui.label(format!("Tick {} of the last {len}", snapshot.tick));
+            }
+
+            if reversal.tick.is_none() {
+                return;
+            }
+            ui.separator();
+            ui.label("Distance from the forward run after the reversal");
+            let points: Vec<[f64; 2]> = reversal
+                .divergence
+                .iter()
+                .filter(|[_, d]| *d > 0.)
+                .map(|[t, d]| [*t, d.log10()])
+                .collect();
+            Plot::new("reversal_divergence")
+                .height(150.)
+                .x_axis_label("time since reversal [s]")
+                .y_axis_label("log10 mean distance [m]")
+                .show(ui, |plot| plot.line(Line::new(points)));
+            match &reversal.report {
+                Some(report) => ui.label(report),
+                None => ui.label("Running backwards..."),
+            };
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewind_buffer_fits_in_its_budget() {
+        assert_eq!(Rewind::capacity(100), REWIND_LENGTH);
+        for particles in [3000, 100_000] {
+            let capacity = Rewind::capacity(particles);
+            assert!(capacity < REWIND_LENGTH);
+            assert!(capacity * Snapshot::size(particles) <= REWIND_BYTES);
+        }
+        assert_eq!(Rewind::capacity(1 << 30), 2);
+    }
+}
@@ -0,0 +1,522 @@
+use std::{
+    error::Error,
+    f32::consts::{FRAC_PI_2, PI},
+    fs,
+};
+
+use bevy::prelude::*;
+use serde::Deserialize;
+
+use crate::{
+    demon::DemonConfig,
+    fields::FieldsConfig,
+    geometry::{polygon_area, polygon_contains, polygon_edges, WallMotion, WallShape},
+    initial::InitialConditions,
+};
+
+/// Number of segments a full circle is split in when the walls are turned into a polygon.
+const ARC_SEGMENTS: usize = 64;
+
+/// Describes the container the gas is simulated in. Scenarios are written in RON and passed as
+/// the first command line argument, e.g. `cargo run -- scenarios/stadium.ron`.
+/// All lengths are in meters and the container is centered on the origin.
+#[derive(Resource, Deserialize, Debug, Clone)]
+pub struct Scenario {
+    pub container: Container,
+    #[serde(default)]
+    pub obstacles: Vec<Obstacle>,
+    /// Thin walls that move, to shake, shear or compress the gas.
+    #[serde(default)]
+    pub moving_walls: Vec<MovingWall>,
+    /// Walls kept at a temperature, which heat or cool the gas. Their temperatures can be changed
+    /// from the control panel.
+    #[serde(default)]
+    pub thermal_walls: Vec<ThermalWall>,
+    /// Splits the container in two with a partition operated by Maxwell's demon.
+    #[serde(default)]
+    pub demon: Option<DemonConfig>,
+    /// Resolution and export settings of the temperature, density and velocity fields.
+    #[serde(default)]
+    pub fields: FieldsConfig,
+    /// Layout and velocities the particles start with. Can also be changed from the control panel.
+    #[serde(default)]
+    pub initial_conditions: InitialConditions,
+}
+
+/// The outer boundary of the simulation. Particles are kept inside of it.
+#[derive(Deserialize, Debug, Clone)]
+pub enum Container {
+    Rectangle { width: f32, height: f32 },
+    Circle { radius: f32 },
+    /// Two half circles of the given radius joined by straight walls of the given length.
+    Stadium { length: f32, radius: f32 },
+    /// Any simple polygon, convex or concave. Vertices can be given in either winding order.
+    Polygon { vertices: Vec<(f32, f32)> },
+}
+
+/// Solid objects placed inside of the container. Particles are kept outside of them.
+#[derive(Deserialize, Debug, Clone)]
+pub enum Obstacle {
+    Disc { center: (f32, f32), radius: f32 },
+    Polygon { vertices: Vec<(f32, f32)> },
+    /// A thin barrier. Particles bounce off both sides.
+    Segment { start: (f32, f32), end: (f32, f32) },
+}
+
+/// A thin barrier like [`Obstacle::Segment`], which moves as prescribed. Particles bounce off it
+/// in its own frame, so that it gives them its velocity.
+#[derive(Deserialize, Debug, Clone)]
+pub struct MovingWall {
+    pub start: (f32, f32),
+    pub end: (f32, f32),
+    pub motion: WallMotion,
+}
+
+/// A wall that gives the particles bouncing off it random velocities at its temperature, instead
+/// of bouncing them back.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ThermalWall {
+    /// Index of the wall in [`Scenario::walls`]. The container comes first: a rectangle has its
+    /// top, right, bottom and left walls in that order, a polygon one wall per edge from its
+    /// first vertex on.
+    pub wall: usize,
+    /// In kelvins
+    pub temperature: f32,
+}
+
+impl Default for Scenario {
+    fn default() -> Self {
+        Scenario {
+            container: Container::Rectangle {
+                width: 1000.,
+                height: 700.,
+            },
+            obstacles: Vec::new(),
+            moving_walls: Vec::new(),
+            thermal_walls: Vec::new(),
+            demon: None,
+            fields: FieldsConfig::default(),
+            initial_conditions: InitialConditions::default(),
+        }
+    }
+}
+
+impl Scenario {
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        Scenario::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Read a scenario written in RON, and check that its container and obstacles have a shape
+    /// that walls can be built from and that its thermal walls exist.
+    pub fn parse(contents: &str) -> Result<Self, Box<dyn Error>> {
+        let scenario: Scenario = ron::from_str(contents)?;
+        scenario.container.check()?;
+        for obstacle in &scenario.obstacles {
+            obstacle.check()?;
+        }
+        for wall in &scenario.moving_walls {
+            wall.barrier().check()?;
+        }
+        let walls = scenario.walls().len();
+        if let Some(thermal) = scenario.thermal_walls.iter().find(|t| t.wall >= walls) {
+            return Err(format!("there is no wall {}, only {walls}", thermal.wall).into());
+        }
+        Ok(scenario)
+    }
+
+    /// The centerlines of every wall in the scenario: container, obstacles, moving walls where
+    /// they start and partitions.
+    pub fn walls(&self) -> Vec<WallShape> {
+        let mut walls = self.container.walls();
+        for obstacle in &self.obstacles {
+            walls.extend(obstacle.walls());
+        }
+        for wall in &self.moving_walls {
+            walls.extend(wall.barrier().walls());
+        }
+        if let Some(demon) = &self.demon {
+            walls.extend(demon.partition_walls(self));
+        }
+        walls
+    }
+
+    /// How each of the [`Scenario::walls`] moves.
+    pub fn wall_motions(&self) -> Vec<WallMotion> {
+        let fixed = self.container.walls().len()
+            + self.obstacles.iter().map(|o| o.walls().len()).sum::<usize>();
+        let mut motions = vec![WallMotion::Fixed; fixed];
+        motions.extend(self.moving_walls.iter().map(|w| w.motion.clone()));
+        motions.resize(self.walls().len(), WallMotion::Fixed);
+        motions
+    }
+
+    /// Number of walls at the start of [`Scenario::walls`] that belong to the container.
+    pub fn container_wall_count(&self) -> usize {
+        self.container.walls().len()
+    }
+
+    /// Length of the inner side of the container walls, which is what the gas pushes against.
+    pub fn inner_perimeter(&self, wall_thickness: f32) -> f32 {
+        self.container.inner_perimeter(wall_thickness)
+    }
+
+    /// The inner side of the container walls as a closed polygon, counterclockwise, with arcs
+    /// split in short segments. Obstacles are left out.
+    pub fn inner_outline(&self, wall_thickness: f32) -> Vec<Vec2> {
+        self.container.inner_outline(wall_thickness)
+    }
+
+    /// The area that is actually available to the particles, that is the inside of the
+    /// container minus the walls and obstacles.
+    pub fn free_area(&self, wall_thickness: f32) -> f32 {
+        self.container.inner_area(wall_thickness)
+            - self
+                .obstacles
+                .iter()
+                .map(|o| o.outer_area(wall_thickness))
+                .sum::<f32>()
+            - self
+                .moving_walls
+                .iter()
+                .map(|w| w.barrier().outer_area(wall_thickness))
+                .sum::<f32>()
+    }
+
+    /// Whether a disc of the given radius can be placed at `point` without overlapping any wall.
+    /// `walls` must be the result of [`Scenario::walls`], which is passed in so that it can be
+    /// reused when checking many points.
+    pub fn fits_among(
+        &self,
+        walls: &[WallShape],
+        point: Vec2,
+        radius: f32,
+        wall_thickness: f32,
+    ) -> bool {
+        let clearance = radius + wall_thickness / 2.;
+        // Keep the trapdoor clear too, since it isn't a wall
+        let clear_of_partition = self
+            .demon
+            .as_ref()
+            .is_none_or(|d| (point.x - d.partition_x).abs() > clearance);
+        clear_of_partition
+            && self.is_inside(point)
+            && walls
+                .iter()
+                .all(|w| w.closest_point(point).distance(point) > clearance)
+    }
+
+    /// Whether `point` is inside of the container and outside of every obstacle.
+    pub fn is_inside(&self, point: Vec2) -> bool {
+        self.container.contains(point) && self.obstacles.iter().all(|o| !o.contains(point))
+    }
+
+    /// Split the bounding box in square cells of the given size and return the centers of those
+    /// that are free, i.e. inside of the container and not covered by any wall or obstacle.
+    /// Useful to estimate the free area of a region.
+    pub fn free_cells(&self, cell_size: f32, wall_thickness: f32) -> Vec<Vec2> {
+        let bounds = self.bounds(wall_thickness);
+        let walls = self.walls();
+        let columns = (bounds.width() / cell_size).ceil() as u32;
+        let rows = (bounds.height() / cell_size).ceil() as u32;
+
+        (0..columns)
+            .flat_map(|i| (0..rows).map(move |j| Vec2::new(i as f32 + 0.5, j as f32 + 0.5)))
+            .map(|ij| bounds.min + cell_size * ij)
+            .filter(|point| {
+                self.is_inside(*point)
+                    && walls
+                        .iter()
+                        .all(|w| w.closest_point(*point).distance(*point) > wall_thickness / 2.)
+            })
+            .collect()
+    }
+
+    /// Bounding box of the container, walls included.
+    pub fn bounds(&self, wall_thickness: f32) -> Rect {
+        let half_thickness = wall_thickness / 2.;
+        let half_size = match &self.container {
+            Container::Rectangle { width, height } => Vec2::new(width / 2., height / 2.),
+            Container::Circle { radius } => Vec2::splat(*radius),
+            Container::Stadium { length, radius } => Vec2::new(length / 2. + radius, *radius),
+            Container::Polygon { vertices } => {
+                let points = to_vec2s(vertices);
+                let min = points.iter().fold(Vec2::MAX, |acc, p| acc.min(*p));
+                let max = points.iter().fold(Vec2::MIN, |acc, p| acc.max(*p));
+                return Rect::from_corners(min, max).inflate(half_thickness);
+            }
+        };
+        Rect::from_center_half_size(Vec2::ZERO, half_size).inflate(half_thickness)
+    }
+}
+
+impl Container {
+    /// Fails if the container has no inside, which would leave the particles nowhere to go.
+    fn check(&self) -> Result<(), String> {
+        match self {
+            Container::Rectangle { width, height } if *width <= 0. || *height <= 0. => {
+                Err(format!("the container must have a positive size, not {width} by {height}"))
+            }
+            Container::Circle { radius } | Container::Stadium { radius, .. } if *radius <= 0. => {
+                Err(format!("the radius of the container must be positive, not {radius}"))
+            }
+            Container::Stadium { length, .. } if *length < 0. => {
+                Err(format!("the length of the stadium can't be negative, not {length}"))
+            }
+            Container::Polygon { vertices } => check_polygon(vertices, "container"),
+            _ => Ok(()),
+        }
+    }
+
+    fn walls(&self) -> Vec<WallShape> {
+        match self {
+            Container::Rectangle { width, height } => {
+                let half = Vec2::new(width / 2., height / 2.);
+                polygon_edges(&[
+                    Vec2::new(-half.x, half.y),
+                    Vec2::new(half.x, half.y),
+                    Vec2::new(half.x, -half.y),
+                    Vec2::new(-half.x, -half.y),
+                ])
+            }
+            Container::Circle { radius } => vec![WallShape::circle(Vec2::ZERO, *radius)],
+            Container::Stadium { length, radius } => {
+                let half_length = length / 2.;
+                vec![
+                    WallShape::Segment {
+                        start: Vec2::new(-half_length, *radius),
+                        end: Vec2::new(half_length, *radius),
+                    },
+                    WallShape::Arc {
+                        center: Vec2::new(half_length, 0.),
+                        radius: *radius,
+                        start_angle: -FRAC_PI_2,
+                        end_angle: FRAC_PI_2,
+                    },
+                    WallShape::Segment {
+                        start: Vec2::new(half_length, -radius),
+                        end: Vec2::new(-half_length, -radius),
+                    },
+                    WallShape::Arc {
+                        center: Vec2::new(-half_length, 0.),
+                        radius: *radius,
+                        start_angle: FRAC_PI_2,
+                        end_angle: 3. * FRAC_PI_2,
+                    },
+                ]
+            }
+            Container::Polygon { vertices } => polygon_edges(&to_vec2s(vertices)),
+        }
+    }
+
+    fn contains(&self, point: Vec2) -> bool {
+        match self {
+            Container::Rectangle { width, height } => {
+                point.x.abs() < width / 2. && point.y.abs() < height / 2.
+            }
+            Container::Circle { radius } => point.length() < *radius,
+            Container::Stadium { length, radius } => {
+                let closest_on_axis = point.x.clamp(-length / 2., length / 2.);
+                point.distance(Vec2::new(closest_on_axis, 0.)) < *radius
+            }
+            Container::Polygon { vertices } => polygon_contains(&to_vec2s(vertices), point),
+        }
+    }
+
+    /// Area enclosed by the inner side of the walls.
+    fn inner_area(&self, wall_thickness: f32) -> f32 {
+        let half_thickness = wall_thickness / 2.;
+        match self {
+            Container::Rectangle { width, height } => {
+                (width - wall_thickness) * (height - wall_thickness)
+            }
+            Container::Circle { radius } => PI * (radius - half_thickness).powi(2),
+            Container::Stadium { length, radius } => {
+                length * (2. * radius - wall_thickness) + PI * (radius - half_thickness).powi(2)
+            }
+            Container::Polygon { vertices } => {
+                // Approximate, exact only if the polygon is convex and ignoring the corners
+                let points = to_vec2s(vertices);
+                let perimeter: f32 = polygon_edges(&points).iter().map(|e| e.length()).sum();
+                polygon_area(&points) - perimeter * half_thickness
+            }
+        }
+    }
+
+    fn inner_outline(&self, wall_thickness: f32) -> Vec<Vec2> {
+        let half_thickness = wall_thickness / 2.;
+        // Half circle starting at the given angle, both ends included
+        let half_circle = |center: Vec2, radius: f32, start: f32| {
+            (0..=ARC_SEGMENTS / 2).map(move |k| {
+                let angle = start + 2. * PI * k as f32 / ARC_SEGMENTS as f32;
+                center + (radius - half_thickness) * Vec2::from_angle(angle)
+            })
+        };
+        match self {
+            Container::Rectangle { width, height } => {
+                let half = Vec2::new(*width, *height) / 2. - half_thickness;
+                vec![
+                    Vec2::new(-half.x, -half.y),
+                    Vec2::new(half.x, -half.y),
+                    Vec2::new(half.x, half.y),
+                    Vec2::new(-half.x, half.y),
+                ]
+            }
+            Container::Circle { radius } => {
+                let mut outline: Vec<Vec2> = half_circle(Vec2::ZERO, *radius, 0.).collect();
+                outline.extend(half_circle(Vec2::ZERO, *radius, PI).skip(1));
+                outline.pop();
+                outline
+            }
+            Container::Stadium { length, radius } => {
+                let center = Vec2::new(length / 2., 0.);
+                half_circle(center, *radius, -FRAC_PI_2)
+                    .chain(half_circle(-center, *radius, FRAC_PI_2))
+                    .collect()
+            }
+            // Approximate like the inner area, the centerline of the walls
+            Container::Polygon { vertices } => {
+                let mut outline = to_vec2s(vertices);
+                let n = outline.len();
+                let double_area: f32 =
+                    (0..n).map(|i| outline[i].perp_dot(outline[(i + 1) % n])).sum();
+                if double_area < 0. {
+                    outline.reverse();
+                }
+                outline
+            }
+        }
+    }
+
+    /// Length of the inner side of the walls.
+    fn inner_perimeter(&self, wall_thickness: f32) -> f32 {
+        let half_thickness = wall_thickness / 2.;
+        match self {
+            Container::Rectangle { width, height } => 2. * (width + height) - 4. * wall_thickness,
+            Container::Circle { radius } => 2. * PI * (radius - half_thickness),
+            Container::Stadium { length, radius } => {
+                2. * length + 2. * PI * (radius - half_thickness)
+            }
+            // Approximate, the corners are ignored
+            Container::Polygon { vertices } => polygon_edges(&to_vec2s(vertices))
+                .iter()
+                .map(|e| e.length())
+                .sum(),
+        }
+    }
+}
+
+impl Obstacle {
+    /// Fails if the obstacle is too degenerate for its walls to have a normal everywhere.
+    fn check(&self) -> Result<(), String> {
+        match self {
+            Obstacle::Disc { radius, .. } if *radius <= 0. => {
+                Err(format!("the radius of a disc must be positive, not {radius}"))
+            }
+            Obstacle::Polygon { vertices } => check_polygon(vertices, "obstacle"),
+            Obstacle::Segment { start, end } if start == end => {
+                Err(format!("a segment must have two distinct ends, not {start:?} twice"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn walls(&self) -> Vec<WallShape> {
+        match self {
+            Obstacle::Disc { center, radius } => {
+                vec![WallShape::circle(Vec2::from(*center), *radius)]
+            }
+            Obstacle::Polygon { vertices } => polygon_edges(&to_vec2s(vertices)),
+            Obstacle::Segment { start, end } => vec![WallShape::Segment {
+                start: Vec2::from(*start),
+                end: Vec2::from(*end),
+            }],
+        }
+    }
+
+    fn contains(&self, point: Vec2) -> bool {
+        match self {
+            Obstacle::Disc { center, radius } => point.distance(Vec2::from(*center)) < *radius,
+            Obstacle::Polygon { vertices } => polygon_contains(&to_vec2s(vertices), point),
+            Obstacle::Segment { .. } => false,
+        }
+    }
+
+    /// Area covered by the obstacle, walls included.
+    fn outer_area(&self, wall_thickness: f32) -> f32 {
+        let half_thickness = wall_thickness / 2.;
+        match self {
+            Obstacle::Disc { radius, .. } => PI * (radius + half_thickness).powi(2),
+            Obstacle::Polygon { vertices } => {
+                let points = to_vec2s(vertices);
+                let perimeter: f32 = polygon_edges(&points).iter().map(|e| e.length()).sum();
+                polygon_area(&points) + perimeter * half_thickness
+            }
+            Obstacle::Segment { start, end } => {
+                Vec2::from(*start).distance(Vec2::from(*end)) * wall_thickness
+            }
+        }
+    }
+}
+
+impl MovingWall {
+    /// The barrier where the wall starts.
+    fn barrier(&self) -> Obstacle {
+        Obstacle::Segment {
+            start: self.start,
+            end: self.end,
+        }
+    }
+}
+
+/// Fails unless the vertices enclose some area, `what` naming the polygon in the message.
+fn check_polygon(vertices: &[(f32, f32)], what: &str) -> Result<(), String> {
+    if vertices.len() < 3 {
+        return Err(format!(
+            "a {what} polygon needs at least 3 vertices, not {}",
+            vertices.len()
+        ));
+    }
+    if polygon_area(&to_vec2s(vertices)) <= 0. {
+        return Err(format!("the {what} polygon has no area, its vertices are all in a line"));
+    }
+    Ok(())
+}
+
+fn to_vec2s(points: &[(f32, f32)]) -> Vec<Vec2> {
+    points.iter().map(|p| Vec2::from(*p)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn degenerate_containers_are_rejected() {
+        let degenerate = [
+            "(container: Polygon(vertices: []))",
+            "(container: Polygon(vertices: [(0., 0.), (10., 0.)]))",
+            "(container: Polygon(vertices: [(0., 0.), (10., 0.), (20., 0.)]))",
+            "(container: Circle(radius: 0.))",
+            "(container: Circle(radius: -5.))",
+            "(container: Stadium(length: 10., radius: 0.))",
+            "(container: Rectangle(width: 100., height: 0.))",
+            "(container: Circle(radius: 100.), obstacles: [Disc(center: (0., 0.), radius: 0.)])",
+            "(container: Circle(radius: 100.), obstacles: [Polygon(vertices: [(0., 0.)])])",
+            "(container: Circle(radius: 100.), thermal_walls: [(wall: 1, temperature: 300.)])",
+        ];
+        for contents in degenerate {
+            assert!(Scenario::parse(contents).is_err(), "{contents}");
+        }
+        let triangle = "(container: Polygon(vertices: [(0., 0.), (10., 0.), (0., 10.)]))";
+        assert!(Scenario::parse(triangle).is_ok());
+    }
+
+    #[test]
+    fn bundled_scenarios_load() {
+        for entry in fs::read_dir("scenarios").unwrap() {
+            let path = entry.unwrap().path();
+            let scenario = Scenario::load(path.to_str().unwrap());
+            assert!(scenario.is_ok(), "{}: {}", path.display(), scenario.unwrap_err());
+        }
+    }
+}
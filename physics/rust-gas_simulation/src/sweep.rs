@@ -0,0 +1,489 @@
+use std::{
+    error::Error,
+    f64::consts::PI,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use gas_simulation::kinetic;
+use rand::{rngs::StdRng, SeedableRng};
+use serde::Deserialize;
+
+use crate::{
+    initial::{InitError, Layout},
+    physics::Gas,
+    scenario::{Container, Scenario},
+    transport::{Driving, Exchange, GreenKubo},
+    units::BOLTZMANN_CONSTANT,
+    SimulationConfig, PARTICLE_MASS, WALL_THICKNESS,
+};
+
+/// A batch of headless runs over a grid of parameters, written in RON and run with
+/// `cargo run --release -- sweep sweeps/pressure.ron`. Every combination of parameter values is
+/// run once per seed, and the mean and standard error of each observable over the seeds is
+/// written to a CSV file, one row per combination and observable. The standard error is left
+/// empty with a single seed.
+///
+/// The shear viscosity and thermal conductivity are measured at equilibrium with the Green-Kubo
+/// relations, unless momentum or heat is driven through the gas, in which case the one driven is
+/// measured from the response of the gas. See [`GreenKubo`] and [`Exchange`]. Each run also
+/// estimates their standard errors from blocks of its production run, which are written as
+/// observables of their own, averaged over the seeds like the others.
+#[derive(Deserialize, Debug)]
+pub struct Sweep {
+    /// Scenario file the parameters are applied to. The default box if missing.
+    #[serde(default)]
+    pub scenario: Option<String>,
+    pub parameters: Vec<Parameter>,
+    /// Number of runs for each combination of parameters
+    #[serde(default = "one")]
+    pub seeds: u32,
+    /// Seed of the first run of each combination, the others follow in order
+    #[serde(default)]
+    pub first_seed: u64,
+    /// Simulated time, in seconds, before measuring anything
+    pub equilibration: f32,
+    /// Simulated time, in seconds, over which the observables are averaged
+    pub production: f32,
+    /// Path of the CSV file to write the results to
+    pub output: String,
+    /// Number of runs done at the same time. All CPU cores if missing.
+    #[serde(default)]
+    pub threads: Option<usize>,
+    /// Simulate in reduced units. The results are written in SI units either way.
+    #[serde(default)]
+    pub reduced_units: bool,
+    /// Longest lag of the Green-Kubo correlation functions, in seconds. If missing,
+    /// [`CORRELATION_COLLISIONS`] times the time between collisions predicted by Enskog's theory.
+    #[serde(default)]
+    pub correlation_time: Option<f32>,
+    /// Momentum or heat to drive through the gas during the whole run, which needs a rectangular
+    /// container without obstacles. Nothing is driven if missing.
+    #[serde(default)]
+    pub driving: Option<Driving>,
+}
+
+/// A scenario or simulation parameter and the values it takes in the sweep.
+#[derive(Deserialize, Debug)]
+pub struct Parameter {
+    /// One of [`PARAMETERS`]
+    pub name: String,
+    pub values: Values,
+}
+
+#[derive(Deserialize, Debug)]
+pub enum Values {
+    List(Vec<f32>),
+    /// `steps` evenly spaced values from `start` to `end`, both included.
+    Range { start: f32, end: f32, steps: u32 },
+}
+
+/// Names of the parameters that can be swept.
+pub const PARAMETERS: [&str; 15] = [
+    "number_of_particles",
+    "particle_radius",
+    "temperature",
+    "physics_rate",
+    "tangential_restitution",
+    "restitution",
+    "wall_restitution",
+    "heating_rate",
+    "width",
+    "height",
+    "radius",
+    "length",
+    "packing_fraction",
+    "temperature_ratio",
+    "density_ratio",
+];
+
+/// Names of the measured quantities, in the order [`simulate`] returns them.
+const OBSERVABLES: [&str; 15] = [
+    "temperature",
+    "rotational_temperature",
+    "heat_capacity",
+    "pressure",
+    "compressibility_factor",
+    "collision_rate",
+    "mean_free_path",
+    "packing_fraction",
+    "escaped_particles",
+    "shear_viscosity",
+    "thermal_conductivity",
+    "enskog_shear_viscosity",
+    "enskog_thermal_conductivity",
+    "shear_viscosity_error",
+    "thermal_conductivity_error",
+];
+
+/// Default longest lag of the Green-Kubo correlation functions, in mean times between two
+/// collisions of a particle. Long enough for the integrals to level off, and short enough that
+/// the walls don't bend them back down.
+const CORRELATION_COLLISIONS: f64 = 8.;
+
+fn one() -> u32 {
+    1
+}
+
+impl Values {
+    fn expand(&self) -> Vec<f32> {
+        match self {
+            Values::List(values) => values.clone(),
+            Values::Range { start, end, steps } => match steps {
+                0 => Vec::new(),
+                1 => vec![*start],
+                _ => (0..*steps)
+                    .map(|i| start + (end - start) * i as f32 / (steps - 1) as f32)
+                    .collect(),
+            },
+        }
+    }
+}
+
+/// Set a parameter of the scenario or of the simulation, by name.
+fn apply(
+    name: &str,
+    value: f32,
+    scenario: &mut Scenario,
+    config: &mut SimulationConfig,
+) -> Result<(), String> {
+    let layout = &mut config.initial_conditions.layout;
+    let target = match (name, &mut scenario.container, layout) {
+        ("number_of_particles", ..) => {
+            config.number_of_particles = value.round() as u32;
+            return Ok(());
+        }
+        ("particle_radius", ..) => &mut config.particle_radius,
+        ("temperature", ..) => &mut config.temperature,
+        ("physics_rate", ..) => {
+            config.physics_rate = value as f64;
+            return Ok(());
+        }
+        ("tangential_restitution", ..) => {
+            config.tangential_restitution = value as f64;
+            return Ok(());
+        }
+        ("restitution", ..) => {
+            config.restitution = value as f64;
+            return Ok(());
+        }
+        ("wall_restitution", ..) => {
+            config.wall_restitution = value as f64;
+            return Ok(());
+        }
+        ("heating_rate", ..) => {
+            config.heating_rate = value as f64;
+            return Ok(());
+        }
+        ("width", Container::Rectangle { width, .. }, _) => width,
+        ("height", Container::Rectangle { height, .. }, _) => height,
+        ("radius", Container::Circle { radius } | Container::Stadium { radius, .. }, _) => radius,
+        ("length", Container::Stadium { length, .. }, _) => length,
+        ("packing_fraction", _, Layout::Hexagonal { packing_fraction }) => packing_fraction,
+        ("temperature_ratio", _, Layout::HotCold { temperature_ratio })
+        | ("temperature_ratio", _, Layout::ShockTube { temperature_ratio, .. }) => {
+            temperature_ratio
+        }
+        ("density_ratio", _, Layout::ShockTube { density_ratio, .. }) => density_ratio,
+        _ if PARAMETERS.contains(&name) => {
+            return Err(format!(
+                "'{name}' doesn't apply to this container or initial layout"
+            ))
+        }
+        _ => {
+            return Err(format!(
+                "unknown parameter '{name}', expected one of {}",
+                PARAMETERS.join(", ")
+            ))
+        }
+    };
+    *target = value;
+    Ok(())
+}
+
+/// Run the sweep described in the file at `path` and write its results.
+pub fn run(path: &str) -> Result<(), Box<dyn Error>> {
+    let sweep: Sweep = ron::from_str(&fs::read_to_string(path)?)?;
+    let base = match &sweep.scenario {
+        Some(path) => Scenario::load(path)?,
+        None => Scenario::default(),
+    };
+
+    // Every combination of parameter values, the first parameter varying the slowest
+    let mut combinations: Vec<Vec<f32>> = vec![Vec::new()];
+    for parameter in &sweep.parameters {
+        let values = parameter.values.expand();
+        combinations = combinations
+            .iter()
+            .flat_map(|c| values.iter().map(move |v| [c.as_slice(), &[*v]].concat()))
+            .collect();
+    }
+    // Catch typos before spending any time on the runs
+    for combination in &combinations {
+        setup(&sweep, &base, combination, sweep.first_seed)?;
+    }
+
+    let runs: Vec<(usize, u64)> = (0..combinations.len())
+        .flat_map(|c| (0..sweep.seeds as u64).map(move |s| (c, sweep.first_seed + s)))
+        .collect();
+    let threads = sweep
+        .threads
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+        .max(1);
+    println!(
+        "Running {} combinations x {} seeds on {threads} threads",
+        combinations.len(),
+        sweep.seeds
+    );
+
+    // Workers take the next run until there are none left. Every run has its own seed, so the
+    // results don't depend on the number of threads or on the order the runs finish in
+    let next = AtomicUsize::new(0);
+    let finished = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<[f64; OBSERVABLES.len()]>>> = Mutex::new(vec![None; runs.len()]);
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some((c, seed)) = runs.get(i) else {
+                    break;
+                };
+                let measured = setup(&sweep, &base, &combinations[*c], *seed)
+                    .and_then(|(scenario, config)| {
+                        simulate(&sweep, &scenario, &config).map_err(|e| e.to_string())
+                    });
+                match measured {
+                    Ok(measured) => results.lock().unwrap()[i] = Some(measured),
+                    Err(e) => eprintln!("Run {i} (seed {seed}) failed: {e}"),
+                }
+                let done = finished.fetch_add(1, Ordering::Relaxed) + 1;
+                println!("Finished run {done} of {}", runs.len());
+            });
+        }
+    });
+    let results = results.into_inner().unwrap();
+
+    let mut writer = BufWriter::new(File::create(&sweep.output)?);
+    let names: Vec<&str> = sweep.parameters.iter().map(|p| p.name.as_str()).collect();
+    writeln!(writer, "{},observable,mean,stderr,runs", names.join(","))?;
+    for (c, combination) in combinations.iter().enumerate() {
+        let measured: Vec<&[f64; OBSERVABLES.len()]> = runs
+            .iter()
+            .zip(&results)
+            .filter(|((run_c, _), _)| *run_c == c)
+            .filter_map(|(_, result)| result.as_ref())
+            .collect();
+        if measured.is_empty() {
+            continue;
+        }
+        let values: Vec<String> = combination.iter().map(|v| v.to_string()).collect();
+        for (o, observable) in OBSERVABLES.iter().enumerate() {
+            let samples: Vec<f64> = measured.iter().map(|m| m[o]).collect();
+            let (mean, stderr) = mean_and_stderr(&samples);
+            let stderr = stderr.map(|s| format!("{s:e}")).unwrap_or_default();
+            writeln!(
+                writer,
+                "{},{observable},{mean:e},{stderr},{}",
+                values.join(","),
+                samples.len()
+            )?;
+        }
+    }
+    writer.flush()?;
+    println!("Results written to {}", sweep.output);
+    Ok(())
+}
+
+/// The scenario and configuration of one run.
+fn setup(
+    sweep: &Sweep,
+    base: &Scenario,
+    combination: &[f32],
+    seed: u64,
+) -> Result<(Scenario, SimulationConfig), String> {
+    let mut scenario = base.clone();
+    let mut config = SimulationConfig {
+        initial_conditions: scenario.initial_conditions.clone(),
+        thermal_walls: scenario.thermal_walls.clone(),
+        seed,
+        reduced_units: sweep.reduced_units,
+        ..Default::default()
+    };
+    for (parameter, value) in sweep.parameters.iter().zip(combination) {
+        apply(&parameter.name, *value, &mut scenario, &mut config)?;
+    }
+    let rectangle = matches!(scenario.container, Container::Rectangle { .. });
+    let empty = scenario.obstacles.is_empty() && scenario.moving_walls.is_empty();
+    if sweep.driving.is_some() && !(rectangle && empty && scenario.demon.is_none()) {
+        return Err("driving needs a rectangular container without anything in it".to_string());
+    }
+    Ok((scenario, config))
+}
+
+/// Set up the gas of a scenario the same way the app does, so that the same seed gives the same
+/// initial conditions.
+pub fn new_gas(scenario: &Scenario, config: &SimulationConfig) -> Result<Gas, InitError> {
+    let spawns = config.initial_conditions.generate(
+        scenario,
+        config.number_of_particles,
+        config.particle_radius,
+        config.temperature,
+        &mut StdRng::seed_from_u64(config.seed),
+    )?;
+    Ok(crate::new_gas(&spawns, scenario, config))
+}
+
+/// Equilibrate a gas, then average the observables over the production run.
+fn simulate(
+    sweep: &Sweep,
+    scenario: &Scenario,
+    config: &SimulationConfig,
+) -> Result<[f64; OBSERVABLES.len()], InitError> {
+    let mut gas = new_gas(scenario, config)?;
+    let n = gas.positions.len() as f64;
+    let dt = 1. / config.physics_rate;
+    let bounds = scenario.bounds(WALL_THICKNESS).inflate(-WALL_THICKNESS);
+    let steps = ((sweep.production as f64 / dt).round() as u32).max(1);
+    let mut exchange = sweep.driving.map(|driving| {
+        Exchange::new(driving, bounds.min.as_dvec2(), bounds.max.as_dvec2(), steps)
+    });
+    for _ in 0..(sweep.equilibration as f64 / dt).round() as u32 {
+        gas.step(dt);
+        if let Some(exchange) = &mut exchange {
+            exchange.drive(&mut gas);
+        }
+    }
+
+    let area = scenario.free_area(WALL_THICKNESS) as f64;
+    let radius = config.particle_radius as f64;
+    let mass = PARTICLE_MASS as f64;
+    let correlation_time = match sweep.correlation_time {
+        Some(time) => time as f64,
+        None => {
+            let temperature = config.temperature as f64;
+            let frequency = kinetic::collision_frequency(n / area, 2. * radius, mass, temperature);
+            CORRELATION_COLLISIONS / frequency
+        }
+    };
+    let mut green_kubo = GreenKubo::new((correlation_time / dt).round() as usize, dt, steps);
+    if let Some(exchange) = &mut exchange {
+        exchange.restart();
+    }
+    let container_walls = scenario.container_wall_count();
+    let mut impulse = 0.;
+    let mut collisions = 0;
+    let mut kinetic_energy = 0.;
+    let mut rotational_energy = 0.;
+    let mut speed = 0.;
+    for _ in 0..steps {
+        let report = gas.step(dt);
+        match &mut exchange {
+            Some(exchange) => {
+                exchange.record(&gas, &report, dt);
+                exchange.drive(&mut gas);
+            }
+            None => green_kubo.record(&gas, &report, dt),
+        }
+        collisions += report.collisions.len() as u64;
+        impulse += report.wall_impulses[..container_walls].iter().sum::<f64>();
+        kinetic_energy += gas.translational_energy();
+        rotational_energy += gas.rotational_energy();
+        speed += gas.velocities.iter().map(|v| v.length()).sum::<f64>() / n;
+    }
+    let time = steps as f64 * dt;
+    kinetic_energy /= steps as f64;
+    rotational_energy /= steps as f64;
+    speed *= gas.units.speed() / steps as f64;
+
+    // In two dimensions the mean kinetic energy of a particle is kT, and pressure is a force per
+    // unit length of wall
+    let temperature = kinetic_energy / (n * BOLTZMANN_CONSTANT);
+    // Rotation has a single degree of freedom. The heat capacity is in units of k per particle,
+    // 1 for smooth discs and 3/2 for rough ones at equipartition
+    let rotational_temperature = 2. * rotational_energy / (n * BOLTZMANN_CONSTANT);
+    let heat_capacity = (kinetic_energy + rotational_energy) / kinetic_energy;
+    let pressure = impulse / time / scenario.inner_perimeter(WALL_THICKNESS) as f64;
+    let compressibility = pressure * area / kinetic_energy;
+    // Every collision involves two particles
+    let collision_rate = 2. * collisions as f64 / n / time;
+    let mean_free_path = speed / collision_rate;
+    let packing_fraction = n * PI * radius * radius / area;
+    // Particles that got through the walls make every other observable suspect
+    let escaped = (0..gas.positions.len())
+        .filter(|i| !scenario.is_inside(gas.position(*i)))
+        .count();
+
+    // Transport coefficients, against Enskog's theory for the same density and temperature,
+    // with the errors of this run from the spread between blocks of it
+    let (viscosity, conductivity, errors, density, local_temperature) = match &exchange {
+        Some(exchange) => (
+            exchange.viscosity(),
+            exchange.conductivity(),
+            exchange.errors(),
+            exchange.density(),
+            exchange.temperature(),
+        ),
+        None => (
+            green_kubo.viscosity(area),
+            green_kubo.conductivity(area),
+            green_kubo.errors(area),
+            n / area,
+            temperature,
+        ),
+    };
+    let diameter = 2. * radius;
+    let enskog_viscosity = kinetic::enskog_viscosity(density, diameter, mass, local_temperature);
+    let enskog_conductivity =
+        kinetic::enskog_conductivity(density, diameter, mass, local_temperature);
+    Ok([
+        temperature,
+        rotational_temperature,
+        heat_capacity,
+        pressure,
+        compressibility,
+        collision_rate,
+        mean_free_path,
+        packing_fraction,
+        escaped as f64,
+        viscosity,
+        conductivity,
+        enskog_viscosity,
+        enskog_conductivity,
+        errors[0],
+        errors[1],
+    ])
+}
+
+/// Mean of the samples and its standard error, which takes at least two samples to estimate.
+pub fn mean_and_stderr(samples: &[f64]) -> (f64, Option<f64>) {
+    let n = samples.len() as f64;
+    let mean = samples.iter().sum::<f64>() / n;
+    if samples.len() < 2 {
+        return (mean, None);
+    }
+    let variance = samples.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / (n - 1.);
+    (mean, Some((variance / n).sqrt()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_sample_has_no_standard_error() {
+        assert_eq!(mean_and_stderr(&[3.5]), (3.5, None));
+    }
+
+    #[test]
+    fn standard_error_uses_the_sample_variance() {
+        let (mean, stderr) = mean_and_stderr(&[1., 2., 3., 6.]);
+        assert_eq!(mean, 3.);
+        // Sample variance 14/3, over 4 samples
+        assert!((stderr.unwrap() - (14f64 / 3. / 4.).sqrt()).abs() < 1e-12);
+    }
+}
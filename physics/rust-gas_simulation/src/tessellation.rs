@@ -0,0 +1,397 @@
+//! Delaunay triangulation of the particle centers, whose edges join the particles that are
+//! neighbours in the Voronoi diagram, and the Voronoi cells themselves.
+
+use bevy::math::DVec2;
+use rayon::prelude::*;
+
+/// No triangle on the other side of an edge.
+const NONE: usize = usize::MAX;
+
+/// Delaunay triangulation of a set of points, built with the Bowyer-Watson algorithm: every
+/// point is inserted in turn, the triangles whose circumcircle holds it are removed, and the hole
+/// is filled with triangles fanning out from it. The points are inserted in the order of a
+/// snaking scan across rows of cells, so that finding the triangle a point falls in takes only a
+/// few steps from the last one, and the whole triangulation takes about linear time.
+pub struct Triangulation {
+    /// Indices of the corners of every triangle, counterclockwise
+    pub triangles: Vec<[usize; 3]>,
+}
+
+/// The region closer to a particle than to any other, within a boundary.
+#[derive(Clone)]
+pub struct VoronoiCell {
+    /// Corners of the cell, counterclockwise if the boundary is
+    pub vertices: Vec<DVec2>,
+    /// Particle on the other side of the edge from each corner to the next, or `None` along the
+    /// boundary
+    pub edges: Vec<Option<usize>>,
+}
+
+/// Triangulation being built, with three extra corners far away which hold all the points.
+struct Mesh {
+    /// The first three are the far corners, the others are the points
+    corners: Vec<DVec2>,
+    triangles: Vec<[usize; 3]>,
+    /// Triangle on the other side of the edge facing each corner, or [`NONE`] on the outside
+    adjacent: Vec<[usize; 3]>,
+    removed: Vec<bool>,
+    /// Triangle the last point was inserted into, where the search for the next one starts
+    last: usize,
+}
+
+impl Triangulation {
+    pub fn new(points: &[DVec2]) -> Self {
+        if points.len() < 3 {
+            return Triangulation {
+                triangles: Vec::new(),
+            };
+        }
+        let mut mesh = Mesh::new(points);
+        for i in insertion_order(points) {
+            mesh.insert(i);
+        }
+        Triangulation {
+            triangles: mesh.finish(),
+        }
+    }
+
+    /// Neighbours of every point: the other ends of the edges from it, without duplicates.
+    pub fn neighbours(&self, count: usize) -> Vec<Vec<usize>> {
+        let mut neighbours = vec![Vec::with_capacity(6); count];
+        for [a, b, c] in &self.triangles {
+            for (i, j) in [(*a, *b), (*b, *c), (*c, *a)] {
+                // Edges inside the hull are shared by two triangles, running opposite ways
+                if !neighbours[i].contains(&j) {
+                    neighbours[i].push(j);
+                    neighbours[j].push(i);
+                }
+            }
+        }
+        neighbours
+    }
+
+    /// Voronoi cell of every point, cut off at the boundary, a closed polygon which can be
+    /// concave. Each cell is the boundary cut in turn by the perpendicular bisector of every
+    /// edge from the point, keeping the side of the point.
+    pub fn voronoi_cells(&self, points: &[DVec2], boundary: &[DVec2]) -> Vec<VoronoiCell> {
+        let start = VoronoiCell {
+            vertices: boundary.to_vec(),
+            edges: vec![None; boundary.len()],
+        };
+        self.neighbours(points.len())
+            .par_iter()
+            .enumerate()
+            .map(|(i, neighbours)| {
+                let mut cell = start.clone();
+                for j in neighbours {
+                    let normal = points[*j] - points[i];
+                    let middle = (points[i] + points[*j]) / 2.;
+                    cell = cell.cut(*j, |x| (x - middle).dot(normal));
+                    if cell.vertices.is_empty() {
+                        break;
+                    }
+                }
+                cell
+            })
+            .collect()
+    }
+}
+
+impl VoronoiCell {
+    pub fn area(&self) -> f64 {
+        let n = self.vertices.len();
+        let double_area: f64 = (0..n)
+            .map(|k| self.vertices[k].perp_dot(self.vertices[(k + 1) % n]))
+            .sum();
+        double_area.abs() / 2.
+    }
+
+    /// Number of particles the cell shares an edge with.
+    pub fn neighbour_count(&self) -> usize {
+        let mut neighbours: Vec<usize> = self.edges.iter().flatten().copied().collect();
+        neighbours.sort_unstable();
+        neighbours.dedup();
+        neighbours.len()
+    }
+
+    /// Whether part of the cell is cut off by the boundary.
+    pub fn on_boundary(&self) -> bool {
+        self.edges.contains(&None)
+    }
+
+    /// The part of the polygon where `side` is negative, which must be linear. The new edge along
+    /// the cut goes to the given neighbour.
+    fn cut(self, neighbour: usize, side: impl Fn(DVec2) -> f64) -> Self {
+        let n = self.vertices.len();
+        let mut cut = VoronoiCell {
+            vertices: Vec::with_capacity(n + 1),
+            edges: Vec::with_capacity(n + 1),
+        };
+        for k in 0..n {
+            let (a, b) = (self.vertices[k], self.vertices[(k + 1) % n]);
+            let (side_a, side_b) = (side(a), side(b));
+            if side_a <= 0. {
+                cut.vertices.push(a);
+                cut.edges.push(self.edges[k]);
+            }
+            if (side_a <= 0.) != (side_b <= 0.) {
+                // Leaving the edge where it crosses the cut, along the cut if it goes out
+                cut.vertices.push(a + (b - a) * side_a / (side_a - side_b));
+                cut.edges.push(if side_a <= 0. { Some(neighbour) } else { self.edges[k] });
+            }
+        }
+        cut
+    }
+}
+
+impl Mesh {
+    /// A single triangle large enough for every circumcircle of the points to fit inside, so
+    /// that its corners barely change the triangulation near the hull.
+    fn new(points: &[DVec2]) -> Self {
+        let min = points.iter().copied().reduce(DVec2::min).unwrap();
+        let max = points.iter().copied().reduce(DVec2::max).unwrap();
+        let center = (min + max) / 2.;
+        let size = 1000. * (max - min).max_element().max(f64::EPSILON);
+        let mut corners = vec![
+            center + size * DVec2::new(-1., -1.),
+            center + size * DVec2::new(1., -1.),
+            center + size * DVec2::new(0., 1.),
+        ];
+        corners.extend_from_slice(points);
+        Mesh {
+            corners,
+            triangles: vec![[0, 1, 2]],
+            adjacent: vec![[NONE; 3]],
+            removed: vec![false],
+            last: 0,
+        }
+    }
+
+    /// Add a point to the triangulation. In exact arithmetic the triangles whose circumcircle
+    /// holds it form a cavity it sees all of the edges of, but rounding can make a point that is
+    /// on or very close to a circumcircle, like with points on a regular grid, leave out a
+    /// triangle it needs. The cavity is grown until the point sees every edge of it from the
+    /// inside, so that the triangles filling it are never flat or inside out. If the hole still
+    /// can't be filled, the point is left out rather than breaking the triangulation.
+    fn insert(&mut self, point: usize) {
+        let vertex = point + 3;
+        let p = self.corners[vertex];
+        let start = self.locate(p);
+
+        // Every triangle whose circumcircle holds the point, which are all connected
+        let mut cavity = vec![start];
+        self.removed[start] = true;
+        let mut i = 0;
+        while i < cavity.len() {
+            let t = cavity[i];
+            for k in 0..3 {
+                let other = self.adjacent[t][k];
+                if other == NONE || self.removed[other] {
+                    continue;
+                }
+                if self.in_circumcircle(other, p) || !self.sees(t, k, p) {
+                    self.removed[other] = true;
+                    cavity.push(other);
+                }
+            }
+            i += 1;
+        }
+
+        // The edges of the hole, each with the triangle outside of it, which must go around the
+        // point once: every corner starts exactly one edge and ends exactly one
+        let mut boundary = Vec::with_capacity(cavity.len() + 2);
+        for &t in &cavity {
+            for k in 0..3 {
+                let outside = self.adjacent[t][k];
+                if outside == NONE || !self.removed[outside] {
+                    let [a, b] = [1, 2].map(|j| self.triangles[t][(k + j) % 3]);
+                    boundary.push((a, b, t, outside));
+                }
+            }
+        }
+        let closed = boundary.iter().all(|(_, b, _, _)| {
+            boundary.iter().filter(|(a, ..)| a == b).count() == 1
+                && boundary.iter().filter(|(_, end, ..)| end == b).count() == 1
+        });
+        if !closed {
+            for t in cavity {
+                self.removed[t] = false;
+            }
+            return;
+        }
+
+        // Fill the hole with a triangle from each edge of its boundary to the point. Each new
+        // triangle is found by the corner it starts from, to join it to the next one around.
+        let first = self.triangles.len();
+        for &(a, b, t, outside) in &boundary {
+            let new = self.triangles.len();
+            self.triangles.push([a, b, vertex]);
+            self.adjacent.push([NONE, NONE, outside]);
+            self.removed.push(false);
+            if outside != NONE {
+                for back in &mut self.adjacent[outside] {
+                    if *back == t {
+                        *back = new;
+                    }
+                }
+            }
+        }
+        for (k, &(_, b, _, _)) in boundary.iter().enumerate() {
+            let new = first + k;
+            // The boundary was checked to be closed, so the next edge is always there
+            if let Some(next) = boundary.iter().position(|(a, ..)| *a == b) {
+                self.adjacent[new][0] = first + next;
+                self.adjacent[first + next][1] = new;
+            }
+        }
+        self.last = first;
+    }
+
+    /// Whether the point is strictly on the inner side of the edge of triangle `t` facing its
+    /// corner `k`.
+    fn sees(&self, t: usize, k: usize, p: DVec2) -> bool {
+        let [a, b] = [1, 2].map(|j| self.corners[self.triangles[t][(k + j) % 3]]);
+        (b - a).perp_dot(p - a) > 0.
+    }
+
+    /// Triangle the point falls in, found by walking from the last one towards it across the
+    /// edges the point is beyond. The walk always ends in a Delaunay triangulation, but rounding
+    /// errors could make it go around in circles, after which every triangle is tried.
+    fn locate(&self, p: DVec2) -> usize {
+        let mut t = self.last;
+        'walk: for _ in 0..self.triangles.len() {
+            let [a, b, c] = self.triangles[t].map(|v| self.corners[v]);
+            for (k, (from, to)) in [(b, c), (c, a), (a, b)].into_iter().enumerate() {
+                if (to - from).perp_dot(p - from) < 0. && self.adjacent[t][k] != NONE {
+                    t = self.adjacent[t][k];
+                    continue 'walk;
+                }
+            }
+            return t;
+        }
+        (0..self.triangles.len())
+            .filter(|t| !self.removed[*t])
+            .min_by(|s, t| self.outside(*s, p).total_cmp(&self.outside(*t, p)))
+            .unwrap_or(self.last)
+    }
+
+    /// How far the point is beyond the edges of the triangle, zero when it's inside.
+    fn outside(&self, t: usize, p: DVec2) -> f64 {
+        let [a, b, c] = self.triangles[t].map(|v| self.corners[v]);
+        [(b, c), (c, a), (a, b)]
+            .into_iter()
+            .map(|(from, to)| (-(to - from).perp_dot(p - from)).max(0.))
+            .sum()
+    }
+
+    fn in_circumcircle(&self, t: usize, p: DVec2) -> bool {
+        let [a, b, c] = self.triangles[t].map(|v| self.corners[v] - p);
+        let determinant = a.length_squared() * b.perp_dot(c)
+            + b.length_squared() * c.perp_dot(a)
+            + c.length_squared() * a.perp_dot(b);
+        determinant > 0.
+    }
+
+    /// The triangles left, without the ones touching the far corners, renumbered as the points.
+    fn finish(self) -> Vec<[usize; 3]> {
+        self.triangles
+            .into_iter()
+            .zip(self.removed)
+            .filter(|(triangle, removed)| !removed && triangle.iter().all(|v| *v >= 3))
+            .map(|(triangle, _)| triangle.map(|v| v - 3))
+            .collect()
+    }
+}
+
+/// Order in which to insert the points: row after row of cells about as wide as the spacing
+/// between points, going right along one row and left along the next.
+fn insertion_order(points: &[DVec2]) -> Vec<usize> {
+    let min = points.iter().copied().reduce(DVec2::min).unwrap();
+    let max = points.iter().copied().reduce(DVec2::max).unwrap();
+    let size = (max - min).max(DVec2::splat(f64::EPSILON));
+    let spacing = (size.x * size.y / points.len() as f64).sqrt().max(f64::EPSILON);
+    let cell = |x: DVec2| ((x - min) / spacing).floor().as_ivec2();
+    let mut order: Vec<usize> = (0..points.len()).collect();
+    order.sort_by_key(|i| {
+        let cell = cell(points[*i]);
+        let column = if cell.y % 2 == 0 { cell.x } else { -cell.x };
+        (cell.y, column)
+    });
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    fn random_points(count: usize, size: f64, seed: u64) -> Vec<DVec2> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..count)
+            .map(|_| DVec2::new(rng.gen(), rng.gen()) * size)
+            .collect()
+    }
+
+    /// Points on a square lattice, where the four corners of every cell lie on one circle.
+    fn grid(side: usize) -> Vec<DVec2> {
+        (0..side * side)
+            .map(|k| DVec2::new((k % side) as f64, (k / side) as f64))
+            .collect()
+    }
+
+    fn area(points: &[DVec2], [a, b, c]: [usize; 3]) -> f64 {
+        (points[b] - points[a]).perp_dot(points[c] - points[a]) / 2.
+    }
+
+    #[test]
+    fn no_point_is_inside_the_circumcircle_of_a_triangle() {
+        let points = random_points(1500, 100., 7);
+        let triangulation = Triangulation::new(&points);
+        assert!(!triangulation.triangles.is_empty());
+        for triangle in &triangulation.triangles {
+            let [a, b, c] = triangle.map(|v| points[v]);
+            assert!((b - a).perp_dot(c - a) > 0., "not counterclockwise");
+            for (i, p) in points.iter().enumerate() {
+                if triangle.contains(&i) {
+                    continue;
+                }
+                let [a, b, c] = [a, b, c].map(|x| x - *p);
+                let determinant = a.length_squared() * b.perp_dot(c)
+                    + b.length_squared() * c.perp_dot(a)
+                    + c.length_squared() * a.perp_dot(b);
+                assert!(determinant <= 1e-6, "point {i} is inside {triangle:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn regular_grid_is_covered_without_gaps() {
+        let side = 30;
+        let points = grid(side);
+        let triangulation = Triangulation::new(&points);
+        // Two triangles per cell of the grid, none of them flat
+        assert_eq!(triangulation.triangles.len(), 2 * (side - 1).pow(2));
+        assert!(triangulation.triangles.iter().all(|t| area(&points, *t) > 0.));
+        let covered: f64 = triangulation.triangles.iter().map(|t| area(&points, *t)).sum();
+        assert!((covered - ((side - 1) as f64).powi(2)).abs() < 1e-9);
+        let neighbours = triangulation.neighbours(points.len());
+        assert!(neighbours.iter().all(|n| n.len() >= 2));
+    }
+
+    #[test]
+    fn voronoi_cells_add_up_to_the_container() {
+        let boundary = [(0., 0.), (100., 0.), (100., 60.), (0., 60.)].map(DVec2::from);
+        let random: Vec<DVec2> = random_points(800, 1., 3)
+            .into_iter()
+            .map(|p| p * DVec2::new(100., 60.))
+            .collect();
+        let lattice: Vec<DVec2> = grid(20).into_iter().map(|p| p * 3. + 1.5).collect();
+        for points in [random, lattice] {
+            let cells = Triangulation::new(&points).voronoi_cells(&points, &boundary);
+            assert_eq!(cells.len(), points.len());
+            let total: f64 = cells.iter().map(VoronoiCell::area).sum();
+            assert!((total / 6000. - 1.).abs() < 1e-9, "{total}");
+        }
+    }
+}
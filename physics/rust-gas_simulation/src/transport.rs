@@ -0,0 +1,697 @@
+use std::{collections::VecDeque, mem};
+
+use bevy::math::DVec2;
+use serde::Deserialize;
+
+use crate::{
+    lyapunov::fit_slope,
+    physics::{Gas, StepReport},
+    sweep::mean_and_stderr,
+    units::BOLTZMANN_CONSTANT,
+};
+
+/// Thickness of the slabs particles are exchanged between, as a fraction of the height of the
+/// container.
+const SLAB: f64 = 0.1;
+/// Number of layers the measurement window of a reverse non-equilibrium run is split in, to
+/// measure the flow velocity and temperature profiles.
+const LAYERS: usize = 8;
+/// Number of blocks a run is split in to estimate the errors of the transport coefficients from
+/// the spread between them.
+const BLOCKS: usize = 8;
+
+/// Autocorrelation function of a few signals sampled at regular intervals, accumulated over every
+/// pair of samples up to a largest lag. The signals are expected to be equivalent, like the two
+/// components of a flux in an isotropic gas, and their functions are averaged.
+pub struct Autocorrelation {
+    /// Time between two samples, in seconds
+    interval: f64,
+    /// Last samples, the newest first
+    history: VecDeque<Vec<f64>>,
+    /// Sum of the products of samples `lag` intervals apart, for every lag
+    products: Vec<f64>,
+    counts: Vec<u64>,
+    /// Sum of the samples of each signal, to take the mean out
+    sums: Vec<f64>,
+    samples: u64,
+}
+
+impl Autocorrelation {
+    pub fn new(signals: usize, lags: usize, interval: f64) -> Self {
+        Autocorrelation {
+            interval,
+            history: VecDeque::with_capacity(lags + 1),
+            products: vec![0.; lags + 1],
+            counts: vec![0; lags + 1],
+            sums: vec![0.; signals],
+            samples: 0,
+        }
+    }
+
+    pub fn add(&mut self, sample: Vec<f64>) {
+        for (sum, value) in self.sums.iter_mut().zip(&sample) {
+            *sum += value;
+        }
+        self.samples += 1;
+        self.history.push_front(sample);
+        self.history.truncate(self.products.len());
+        let newest = &self.history[0];
+        for (lag, older) in self.history.iter().enumerate() {
+            self.products[lag] += newest.iter().zip(older).map(|(a, b)| a * b).sum::<f64>();
+            self.counts[lag] += newest.len() as u64;
+        }
+    }
+
+    /// Correlation of the fluctuations around the mean, for every lag that has been sampled.
+    pub fn function(&self) -> Vec<f64> {
+        let samples = self.samples as f64;
+        let mean_square = self.sums.iter().map(|s| (s / samples).powi(2)).sum::<f64>()
+            / self.sums.len() as f64;
+        self.products
+            .iter()
+            .zip(&self.counts)
+            .take_while(|(_, count)| **count > 0)
+            .map(|(product, count)| product / *count as f64 - mean_square)
+            .collect()
+    }
+
+    /// Integral of the function from zero to every lag. For signals averaged over each interval,
+    /// the lag zero only counts for half of an interval, which makes it exact: at long lags it
+    /// is the growth rate of the variance of the integrated signals.
+    pub fn running_integral(&self) -> Vec<f64> {
+        let mut integral = 0.;
+        self.function()
+            .iter()
+            .enumerate()
+            .map(|(lag, c)| {
+                integral += if lag == 0 { c / 2. } else { *c } * self.interval;
+                integral
+            })
+            .collect()
+    }
+
+    /// Integral of the function up to the largest lag, or zero before any sample.
+    pub fn integral(&self) -> f64 {
+        self.running_integral().last().copied().unwrap_or(0.)
+    }
+}
+
+/// Kinetic parts of the fluxes through the whole gas, carried by the particles along their paths,
+/// in SI units.
+#[derive(Clone, Copy, Default)]
+struct KineticFluxes {
+    /// Sum of m vx², m vy² and m vx vy
+    stress: [f64; 3],
+    /// Sum of the velocity of each particle times its kinetic energy
+    energy_flux: DVec2,
+    velocity_sum: DVec2,
+    energy: f64,
+    translational_energy: f64,
+}
+
+impl KineticFluxes {
+    fn measure(gas: &Gas) -> Self {
+        let units = gas.units;
+        let mut fluxes = KineticFluxes::default();
+        for i in 0..gas.positions.len() {
+            let v = gas.velocities[i] * units.speed();
+            let m = gas.masses[i] * units.mass;
+            let translational = 0.5 * m * v.length_squared();
+            let rotational = 0.5 * gas.inertias[i] * gas.spins[i].powi(2) * units.energy;
+            let energy = translational + rotational;
+            fluxes.stress[0] += m * v.x * v.x;
+            fluxes.stress[1] += m * v.y * v.y;
+            fluxes.stress[2] += m * v.x * v.y;
+            fluxes.energy_flux += v * energy;
+            fluxes.velocity_sum += v;
+            fluxes.energy += energy;
+            fluxes.translational_energy += translational;
+        }
+        fluxes
+    }
+
+    /// Halfway between two measurements, as an estimate of the average over the step between
+    /// them.
+    fn midpoint(&self, other: &Self) -> Self {
+        let mean = |a: f64, b: f64| (a + b) / 2.;
+        KineticFluxes {
+            stress: [0, 1, 2].map(|k| mean(self.stress[k], other.stress[k])),
+            energy_flux: (self.energy_flux + other.energy_flux) / 2.,
+            velocity_sum: (self.velocity_sum + other.velocity_sum) / 2.,
+            energy: mean(self.energy, other.energy),
+            translational_energy: mean(self.translational_energy, other.translational_energy),
+        }
+    }
+}
+
+/// Equilibrium measurement of the shear viscosity and the thermal conductivity from the
+/// fluctuations of the fluxes, with the Green-Kubo relations:
+///
+/// η = 1 / (A kT) ∫ ⟨Pxy(0) Pxy(t)⟩ dt and λ = 1 / (A kT²) ∫ ⟨Jx(0) Jx(t)⟩ dt
+///
+/// where Pxy is the shear stress times the area A and J the heat flux times the area. Both are
+/// averaged over each step: the particles carry momentum and energy along their paths, and
+/// collisions carry them across the distance between the two centers at once. Half the
+/// difference of the normal stresses fluctuates like the shear stress in an isotropic gas, and
+/// is averaged in with it. Forces from the walls are left out, so this measures the bulk of the
+/// gas, as long as the correlations die out before sound crosses the container.
+///
+/// The run is also split in [`BLOCKS`] blocks, each with correlation functions of its own, and
+/// the spread of the integrals between the blocks gives the error of the whole run.
+pub struct GreenKubo {
+    lags: usize,
+    interval: f64,
+    /// Number of samples in each block
+    block_length: u64,
+    whole: Fluctuations,
+    block: Fluctuations,
+    /// Integrals of each block that is done, for the viscosity and the conductivity
+    blocks: Vec<[f64; 2]>,
+    previous: Option<KineticFluxes>,
+}
+
+/// Correlation functions of the fluxes over some samples.
+struct Fluctuations {
+    stress: Autocorrelation,
+    heat: Autocorrelation,
+    /// Sum of kT over the samples
+    thermal_energy: f64,
+    samples: u64,
+}
+
+impl Fluctuations {
+    fn new(lags: usize, interval: f64) -> Self {
+        Fluctuations {
+            stress: Autocorrelation::new(2, lags, interval),
+            heat: Autocorrelation::new(2, lags, interval),
+            thermal_energy: 0.,
+            samples: 0,
+        }
+    }
+
+    fn add(&mut self, stress: [f64; 3], heat_flux: DVec2, thermal_energy: f64) {
+        self.stress.add(vec![stress[2], (stress[0] - stress[1]) / 2.]);
+        self.heat.add(vec![heat_flux.x, heat_flux.y]);
+        self.thermal_energy += thermal_energy;
+        self.samples += 1;
+    }
+
+    /// Mean temperature over the samples.
+    fn temperature(&self) -> f64 {
+        self.thermal_energy / self.samples as f64 / BOLTZMANN_CONSTANT
+    }
+
+    /// Viscosity and conductivity times the area of the gas.
+    fn integrals(&self) -> [f64; 2] {
+        let thermal_energy = BOLTZMANN_CONSTANT * self.temperature();
+        [
+            self.stress.integral() / thermal_energy,
+            self.heat.integral() / (thermal_energy * self.temperature()),
+        ]
+    }
+}
+
+impl GreenKubo {
+    /// Correlate the fluxes up to `lags` steps of `dt` seconds apart, over a run of about
+    /// `steps` steps.
+    pub fn new(lags: usize, dt: f64, steps: u32) -> Self {
+        GreenKubo {
+            lags,
+            interval: dt,
+            block_length: (steps as u64 / BLOCKS as u64).max(1),
+            whole: Fluctuations::new(lags, dt),
+            block: Fluctuations::new(lags, dt),
+            blocks: Vec::with_capacity(BLOCKS),
+            previous: None,
+        }
+    }
+
+    /// Sample the fluxes over a step of `dt` seconds, which the gas just took.
+    pub fn record(&mut self, gas: &Gas, report: &StepReport, dt: f64) {
+        let now = KineticFluxes::measure(gas);
+        let Some(previous) = self.previous.replace(now) else {
+            return;
+        };
+        let kinetic = previous.midpoint(&now);
+        let mut stress = kinetic.stress;
+        let mut energy_flux = kinetic.energy_flux;
+        for collision in &report.collisions {
+            let mass = gas.masses[collision.pair.0] * gas.units.mass;
+            let [x1, x2] = collision.centers;
+            let separation = x1 - x2;
+            let momentum = mass * collision.velocity_change;
+            stress[0] += separation.x * momentum.x / dt;
+            stress[1] += separation.y * momentum.y / dt;
+            stress[2] += (separation.x * momentum.y + separation.y * momentum.x) / 2. / dt;
+            energy_flux += separation * mass * collision.energy_change / dt;
+        }
+
+        // The energy carried by the flow of the gas as a whole is not heat: take out the
+        // enthalpy of each particle along with the mean velocity
+        let n = gas.positions.len() as f64;
+        let pressure_times_area = (stress[0] + stress[1]) / 2.;
+        let enthalpy = (kinetic.energy + pressure_times_area) / n;
+        let heat_flux = energy_flux - enthalpy * kinetic.velocity_sum;
+
+        let thermal_energy = kinetic.translational_energy / n;
+        self.whole.add(stress, heat_flux, thermal_energy);
+        self.block.add(stress, heat_flux, thermal_energy);
+        if self.block.samples == self.block_length {
+            let fresh = Fluctuations::new(self.lags, self.interval);
+            self.blocks.push(mem::replace(&mut self.block, fresh).integrals());
+        }
+    }
+
+    /// Shear viscosity of a gas spread over `area`, in kg/s.
+    pub fn viscosity(&self, area: f64) -> f64 {
+        self.whole.integrals()[0] / area
+    }
+
+    /// Thermal conductivity of a gas spread over `area`, in W/K.
+    pub fn conductivity(&self, area: f64) -> f64 {
+        self.whole.integrals()[1] / area
+    }
+
+    /// Standard errors of the viscosity and the conductivity of a gas spread over `area`, from
+    /// the blocks. NaN before two blocks are done.
+    pub fn errors(&self, area: f64) -> [f64; 2] {
+        [0, 1].map(|k| block_error(self.blocks.iter().map(|block| block[k] / area)))
+    }
+}
+
+/// Standard error of the mean of the estimates of the blocks of a run, NaN with fewer than two.
+fn block_error(estimates: impl Iterator<Item = f64>) -> f64 {
+    let estimates: Vec<f64> = estimates.filter(|e| e.is_finite()).collect();
+    mean_and_stderr(&estimates).1.unwrap_or(f64::NAN)
+}
+
+/// What a reverse non-equilibrium run carries through the gas.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flux {
+    /// Momentum along the x axis, which shears the gas
+    Momentum,
+    /// Kinetic energy, which sets up a temperature gradient
+    Heat,
+}
+
+/// Settings of a reverse non-equilibrium run, as written in a sweep file.
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct Driving {
+    pub flux: Flux,
+    /// Number of steps between two exchanges. Larger values drive the gas more gently.
+    #[serde(default = "one")]
+    pub every: u32,
+}
+
+fn one() -> u32 {
+    1
+}
+
+/// Müller-Plathe's reverse non-equilibrium method, in a rectangular container: every few steps,
+/// a particle in a slab along the bottom wall swaps its velocity with a particle in a slab along
+/// the top wall. For momentum, the particles moving fastest to the right at the bottom and to the
+/// left at the top swap their velocities along x, which shears the gas. For heat, the hottest
+/// particle at the bottom swaps its velocity and spin with the coldest one at the top, which
+/// heats the top of the container and cools its bottom. Swaps between particles of the same
+/// mass keep both the momentum and the energy, so the gas settles in a steady state without a
+/// thermostat, through which it carries back what the swaps move.
+///
+/// The flux is known from what was swapped, and the response is measured in a window in the
+/// middle of the container, away from the slabs and from the side walls, split in a grid of
+/// cells. Energy can only go through the gas, so the heat flux is what the swaps move divided by
+/// the width of the container. Momentum also goes through the side walls, so the shear stress is
+/// measured in the window instead, like for the Green-Kubo relations. The side walls also turn
+/// the flow around, so that it is partly a vortex, which turns without shearing: the shear rate
+/// is the gradient of the flow along x going up, plus the gradient of the flow along y going
+/// across.
+///
+/// Like [`GreenKubo`], the run is split in [`BLOCKS`] blocks, each measured on its own, and the
+/// spread between them gives the errors.
+pub struct Exchange {
+    driving: Driving,
+    /// Inner corners of the container, in meters
+    min: DVec2,
+    max: DVec2,
+    steps: u32,
+    /// Number of samples in each block
+    block_length: u64,
+    whole: Sums,
+    block: Sums,
+    /// Viscosity and conductivity measured over each block that is done
+    blocks: Vec<[f64; 2]>,
+}
+
+/// What a reverse non-equilibrium run measures, summed over some steps in SI units.
+#[derive(Clone)]
+struct Sums {
+    /// Time since the measurement started, in seconds
+    time: f64,
+    /// Momentum or energy moved from the bottom slab to the top one
+    transferred: f64,
+    /// Cells of the window, row by row from the bottom
+    cells: Vec<Cell>,
+    /// Shear stress from the collisions in the window times its area
+    collisional_stress: f64,
+    samples: u64,
+}
+
+/// Sums over the particles in a cell of the measurement window and over the steps, in SI units.
+#[derive(Clone, Copy, Default)]
+struct Cell {
+    count: f64,
+    mass: f64,
+    velocity: DVec2,
+    speed_squared: f64,
+    /// Sum of m vx vy
+    stress: f64,
+}
+
+impl Cell {
+    fn flow(&self) -> DVec2 {
+        self.velocity / self.count.max(1.)
+    }
+
+    /// Temperature from the velocities relative to the flow, in kelvins.
+    fn temperature(&self) -> f64 {
+        let variance = self.speed_squared / self.count.max(1.) - self.flow().length_squared();
+        0.5 * self.mass / self.count.max(1.) * variance / BOLTZMANN_CONSTANT
+    }
+}
+
+impl Default for Sums {
+    fn default() -> Self {
+        Sums {
+            time: 0.,
+            transferred: 0.,
+            cells: vec![Cell::default(); LAYERS * LAYERS],
+            collisional_stress: 0.,
+            samples: 0,
+        }
+    }
+}
+
+impl Sums {
+    fn count(&self) -> f64 {
+        self.cells.iter().map(|cell| cell.count).sum()
+    }
+
+    /// Mean of a quantity over a row of cells, weighted by the number of particles in them.
+    fn row_mean(&self, row: usize, quantity: impl Fn(&Cell) -> f64) -> f64 {
+        let cells = &self.cells[row * LAYERS..(row + 1) * LAYERS];
+        let count: f64 = cells.iter().map(|cell| cell.count).sum();
+        cells.iter().map(|cell| quantity(cell) * cell.count).sum::<f64>() / count
+    }
+
+    /// Mean of a quantity over a column of cells, weighted by the number of particles in them.
+    fn column_mean(&self, column: usize, quantity: impl Fn(&Cell) -> f64) -> f64 {
+        let cells = self.cells.iter().skip(column).step_by(LAYERS);
+        let count: f64 = cells.clone().map(|cell| cell.count).sum();
+        cells.map(|cell| quantity(cell) * cell.count).sum::<f64>() / count
+    }
+}
+
+impl Exchange {
+    /// Exchange particles in a rectangular container with the given inner corners, in meters,
+    /// over a run of about `steps` steps.
+    pub fn new(driving: Driving, min: DVec2, max: DVec2, steps: u32) -> Self {
+        Exchange {
+            driving,
+            min,
+            max,
+            steps: 0,
+            block_length: (steps as u64 / BLOCKS as u64).max(1),
+            whole: Sums::default(),
+            block: Sums::default(),
+            blocks: Vec::with_capacity(BLOCKS),
+        }
+    }
+
+    /// Start measuring over, once the gas has reached its steady state.
+    pub fn restart(&mut self) {
+        self.steps = 0;
+        self.whole = Sums::default();
+        self.block = Sums::default();
+        self.blocks.clear();
+    }
+
+    /// Swap a pair of particles, if it is time to.
+    pub fn drive(&mut self, gas: &mut Gas) {
+        self.steps += 1;
+        if self.steps < self.driving.every {
+            return;
+        }
+        self.steps = 0;
+        let units = gas.units;
+        let slab = SLAB * (self.max.y - self.min.y);
+        // The particle of each slab that goes the furthest against the gradient, and how far
+        let mut bottom: Option<(usize, f64)> = None;
+        let mut top: Option<(usize, f64)> = None;
+        for i in 0..gas.positions.len() {
+            let y = gas.positions[i].y * units.length;
+            let value = match self.driving.flux {
+                Flux::Momentum => gas.masses[i] * gas.velocities[i].x * units.momentum(),
+                Flux::Heat => {
+                    let rotational = 0.5 * gas.inertias[i] * gas.spins[i].powi(2);
+                    let translational = 0.5 * gas.masses[i] * gas.velocities[i].length_squared();
+                    (translational + rotational) * units.energy
+                }
+            };
+            if y < self.min.y + slab && bottom.is_none_or(|(_, best)| value > best) {
+                bottom = Some((i, value));
+            } else if y > self.max.y - slab && top.is_none_or(|(_, best)| value < best) {
+                top = Some((i, value));
+            }
+        }
+        let (Some((b, from_bottom)), Some((t, from_top))) = (bottom, top) else {
+            return;
+        };
+        // Only swap when it goes against the gradient
+        if from_bottom <= from_top {
+            return;
+        }
+        match self.driving.flux {
+            Flux::Momentum => {
+                let (vb, vt) = (gas.velocities[b].x, gas.velocities[t].x);
+                gas.velocities[b].x = vt;
+                gas.velocities[t].x = vb;
+            }
+            Flux::Heat => {
+                gas.velocities.swap(b, t);
+                gas.spins.swap(b, t);
+            }
+        }
+        self.whole.transferred += from_bottom - from_top;
+        self.block.transferred += from_bottom - from_top;
+    }
+
+    /// Measure the gas after a step of `dt` seconds.
+    pub fn record(&mut self, gas: &Gas, report: &StepReport, dt: f64) {
+        let units = gas.units;
+        let mut collisional_stress = 0.;
+        for collision in &report.collisions {
+            let [x1, x2] = collision.centers;
+            if self.cell((x1 + x2) / 2.).is_none() {
+                continue;
+            }
+            let mass = gas.masses[collision.pair.0] * units.mass;
+            let separation = x1 - x2;
+            let momentum = mass * collision.velocity_change;
+            collisional_stress += (separation.x * momentum.y + separation.y * momentum.x) / 2. / dt;
+        }
+        let cells: Vec<Option<usize>> = gas
+            .positions
+            .iter()
+            .map(|x| self.cell(*x * units.length))
+            .collect();
+        for sums in [&mut self.whole, &mut self.block] {
+            for (i, cell) in cells.iter().enumerate() {
+                let Some(cell) = cell else {
+                    continue;
+                };
+                let v = gas.velocities[i] * units.speed();
+                let m = gas.masses[i] * units.mass;
+                let cell = &mut sums.cells[*cell];
+                cell.count += 1.;
+                cell.mass += m;
+                cell.velocity += v;
+                cell.speed_squared += v.length_squared();
+                cell.stress += m * v.x * v.y;
+            }
+            sums.collisional_stress += collisional_stress;
+            sums.time += dt;
+            sums.samples += 1;
+        }
+        if self.block.samples == self.block_length {
+            let block = mem::take(&mut self.block);
+            self.blocks.push([self.viscosity_of(&block), self.conductivity_of(&block)]);
+        }
+    }
+
+    /// Cell of the measurement window a point is in, if any. The window is the middle half of
+    /// the container, both across and up.
+    fn cell(&self, point: DVec2) -> Option<usize> {
+        let size = self.max - self.min;
+        let relative = (point - self.min - size / 4.) / (size / 2.);
+        let inside = (0. ..1.).contains(&relative.x) && (0. ..1.).contains(&relative.y);
+        let [column, row] = (relative * LAYERS as f64).to_array().map(|k| k as usize);
+        inside.then_some(row * LAYERS + column)
+    }
+
+    /// Area of the measurement window, in square meters.
+    fn window_area(&self) -> f64 {
+        let size = self.max - self.min;
+        size.x * size.y / 4.
+    }
+
+    /// Centers of the layers of cells, in meters, along x for the columns and along y for the
+    /// rows.
+    fn centers(&self) -> impl Iterator<Item = DVec2> + '_ {
+        let size = (self.max - self.min) / 2.;
+        let start = self.min + size / 2.;
+        (0..LAYERS).map(move |k| start + size * (k as f64 + 0.5) / LAYERS as f64)
+    }
+
+    /// Slope of a profile, in its units per meter, from the value of each row going up, or of
+    /// each column going across.
+    fn gradient(&self, profile: impl Fn(usize) -> f64, across: bool) -> f64 {
+        let points: Vec<[f64; 2]> = self
+            .centers()
+            .enumerate()
+            .map(|(k, center)| [if across { center.x } else { center.y }, profile(k)])
+            .filter(|[_, value]| value.is_finite())
+            .collect();
+        fit_slope(&points).unwrap_or(f64::NAN)
+    }
+
+    /// Mean number of particles per unit area in the window.
+    pub fn density(&self) -> f64 {
+        self.whole.count() / self.whole.samples.max(1) as f64 / self.window_area()
+    }
+
+    /// Mean temperature in the window, in kelvins.
+    pub fn temperature(&self) -> f64 {
+        let cells = &self.whole.cells;
+        let sum: f64 = cells.iter().map(|cell| cell.temperature() * cell.count).sum();
+        sum / self.whole.count()
+    }
+
+    /// Shear viscosity, in kg/s: the shear stress over the shear rate. NaN unless momentum is
+    /// exchanged.
+    pub fn viscosity(&self) -> f64 {
+        self.viscosity_of(&self.whole)
+    }
+
+    /// Thermal conductivity, in W/K: the heat flux over the temperature gradient. NaN unless
+    /// heat is exchanged.
+    pub fn conductivity(&self) -> f64 {
+        self.conductivity_of(&self.whole)
+    }
+
+    /// Standard errors of the viscosity and the conductivity, from the blocks. NaN before two
+    /// blocks are done, and for the coefficient that isn't driven.
+    pub fn errors(&self) -> [f64; 2] {
+        [0, 1].map(|k| block_error(self.blocks.iter().map(|block| block[k])))
+    }
+
+    fn viscosity_of(&self, sums: &Sums) -> f64 {
+        if self.driving.flux != Flux::Momentum {
+            return f64::NAN;
+        }
+        // Kinetic stress relative to the flow in each cell
+        let kinetic: f64 = sums
+            .cells
+            .iter()
+            .map(|cell| cell.stress - cell.mass * cell.flow().x * cell.flow().y)
+            .sum();
+        let stress = (kinetic + sums.collisional_stress) / sums.samples as f64 / self.window_area();
+        let shear_rate = self.gradient(|row| sums.row_mean(row, |cell| cell.flow().x), false)
+            + self.gradient(|column| sums.column_mean(column, |cell| cell.flow().y), true);
+        -stress / shear_rate
+    }
+
+    fn conductivity_of(&self, sums: &Sums) -> f64 {
+        if self.driving.flux != Flux::Heat {
+            return f64::NAN;
+        }
+        // Heat goes back down through the gas, across the whole width of the container
+        let flux = sums.transferred / sums.time / (self.max.x - self.min.x);
+        flux / self.gradient(|row| sums.row_mean(row, Cell::temperature), false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+    use rand_distr::{Distribution, StandardNormal};
+
+    use super::*;
+
+    #[test]
+    fn autocorrelation_of_an_autoregressive_signal_decays_geometrically() {
+        // Two independent AR(1) signals of unit variance around a mean of 5, whose correlation
+        // after k samples is a^k
+        let (a, lags, interval) = (0.8_f64, 20, 0.5);
+        let mut autocorrelation = Autocorrelation::new(2, lags, interval);
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut values = [0.; 2];
+        for _ in 0..400_000 {
+            for value in &mut values {
+                let noise: f64 = StandardNormal.sample(&mut rng);
+                *value = a * *value + (1. - a * a).sqrt() * noise;
+            }
+            autocorrelation.add(values.iter().map(|v| v + 5.).collect());
+        }
+        let function = autocorrelation.function();
+        assert_eq!(function.len(), lags + 1);
+        for (k, c) in function.iter().enumerate() {
+            assert!((c - a.powi(k as i32)).abs() < 0.02, "lag {k}: {c}");
+        }
+        // Half of the lag zero, then every other lag, each over an interval
+        let expected: f64 = interval * (0.5 + (1..=lags).map(|k| a.powi(k as i32)).sum::<f64>());
+        let running = autocorrelation.running_integral();
+        assert!((running[0] - interval / 2.).abs() < 0.01);
+        assert!((autocorrelation.integral() - expected).abs() < 0.05 * expected);
+    }
+
+    #[test]
+    fn autocorrelation_of_white_noise_is_a_spike() {
+        let mut autocorrelation = Autocorrelation::new(1, 5, 1.);
+        assert!(autocorrelation.function().is_empty());
+        assert_eq!(autocorrelation.integral(), 0.);
+        let mut rng = StdRng::seed_from_u64(4);
+        for _ in 0..100_000 {
+            let noise: f64 = StandardNormal.sample(&mut rng);
+            autocorrelation.add(vec![2. * noise]);
+        }
+        let function = autocorrelation.function();
+        assert!((function[0] - 4.).abs() < 0.1);
+        assert!(function[1..].iter().all(|c| c.abs() < 0.1), "{function:?}");
+    }
+
+    #[test]
+    fn exchange_window_is_the_middle_of_the_container() {
+        let driving = Driving {
+            flux: Flux::Momentum,
+            every: 1,
+        };
+        let exchange = Exchange::new(driving, DVec2::ZERO, DVec2::new(8., 4.), 100);
+        // The window spans (2, 1) to (6, 3), in 8 by 8 cells of 0.5 by 0.25
+        assert_eq!(exchange.cell(DVec2::new(2.1, 1.1)), Some(0));
+        assert_eq!(exchange.cell(DVec2::new(3., 2.)), Some(4 * LAYERS + 2));
+        assert_eq!(exchange.cell(DVec2::new(5.9, 2.9)), Some(LAYERS * LAYERS - 1));
+        assert_eq!(exchange.cell(DVec2::new(1.9, 2.)), None);
+        assert_eq!(exchange.cell(DVec2::new(4., 3.)), None);
+        assert_eq!(exchange.window_area(), 8.);
+        // Linear profiles up and across, read at the centers of the rows and of the columns
+        let row_y = |k: usize| 1. + 2. * (k as f64 + 0.5) / LAYERS as f64;
+        let column_x = |k: usize| 2. + 4. * (k as f64 + 0.5) / LAYERS as f64;
+        let up = exchange.gradient(|k| 7. - 5. * row_y(k), false);
+        assert!((up + 5.).abs() < 1e-12);
+        let across = exchange.gradient(|k| 3. * column_x(k), true);
+        assert!((across - 3.).abs() < 1e-12);
+        // Empty layers are left out of the fit
+        let gaps = exchange.gradient(|k| if k < 2 { f64::NAN } else { row_y(k) }, false);
+        assert!((gaps - 1.).abs() < 1e-12);
+        assert!(exchange.gradient(|_| f64::NAN, false).is_nan());
+    }
+}
@@ -0,0 +1,55 @@
+/// Boltzmann constant, in J/K.
+pub const BOLTZMANN_CONSTANT: f64 = 1.38e-23;
+
+/// The units the gas is simulated in, each given by its value in SI units. Everything outside of
+/// the gas is in SI units: quantities are converted on the way in and on the way out, for display
+/// and export.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Units {
+    /// In meters
+    pub length: f64,
+    /// In kilograms
+    pub mass: f64,
+    /// In joules
+    pub energy: f64,
+}
+
+impl Units {
+    pub const SI: Units = Units {
+        length: 1.,
+        mass: 1.,
+        energy: 1.,
+    };
+
+    /// Reduced units, in which the particle diameter σ, the particle mass m and the thermal
+    /// energy kT are all 1. Positions and speeds are then numbers of order one to a hundred, far
+    /// from where floating point numbers lose precision.
+    pub fn reduced(diameter: f64, mass: f64, temperature: f64) -> Self {
+        Units {
+            length: diameter,
+            mass,
+            energy: BOLTZMANN_CONSTANT * temperature,
+        }
+    }
+
+    /// In seconds. σ √(m / kT) in reduced units, the time a particle takes to travel its own
+    /// diameter.
+    pub fn time(&self) -> f64 {
+        self.length * (self.mass / self.energy).sqrt()
+    }
+
+    /// In meters per second.
+    pub fn speed(&self) -> f64 {
+        self.length / self.time()
+    }
+
+    /// In kilograms meters per second.
+    pub fn momentum(&self) -> f64 {
+        self.mass * self.speed()
+    }
+
+    /// In kelvins.
+    pub fn temperature(&self) -> f64 {
+        self.energy / BOLTZMANN_CONSTANT
+    }
+}
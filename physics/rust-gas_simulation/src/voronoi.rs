@@ -0,0 +1,425 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+use bevy::{math::DVec2, prelude::*, sprite::Anchor};
+use bevy_egui::{egui, EguiContexts};
+use egui_plot::{Legend, Line, Plot};
+use gas_simulation::{
+    kinetic,
+    tessellation::{Triangulation, VoronoiCell},
+};
+
+use crate::{colormap::viridis, physics::Gas, scenario::Scenario, SimulationState, WALL_THICKNESS};
+
+/// Range of cell areas the overlay colors, relative to the mean area.
+const AREA_RANGE: (f64, f64) = (0.5, 1.5);
+/// Width of the bins of the distribution of free areas, relative to the mean free area.
+const BIN_WIDTH: f64 = 0.05;
+/// The distribution is kept up to this many times the mean free area.
+const LARGEST_FREE_AREA: f64 = 4.;
+/// Cells are drawn shrunk by this fraction towards their particle, so that the color of each
+/// cell can be told apart from its neighbours.
+const SHRINK: f64 = 0.1;
+
+/// Voronoi tessellation of the particles: the cell of a particle is the region closer to it than
+/// to any other, cut off by the container walls (obstacles aren't cut out). The cells can be
+/// drawn on top of the container (press D to switch), colored by their area or by how many
+/// neighbours they have. The window measures the distribution of the free area of the cells
+/// away from the walls, how much larger they are than around a disc in a close packed crystal,
+/// and compares it with the k-gamma distribution.
+pub struct VoronoiPlugin;
+
+impl Plugin for VoronoiPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<VoronoiOverlay>()
+            .init_resource::<Voronoi>()
+            .add_systems(Startup, setup_voronoi)
+            .add_systems(OnEnter(SimulationState::Setup), clear_voronoi)
+            .add_systems(
+                Update,
+                (
+                    cycle_voronoi_overlay,
+                    update_voronoi,
+                    draw_voronoi,
+                    voronoi_window,
+                )
+                    .chain(),
+            );
+    }
+}
+
+/// What the Voronoi cells drawn on top of the container are colored by, if they are drawn.
+#[derive(Resource, Default, Clone, Copy, PartialEq, Eq)]
+pub enum VoronoiOverlay {
+    #[default]
+    Off,
+    Area,
+    Neighbours,
+}
+
+#[derive(Resource)]
+struct Voronoi {
+    /// Number of frames between two updates of the cells
+    every: u32,
+    frames: u32,
+    /// Cell of every particle, by index in the gas, empty when nothing needs them
+    cells: Vec<VoronoiCell>,
+    /// Positions of the particles when the cells were updated, in meters
+    positions: Vec<DVec2>,
+    /// Mean area of all the cells, in m²
+    mean_area: f64,
+    /// Whether the window was open on the last frame
+    shown: bool,
+    statistics: Option<AreaStatistics>,
+    /// Path of the CSV file the distribution is saved to
+    path: String,
+    message: Option<String>,
+}
+
+/// Distribution of the cells away from the walls, over every update since it was cleared.
+struct AreaStatistics {
+    /// Area of the cell of a disc in a close packed crystal, in m²
+    smallest_area: f64,
+    /// Number of cells in each bin of reduced free area (a - a_min)/(⟨a⟩ - a_min), the mean
+    /// being taken at each update
+    counts: Vec<u64>,
+    /// Sums of the reduced free area and of its square over the cells
+    sum: f64,
+    sum_squared: f64,
+    cells: u64,
+    /// Number of cells with each number of neighbours
+    neighbours: Vec<u64>,
+    updates: usize,
+}
+
+#[derive(Component)]
+struct VoronoiText;
+
+impl Default for Voronoi {
+    fn default() -> Self {
+        Voronoi {
+            every: 1,
+            frames: 0,
+            cells: Vec::new(),
+            positions: Vec::new(),
+            mean_area: 0.,
+            shown: false,
+            statistics: None,
+            path: "voronoi.csv".into(),
+            message: None,
+        }
+    }
+}
+
+impl VoronoiOverlay {
+    pub const ALL: [VoronoiOverlay; 3] = [
+        VoronoiOverlay::Off,
+        VoronoiOverlay::Area,
+        VoronoiOverlay::Neighbours,
+    ];
+
+    fn next(self) -> Self {
+        match self {
+            VoronoiOverlay::Off => VoronoiOverlay::Area,
+            VoronoiOverlay::Area => VoronoiOverlay::Neighbours,
+            VoronoiOverlay::Neighbours => VoronoiOverlay::Off,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            VoronoiOverlay::Off => "off",
+            VoronoiOverlay::Area => "area",
+            VoronoiOverlay::Neighbours => "neighbours",
+        }
+    }
+}
+
+impl Voronoi {
+    fn update(&mut self, gas: &Gas, scenario: &Scenario) {
+        self.positions = gas.positions.iter().map(|x| *x * gas.units.length).collect();
+        let boundary: Vec<DVec2> = scenario
+            .inner_outline(WALL_THICKNESS)
+            .iter()
+            .map(|x| x.as_dvec2())
+            .collect();
+        self.cells = Triangulation::new(&self.positions).voronoi_cells(&self.positions, &boundary);
+        self.mean_area =
+            self.cells.iter().map(VoronoiCell::area).sum::<f64>() / self.cells.len().max(1) as f64;
+
+        let diameter = 2. * gas.radii.iter().sum::<f64>() / gas.radii.len().max(1) as f64
+            * gas.units.length;
+        self.statistics
+            .get_or_insert_with(|| AreaStatistics::new(diameter))
+            .add(&self.cells);
+    }
+
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        let statistics = self.statistics.as_ref().ok_or("Nothing measured yet")?;
+        let shape = statistics.shape().unwrap_or(f64::NAN);
+        let mut writer = BufWriter::new(File::create(&self.path)?);
+        writeln!(writer, "reduced_free_area,cells,density,k_gamma")?;
+        for ([x, density], cells) in statistics.distribution().iter().zip(&statistics.counts) {
+            writeln!(
+                writer,
+                "{x},{cells},{density},{}",
+                kinetic::k_gamma_density(shape, *x)
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl AreaStatistics {
+    fn new(diameter: f64) -> Self {
+        AreaStatistics {
+            smallest_area: kinetic::smallest_cell_area(diameter),
+            counts: vec![0; (LARGEST_FREE_AREA / BIN_WIDTH).round() as usize],
+            sum: 0.,
+            sum_squared: 0.,
+            cells: 0,
+            neighbours: Vec::new(),
+            updates: 0,
+        }
+    }
+
+    fn add(&mut self, cells: &[VoronoiCell]) {
+        let inside: Vec<&VoronoiCell> = cells.iter().filter(|c| !c.on_boundary()).collect();
+        if inside.is_empty() {
+            return;
+        }
+        let mean = inside.iter().map(|c| c.area()).sum::<f64>() / inside.len() as f64;
+        let mean_free = mean - self.smallest_area;
+        for cell in inside {
+            let n = cell.neighbour_count();
+            if n >= self.neighbours.len() {
+                self.neighbours.resize(n + 1, 0);
+            }
+            self.neighbours[n] += 1;
+            if mean_free > 0. {
+                let x = (cell.area() - self.smallest_area) / mean_free;
+                if let Some(count) = self.counts.get_mut((x / BIN_WIDTH).max(0.) as usize) {
+                    *count += 1;
+                }
+                self.sum += x;
+                self.sum_squared += x * x;
+                self.cells += 1;
+            }
+        }
+        self.updates += 1;
+    }
+
+    /// Probability density of the reduced free area at the middle of every bin.
+    fn distribution(&self) -> Vec<[f64; 2]> {
+        let total = self.cells.max(1) as f64;
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(i, count)| [(i as f64 + 0.5) * BIN_WIDTH, *count as f64 / total / BIN_WIDTH])
+            .collect()
+    }
+
+    /// Shape of the k-gamma distribution with the same variance, ⟨x⟩²/Var(x).
+    fn shape(&self) -> Option<f64> {
+        let n = self.cells as f64;
+        let mean = self.sum / n;
+        let variance = self.sum_squared / n - mean * mean;
+        (self.cells > 1 && variance > 0.).then(|| mean * mean / variance)
+    }
+
+    /// Fraction of the cells with each number of neighbours, skipping the ones nobody has.
+    fn neighbour_fractions(&self) -> Vec<(usize, f64)> {
+        let total = self.neighbours.iter().sum::<u64>().max(1) as f64;
+        self.neighbours
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(n, count)| (n, *count as f64 / total))
+            .collect()
+    }
+}
+
+/// Color of a cell with the given number of neighbours: grey for six as in a hexagonal lattice,
+/// red and blue for the five and seven of disclinations, and yellow for anything else.
+fn neighbour_color(neighbours: usize) -> Color {
+    match neighbours {
+        6 => Srgba::rgb(0.6, 0.6, 0.6),
+        5 => Srgba::rgb(0.9, 0.2, 0.2),
+        7 => Srgba::rgb(0.2, 0.4, 1.),
+        _ => Srgba::rgb(1., 0.85, 0.2),
+    }
+    .into()
+}
+
+fn setup_voronoi(mut commands: Commands, scenario: Res<Scenario>) {
+    let bounds = scenario.bounds(WALL_THICKNESS);
+    commands.spawn((
+        Text2d::new(""),
+        VoronoiText,
+        Transform::from_xyz(bounds.min.x, bounds.min.y - 100., 0.),
+        Anchor::TopLeft,
+    ));
+}
+
+fn clear_voronoi(mut voronoi: ResMut<Voronoi>) {
+    voronoi.cells.clear();
+    voronoi.statistics = None;
+}
+
+fn cycle_voronoi_overlay(keys: Res<ButtonInput<KeyCode>>, mut overlay: ResMut<VoronoiOverlay>) {
+    if keys.just_pressed(KeyCode::KeyD) {
+        *overlay = overlay.next();
+    }
+}
+
+/// Build the cells again every few frames while they are drawn or the window is open, as long
+/// as the gas has changed.
+fn update_voronoi(
+    gas: Res<Gas>,
+    scenario: Res<Scenario>,
+    overlay: Res<VoronoiOverlay>,
+    mut voronoi: ResMut<Voronoi>,
+) {
+    if *overlay == VoronoiOverlay::Off && !voronoi.shown {
+        voronoi.cells.clear();
+        return;
+    }
+    voronoi.frames += 1;
+    let due = voronoi.frames >= voronoi.every && gas.is_changed();
+    if due || voronoi.cells.len() != gas.positions.len() {
+        voronoi.frames = 0;
+        voronoi.update(&gas, &scenario);
+    }
+}
+
+/// Draw the outline of every cell in its color, shrunk a little towards its particle.
+fn draw_voronoi(
+    overlay: Res<VoronoiOverlay>,
+    voronoi: Res<Voronoi>,
+    mut text_query: Query<&mut Text2d, With<VoronoiText>>,
+    mut gizmos: Gizmos,
+) {
+    let mut text = text_query.single_mut();
+    text.0 = match *overlay {
+        VoronoiOverlay::Off => "Voronoi cells: off (D to change)".to_string(),
+        VoronoiOverlay::Area => format!(
+            "Voronoi cells: area, {} to {} times the mean (D to change)",
+            AREA_RANGE.0, AREA_RANGE.1
+        ),
+        VoronoiOverlay::Neighbours => {
+            "Voronoi cells: neighbours, 5 red, 6 grey, 7 blue (D to change)".to_string()
+        }
+    };
+    if *overlay == VoronoiOverlay::Off {
+        return;
+    }
+
+    for (cell, center) in voronoi.cells.iter().zip(&voronoi.positions) {
+        let color = match *overlay {
+            VoronoiOverlay::Off => return,
+            VoronoiOverlay::Area => {
+                let t = (cell.area() / voronoi.mean_area - AREA_RANGE.0)
+                    / (AREA_RANGE.1 - AREA_RANGE.0);
+                viridis(t as f32).into()
+            }
+            VoronoiOverlay::Neighbours => neighbour_color(cell.neighbour_count()),
+        };
+        let points = cell
+            .vertices
+            .iter()
+            .chain(cell.vertices.first())
+            .map(|v| (*center + (*v - *center) * (1. - SHRINK)).as_vec2());
+        gizmos.linestrip_2d(points, color);
+    }
+}
+
+fn voronoi_window(
+    mut contexts: EguiContexts,
+    mut overlay: ResMut<VoronoiOverlay>,
+    mut voronoi: ResMut<Voronoi>,
+) {
+    let voronoi = &mut *voronoi;
+    voronoi.shown = false;
+    egui::Window::new("Voronoi cells")
+        .default_pos([10., 1400.])
+        .default_open(false)
+        .show(contexts.ctx_mut(), |ui| {
+            voronoi.shown = true;
+            let mut selected = *overlay;
+            egui::ComboBox::from_label("Overlay")
+                .selected_text(selected.label())
+                .show_ui(ui, |ui| {
+                    for option in VoronoiOverlay::ALL {
+                        ui.selectable_value(&mut selected, option, option.label());
+                    }
+                });
+            if selected != *overlay {
+                *overlay = selected;
+            }
+            ui.add(egui::Slider::new(&mut voronoi.every, 1..=60).text("frames between updates"));
+
+            let boundary = voronoi.cells.iter().filter(|c| c.on_boundary()).count();
+            ui.label(format!(
+                "{} cells, {boundary} of them along the walls, mean area {:.4e} m²",
+                voronoi.cells.len(),
+                voronoi.mean_area
+            ));
+            let Some(statistics) = &voronoi.statistics else {
+                return;
+            };
+            let fractions: Vec<String> = statistics
+                .neighbour_fractions()
+                .iter()
+                .map(|(n, fraction)| format!("{n}: {:.1}%", 100. * fraction))
+                .collect();
+            ui.label(format!("Neighbours away from the walls: {}", fractions.join(", ")));
+
+            let shape = statistics.shape();
+            let gamma: Vec<[f64; 2]> = shape
+                .map(|k| {
+                    (1..=400)
+                        .map(|i| i as f64 / 100.)
+                        .map(|x| [x, kinetic::k_gamma_density(k, x)])
+                        .collect()
+                })
+                .unwrap_or_default();
+            Plot::new("voronoi_areas")
+                .height(150.)
+                .legend(Legend::default())
+                .x_axis_label("(a - a_min) / (⟨a⟩ - a_min)")
+                .y_axis_label("probability density")
+                .show(ui, |plot| {
+                    plot.line(Line::new(statistics.distribution()).name("measured"));
+                    plot.line(Line::new(gamma).name("k-gamma"));
+                });
+            ui.label(format!(
+                "{} cells over {} updates, a_min = {:.4e} m²",
+                statistics.cells, statistics.updates, statistics.smallest_area
+            ));
+            match shape {
+                Some(k) => ui.label(format!("Shape of the k-gamma distribution: k = {k:.2}")),
+                None => ui.label("Too few cells to fit the distribution"),
+            };
+
+            ui.horizontal(|ui| {
+                if ui.button("Clear").clicked() {
+                    voronoi.statistics = None;
+                }
+                ui.text_edit_singleline(&mut voronoi.path);
+                if ui.button("Save").clicked() {
+                    let path = &voronoi.path;
+                    voronoi.message = Some(match voronoi.save() {
+                        Ok(()) => format!("Saved to '{path}'"),
+                        Err(e) => format!("Could not save '{path}': {e}"),
+                    });
+                }
+            });
+            if let Some(message) = &voronoi.message {
+                ui.label(message);
+            }
+        });
+}